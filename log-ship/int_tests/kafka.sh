@@ -0,0 +1,17 @@
+#!/bin/bash
+
+set -e
+
+# start a single-node Kafka-compatible broker
+docker run -d --rm --name log-ship-kafka -p 9092:9092 docker.redpanda.com/redpandadata/redpanda:latest \
+  redpanda start --overprovisioned --smp 1 --memory 512M --node-id 0 --check=false \
+  --kafka-addr PLAINTEXT://0.0.0.0:9092 --advertise-kafka-addr PLAINTEXT://localhost:9092
+
+# cleanup the broker when we're done
+trap "docker stop log-ship-kafka" EXIT
+
+# give the broker a chance to start
+sleep 10
+
+# run the tests that require a broker
+KAFKA_BROKERS=localhost:9092 cargo test kafka -- --ignored
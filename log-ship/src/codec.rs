@@ -0,0 +1,255 @@
+//! Serialization of events by outputs
+//!
+//! Outputs that write a stream of events accept the same `codec` and `framing` args, which control how each event
+//! is serialized, and how one serialized event is separated from the next.
+//! Non-JSON (string) events are written as-is by every codec except `msgpack`, which encodes them as a string.
+
+use anyhow::{anyhow, bail, Context, Result};
+use toml::Value;
+
+use crate::Args;
+use crate::event::{Event, JsonValue};
+use crate::template::{event_time, needs_quotes, Template};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Codec {
+    Json,
+    JsonPretty,
+    Logfmt,
+    Raw(String),
+    Csv(Vec<String>),
+    Msgpack,
+    Template(Template),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    Newline,
+    Null,
+    LengthPrefix,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputCodec {
+    codec: Codec,
+    framing: Framing,
+    ts_field: String,
+}
+
+impl OutputCodec {
+    /// Parses the codec args for the given output
+    pub fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        let codec = args.get("codec").unwrap_or(&Value::String("json".to_string())).to_owned();
+        let codec = codec.as_str().ok_or_else(|| anyhow!("The 'codec' arg for {} does not appear to be a string", plugin_name))?;
+
+        let codec = match codec {
+            "json" => Codec::Json,
+            "json_pretty" => Codec::JsonPretty,
+            "logfmt" => Codec::Logfmt,
+            "raw" => {
+                let field = args.get("field").unwrap_or(&Value::String("message".to_string())).to_owned();
+                let field = field.as_str().ok_or_else(|| anyhow!("The 'field' arg for {} does not appear to be a string", plugin_name))?;
+
+                Codec::Raw(field.to_string())
+            }
+            "csv" => {
+                let columns = args.get("columns").ok_or_else(|| anyhow!("The 'columns' arg is required for the csv codec for {}", plugin_name))?;
+                let columns = columns.as_array()
+                    .and_then(|a| a.iter().map(|c| c.as_str().map(|s| s.to_string())).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| anyhow!("The 'columns' arg for {} must be an array of strings", plugin_name))?;
+
+                if columns.is_empty() {
+                    bail!("The 'columns' arg for {} must contain at least one column", plugin_name);
+                }
+
+                Codec::Csv(columns)
+            }
+            "msgpack" => Codec::Msgpack,
+            "template" => {
+                let template = args.get("template").ok_or_else(|| anyhow!("The 'template' arg is required for the template codec for {}", plugin_name))?;
+                let template = template.as_str().ok_or_else(|| anyhow!("The 'template' arg for {} does not appear to be a string", plugin_name))?;
+
+                Codec::Template(Template::parse(template).with_context(|| format!("Parsing the 'template' arg for {}", plugin_name))?)
+            }
+            _ => bail!("Unknown codec '{}' for {}; use one of: json, json_pretty, logfmt, raw, csv, msgpack, template", codec, plugin_name)
+        };
+
+        let framing = args.get("framing").unwrap_or(&Value::String("newline".to_string())).to_owned();
+        let framing = framing.as_str().ok_or_else(|| anyhow!("The 'framing' arg for {} does not appear to be a string", plugin_name))?;
+
+        let framing = match framing {
+            "newline" => Framing::Newline,
+            "null" => Framing::Null,
+            "length_prefix" => Framing::LengthPrefix,
+            _ => bail!("Unknown framing '{}' for {}; use one of: newline, null, length_prefix", framing, plugin_name)
+        };
+
+        let ts_field = args.get("ts_field").unwrap_or(&Value::String("t".to_string())).to_owned();
+        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for {} does not appear to be a string", plugin_name))?.to_string();
+
+        Ok(OutputCodec { codec, framing, ts_field })
+    }
+
+    /// Serializes the event, without any framing
+    pub fn serialize(&self, event: &Event) -> Result<Vec<u8>> {
+        let json = match (event, &self.codec) {
+            (Event::None, _) => bail!("Cannot serialize a None event"),
+            (Event::String(s), Codec::Msgpack) => return Ok(rmp_serde::to_vec(s)?),
+            (Event::String(s), _) => return Ok(s.as_bytes().to_vec()),
+            (Event::Json(json), _) => json
+        };
+
+        let ret = match &self.codec {
+            Codec::Json => serde_json::to_vec(json)?,
+            Codec::JsonPretty => serde_json::to_vec_pretty(json)?,
+            Codec::Logfmt => {
+                let obj = json.as_object().ok_or_else(|| anyhow!("Only JSON objects can be written as logfmt"))?;
+
+                obj.iter()
+                   .map(|(k, v)| format!("{}={}", k, logfmt_value(v)))
+                   .collect::<Vec<_>>()
+                   .join(" ")
+                   .into_bytes()
+            }
+            Codec::Raw(field) => {
+                match json.get(field) {
+                    None => bail!("Field '{}' not found in event", field),
+                    Some(JsonValue::String(s)) => s.as_bytes().to_vec(),
+                    Some(v) => v.to_string().into_bytes()
+                }
+            }
+            Codec::Csv(columns) => {
+                columns.iter()
+                       .map(|c| csv_value(json.get(c)))
+                       .collect::<Vec<_>>()
+                       .join(",")
+                       .into_bytes()
+            }
+            Codec::Msgpack => rmp_serde::to_vec_named(json)?,
+            Codec::Template(template) => template.render_line(event, &event_time(event, self.ts_field.as_str())).into_bytes()
+        };
+
+        Ok(ret)
+    }
+
+    /// Serializes and frames the event, ready to be written
+    pub fn encode(&self, event: &Event) -> Result<Vec<u8>> {
+        let mut ret = self.serialize(event)?;
+
+        match self.framing {
+            Framing::Newline => ret.push(b'\n'),
+            Framing::Null => ret.push(0),
+            Framing::LengthPrefix => {
+                let len = u32::try_from(ret.len()).map_err(|_| anyhow!("Event too large to frame: {} bytes", ret.len()))?;
+                ret.splice(0..0, len.to_be_bytes());
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+fn logfmt_value(value: &JsonValue) -> String {
+    match value {
+        JsonValue::Null => String::new(),
+        JsonValue::String(s) if needs_quotes(s) || s.contains('=') => value.to_string(),
+        JsonValue::String(s) => s.clone(),
+        JsonValue::Array(_) | JsonValue::Object(_) => JsonValue::String(value.to_string()).to_string(),
+        v => v.to_string()
+    }
+}
+
+fn csv_value(value: Option<&JsonValue>) -> String {
+    let value = match value {
+        None | Some(JsonValue::Null) => return String::new(),
+        Some(JsonValue::String(s)) => s.clone(),
+        Some(v) => v.to_string()
+    };
+
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+
+#[cfg(test)]
+mod codec_tests {
+    use serde_json::json;
+    use toml::Value;
+
+    use crate::Args;
+    use crate::codec::OutputCodec;
+    use crate::event::Event;
+
+    fn codec(args: &[(&str, Value)]) -> OutputCodec {
+        let mut codec_args = Args::new();
+
+        for (k, v) in args {
+            codec_args.insert(k.to_string(), v.clone());
+        }
+
+        OutputCodec::from_args(&codec_args, "test").expect("Error parsing codec args")
+    }
+
+    fn codec_str(name: &str) -> OutputCodec {
+        codec(&[("codec", Value::String(name.to_string()))])
+    }
+
+    fn serialize(codec: &OutputCodec, event: &Event) -> String {
+        String::from_utf8(codec.serialize(event).expect("Error serializing")).unwrap()
+    }
+
+    #[test]
+    fn text_codecs() {
+        let event = Event::Json(json!({"host": "web1", "message": "disk full, \"sda\"", "n": 3, "tags": ["a"], "x": null}));
+
+        assert_eq!(r#"{"host":"web1","message":"disk full, \"sda\"","n":3,"tags":["a"],"x":null}"#, serialize(&codec(&[]), &event));
+        assert!(serialize(&codec_str("json_pretty"), &event).contains("\n  \"host\": \"web1\",\n"));
+        assert_eq!(r#"host=web1 message="disk full, \"sda\"" n=3 tags="[\"a\"]" x="#, serialize(&codec_str("logfmt"), &event));
+        assert_eq!("disk full, \"sda\"", serialize(&codec_str("raw"), &event));
+
+        let csv = codec(&[("codec", Value::String("csv".to_string())),
+                          ("columns", Value::Array(vec![Value::String("host".to_string()), Value::String("missing".to_string()), Value::String("message".to_string())]))]);
+        assert_eq!(r#"web1,,"disk full, ""sda""""#, serialize(&csv, &event));
+
+        let template = codec(&[("codec", Value::String("template".to_string())), ("template", Value::String("{host} {n}: {+message}".to_string()))]);
+        assert_eq!("web1 3: disk full, \"sda\"", serialize(&template, &event));
+
+        // strings are written as-is
+        assert_eq!("plain line", serialize(&codec_str("logfmt"), &Event::from("plain line")));
+    }
+
+    #[test]
+    fn msgpack() {
+        let event = Event::Json(json!({"a": 1}));
+        let encoded = codec_str("msgpack").serialize(&event).expect("Error serializing");
+        let decoded: serde_json::Value = rmp_serde::from_slice(encoded.as_slice()).expect("Error decoding");
+
+        assert_eq!(json!({"a": 1}), decoded);
+    }
+
+    #[test]
+    fn framing() {
+        let event = Event::from("abc");
+
+        assert_eq!(b"abc\n".to_vec(), codec(&[]).encode(&event).unwrap());
+        assert_eq!(b"abc\0".to_vec(), codec(&[("framing", Value::String("null".to_string()))]).encode(&event).unwrap());
+        assert_eq!(b"\0\0\0\x03abc".to_vec(), codec(&[("framing", Value::String("length_prefix".to_string()))]).encode(&event).unwrap());
+    }
+
+    #[test]
+    fn errors() {
+        let mut args = Args::new();
+
+        args.insert("codec".to_string(), Value::String("yaml".to_string()));
+        assert!(OutputCodec::from_args(&args, "test").is_err());
+
+        args.insert("codec".to_string(), Value::String("csv".to_string()));
+        assert!(OutputCodec::from_args(&args, "test").is_err());
+
+        assert!(codec_str("raw").serialize(&Event::Json(json!({"other": 1}))).is_err());
+        assert!(codec_str("logfmt").serialize(&Event::Json(json!([1, 2]))).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use anyhow::{anyhow, Result};
+
 pub mod logging;
 pub mod config_utils;
 
@@ -8,6 +10,15 @@ pub use logging::{setup, setup_with_level, FilterLevel, error, debug, info, warn
 pub use logging::test::init_test_logger;
 
 
+/// Reads the hostname of the local machine
+pub fn hostname() -> Result<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|h| h.trim().to_string())
+        .map_err(|e| anyhow!("Unable to find the hostname: {}", e))
+}
+
+
 #[macro_export]
 macro_rules! duration_ms {
     ($exp:expr) => {{
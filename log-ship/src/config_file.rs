@@ -74,6 +74,17 @@ impl ConfigFile {
             } else {
                 bail!("Output {} not found for route {}", route.output, route.name);
             }
+
+            if let Some(dead_letter) = route.dead_letter.as_ref() {
+                if self.outputs.iter().any(|o| o.name == *dead_letter) {
+                    if print_route {
+                        println!("  ⮱ DEAD LETTER: {}", dead_letter);
+                        println!();
+                    }
+                } else {
+                    bail!("Dead-letter output {} not found for route {}", dead_letter, route.name);
+                }
+            }
         }
 
         Ok( () )
@@ -150,5 +161,8 @@ pub struct Route {
     pub transforms: Vec<String>,
 
     pub output: String,
+
+    /// Output for events that cannot be processed
+    pub dead_letter: Option<String>,
 }
 
@@ -1,9 +1,11 @@
 use std::fmt::{Display, Formatter};
 
+use serde::{Deserialize, Serialize};
+
 // pub type JsonValue = simd_json::value::owned::Value;
 pub type JsonValue = serde_json::Value;
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub enum Event {
     None,
     Json(JsonValue),
@@ -0,0 +1,319 @@
+//! Paths used to address fields nested inside an event
+//!
+//! A path is a list of object keys separated by `.`, with `[n]` to index into an array: `a.b[0].c`.
+//! Use `\` to escape a `.` or `[` that is part of a key, like the `cpu0\.user` keys emitted by the `metrics` input.
+//! The last key of a path passed to `remove_glob` can contain `*` and `?` wildcards.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Map;
+
+use crate::event::JsonValue;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldPath {
+    segments: Vec<Segment>,
+}
+
+impl FieldPath {
+    /// Parses a path, ensuring there are no empty keys, and all indexes are closed numbers
+    pub fn parse(path: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut key: Option<String> = None;
+        let mut need_key = true; // at the start, or after a `.`
+        let mut chars = path.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => {
+                    match key.take() {
+                        Some(k) => segments.push(Segment::Key(k)),
+                        None if need_key => bail!("Empty key in path '{}'", path),
+                        None => ()
+                    }
+
+                    need_key = true;
+                }
+                '[' => {
+                    match key.take() {
+                        Some(k) => segments.push(Segment::Key(k)),
+                        None if need_key => bail!("Index without a key in path '{}'", path),
+                        None => ()
+                    }
+
+                    let mut index = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some(c) => index.push(c),
+                            None => bail!("Unclosed '[' in path '{}'", path)
+                        }
+                    }
+
+                    let index = index.trim().parse::<usize>().map_err(|_| anyhow!("Invalid index '{}' in path '{}'", index, path))?;
+
+                    segments.push(Segment::Index(index));
+                    need_key = false;
+                }
+                ']' => bail!("Unexpected ']' in path '{}'", path),
+                c => {
+                    if key.is_none() && !need_key {
+                        bail!("Expected '.' or '[' after an index in path '{}'", path);
+                    }
+
+                    let c = if c == '\\' {
+                        chars.next().ok_or_else(|| anyhow!("Path '{}' ends with an escape", path))?
+                    } else {
+                        c
+                    };
+
+                    key.get_or_insert_with(String::new).push(c);
+                    need_key = false;
+                }
+            }
+        }
+
+        match key {
+            Some(k) => segments.push(Segment::Key(k)),
+            None if need_key => bail!("Empty key in path '{}'", path),
+            None => ()
+        }
+
+        Ok(FieldPath { segments })
+    }
+
+    /// Creates a path from a list of keys, without any parsing
+    pub fn from_keys<S: Into<String>>(keys: impl IntoIterator<Item=S>) -> Self {
+        FieldPath { segments: keys.into_iter().map(|k| Segment::Key(k.into())).collect() }
+    }
+
+    /// Returns a new path with the key added to the end
+    pub fn join(&self, key: &str) -> Self {
+        let mut segments = self.segments.clone();
+        segments.push(Segment::Key(key.to_string()));
+
+        FieldPath { segments }
+    }
+
+    /// The last key in the path, if it doesn't end with an index
+    pub fn last_key(&self) -> Option<&str> {
+        match self.segments.last() {
+            Some(Segment::Key(k)) => Some(k.as_str()),
+            _ => None
+        }
+    }
+
+    pub fn get<'a>(&self, value: &'a JsonValue) -> Option<&'a JsonValue> {
+        self.segments.iter().try_fold(value, |v, segment| match segment {
+            Segment::Key(k) => v.as_object()?.get(k),
+            Segment::Index(i) => v.as_array()?.get(*i),
+        })
+    }
+
+    pub fn get_mut<'a>(&self, value: &'a mut JsonValue) -> Option<&'a mut JsonValue> {
+        self.segments.iter().try_fold(value, |v, segment| match segment {
+            Segment::Key(k) => v.as_object_mut()?.get_mut(k),
+            Segment::Index(i) => v.as_array_mut()?.get_mut(*i),
+        })
+    }
+
+    /// Inserts the value, creating any missing objects along the path, and replacing any existing value
+    ///
+    /// An index can replace an element of an array, or append to it when it's equal to the length of the array.
+    pub fn insert(&self, root: &mut JsonValue, value: JsonValue) -> Result<()> {
+        let (last, parents) = self.segments.split_last().ok_or_else(|| anyhow!("Cannot insert with an empty path"))?;
+        let mut cur = root;
+
+        for segment in parents {
+            cur = match segment {
+                Segment::Key(k) => {
+                    cur.as_object_mut()
+                       .ok_or_else(|| anyhow!("Cannot insert '{}' into a non-object", k))?
+                       .entry(k.clone())
+                       .or_insert_with(|| JsonValue::Object(Map::new()))
+                }
+                Segment::Index(i) => {
+                    cur.as_array_mut()
+                       .and_then(|a| a.get_mut(*i))
+                       .ok_or_else(|| anyhow!("Index {} not found", i))?
+                }
+            };
+        }
+
+        match last {
+            Segment::Key(k) => {
+                cur.as_object_mut()
+                   .ok_or_else(|| anyhow!("Cannot insert '{}' into a non-object", k))?
+                   .insert(k.clone(), value);
+            }
+            Segment::Index(i) => {
+                let array = cur.as_array_mut().ok_or_else(|| anyhow!("Cannot insert index {} into a non-array", i))?;
+
+                if *i < array.len() {
+                    array[*i] = value;
+                } else if *i == array.len() {
+                    array.push(value);
+                } else {
+                    bail!("Index {} is past the end of the array", i);
+                }
+            }
+        }
+
+        Ok( () )
+    }
+
+    /// Removes the value at the path, returning it
+    pub fn remove(&self, root: &mut JsonValue) -> Option<JsonValue> {
+        let (last, parent) = self.split_last()?;
+        let parent = parent.get_mut(root)?;
+
+        match last {
+            Segment::Key(k) => parent.as_object_mut()?.remove(k),
+            Segment::Index(i) => {
+                let array = parent.as_array_mut()?;
+
+                if *i < array.len() {
+                    Some(array.remove(*i))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Removes all the keys matching the wildcards in the last key of the path, returning the number removed
+    pub fn remove_glob(&self, root: &mut JsonValue) -> usize {
+        let pattern = match self.last_key() {
+            Some(k) if k.contains(['*', '?']) => k,
+            _ => return self.remove(root).map(|_| 1).unwrap_or(0),
+        };
+
+        let obj = match self.split_last().and_then(|(_, parent)| parent.get_mut(root)).and_then(|p| p.as_object_mut()) {
+            Some(obj) => obj,
+            None => return 0
+        };
+
+        let before = obj.len();
+
+        obj.retain(|k, _| !glob_match(pattern, k));
+
+        before - obj.len()
+    }
+
+    fn split_last(&self) -> Option<(&Segment, FieldPath)> {
+        let (last, parents) = self.segments.split_last()?;
+
+        Some((last, FieldPath { segments: parents.to_vec() }))
+    }
+}
+
+/// Matches a string against a pattern with `*` (any run of characters) and `?` (any single character)
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    let (mut p, mut i) = (0, 0);
+
+    // the position of the last '*' in the pattern, and of the string it's matched up to
+    let mut star = None;
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                // have the last '*' match one more character, and try the rest of the pattern again
+                Some((star_p, star_i)) => {
+                    star = Some((star_p, star_i + 1));
+                    p = star_p + 1;
+                    i = star_i + 1;
+                }
+                None => return false
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+
+#[cfg(test)]
+mod field_path_tests {
+    use serde_json::json;
+
+    use crate::field_path::{FieldPath, glob_match, Segment};
+
+    #[test]
+    fn parse() {
+        let path = FieldPath::parse("a.b[0][2].c\\.d").expect("Error parsing path");
+
+        assert_eq!(vec![Segment::Key("a".to_string()), Segment::Key("b".to_string()), Segment::Index(0), Segment::Index(2), Segment::Key("c.d".to_string())], path.segments);
+
+        for bad in ["", "a.", ".a", "a..b", "[0]", "a.[0]", "a[0]b", "a[x]", "a[0", "a]", "a\\"] {
+            assert!(FieldPath::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn get_insert_remove() {
+        let mut event = json!({"a": {"b": [{"c": 1}, {"c": 2}]}, "cpu0.user": 3.5});
+
+        assert_eq!(Some(&json!(2)), FieldPath::parse("a.b[1].c").unwrap().get(&event));
+        assert_eq!(Some(&json!(3.5)), FieldPath::parse("cpu0\\.user").unwrap().get(&event));
+        assert_eq!(None, FieldPath::parse("a.b[2].c").unwrap().get(&event));
+
+        FieldPath::parse("a.b[0].d").unwrap().insert(&mut event, json!("x")).unwrap();
+        FieldPath::parse("a.b[2]").unwrap().insert(&mut event, json!(3)).unwrap();
+        FieldPath::parse("x.y.z").unwrap().insert(&mut event, json!(true)).unwrap();
+        assert!(FieldPath::parse("a.b[5]").unwrap().insert(&mut event, json!(5)).is_err());
+        assert!(FieldPath::parse("x.y.z.w").unwrap().insert(&mut event, json!(5)).is_err());
+
+        assert_eq!(Some(json!(1)), FieldPath::parse("a.b[0].c").unwrap().remove(&mut event));
+        assert_eq!(Some(json!({"c": 2})), FieldPath::parse("a.b[1]").unwrap().remove(&mut event));
+        assert_eq!(None, FieldPath::parse("a.missing").unwrap().remove(&mut event));
+
+        assert_eq!(json!({"a": {"b": [{"d": "x"}, 3]}, "cpu0.user": 3.5, "x": {"y": {"z": true}}}), event);
+    }
+
+    #[test]
+    fn remove_glob() {
+        let mut event = json!({"headers": {"x-id": 1, "x-trace": 2, "host": "h"}, "debug_a": 1, "debug_b": 2, "msg": "m"});
+
+        assert_eq!(2, FieldPath::parse("headers.x-*").unwrap().remove_glob(&mut event));
+        assert_eq!(2, FieldPath::parse("debug_?").unwrap().remove_glob(&mut event));
+        assert_eq!(1, FieldPath::parse("msg").unwrap().remove_glob(&mut event));
+        assert_eq!(0, FieldPath::parse("missing.*").unwrap().remove_glob(&mut event));
+
+        assert_eq!(json!({"headers": {"host": "h"}}), event);
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("x-*", "x-id"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b?c*", "aXXbYcZZ"));
+        assert!(!glob_match("a*b?c", "aXXbYcZZ"));
+        assert!(!glob_match("?", ""));
+
+        // '?' matches a character, not a byte
+        assert!(glob_match("caf?", "café"));
+        assert!(glob_match("??", "日本"));
+        assert!(!glob_match("???", "日本"));
+
+        // doesn't backtrack exponentially
+        let key = "a".repeat(10_000);
+        assert!(!glob_match("*a*a*a*a*a*a*a*a*b", key.as_str()));
+        assert!(glob_match("*a*a*a*a*a*a*a*a*", key.as_str()));
+    }
+}
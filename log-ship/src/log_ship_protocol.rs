@@ -0,0 +1,349 @@
+//! The framed protocol used to forward events from one log-ship to another
+//!
+//! Every frame is a single type byte, followed by a big-endian u32 length, followed by the payload:
+//! - `H` hello: protocol version byte, then the shared token; sent by the client when it connects
+//! - `O` ok: empty; sent by the server when the token matches
+//! - `R` reject: the reason; sent by the server before closing the connection
+//! - `B` batch: big-endian u64 sequence number, compression byte, then the (compressed) JSON array of events
+//! - `A` ack: big-endian u64 sequence number; sent by the server once every event in the batch has been processed
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::Compression as GzLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::event::Event;
+
+const PROTO_VERSION: u8 = 1;
+
+const CODE_HELLO: u8 = b'H';
+const CODE_OK: u8 = b'O';
+const CODE_REJECT: u8 = b'R';
+const CODE_BATCH: u8 = b'B';
+const CODE_ACK: u8 = b'A';
+
+const HEADER_LEN: usize = 5;
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(compression: &str) -> Result<Self> {
+        match compression {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => bail!("Unknown compression '{}'; use one of: none, gzip, zstd", compression)
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> io::Result<Self> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown compression code: {}", code)))
+        }
+    }
+
+    fn compress(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+                encoder.write_all(data.as_slice())?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(data.as_slice(), 0)
+        }
+    }
+
+    /// Decompresses the data, failing if it's larger than `MAX_FRAME_LEN`, so a small frame can't expand without limit
+    fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+
+        // read one more byte than the limit, to know if it's been exceeded
+        let limit = MAX_FRAME_LEN as u64 + 1;
+
+        match self {
+            Compression::None => return Ok(data.to_vec()),
+            Compression::Gzip => GzDecoder::new(data).take(limit).read_to_end(&mut buf)?,
+            Compression::Zstd => zstd::stream::read::Decoder::new(data)?.take(limit).read_to_end(&mut buf)?
+        };
+
+        if buf.len() > MAX_FRAME_LEN {
+            return Err(invalid_data(format!("Decompressed batch is larger than {} bytes", MAX_FRAME_LEN).as_str()));
+        }
+
+        Ok(buf)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Hello { version: u8, token: String },
+    Ok,
+    Reject(String),
+    Batch { seq: u64, events: Vec<Event> },
+    Ack(u64),
+}
+
+impl Frame {
+    pub fn hello(token: &str) -> Self {
+        Frame::Hello { version: PROTO_VERSION, token: token.to_string() }
+    }
+
+    /// Checks the version and token of a hello frame, returning the reason it's not acceptable
+    pub fn check_hello(&self, expected_token: &str) -> std::result::Result<(), String> {
+        match self {
+            Frame::Hello { version, token } => {
+                if *version != PROTO_VERSION {
+                    Err(format!("Unsupported protocol version: {}", version))
+                } else if !constant_time_eq(token.as_bytes(), expected_token.as_bytes()) {
+                    Err("Invalid token".to_string())
+                } else {
+                    Ok( () )
+                }
+            }
+            _ => Err("Expected hello".to_string())
+        }
+    }
+}
+
+/// Compares the bytes in time that only depends upon their length, so the token can't be guessed by timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The error when a frame is larger than `MAX_FRAME_LEN`, so it can never be sent
+#[derive(Debug)]
+pub struct FrameTooLarge(usize);
+
+impl Display for FrameTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Frame too large: {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for FrameTooLarge { }
+
+/// Returns true if encoding failed because the frame is too large, instead of an I/O error
+pub fn is_frame_too_large(e: &io::Error) -> bool {
+    e.get_ref().map(|e| e.is::<FrameTooLarge>()).unwrap_or(false)
+}
+
+/// Codec for reading and writing frames; batches are compressed with the given compression
+pub struct LogShipCodec {
+    compression: Compression,
+}
+
+impl LogShipCodec {
+    pub fn new(compression: Compression) -> Self {
+        LogShipCodec { compression }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Decoder for LogShipCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_LEN {
+            return Ok(None)
+        }
+
+        let code = src[0];
+        let len = BigEndian::read_u32(&src[1..HEADER_LEN]) as usize;
+
+        if len > MAX_FRAME_LEN {
+            return Err(invalid_data(format!("Frame too large: {} bytes", len).as_str()));
+        }
+
+        if src.len() < HEADER_LEN + len {
+            // wait for the rest of the frame
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None)
+        }
+
+        src.advance(HEADER_LEN);
+        let payload = src.split_to(len);
+
+        let frame = match code {
+            CODE_HELLO => {
+                if payload.is_empty() {
+                    return Err(invalid_data("Empty hello frame"));
+                }
+
+                let token = String::from_utf8(payload[1..].to_vec()).map_err(|_| invalid_data("Token is not UTF-8"))?;
+
+                Frame::Hello { version: payload[0], token }
+            }
+            CODE_OK => Frame::Ok,
+            CODE_REJECT => Frame::Reject(String::from_utf8_lossy(payload.as_ref()).into_owned()),
+            CODE_BATCH => {
+                if payload.len() < 9 {
+                    return Err(invalid_data("Batch frame too short"));
+                }
+
+                let seq = BigEndian::read_u64(&payload[0..8]);
+                let body = Compression::from_code(payload[8])?.decompress(&payload[9..])?;
+                let events = serde_json::from_slice(body.as_slice())?;
+
+                Frame::Batch { seq, events }
+            }
+            CODE_ACK => {
+                if payload.len() != 8 {
+                    return Err(invalid_data("Ack frame must be 8 bytes"));
+                }
+
+                Frame::Ack(BigEndian::read_u64(payload.as_ref()))
+            }
+            _ => return Err(invalid_data(format!("Unknown frame type: {}", code).as_str()))
+        };
+
+        Ok(Some(frame))
+    }
+}
+
+impl Encoder<Frame> for LogShipCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let (code, payload) = match frame {
+            Frame::Hello { version, token } => {
+                let mut payload = vec![version];
+                payload.extend_from_slice(token.as_bytes());
+                (CODE_HELLO, payload)
+            }
+            Frame::Ok => (CODE_OK, Vec::new()),
+            Frame::Reject(reason) => (CODE_REJECT, reason.into_bytes()),
+            Frame::Batch { seq, events } => {
+                let body = self.compression.compress(serde_json::to_vec(&events)?)?;
+                let mut payload = Vec::with_capacity(9 + body.len());
+
+                payload.put_u64(seq);
+                payload.put_u8(self.compression.code());
+                payload.extend_from_slice(body.as_slice());
+
+                (CODE_BATCH, payload)
+            }
+            Frame::Ack(seq) => (CODE_ACK, seq.to_be_bytes().to_vec())
+        };
+
+        if payload.len() > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge(payload.len())));
+        }
+
+        dst.reserve(HEADER_LEN + payload.len());
+        dst.put_u8(code);
+        dst.put_u32(payload.len() as u32);
+        dst.extend_from_slice(payload.as_slice());
+
+        Ok( () )
+    }
+}
+
+
+#[cfg(test)]
+mod log_ship_protocol_tests {
+    use bytes::BytesMut;
+    use serde_json::json;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::event::Event;
+    use crate::log_ship_protocol::{Compression, Frame, LogShipCodec, MAX_FRAME_LEN, constant_time_eq, is_frame_too_large};
+
+    #[test]
+    fn round_trip() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let mut codec = LogShipCodec::new(compression);
+            let frames = vec![
+                Frame::hello("secret"),
+                Frame::Ok,
+                Frame::Reject("Invalid token".to_string()),
+                Frame::Batch { seq: 7, events: vec![Event::Json(json!({"a": 1, "b": [true, null]})), Event::from("hello")] },
+                Frame::Ack(7),
+            ];
+            let mut buf = BytesMut::new();
+
+            for frame in frames.iter() {
+                codec.encode(frame.clone(), &mut buf).expect("Error encoding");
+            }
+
+            // a partial frame should not decode
+            let mut partial = BytesMut::from(&buf[0..3]);
+            assert_eq!(None, codec.decode(&mut partial).expect("Error decoding"));
+
+            for frame in frames.into_iter() {
+                assert_eq!(Some(frame), codec.decode(&mut buf).expect("Error decoding"));
+            }
+
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn check_hello() {
+        assert!(Frame::hello("secret").check_hello("secret").is_ok());
+        assert!(Frame::hello("wrong").check_hello("secret").is_err());
+        assert!(Frame::Hello { version: 99, token: "secret".to_string() }.check_hello("secret").is_err());
+        assert!(Frame::Ok.check_hello("secret").is_err());
+
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+
+    #[test]
+    fn size_limits() {
+        // a batch that's too large can't be encoded, and the error says so
+        let mut codec = LogShipCodec::new(Compression::None);
+        let mut buf = BytesMut::new();
+        let events = vec![Event::String("x".repeat(MAX_FRAME_LEN))];
+        let err = codec.encode(Frame::Batch { seq: 1, events }, &mut buf).expect_err("Expected an error");
+
+        assert!(is_frame_too_large(&err));
+        assert!(buf.is_empty());
+
+        // a small frame that decompresses to more than the limit is rejected
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let events = vec![Event::String("x".repeat(MAX_FRAME_LEN / 2)), Event::String("x".repeat(MAX_FRAME_LEN / 2))];
+
+            LogShipCodec::new(compression).encode(Frame::Batch { seq: 1, events }, &mut buf).expect("Error encoding");
+
+            let err = LogShipCodec::new(compression).decode(&mut buf).expect_err("Expected an error");
+
+            assert!(!is_frame_too_large(&err));
+            assert!(err.to_string().contains("Decompressed batch"), "{}", err);
+            buf.clear();
+        }
+    }
+}
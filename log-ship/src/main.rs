@@ -10,7 +10,7 @@ use stream_cancel::Tripwire;
 use futures::future::join_all;
 use tokio::signal;
 use tokio::signal::unix::SignalKind;
-use tokio::sync::Semaphore;
+use tokio::sync::{broadcast, Semaphore};
 
 use plugins::*; // import all the plugins
 
@@ -19,7 +19,7 @@ use crate::common::logging::{setup_with_level, FilterLevel, debug, error, info,
 use crate::common::logging::setup_with_level_location;
 
 use crate::config_file::{ConfigFile, merge_globals};
-use crate::plugin::{Args, Plugin};
+use crate::plugin::{Args, DeadLetter, Plugin};
 
 mod common;
 mod config_file;
@@ -27,6 +27,10 @@ mod plugins;
 mod plugin;
 mod event;
 mod lumberjack_decoder;
+mod template;
+mod codec;
+mod log_ship_protocol;
+mod field_path;
 
 const CONFIG_FILE_NAME: &str = "log-ship.toml";
 
@@ -119,6 +123,9 @@ async fn main() -> anyhow::Result<()> {
         Metrics::name() => Metrics::factory(),
         UdpSocketInput::name() => UdpSocketInput::factory(),
         LumberjackInput::name() => LumberjackInput::factory(),
+        KafkaInput::name() => KafkaInput::factory(),
+        LogShipInput::name() => LogShipInput::factory(),
+        PrometheusScrapeInput::name() => PrometheusScrapeInput::factory(),
     };
     let output_plugins = hashmap! {
         StdOutput::name() => StdOutput::factory(),
@@ -126,6 +133,9 @@ async fn main() -> anyhow::Result<()> {
         UnixSocketOutput::name() => UnixSocketOutput::factory(),
         SpeedTest::name() => SpeedTest::factory(),
         FileOutput::name() => FileOutput::factory(),
+        ElasticsearchOutput::name() => ElasticsearchOutput::factory(),
+        KafkaOutput::name() => KafkaOutput::factory(),
+        LogShipOutput::name() => LogShipOutput::factory(),
     };
     let transform_plugins = hashmap! {
         PythonScript::name() => PythonScript::factory(),
@@ -134,6 +144,12 @@ async fn main() -> anyhow::Result<()> {
         LogFmtParser::name() => LogFmtParser::factory(),
         SyslogParser::name() => SyslogParser::factory(),
         FortinetParser::name() => FortinetParser::factory(),
+        GrokTransform::name() => GrokTransform::factory(),
+        WebServerTransform::name() => WebServerTransform::factory(),
+        ParseTimestampTransform::name() => ParseTimestampTransform::factory(),
+        FieldsTransform::name() => FieldsTransform::factory(),
+        KvTransform::name() => KvTransform::factory(),
+        CoerceTransform::name() => CoerceTransform::factory(),
     };
 
     info!("Starting log-ship with config file: {}", config_file_path.display());
@@ -182,6 +198,29 @@ async fn main() -> anyhow::Result<()> {
         let mut output_plugin = output_plugins.get(plugin_type.as_str())
             .ok_or_else(|| anyhow!("No output plugin of type {} found", plugin_type))?(args.clone(), tripwire.clone())?;
 
+        // setup the optional dead-letter output, and connect it to the inputs, transforms & output
+        let dead_letter_plugin = match route.dead_letter.as_ref() {
+            None => None,
+            Some(dead_letter) => {
+                let (plugin_type, args) = output_configs.get(dead_letter.as_str())
+                    .ok_or_else(|| anyhow!("In route {}, the dead-letter output {} was not found. Ensure the config file has an [[output]] entry with the appropriate name", route.name, dead_letter))?;
+                let mut plugin = output_plugins.get(plugin_type.as_str())
+                    .ok_or_else(|| anyhow!("No output plugin of type {} found", plugin_type))?(args.clone(), tripwire.clone())?;
+
+                let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+                plugin.connect_receiver(sender.subscribe());
+
+                for p in input_transform_list.iter_mut() {
+                    p.connect_dead_letter(DeadLetter::new(sender.clone(), semaphore.clone()));
+                }
+
+                output_plugin.connect_dead_letter(DeadLetter::new(sender.clone(), semaphore.clone()));
+
+                Some(plugin)
+            }
+        };
+
         info!("Constructed route {}", route.name);
 
         let sem_clone = route_semaphore.clone();
@@ -205,6 +244,10 @@ async fn main() -> anyhow::Result<()> {
             // call run after it's been hooked up
             plugin_join_handles.push(tokio::spawn(async move { output_plugin.run().await }));
 
+            if let Some(mut dead_letter_plugin) = dead_letter_plugin {
+                plugin_join_handles.push(tokio::spawn(async move { dead_letter_plugin.run().await }));
+            }
+
             // go backwards through the inputs calling run on them
             for mut p in input_transform_list.into_iter().rev() {
                 plugin_join_handles.push(tokio::spawn(async move { p.run().await }));
@@ -1,16 +1,20 @@
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::Utc;
+use serde_json::json;
 use stream_cancel::Tripwire;
 use tokio::runtime::Handle;
-use tokio::sync::broadcast::Receiver;
-use tokio::sync::{OwnedSemaphorePermit};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::task;
 use toml::value::Table;
 
-use crate::event::Event;
+use crate::common::logging::error;
+use crate::event::{Event, JsonValue};
 
 // TODO: convert to a struct so we can more easily get arguments
 pub type Args = Table;
@@ -32,6 +36,18 @@ impl Callback {
         Callback { callback: Box::new(|| {  } ) }
     }
 
+    /// Creates a callback shared by `count` events derived from a single event;
+    /// the original callback is only called once all of them have been called back
+    pub fn shared(count: usize, callback: Arc<Callback>) -> Arc<Self> {
+        let remaining = AtomicUsize::new(count);
+
+        Arc::new(Callback::new(move || {
+            if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                callback.call();
+            }
+        }))
+    }
+
     /// Calls the call back closure
     pub fn call(&self) {
         (self.callback)();
@@ -45,6 +61,60 @@ impl Debug for Callback {
 }
 
 
+/// Sends events that a plugin could not process to the route's dead-letter output
+#[derive(Clone)]
+pub struct DeadLetter {
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+}
+
+impl DeadLetter {
+    pub fn new(sender: Sender<ChannelType>, semaphore: Arc<Semaphore>) -> Self {
+        DeadLetter { sender, semaphore }
+    }
+
+    /// Wraps the original event in an envelope describing the error
+    pub fn envelope(plugin: &str, error: impl Display, original: Event) -> Event {
+        let original = match original {
+            Event::Json(json) => json,
+            Event::String(s) => JsonValue::from(s),
+            Event::None => JsonValue::Null
+        };
+
+        Event::Json(json!({
+            "plugin": plugin,
+            "error": error.to_string(),
+            "timestamp": Utc::now().to_rfc3339(),
+            "original": original,
+        }))
+    }
+
+    /// Sends the event to the dead-letter output; the callback is passed along, so the source advances once it's written
+    pub async fn send(&self, plugin: &str, error: impl Display, original: Event, callback: Arc<Callback>) {
+        let event = Self::envelope(plugin, error, original);
+
+        let permit = match self.semaphore.clone().acquire_owned().await {
+            Ok(p) => p,
+            Err(_) => {
+                callback.call();
+                return
+            }
+        };
+
+        if let Err(e) = self.sender.send((event, Arc::new(permit), callback)) {
+            error!("Error sending event to dead-letter output: {:?}", e.0.0);
+            e.0.2.call();
+        }
+    }
+}
+
+impl Debug for DeadLetter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DeadLetter")
+    }
+}
+
+
 #[async_trait]
 pub trait Plugin {
     /// Static method for getting the name of the plugin
@@ -76,6 +146,10 @@ pub trait Plugin {
     fn connect_receiver(&mut self, _receiver: Receiver<ChannelType>) {
         panic!("connect_receiver called on unimplemented instance");
     }
+
+    /// Connects the route's dead-letter output, for events that cannot be processed
+    /// Plugins that don't support a dead-letter output ignore it
+    fn connect_dead_letter(&mut self, _dead_letter: DeadLetter) { }
 }
 
 // macros to make things _slightly_ easier
@@ -158,3 +232,28 @@ macro_rules! send_event {
         }
     }
 }
+
+
+#[cfg(test)]
+mod plugin_tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::plugin::Callback;
+
+    #[test]
+    fn shared_callback() {
+        let called = Arc::new(AtomicUsize::new(0));
+        let called_clone = called.clone();
+        let callback = Arc::new(Callback::new(move || { called_clone.fetch_add(1, Ordering::SeqCst); }));
+
+        let shared = Callback::shared(3, callback);
+
+        shared.call();
+        shared.call();
+        assert_eq!(0, called.load(Ordering::SeqCst));
+
+        shared.call();
+        assert_eq!(1, called.load(Ordering::SeqCst));
+    }
+}
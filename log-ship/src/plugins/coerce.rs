@@ -0,0 +1,399 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::common::logging::{debug, error, warn};
+use crate::event::{Event, JsonValue};
+use crate::field_path::FieldPath;
+use crate::{Args, Plugin, send_event, connect_receiver, create_event_stream, get_receiver, recv_event, create_sender_semaphore};
+use crate::plugin::{PluginType, ChannelType, DeadLetter};
+use crate::plugins::grok::NoMatch;
+use crate::plugins::insert_field::toml2jsonvalue;
+use crate::plugins::parse_ts::{TimestampFormats, TsOutput};
+
+#[derive(Debug, Clone, PartialEq)]
+enum FieldType {
+    Int,
+    Float,
+    Bool,
+    Ip,
+    Timestamp(TimestampFormats, TsOutput),
+    String,
+}
+
+impl FieldType {
+    /// Converts the value to this type
+    fn coerce(&self, value: &JsonValue) -> Result<JsonValue> {
+        match (self, value) {
+            (FieldType::Int, JsonValue::Number(n)) if n.is_i64() || n.is_u64() => Ok(value.clone()),
+            (FieldType::Int, JsonValue::Number(n)) => {
+                n.as_f64().filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64).map(|f| JsonValue::from(f as i64)).ok_or_else(|| anyhow!("{} is not an integer", n))
+            }
+            (FieldType::Int, JsonValue::String(s)) => {
+                let s = s.trim();
+
+                s.parse::<i64>()
+                 .ok()
+                 .or_else(|| s.parse::<f64>().ok().filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64).map(|f| f as i64))
+                 .map(JsonValue::from)
+                 .ok_or_else(|| anyhow!("'{}' is not an integer", s))
+            }
+            (FieldType::Float, JsonValue::Number(n)) => n.as_f64().map(JsonValue::from).ok_or_else(|| anyhow!("{} is not a float", n)),
+            (FieldType::Float, JsonValue::String(s)) => {
+                s.trim().parse::<f64>().ok().filter(|f| f.is_finite()).map(JsonValue::from).ok_or_else(|| anyhow!("'{}' is not a float", s))
+            }
+            (FieldType::Bool, JsonValue::Bool(_)) => Ok(value.clone()),
+            (FieldType::Bool, JsonValue::Number(n)) => {
+                match n.as_i64() {
+                    Some(0) => Ok(JsonValue::from(false)),
+                    Some(1) => Ok(JsonValue::from(true)),
+                    _ => bail!("{} is not a bool", n)
+                }
+            }
+            (FieldType::Bool, JsonValue::String(s)) => {
+                match s.trim().to_ascii_lowercase().as_str() {
+                    "true" | "yes" | "on" | "1" => Ok(JsonValue::from(true)),
+                    "false" | "no" | "off" | "0" => Ok(JsonValue::from(false)),
+                    _ => bail!("'{}' is not a bool", s)
+                }
+            }
+            (FieldType::Ip, JsonValue::String(s)) => {
+                s.trim().parse::<IpAddr>().map(|ip| JsonValue::from(ip.to_string())).map_err(|_| anyhow!("'{}' is not an IP address", s))
+            }
+            (FieldType::Timestamp(formats, output), value) => {
+                formats.parse(value).and_then(|dt| output.format(&dt)).ok_or_else(|| anyhow!("{} is not a recognized timestamp", value))
+            }
+            (FieldType::String, JsonValue::String(_)) => Ok(value.clone()),
+            (FieldType::String, JsonValue::Null) => bail!("null is not a string"),
+            (FieldType::String, value) => Ok(JsonValue::from(value.to_string())),
+            (field_type, value) => bail!("{} cannot be converted to {:?}", value, field_type)
+        }
+    }
+}
+
+/// The type, and optional default, of a field
+#[derive(Debug, Clone, PartialEq)]
+struct FieldSchema {
+    name: String,
+    path: FieldPath,
+    field_type: FieldType,
+    default: Option<JsonValue>,
+}
+
+impl FieldSchema {
+    fn from_toml(name: &str, value: &Value, plugin_name: &str) -> Result<Self> {
+        let empty = Args::new();
+
+        let (type_name, table) = match value {
+            Value::String(t) => (t.as_str(), &empty),
+            Value::Table(table) => {
+                let t = table.get("type").and_then(|t| t.as_str()).ok_or_else(|| anyhow!("The '{}' field for {} requires a 'type' string", name, plugin_name))?;
+                (t, table)
+            }
+            _ => bail!("The '{}' field for {} must be a type, or a table with a 'type'", name, plugin_name)
+        };
+
+        let field_type = match type_name {
+            "int" => FieldType::Int,
+            "float" => FieldType::Float,
+            "bool" => FieldType::Bool,
+            "ip" => FieldType::Ip,
+            "timestamp" => FieldType::Timestamp(TimestampFormats::from_args(table, plugin_name)?, TsOutput::from_args(table, plugin_name)?),
+            "string" => FieldType::String,
+            t => bail!("Unknown type '{}' for the '{}' field for {}; must be one of: int, float, bool, ip, timestamp, string", t, name, plugin_name)
+        };
+
+        // make sure the default is the right type
+        let default = match table.get("default") {
+            None => None,
+            Some(d) => {
+                let d = toml2jsonvalue(d)?;
+                Some(field_type.coerce(&d).map_err(|e| anyhow!("The default for the '{}' field for {} is invalid: {}", name, plugin_name, e))?)
+            }
+        };
+
+        Ok(FieldSchema { name: name.to_string(), path: FieldPath::parse(name)?, field_type, default })
+    }
+}
+
+/// Converts fields to their declared types
+#[derive(Debug)]
+struct Coercer {
+    fields: Vec<FieldSchema>,
+    strict: bool,
+    failure: NoMatch,
+}
+
+impl Coercer {
+    fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        let fields = args.get("fields").ok_or_else(|| anyhow!("Could not find 'fields' arg for {}", plugin_name))?;
+        let fields = fields.as_table().ok_or_else(|| anyhow!("The 'fields' arg for {} does not appear to be a table", plugin_name))?;
+        let fields = fields.iter().map(|(name, value)| FieldSchema::from_toml(name, value, plugin_name)).collect::<Result<Vec<_>>>()?;
+
+        let strict = args.get("strict").unwrap_or(&Value::Boolean(false)).to_owned();
+        let strict = strict.as_bool().ok_or_else(|| anyhow!("The 'strict' arg for {} does not appear to be a bool", plugin_name))?;
+
+        let tag = args.get("tag").unwrap_or(&Value::String(format!("_{}_failure", plugin_name))).to_owned();
+        let tag = tag.as_str().ok_or_else(|| anyhow!("The 'tag' arg for {} does not appear to be a string", plugin_name))?;
+
+        Ok(Coercer { fields, strict, failure: NoMatch::Tag(tag.to_string()) })
+    }
+
+    /// Converts the fields, using the default for missing or invalid fields
+    ///
+    /// In strict mode, a field that is invalid or missing without a default returns the unchanged log and the errors.
+    /// Otherwise, missing fields are skipped, invalid fields are left as-is, and the log is tagged.
+    fn process(&self, mut json: JsonValue) -> std::result::Result<JsonValue, (JsonValue, String)> {
+        let mut updates = Vec::new();
+        let mut errors = Vec::new();
+
+        for field in self.fields.iter() {
+            let value = match field.path.get(&json) {
+                Some(value) => value,
+                None => {
+                    match &field.default {
+                        Some(default) => updates.push((&field.path, default.clone())),
+                        None if self.strict => errors.push(format!("'{}' is missing", field.name)),
+                        None => ()
+                    }
+
+                    continue
+                }
+            };
+
+            let result = field.field_type.coerce(value).map(|v| (v != *value).then_some(v));
+
+            match (result, &field.default) {
+                (Ok(Some(value)), _) => updates.push((&field.path, value)),
+                (Ok(None), _) => (),
+                (Err(_), Some(default)) => updates.push((&field.path, default.clone())),
+                (Err(e), None) => errors.push(format!("'{}' {}", field.name, e))
+            }
+        }
+
+        if self.strict && !errors.is_empty() {
+            return Err((json, errors.join("; ")));
+        }
+
+        for (path, value) in updates {
+            if let Err(e) = path.insert(&mut json, value) {
+                warn!("Error inserting field: {}", e);
+            }
+        }
+
+        if !errors.is_empty() {
+            debug!("Fields do not match the schema: {}", errors.join("; "));
+
+            if let JsonValue::Object(obj) = json {
+                json = JsonValue::Object(self.failure.apply(obj).unwrap_or_default());
+            }
+        }
+
+        Ok(json)
+    }
+}
+
+pub struct CoerceTransform {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    dead_letter: Option<DeadLetter>,
+    coercer: Coercer,
+}
+
+#[async_trait]
+impl Plugin for CoerceTransform {
+    fn name() -> &'static str {
+        "coerce"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+        debug!("CoerceTransform args: {:#?}", args);
+
+        let coercer = Coercer::from_args(&args, Self::name())?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(CoerceTransform {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            sender,
+            semaphore,
+            dead_letter: None, // set in connect_dead_letter
+            coercer,
+        }))
+    }
+
+    async fn run(&mut self) {
+        let mut event_stream = create_event_stream!(self);
+
+        // grab an event and pass it along
+        while let Some(event) = event_stream.next().await {
+            let (event, callback) = recv_event!(event);
+
+            let event = match event {
+                Event::None => {
+                    callback.call();
+                    continue // nothing to do here
+                }
+                Event::Json(json @ JsonValue::Object(_)) => {
+                    match self.coercer.process(json) {
+                        Ok(json) => Event::Json(json),
+                        Err((original, e)) => {
+                            match &self.dead_letter {
+                                Some(dead_letter) => dead_letter.send(Self::name(), e, Event::Json(original), callback).await,
+                                None => {
+                                    warn!("Dropping log that does not match the schema: {}", e);
+                                    callback.call();
+                                }
+                            }
+
+                            continue
+                        }
+                    }
+                }
+                Event::Json(_) => {
+                    error!("Invalid JSON value received");
+                    return
+                }
+                Event::String(_) => {
+                    warn!("Found non-JSON log, skipping {}", Self::name());
+                    callback.call();
+                    continue
+                }
+            };
+
+            send_event!(self, event, callback);
+        }
+    }
+
+    // boilerplate methods
+    get_receiver!{}
+    connect_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+
+#[cfg(test)]
+mod coerce_tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::sync::{broadcast, Semaphore};
+    use tokio::time::timeout;
+
+    use crate::Args;
+    use crate::common::init_test_logger;
+    use crate::event::Event;
+    use crate::plugin::{Callback, DeadLetter, Plugin};
+    use crate::plugins::coerce::{CoerceTransform, Coercer};
+
+    const FIELDS: &str = r#"
+        [fields]
+        srcport = "int"
+        proto = { type = "int", default = 0 }
+        "http.ratio" = "float"
+        blocked = "bool"
+        src = "ip"
+        t = { type = "timestamp", formats = ["%Y-%m-%d %H:%M:%S"], ts_type = "epoch_s" }
+        code = "string"
+    "#;
+
+    fn coercer(extra: &str) -> Coercer {
+        let args: Args = toml::from_str(format!("{}\n{}", extra, FIELDS).as_str()).expect("Error parsing TOML");
+
+        Coercer::from_args(&args, "coerce").expect("Error parsing args")
+    }
+
+    #[test]
+    fn coerce() {
+        let coercer = coercer("");
+        let event = json!({"srcport": "38348", "proto": "tcp", "http": {"ratio": "0.5"}, "blocked": "Yes", "src": " 10.0.0.1 ", "t": "2023-07-07 14:02:12", "code": 404, "other": "x"});
+
+        assert_eq!(Ok(json!({"srcport": 38348, "proto": 0, "http": {"ratio": 0.5}, "blocked": true, "src": "10.0.0.1", "t": 1688738532, "code": "404", "other": "x"})),
+                   coercer.process(event));
+
+        // missing fields are skipped unless they have a default, and invalid fields are tagged
+        assert_eq!(Ok(json!({"srcport": "nope", "proto": 0, "tags": ["_coerce_failure"]})), coercer.process(json!({"srcport": "nope"})));
+    }
+
+    #[test]
+    fn strict() {
+        let coercer = coercer("strict = true");
+        let event = json!({"srcport": 80.0, "http": {"ratio": 1}, "blocked": 0, "src": "::1", "t": "2023-07-07 14:02:12", "code": "a"});
+
+        assert_eq!(Ok(json!({"srcport": 80, "proto": 0, "http": {"ratio": 1.0}, "blocked": false, "src": "::1", "t": 1688738532, "code": "a"})),
+                   coercer.process(event));
+
+        let event = json!({"srcport": 80, "src": "10.0.0.256"});
+        let (original, error) = coercer.process(event.clone()).unwrap_err();
+
+        assert_eq!(event, original);
+        assert_eq!("'blocked' is missing; 'code' is missing; 'http.ratio' is missing; 'src' '10.0.0.256' is not an IP address; 't' is missing", error);
+    }
+
+    #[test]
+    fn bad_args() {
+        for fields in [r#"a = "nope""#, r#"a = { default = 1 }"#, r#"a = { type = "int", default = "x" }"#, r#"a = 5"#] {
+            let args: Args = toml::from_str(format!("[fields]\n{}", fields).as_str()).unwrap();
+            assert!(Coercer::from_args(&args, "coerce").is_err(), "{}", fields);
+        }
+    }
+
+    #[test]
+    fn default_env() {
+        let args: Args = toml::from_str(r#"fields = { a = { type = "string", default = "$${HOME}" } }"#).unwrap();
+        let coercer = Coercer::from_args(&args, "coerce").expect("Error parsing args");
+
+        assert_eq!(Ok(json!({"a": "${HOME}"})), coercer.process(json!({})));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dead_letter() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let mut args: Args = toml::from_str(format!("strict = true\n{}", FIELDS).as_str()).unwrap();
+        args.insert("channel_size".to_string(), toml::Value::Integer(4));
+
+        let mut transform = CoerceTransform::new(args, tripwire).await.expect("Error creating CoerceTransform");
+        let (sender, receiver) = broadcast::channel(4);
+        let (dl_sender, mut dl_receiver) = broadcast::channel(4);
+
+        transform.connect_receiver(receiver);
+        transform.connect_dead_letter(DeadLetter::new(dl_sender, Arc::new(Semaphore::new(4))));
+
+        let jh = tokio::spawn(async move { transform.run().await });
+
+        let permit = Arc::new(Semaphore::new(1)).acquire_owned().await.unwrap();
+        sender.send((Event::Json(json!({"srcport": "x"})), Arc::new(permit), Arc::new(Callback::empty()))).unwrap();
+
+        let (event, _, _) = timeout(Duration::from_secs(5), dl_receiver.recv()).await.expect("Timed out").unwrap();
+        let envelope = match event {
+            Event::Json(json) => json,
+            e => panic!("Unexpected event: {:?}", e)
+        };
+
+        assert_eq!(json!("coerce"), envelope["plugin"]);
+        assert_eq!(json!({"srcport": "x"}), envelope["original"]);
+        assert!(envelope["error"].as_str().unwrap().starts_with("'blocked' is missing"));
+        assert!(envelope["timestamp"].is_string());
+
+        trigger.cancel();
+        jh.await.unwrap();
+    }
+}
@@ -0,0 +1,584 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::broadcast::Receiver;
+use tokio::time::Instant;
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::common::logging::{debug, error, warn};
+use crate::{Args, connect_receiver, create_event_stream, recv_event};
+use crate::event::{Event, JsonValue};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback, DeadLetter};
+use crate::template::{event_time, Template};
+
+
+/// A single document waiting to be indexed
+struct BulkItem {
+    index: String,
+    doc: JsonValue,
+    callback: Arc<Callback>,
+}
+
+/// Outcome of a single item in a _bulk response
+#[derive(Debug, PartialEq)]
+enum ItemResult {
+    Success,
+    Retry(String),
+    Failed(String),
+}
+
+/// Output plugin that sends logs to Elasticsearch or OpenSearch via the _bulk API
+pub struct ElasticsearchOutput {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    client: Client,
+    bulk_url: String,
+    username: Option<String>,
+    password: Option<String>,
+    api_key: Option<String>,
+    index: Template,
+    ts_field: String,
+    batch_size: usize,
+    batch_timeout: Duration,
+    max_retries: u32,
+    dead_letter: Option<DeadLetter>,
+    /// the number of logs that could not be indexed, and were dropped
+    dropped: u64,
+}
+
+impl ElasticsearchOutput {
+    /// Builds the newline delimited body for the _bulk API
+    fn bulk_body(items: &[BulkItem]) -> String {
+        let mut body = String::new();
+
+        for item in items {
+            let action = serde_json::json!({ "index": { "_index": item.index } });
+
+            body.push_str(action.to_string().as_str());
+            body.push('\n');
+            body.push_str(item.doc.to_string().as_str());
+            body.push('\n');
+        }
+
+        body
+    }
+
+    /// Converts the "items" of a _bulk response into a result for each item sent
+    fn parse_bulk_response(response: &JsonValue, num_items: usize) -> Result<Vec<ItemResult>> {
+        // fast-path when nothing failed
+        if response.get("errors").and_then(|e| e.as_bool()) == Some(false) {
+            return Ok((0..num_items).map(|_| ItemResult::Success).collect())
+        }
+
+        let items = response.get("items")
+            .and_then(|i| i.as_array())
+            .ok_or_else(|| anyhow!("No 'items' found in _bulk response"))?;
+
+        if items.len() != num_items {
+            bail!("Sent {} items, but the _bulk response has {} items", num_items, items.len());
+        }
+
+        let results = items.iter().map(|item| {
+            // each item is keyed by the action: {"index": { "status": 201, ... }}
+            let item = item.as_object().and_then(|o| o.values().next());
+            let status = item.and_then(|i| i.get("status")).and_then(|s| s.as_u64()).unwrap_or(0);
+            let error = item.and_then(|i| i.get("error")).map(|e| e.to_string()).unwrap_or_else(|| format!("status {}", status));
+
+            match status {
+                200..=299 => ItemResult::Success,
+                // too many requests, or a server-side problem
+                429 | 500..=599 => ItemResult::Retry(error),
+                _ => ItemResult::Failed(error)
+            }
+        }).collect();
+
+        Ok(results)
+    }
+
+    /// Sends the items via the _bulk API, retrying only the items that failed with a retryable error;
+    /// returns the items that could not be indexed, with the reason why
+    async fn send_batch(&self, mut items: Vec<BulkItem>) -> Vec<(BulkItem, String)> {
+        let mut failed = Vec::new();
+        let mut attempt = 0;
+
+        while !items.is_empty() {
+            if attempt > 0 {
+                // back-off exponentially, capping at ~25s
+                let wait = Duration::from_millis(100 * 2_u64.pow(attempt.min(8)));
+
+                debug!("Retrying {} items in {}ms", items.len(), wait.as_millis());
+
+                if tokio::time::timeout(wait, self.tripwire.clone()).await.is_ok() {
+                    // the callbacks aren't called, so the logs can be sent again after a restart
+                    warn!("Shutdown before {} items could be sent to {}", items.len(), self.bulk_url);
+                    return failed;
+                }
+            }
+
+            if attempt > self.max_retries {
+                let reason = format!("Unable to send to {} after {} retries", self.bulk_url, self.max_retries);
+
+                failed.extend(items.into_iter().map(|item| (item, reason.clone())));
+                return failed;
+            }
+
+            attempt += 1;
+
+            let results = match self.send_bulk(&items).await {
+                Ok(Some(results)) => results,
+                // retry the whole request
+                Ok(None) => continue,
+                Err(e) => {
+                    let reason = format!("{:#}", e);
+
+                    failed.extend(items.into_iter().map(|item| (item, reason.clone())));
+                    return failed;
+                }
+            };
+
+            // only keep the items that need to be retried
+            let mut retry_items = Vec::new();
+
+            for (item, result) in items.into_iter().zip(results) {
+                match result {
+                    ItemResult::Success => item.callback.call(),
+                    ItemResult::Retry(e) => {
+                        debug!("Retrying item for index {}: {}", item.index, e);
+                        retry_items.push(item);
+                    }
+                    // these will never succeed
+                    ItemResult::Failed(e) => {
+                        let reason = format!("Error indexing document into {}: {}", item.index, e);
+
+                        failed.push((item, reason));
+                    }
+                }
+            }
+
+            items = retry_items;
+        }
+
+        failed
+    }
+
+    /// Makes a single _bulk request, returning None if the whole request should be retried
+    async fn send_bulk(&self, items: &[BulkItem]) -> Result<Option<Vec<ItemResult>>> {
+        let mut request = self.client
+            .post(self.bulk_url.as_str())
+            .header("Content-Type", "application/x-ndjson")
+            .body(Self::bulk_body(items));
+
+        if let Some(username) = self.username.as_ref() {
+            request = request.basic_auth(username, self.password.as_ref());
+        } else if let Some(api_key) = self.api_key.as_ref() {
+            request = request.header("Authorization", format!("ApiKey {}", api_key));
+        }
+
+        let response = match request.send().await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Error sending _bulk request: {}", e);
+                return Ok(None)
+            }
+        };
+
+        let status = response.status();
+
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            warn!("Retryable status from _bulk request: {}", status);
+            return Ok(None)
+        } else if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("Error status from _bulk request: {}: {}", status, body);
+        }
+
+        let body = response.bytes().await.context("Reading _bulk response")?;
+        let body: JsonValue = serde_json::from_slice(body.as_ref()).context("Parsing _bulk response")?;
+
+        Self::parse_bulk_response(&body, items.len()).map(Some)
+    }
+
+    /// Sends the items that could not be indexed to the dead-letter output, or logs and drops them
+    async fn reject(&mut self, failed: Vec<(BulkItem, String)>) {
+        for (item, reason) in failed {
+            match &self.dead_letter {
+                Some(dead_letter) => dead_letter.send(Self::name(), reason, Event::Json(item.doc), item.callback).await,
+                None => {
+                    self.dropped += 1;
+                    error!("{}; dropping the log ({} dropped so far)", reason, self.dropped);
+
+                    // counted as processed, so the source isn't stuck on it
+                    item.callback.call();
+                }
+            }
+        }
+    }
+
+    /// Sends a batch, handling the items that could not be indexed
+    async fn flush(&mut self, batch: Vec<BulkItem>) {
+        let failed = self.send_batch(batch).await;
+
+        self.reject(failed).await;
+    }
+}
+
+#[async_trait]
+impl Plugin for ElasticsearchOutput {
+    fn name() -> &'static str where Self: Sized {
+        "elasticsearch"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
+        debug!("ElasticsearchOutput args: {:#?}", args);
+
+        // grab the base URL of the cluster
+        let url = args.get("url").ok_or_else(|| anyhow!("Could not find 'url' arg for {}", Self::name()))?;
+        let url = url.as_str().ok_or_else(|| anyhow!("The 'url' arg for {} does not appear to be a string", Self::name()))?;
+        let bulk_url = format!("{}/_bulk", url.trim_end_matches('/'));
+
+        let index = args.get("index").unwrap_or(&Value::String("logs-%Y.%m.%d".to_string())).to_owned();
+        let index = index.as_str().ok_or_else(|| anyhow!("The 'index' arg for {} does not appear to be a string", Self::name()))?;
+        let index = Template::parse(index).with_context(|| format!("Parsing the 'index' arg for {}", Self::name()))?;
+
+        let ts_field = args.get("ts_field").unwrap_or(&Value::String("t".to_string())).to_owned();
+        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for {} does not appear to be a string", Self::name()))?.to_string();
+
+        let batch_size = args.get("batch_size").unwrap_or(&Value::Integer(500));
+        let batch_size = batch_size.as_integer().ok_or_else(|| anyhow!("The 'batch_size' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if !(1..=10_000).contains(&batch_size) {
+            bail!("Nonsensical value {} for 'batch_size' for {}; should be between 1 and 10000", batch_size, Self::name());
+        }
+
+        let batch_timeout = args.get("batch_timeout_ms").unwrap_or(&Value::Integer(1000));
+        let batch_timeout = batch_timeout.as_integer().ok_or_else(|| anyhow!("The 'batch_timeout_ms' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if batch_timeout < 1 {
+            bail!("The 'batch_timeout_ms' arg for {} must be positive", Self::name());
+        }
+
+        let max_retries = args.get("max_retries").unwrap_or(&Value::Integer(5));
+        let max_retries = max_retries.as_integer().ok_or_else(|| anyhow!("The 'max_retries' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if max_retries < 0 {
+            bail!("The 'max_retries' arg for {} cannot be negative", Self::name());
+        }
+
+        // optional authentication
+        let username = match args.get("username") {
+            Some(u) => Some(u.as_str().ok_or_else(|| anyhow!("The 'username' arg for {} does not appear to be a string", Self::name()))?.to_string()),
+            None => None
+        };
+        let password = match args.get("password") {
+            Some(p) => Some(p.as_str().ok_or_else(|| anyhow!("The 'password' arg for {} does not appear to be a string", Self::name()))?.to_string()),
+            None => None
+        };
+        let api_key = match args.get("api_key") {
+            Some(k) => Some(k.as_str().ok_or_else(|| anyhow!("The 'api_key' arg for {} does not appear to be a string", Self::name()))?.to_string()),
+            None => None
+        };
+
+        if username.is_some() && api_key.is_some() {
+            bail!("Only one of 'username' or 'api_key' can be specified for {}", Self::name());
+        }
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()
+            .context("Creating HTTP client")?;
+
+        Ok(Box::new(ElasticsearchOutput {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            client,
+            bulk_url,
+            username,
+            password,
+            api_key,
+            index,
+            ts_field,
+            batch_size: batch_size as usize,
+            batch_timeout: Duration::from_millis(batch_timeout as u64),
+            max_retries: max_retries as u32,
+            dead_letter: None, // set in connect_dead_letter
+            dropped: 0,
+        }))
+    }
+
+    async fn run(&mut self) {
+        debug!("ElasticsearchOutput running...");
+
+        let mut event_stream = create_event_stream!(self);
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut deadline = Instant::now() + self.batch_timeout;
+
+        loop {
+            let op_event = tokio::select! {
+                op_event = event_stream.next() => op_event,
+                _ = tokio::time::sleep_until(deadline), if !batch.is_empty() => {
+                    self.flush(std::mem::take(&mut batch)).await;
+
+                    continue
+                }
+            };
+
+            let event = match op_event {
+                Some(event) => event,
+                None => break
+            };
+
+            let (event, callback) = recv_event!(event);
+
+            let doc = match event {
+                Event::None => {
+                    callback.call();
+                    continue
+                }
+                Event::String(_) => {
+                    warn!("Found non-JSON log, skipping {}", Self::name());
+                    callback.call();
+                    continue
+                }
+                Event::Json(ref json) => {
+                    // Elasticsearch rejects index names with uppercase characters
+                    let index = self.index.render(&event, &event_time(&event, self.ts_field.as_str())).to_lowercase();
+
+                    BulkItem { index, doc: json.clone(), callback }
+                }
+            };
+
+            if batch.is_empty() {
+                deadline = Instant::now() + self.batch_timeout;
+            }
+
+            batch.push(doc);
+
+            if batch.len() >= self.batch_size {
+                self.flush(std::mem::take(&mut batch)).await;
+            }
+        }
+
+        // send whatever is left
+        if !batch.is_empty() {
+            self.flush(batch).await;
+        }
+
+        debug!("ElasticsearchOutput closing");
+    }
+
+    // boilerplate method
+    connect_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+
+#[cfg(test)]
+mod elasticsearch_tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::{broadcast, mpsc, Semaphore};
+    use toml::Value;
+
+    use crate::common::init_test_logger;
+    use crate::event::{Event, JsonValue};
+    use crate::plugin::{Args, Callback, DeadLetter, Plugin};
+    use crate::plugins::elasticsearch::{ElasticsearchOutput, ItemResult};
+
+    /// Starts a mock _bulk endpoint that replies with the given responses in order, and sends back the request bodies
+    async fn mock_bulk_endpoint(responses: Vec<(u16, JsonValue)>) -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Error binding");
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut responses = responses.into_iter();
+            let (stream, _) = listener.accept().await.expect("Error accepting");
+            let mut stream = BufReader::new(stream);
+
+            loop {
+                // read the headers, looking for the length of the body
+                let mut content_length = 0;
+
+                loop {
+                    let mut line = String::new();
+
+                    if stream.read_line(&mut line).await.expect("Error reading") == 0 {
+                        return;
+                    }
+
+                    if line == "\r\n" {
+                        break;
+                    }
+
+                    if let Some(len) = line.to_lowercase().strip_prefix("content-length: ") {
+                        content_length = len.trim().parse::<usize>().expect("Error parsing length");
+                    }
+                }
+
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).await.expect("Error reading body");
+                tx.send(String::from_utf8(body).unwrap()).unwrap();
+
+                let (status, response) = responses.next().expect("No more responses");
+                let response = response.to_string();
+                let response = format!("HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, response.len(), response);
+
+                stream.get_mut().write_all(response.as_bytes()).await.expect("Error writing");
+            }
+        });
+
+        (port, rx)
+    }
+
+    #[test]
+    fn parse_bulk_response() {
+        let response = json!({"took": 1, "errors": false, "items": [{"index": {"status": 201}}]});
+        assert_eq!(vec![ItemResult::Success], ElasticsearchOutput::parse_bulk_response(&response, 1).unwrap());
+
+        let response = json!({"took": 1, "errors": true, "items": [
+            {"index": {"status": 201}},
+            {"index": {"status": 429, "error": {"type": "es_rejected_execution_exception"}}},
+            {"index": {"status": 400, "error": {"type": "mapper_parsing_exception"}}},
+        ]});
+        let results = ElasticsearchOutput::parse_bulk_response(&response, 3).unwrap();
+
+        assert_eq!(ItemResult::Success, results[0]);
+        assert!(matches!(results[1], ItemResult::Retry(_)));
+        assert!(matches!(results[2], ItemResult::Failed(_)));
+
+        assert!(ElasticsearchOutput::parse_bulk_response(&response, 2).is_err());
+    }
+
+    #[tokio::test]
+    async fn retry_failed_items() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+
+        // the 2nd item is rejected the first time, the 3rd can never be indexed
+        let (port, mut bodies) = mock_bulk_endpoint(vec![
+            (200, json!({"took": 1, "errors": true, "items": [
+                {"index": {"status": 201}},
+                {"index": {"status": 429, "error": {"type": "es_rejected_execution_exception"}}},
+                {"index": {"status": 400, "error": {"type": "mapper_parsing_exception"}}},
+            ]})),
+            (200, json!({"took": 1, "errors": false, "items": [{"index": {"status": 201}}]})),
+        ]).await;
+
+        let mut args = Args::new();
+        args.insert("url".to_string(), Value::String(format!("http://127.0.0.1:{}/", port)));
+        args.insert("index".to_string(), Value::String("logs-{app}-%Y.%m.%d".to_string()));
+        args.insert("batch_size".to_string(), Value::Integer(3));
+
+        let mut output = ElasticsearchOutput::new(args, tripwire.clone()).await.expect("Error creating ElasticsearchOutput");
+        let (sender, receiver) = broadcast::channel(10);
+        let semaphore = Arc::new(Semaphore::new(10));
+        let called = Arc::new(AtomicUsize::new(0));
+        let (dl_sender, mut dl_receiver) = broadcast::channel(10);
+
+        output.connect_receiver(receiver);
+        output.connect_dead_letter(DeadLetter::new(dl_sender, Arc::new(Semaphore::new(10))));
+
+        let jh = tokio::spawn(async move { output.run().await });
+
+        for i in 0..3 {
+            let called_clone = called.clone();
+            let callback = Arc::new(Callback::new(move || { called_clone.fetch_add(1, Ordering::SeqCst); }));
+            let event = Event::Json(json!({"app": "Web", "i": i, "t": 1688738532000_i64}));
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+            sender.send((event, Arc::new(permit), callback)).expect("Error sending");
+        }
+
+        let first = bodies.recv().await.expect("Error getting first body");
+        let lines = first.lines().collect::<Vec<_>>();
+
+        // the index name is lowercased
+        assert_eq!(6, lines.len());
+        assert_eq!(json!({"index": {"_index": "logs-web-2023.07.07"}}), serde_json::from_str::<JsonValue>(lines[0]).unwrap());
+
+        // only the rejected item should be sent again
+        let second = bodies.recv().await.expect("Error getting second body");
+        let lines = second.lines().collect::<Vec<_>>();
+
+        assert_eq!(2, lines.len());
+        assert_eq!(Some(1), serde_json::from_str::<JsonValue>(lines[1]).unwrap().get("i").and_then(|i| i.as_i64()));
+
+        // the item that can never be indexed goes to the dead-letter output, along with its callback
+        let (dead_letter, _permit, callback) = dl_receiver.recv().await.expect("Error getting dead letter");
+
+        match dead_letter {
+            Event::Json(json) => {
+                assert_eq!(json!("elasticsearch"), json["plugin"]);
+                assert_eq!(json!(2), json["original"]["i"]);
+            }
+            _ => panic!("Expected a JSON dead letter")
+        }
+
+        callback.call();
+
+        // give the output a chance to call the callbacks
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(3, called.load(Ordering::SeqCst));
+
+        trigger.cancel();
+        jh.await.expect("Error waiting");
+    }
+
+    #[tokio::test]
+    async fn keep_running_after_error() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+
+        // the first batch can never be sent, but the output keeps going
+        let (port, mut bodies) = mock_bulk_endpoint(vec![
+            (400, json!({"error": "bad request"})),
+            (200, json!({"took": 1, "errors": false, "items": [{"index": {"status": 201}}]})),
+        ]).await;
+
+        let mut args = Args::new();
+        args.insert("url".to_string(), Value::String(format!("http://127.0.0.1:{}", port)));
+        args.insert("batch_size".to_string(), Value::Integer(1));
+
+        let mut output = ElasticsearchOutput::new(args, tripwire.clone()).await.expect("Error creating ElasticsearchOutput");
+        let (sender, receiver) = broadcast::channel(10);
+        let semaphore = Arc::new(Semaphore::new(10));
+        let called = Arc::new(AtomicUsize::new(0));
+
+        output.connect_receiver(receiver);
+
+        let jh = tokio::spawn(async move { output.run().await });
+
+        for i in 0..2 {
+            let called_clone = called.clone();
+            let callback = Arc::new(Callback::new(move || { called_clone.fetch_add(1, Ordering::SeqCst); }));
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+            sender.send((Event::Json(json!({"i": i})), Arc::new(permit), callback)).expect("Error sending");
+        }
+
+        bodies.recv().await.expect("Error getting first body");
+        bodies.recv().await.expect("Error getting second body");
+
+        // without a dead-letter output, the failed log is dropped, and counted as processed
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(2, called.load(Ordering::SeqCst));
+
+        trigger.cancel();
+        jh.await.expect("Error waiting");
+    }
+}
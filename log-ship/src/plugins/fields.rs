@@ -0,0 +1,331 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use serde_json::Map;
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::common::logging::{debug, error, warn};
+use crate::event::{Event, JsonValue};
+use crate::field_path::FieldPath;
+use crate::{Args, Plugin, send_event, connect_receiver, create_event_stream, get_receiver, recv_event, create_sender_semaphore};
+use crate::plugin::{PluginType, ChannelType};
+
+
+/// A single operation on the fields of an event
+#[derive(Debug, Clone, PartialEq)]
+enum FieldOp {
+    Rename { from: FieldPath, to: FieldPath },
+    Remove { fields: Vec<FieldPath> },
+    Copy { from: FieldPath, to: FieldPath },
+    /// moves each field into the object, keeping its name
+    Move { fields: Vec<FieldPath>, to: FieldPath },
+    /// `None` is the whole event
+    Flatten { field: Option<FieldPath>, separator: String },
+    Unflatten { field: Option<FieldPath>, separator: String },
+}
+
+impl FieldOp {
+    fn from_table(table: &Value, plugin_name: &str) -> Result<Self> {
+        let table = table.as_table().ok_or_else(|| anyhow!("Each entry in the 'ops' arg for {} must be a table", plugin_name))?;
+        let op = table.get("op").and_then(|op| op.as_str()).ok_or_else(|| anyhow!("Each entry in the 'ops' arg for {} must have an 'op' string", plugin_name))?;
+
+        let path = |name: &str| -> Result<FieldPath> {
+            let path = table.get(name).ok_or_else(|| anyhow!("The '{}' op for {} requires a '{}' arg", op, plugin_name, name))?;
+            let path = path.as_str().ok_or_else(|| anyhow!("The '{}' arg of the '{}' op for {} does not appear to be a string", name, op, plugin_name))?;
+
+            FieldPath::parse(path)
+        };
+
+        let paths = || -> Result<Vec<FieldPath>> {
+            match table.get("fields") {
+                None => Ok(vec![path("field")?]),
+                Some(Value::String(f)) => Ok(vec![FieldPath::parse(f)?]),
+                Some(Value::Array(a)) => {
+                    a.iter()
+                     .map(|f| f.as_str().ok_or_else(|| anyhow!("Found non-string field in 'fields' of the '{}' op for {}", op, plugin_name)).and_then(FieldPath::parse))
+                     .collect()
+                }
+                Some(_) => bail!("The 'fields' arg of the '{}' op for {} must be a string or an array of strings", op, plugin_name)
+            }
+        };
+
+        let optional_path = || -> Result<Option<FieldPath>> {
+            table.get("field").map(|_| path("field")).transpose()
+        };
+
+        let separator = || -> Result<String> {
+            let separator = table.get("separator").unwrap_or(&Value::String(".".to_string())).to_owned();
+            let separator = separator.as_str().ok_or_else(|| anyhow!("The 'separator' arg of the '{}' op for {} does not appear to be a string", op, plugin_name))?;
+
+            if separator.is_empty() {
+                bail!("The 'separator' arg of the '{}' op for {} cannot be empty", op, plugin_name);
+            }
+
+            Ok(separator.to_string())
+        };
+
+        Ok(match op {
+            "rename" => FieldOp::Rename { from: path("from")?, to: path("to")? },
+            "remove" => FieldOp::Remove { fields: paths()? },
+            "copy" => FieldOp::Copy { from: path("from")?, to: path("to")? },
+            "move" => {
+                let fields = paths()?;
+
+                if fields.iter().any(|f| f.last_key().is_none()) {
+                    bail!("The fields of the 'move' op for {} cannot end with an index", plugin_name);
+                }
+
+                FieldOp::Move { fields, to: path("to")? }
+            }
+            "flatten" => FieldOp::Flatten { field: optional_path()?, separator: separator()? },
+            "unflatten" => FieldOp::Unflatten { field: optional_path()?, separator: separator()? },
+            op => bail!("Unknown op '{}' for {}; must be one of: rename, remove, copy, move, flatten, unflatten", op, plugin_name)
+        })
+    }
+
+    fn apply(&self, event: &mut JsonValue) -> Result<()> {
+        match self {
+            FieldOp::Rename { from, to } => {
+                if let Some(value) = from.remove(event) {
+                    move_value(event, from, to, value)?;
+                }
+            }
+            FieldOp::Remove { fields } => {
+                for field in fields {
+                    field.remove_glob(event);
+                }
+            }
+            FieldOp::Copy { from, to } => {
+                if let Some(value) = from.get(event).cloned() {
+                    to.insert(event, value)?;
+                }
+            }
+            FieldOp::Move { fields, to } => {
+                for field in fields {
+                    if let Some(value) = field.remove(event) {
+                        // checked when parsing the args
+                        let key = field.last_key().expect("Move of a path ending in an index");
+
+                        move_value(event, field, &to.join(key), value)?;
+                    }
+                }
+            }
+            FieldOp::Flatten { field, separator } => {
+                if let Some(JsonValue::Object(obj)) = target(event, field) {
+                    let mut flat = Map::new();
+
+                    for (key, value) in std::mem::take(obj) {
+                        flatten_into(key, value, separator, &mut flat);
+                    }
+
+                    *obj = flat;
+                }
+            }
+            FieldOp::Unflatten { field, separator } => {
+                if let Some(JsonValue::Object(obj)) = target(event, field) {
+                    let mut nested = JsonValue::Object(Map::new());
+                    let (dotted, plain) : (Vec<_>, Vec<_>) = std::mem::take(obj).into_iter().partition(|(k, _)| k.contains(separator.as_str()));
+
+                    // insert the plain keys first, so dotted keys are merged into any existing objects
+                    for (key, value) in plain {
+                        nested.as_object_mut().unwrap().insert(key, value);
+                    }
+
+                    for (key, value) in dotted {
+                        let path = FieldPath::from_keys(key.split(separator.as_str()));
+
+                        // keep the key as-is when it conflicts with a non-object value
+                        if path.get(&nested).is_none() && path.insert(&mut nested, value.clone()).is_ok() {
+                            continue
+                        }
+
+                        nested.as_object_mut().unwrap().insert(key, value);
+                    }
+
+                    if let JsonValue::Object(nested) = nested {
+                        *obj = nested;
+                    }
+                }
+            }
+        }
+
+        Ok( () )
+    }
+}
+
+/// Inserts a value removed from one path into another, putting it back if that fails
+fn move_value(event: &mut JsonValue, from: &FieldPath, to: &FieldPath, value: JsonValue) -> Result<()> {
+    if let Err(e) = to.insert(event, value.clone()) {
+        from.insert(event, value)?;
+        return Err(e);
+    }
+
+    Ok( () )
+}
+
+fn target<'a>(event: &'a mut JsonValue, field: &Option<FieldPath>) -> Option<&'a mut JsonValue> {
+    match field {
+        None => Some(event),
+        Some(field) => field.get_mut(event)
+    }
+}
+
+/// Adds the value to the map, recursively joining the keys of any nested objects with the separator
+pub fn flatten_into(key: String, value: JsonValue, separator: &str, flat: &mut Map<String, JsonValue>) {
+    match value {
+        JsonValue::Object(obj) if !obj.is_empty() => {
+            for (k, v) in obj {
+                flatten_into(format!("{}{}{}", key, separator, k), v, separator, flat);
+            }
+        }
+        value => {
+            flat.insert(key, value);
+        }
+    }
+}
+
+pub struct FieldsTransform {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    ops: Vec<FieldOp>,
+}
+
+impl FieldsTransform {
+    fn parse_ops(args: &Args) -> Result<Vec<FieldOp>> {
+        let ops = args.get("ops").ok_or_else(|| anyhow!("Could not find 'ops' arg for {}", Self::name()))?;
+        let ops = ops.as_array().ok_or_else(|| anyhow!("The 'ops' arg for {} does not appear to be an array", Self::name()))?;
+
+        ops.iter().map(|op| FieldOp::from_table(op, Self::name())).collect()
+    }
+
+    /// Applies each op in order, stopping at the first error
+    fn process(ops: &[FieldOp], event: &mut JsonValue) -> Result<()> {
+        ops.iter().try_for_each(|op| op.apply(event))
+    }
+}
+
+#[async_trait]
+impl Plugin for FieldsTransform {
+    fn name() -> &'static str {
+        "fields"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+        debug!("FieldsTransform args: {:#?}", args);
+
+        let ops = Self::parse_ops(&args)?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(FieldsTransform {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            sender,
+            semaphore,
+            ops
+        }))
+    }
+
+    async fn run(&mut self) {
+        let mut event_stream = create_event_stream!(self);
+
+        // grab an event and pass it along
+        while let Some(event) = event_stream.next().await {
+            let (event, callback) = recv_event!(event);
+
+            let event = match event {
+                Event::None => {
+                    callback.call();
+                    continue // nothing to do here
+                }
+                Event::Json(mut json @ JsonValue::Object(_)) => {
+                    if let Err(e) = Self::process(&self.ops, &mut json) {
+                        warn!("Error applying {} ops: {}", Self::name(), e);
+                    }
+
+                    Event::Json(json)
+                }
+                Event::Json(_) => {
+                    error!("Invalid JSON value received");
+                    return
+                }
+                Event::String(_) => {
+                    warn!("Found non-JSON log, skipping {}", Self::name());
+                    callback.call();
+                    continue
+                }
+            };
+
+            send_event!(self, event, callback);
+        }
+    }
+
+    // boilerplate methods
+    get_receiver!{}
+    connect_receiver!{}
+}
+
+
+#[cfg(test)]
+mod fields_tests {
+    use serde_json::json;
+
+    use crate::Args;
+    use crate::event::JsonValue;
+    use crate::plugins::fields::FieldsTransform;
+
+    fn apply(ops: &str, mut event: JsonValue) -> JsonValue {
+        let args: Args = toml::from_str(ops).expect("Error parsing TOML");
+        let ops = FieldsTransform::parse_ops(&args).expect("Error parsing ops");
+
+        FieldsTransform::process(&ops, &mut event).expect("Error applying ops");
+
+        event
+    }
+
+    #[test]
+    fn rename_remove_copy_move() {
+        let ops = r#"ops = [
+            { op = "rename", from = "req.headers[0]", to = "user_agent" },
+            { op = "remove", fields = ["debug_*", "req.headers"] },
+            { op = "copy", from = "req.status", to = "status" },
+            { op = "move", fields = ["user_agent", "client"], to = "http.request" },
+        ]"#;
+        let event = json!({"req": {"status": 200, "headers": ["curl", "gzip"]}, "debug_a": 1, "debug_b": 2, "client": "1.2.3.4", "msg": "hi"});
+
+        assert_eq!(json!({"req": {"status": 200}, "status": 200, "http": {"request": {"user_agent": "curl", "client": "1.2.3.4"}}, "msg": "hi"}), apply(ops, event));
+    }
+
+    #[test]
+    fn flatten_unflatten() {
+        let event = json!({"cpu0": {"user": 1.5, "system": 0.5}, "mem": {"used": {"bytes": 10}, "empty": {}}, "host": "h"});
+        let flat = json!({"cpu0.user": 1.5, "cpu0.system": 0.5, "mem.used.bytes": 10, "mem.empty": {}, "host": "h"});
+
+        assert_eq!(flat, apply(r#"ops = [{ op = "flatten" }]"#, event.clone()));
+        assert_eq!(event, apply(r#"ops = [{ op = "unflatten" }]"#, flat));
+
+        let event = json!({"metrics": {"a": {"b": 1}}, "a": {"b": 2}});
+        assert_eq!(json!({"metrics": {"a_b": 1}, "a": {"b": 2}}), apply(r#"ops = [{ op = "flatten", field = "metrics", separator = "_" }]"#, event));
+
+        // conflicting keys are left flat
+        let event = json!({"a": 1, "a.b": 2, "c": {"d": 3}, "c.e": 4});
+        assert_eq!(json!({"a": 1, "a.b": 2, "c": {"d": 3, "e": 4}}), apply(r#"ops = [{ op = "unflatten" }]"#, event));
+    }
+
+    #[test]
+    fn bad_ops() {
+        for ops in [r#"ops = [{ op = "nope" }]"#, r#"ops = [{ op = "rename", from = "a" }]"#, r#"ops = [{ op = "move", field = "a[0]", to = "b" }]"#, r#"ops = [{ op = "copy", from = "a..b", to = "c" }]"#] {
+            let args: Args = toml::from_str(ops).unwrap();
+            assert!(FieldsTransform::parse_ops(&args).is_err(), "{}", ops);
+        }
+    }
+}
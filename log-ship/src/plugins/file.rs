@@ -1,17 +1,20 @@
+use std::collections::HashMap;
 use std::fs;
 
 use std::io::{SeekFrom};
 use std::os::unix::fs::MetadataExt;
-use std::path::{PathBuf};
-use std::time::{Instant};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use flate2::Compression as GzLevel;
+use flate2::write::GzEncoder;
 use glob::{glob, GlobError};
 use inotify::{Inotify, WatchMask, EventMask};
 use stream_cancel::{StreamExt, Tripwire};
-use tokio::fs::File;
+use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncBufReadExt, AsyncSeekExt, AsyncWriteExt, BufStream, BufWriter};
 use tokio::select;
 use tokio::sync::{broadcast, Semaphore};
@@ -21,10 +24,12 @@ use tokio_stream::{StreamExt as TokioStreamExt};
 use tokio_stream::wrappers::{BroadcastStream};
 use toml::Value;
 
+use crate::codec::OutputCodec;
 use crate::common::logging::{debug, error, info, warn};
 use crate::{Args, connect_receiver, create_event_stream, create_sender_semaphore, get_receiver, recv_event, send_event};
 use crate::event::Event;
-use crate::plugin::{Plugin, PluginType, ChannelType, Callback};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback, DeadLetter};
+use crate::template::{event_time, Template};
 
 // holds the state for a given file
 struct FileInputInstance {
@@ -36,6 +41,7 @@ struct FileInputInstance {
     semaphore: Arc<Semaphore>,
     tripwire: Tripwire,
     try_parse: bool, // should we try and parse as JSON
+    dead_letter: Option<DeadLetter>,
 }
 
 pub struct FileInput {
@@ -118,6 +124,7 @@ impl FileInputInstance {
             semaphore: semaphore.clone(),
             tripwire: tripwire.clone(),
             try_parse,
+            dead_letter: None, // set in connect_dead_letter
         })
     }
 
@@ -139,10 +146,17 @@ impl FileInputInstance {
             match serde_json::from_str(line.as_str()) {
                 Ok(json) => Event::Json(json),
                 Err(e) => {
-                    warn!("Error parsing JSON: {:?}", e);
+                    match &self.dead_letter {
+                        // the callback is called once the dead-letter output has written it
+                        Some(dead_letter) => dead_letter.send(FileInput::name(), e, Event::String(line), cb).await,
+                        None => {
+                            warn!("Error parsing JSON: {:?}", e);
+
+                            // count it as processed in the state file
+                            cb.call();
+                        }
+                    }
 
-                    // count it as processed in the state file
-                    cb.call();
                     return
                 }
             }
@@ -402,6 +416,12 @@ impl Plugin for FileInput {
 
     // boilerplate method
     get_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        for instance in self.file_instances.iter_mut() {
+            instance.dead_letter.replace(dead_letter.clone());
+        }
+    }
 }
 
 #[cfg(test)]
@@ -414,9 +434,15 @@ mod file_input_tests {
     use toml::Value;
     use stream_cancel::Tripwire;
 
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tokio::sync::{broadcast, Semaphore};
+
     use crate::common::{debug, init_test_logger};
     use crate::{Args, FileInput, Plugin};
     use crate::event::Event;
+    use crate::plugin::DeadLetter;
 
     fn append<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) {
         let mut file = OpenOptions::new().create(true).append(true).open(path.as_ref()).expect("Error opening file");
@@ -812,13 +838,220 @@ mod file_input_tests {
         }
     }
 
+    #[tokio::test]
+    async fn bad_json_dead_letter() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let mut args = Args::new();
+        let dir = tempfile::TempDir::new().unwrap().into_path();
+        let file_path = dir.join("log");
+
+        args.insert("channel_size".to_string(), Value::Integer(1));
+        args.insert("path".to_string(), Value::String(format!("{}", file_path.display())));
+        args.insert("parse_json".to_string(), Value::Boolean(true));
+
+        let mut fi = FileInput::new(args.clone(), tripwire.clone()).await.expect("Error creating FileInput");
+        let mut recv = fi.get_receiver();
+        let (dl_sender, mut dl_recv) = broadcast::channel(1);
+
+        fi.connect_dead_letter(DeadLetter::new(dl_sender, Arc::new(Semaphore::new(1))));
+
+        let jh = tokio::spawn(async move { fi.run().await });
+
+        // give the thread a chance to spawn
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        fs::write(&file_path, "not json\n{\"a\": 1}\n").expect("Error writing to file");
+
+        // the bad line goes to the dead-letter output, and the state file is updated once it's handled
+        let (event, _semaphore, callback) = dl_recv.recv().await.expect("Error receiving");
+        let envelope = match event {
+            Event::Json(json) => json,
+            e => panic!("Unexpected event: {:?}", e)
+        };
+
+        assert_eq!(json!("file"), envelope["plugin"]);
+        assert_eq!(json!("not json"), envelope["original"]);
+        callback.call();
+        assert_eq!("9", fs::read_to_string(dir.join("log.state")).unwrap());
+
+        let (event, _semaphore, callback) = recv.recv().await.expect("Error receiving");
+        assert_eq!(Event::Json(json!({"a": 1})), event);
+        callback.call();
+
+        trigger.cancel(); // stop the FileInput
+
+        assert!(jh.await.is_ok());
+    }
 }
 
 
+/// An output file that's been opened, and its size & age for rotation
+struct OutputFile {
+    writer: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+    last_used: u64,
+}
+
 pub struct FileOutput {
     tripwire: Tripwire,
-    file: BufWriter<File>,
     receiver: Option<Receiver<ChannelType>>,
+    codec: OutputCodec,
+    path: Template,
+    ts_field: String,
+    append: bool,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    retain: usize,
+    compress: bool,
+    max_open_files: usize,
+    files: HashMap<PathBuf, OutputFile>,
+    closed: HashMap<PathBuf, Instant>, // when files closed to make room were opened, so they're still rotated on time
+    started: SystemTime, // files last modified before this are truncated when not appending
+    uses: u64,
+}
+
+impl FileOutput {
+    /// Opens the file, creating its directory if needed, and closing the least recently used file if too many are open
+    async fn open(&mut self, path: &Path) -> Result<()> {
+        if self.files.len() >= self.max_open_files {
+            let lru = self.files.iter().min_by_key(|(_, f)| f.last_used).map(|(p, _)| p.clone());
+
+            if let Some(lru) = lru {
+                let mut file = self.files.remove(&lru).unwrap();
+                file.writer.flush().await.with_context(|| format!("Error flushing {}", lru.display()))?;
+
+                if self.max_age.is_some() {
+                    self.closed.insert(lru, file.opened);
+                }
+            }
+        }
+
+        self.rotate_closed().await?;
+
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await.with_context(|| format!("Error creating directory {}", dir.display()))?;
+        }
+
+        // only truncate files we haven't written to yet
+        let truncate = !self.append && match tokio::fs::metadata(path).await {
+            Ok(metadata) => metadata.modified().map(|m| m < self.started).unwrap_or(true),
+            Err(_) => false
+        };
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(!truncate)
+            .truncate(truncate)
+            .open(path).await
+            .with_context(|| format!("Error attempting to open {}", path.display()))?;
+        let size = file.metadata().await?.len();
+
+        self.files.insert(path.to_path_buf(), OutputFile {
+            writer: BufWriter::new(file),
+            size,
+            opened: self.closed.remove(path).unwrap_or_else(Instant::now),
+            last_used: self.uses,
+        });
+
+        Ok( () )
+    }
+
+    /// Rotates the closed files that are due, so only files opened within `rotate_secs` are remembered
+    async fn rotate_closed(&mut self) -> Result<()> {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age,
+            None => return Ok( () )
+        };
+
+        let due = self.closed.iter().filter(|(_, opened)| opened.elapsed() >= max_age).map(|(p, _)| p.clone()).collect::<Vec<_>>();
+
+        for path in due {
+            self.closed.remove(&path);
+
+            if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                self.rotate(path.as_path()).await?;
+            }
+        }
+
+        Ok( () )
+    }
+
+    /// Closes the file, and shifts it and the previously rotated files: file -> file.1 -> file.2, etc
+    async fn rotate(&mut self, path: &Path) -> Result<()> {
+        if let Some(mut file) = self.files.remove(path) {
+            file.writer.flush().await.with_context(|| format!("Error flushing {}", path.display()))?;
+        }
+
+        debug!("Rotating {}", path.display());
+
+        let ext = if self.compress { ".gz" } else { "" };
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}{}", path.display(), n, ext));
+
+        if self.retain == 0 {
+            tokio::fs::remove_file(path).await.with_context(|| format!("Error removing {}", path.display()))?;
+            return Ok( () )
+        }
+
+        // shift the previously rotated files, dropping the oldest
+        if tokio::fs::try_exists(rotated(self.retain)).await.unwrap_or(false) {
+            tokio::fs::remove_file(rotated(self.retain)).await?;
+        }
+
+        for n in (1..self.retain).rev() {
+            if tokio::fs::try_exists(rotated(n)).await.unwrap_or(false) {
+                tokio::fs::rename(rotated(n), rotated(n + 1)).await?;
+            }
+        }
+
+        if self.compress {
+            let (src, dst) = (path.to_path_buf(), rotated(1));
+
+            tokio::task::spawn_blocking(move || -> Result<()> {
+                let mut encoder = GzEncoder::new(fs::File::create(&dst)?, GzLevel::default());
+
+                std::io::copy(&mut fs::File::open(&src)?, &mut encoder)?;
+                encoder.finish()?;
+                fs::remove_file(&src)?;
+
+                Ok( () )
+            }).await?.with_context(|| format!("Error compressing {}", path.display()))?;
+        } else {
+            tokio::fs::rename(path, rotated(1)).await?;
+        }
+
+        Ok( () )
+    }
+
+    /// Writes the bytes to the file, opening or rotating it as needed
+    async fn write(&mut self, path: PathBuf, bytes: &[u8]) -> Result<()> {
+        self.uses += 1;
+
+        if !self.files.contains_key(&path) {
+            self.open(path.as_path()).await?;
+        }
+
+        let needs_rotation = self.files.get(&path).map(|f| {
+            let too_big = self.max_size.map(|max| f.size > 0 && f.size + bytes.len() as u64 > max).unwrap_or(false);
+            let too_old = self.max_age.map(|max| f.opened.elapsed() >= max).unwrap_or(false);
+
+            too_big || too_old
+        }).unwrap_or(false);
+
+        if needs_rotation {
+            self.rotate(path.as_path()).await?;
+            self.open(path.as_path()).await?;
+        }
+
+        let file = self.files.get_mut(&path).unwrap();
+
+        file.writer.write_all(bytes).await.with_context(|| format!("Error writing to {}", path.display()))?;
+        file.size += bytes.len() as u64;
+        file.last_used = self.uses;
+
+        Ok( () )
+    }
 }
 
 #[async_trait]
@@ -830,15 +1063,78 @@ impl Plugin for FileOutput {
     async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
         debug!("FileOutput args: {:#?}", args);
 
-        // grab the path of the file to read
+        // grab the path of the file to write, which can be a template
         let file_path = args.get("path").ok_or_else(|| anyhow!("Could not find 'path' arg for FileOutput"))?;
         let file_path = file_path.as_str().ok_or_else(|| anyhow!("The 'path' arg for FileOutput does not appear to be a string"))?;
-        let file = BufWriter::new(File::create(file_path).await.context(format!("Error attempting to open {}", file_path))?);
+        let path = Template::parse(file_path).context("Parsing the 'path' arg for FileOutput")?;
+
+        let ts_field = args.get("ts_field").unwrap_or(&Value::String("t".to_string())).to_owned();
+        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for FileOutput does not appear to be a string"))?.to_string();
+
+        let append = args.get("append").unwrap_or(&Value::Boolean(true));
+        let append = append.as_bool().ok_or_else(|| anyhow!("The 'append' arg for FileOutput does not appear to be a boolean"))?;
+
+        let max_size = match args.get("rotate_size_mb") {
+            None => None,
+            Some(v) => {
+                let mb = v.as_integer().ok_or_else(|| anyhow!("The 'rotate_size_mb' arg for FileOutput does not appear to be an integer"))?;
+
+                if mb < 1 {
+                    bail!("The 'rotate_size_mb' arg for FileOutput must be positive");
+                }
+
+                Some(mb as u64 * 1024 * 1024)
+            }
+        };
+
+        let max_age = match args.get("rotate_secs") {
+            None => None,
+            Some(v) => {
+                let secs = v.as_integer().ok_or_else(|| anyhow!("The 'rotate_secs' arg for FileOutput does not appear to be an integer"))?;
+
+                if secs < 1 {
+                    bail!("The 'rotate_secs' arg for FileOutput must be positive");
+                }
+
+                Some(Duration::from_secs(secs as u64))
+            }
+        };
+
+        let retain = args.get("retain").unwrap_or(&Value::Integer(5));
+        let retain = retain.as_integer().ok_or_else(|| anyhow!("The 'retain' arg for FileOutput does not appear to be an integer"))?;
+
+        if !(0..=1000).contains(&retain) {
+            bail!("Nonsensical value {} for 'retain' for FileOutput; should be between 0 and 1000", retain);
+        }
+
+        let compress = args.get("compress").unwrap_or(&Value::Boolean(false));
+        let compress = compress.as_bool().ok_or_else(|| anyhow!("The 'compress' arg for FileOutput does not appear to be a boolean"))?;
+
+        let max_open_files = args.get("max_open_files").unwrap_or(&Value::Integer(16));
+        let max_open_files = max_open_files.as_integer().ok_or_else(|| anyhow!("The 'max_open_files' arg for FileOutput does not appear to be an integer"))?;
+
+        if !(1..=1024).contains(&max_open_files) {
+            bail!("Nonsensical value {} for 'max_open_files' for FileOutput; should be between 1 and 1024", max_open_files);
+        }
+
+        let codec = OutputCodec::from_args(&args, Self::name())?;
 
         Ok(Box::new(FileOutput {
             tripwire,
-            file,
             receiver: None, // set in connect_receiver
+            codec,
+            path,
+            ts_field,
+            append,
+            max_size,
+            max_age,
+            retain: retain as usize,
+            compress,
+            max_open_files: max_open_files as usize,
+            files: HashMap::new(),
+            closed: HashMap::new(),
+            started: SystemTime::now(),
+            uses: 0,
         }))
     }
 
@@ -852,17 +1148,25 @@ impl Plugin for FileOutput {
 
         while let Some(event) = event_stream.next().await {
             let (event, callback) = recv_event!(event);
-            let event_str = event.to_string();
-
-            // debug!("FileOutput event: {}", event_str);
 
-            if let Err(e) = self.file.write_all(event_str.as_bytes()).await {
-                error!("Error writing to file: {:?}", e);
-                return; // return if we can't write
+            // skip the None events
+            if Event::None == event {
+                callback.call();
+                continue;
             }
 
-            // write the newline
-            if let Err(e) = self.file.write_all("\n".as_bytes()).await {
+            let event_bytes = match self.codec.encode(&event) {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("Error serializing event: {}", e);
+                    callback.call();
+                    continue;
+                }
+            };
+
+            let path = PathBuf::from(self.path.render_path(&event, &event_time(&event, self.ts_field.as_str())));
+
+            if let Err(e) = self.write(path, event_bytes.as_slice()).await {
                 error!("Error writing to file: {:?}", e);
                 return; // return if we can't write
             }
@@ -872,8 +1176,10 @@ impl Plugin for FileOutput {
             count += 1;
         }
 
-        if let Err(e) = self.file.flush().await {
-            error!("Error flushing file: {:?}", e);
+        for (path, file) in self.files.iter_mut() {
+            if let Err(e) = file.writer.flush().await {
+                error!("Error flushing {}: {:?}", path.display(), e);
+            }
         }
 
         let secs = Instant::now().duration_since(start).as_secs_f64();
@@ -884,3 +1190,172 @@ impl Plugin for FileOutput {
     connect_receiver!{}
 }
 
+
+#[cfg(test)]
+mod file_output_tests {
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::sync::{broadcast, Semaphore};
+    use toml::Value;
+
+    use crate::common::init_test_logger;
+    use crate::event::Event;
+    use crate::plugin::{Args, Callback, Plugin};
+    use crate::plugins::FileOutput;
+
+    async fn write_events(args: Args, events: Vec<Event>) {
+        let (trigger, tripwire) = Tripwire::new();
+        let mut output = FileOutput::new(args, tripwire).await.expect("Error creating FileOutput");
+        let (sender, receiver) = broadcast::channel(events.len() + 1);
+        let semaphore = Arc::new(Semaphore::new(events.len() + 1));
+
+        output.connect_receiver(receiver);
+
+        for event in events {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            sender.send((event, Arc::new(permit), Arc::new(Callback::empty()))).expect("Error sending");
+        }
+
+        // closing the channel stops the output once everything is written
+        drop(sender);
+        output.run().await;
+        drop(trigger);
+    }
+
+    fn path_args(path: &Path) -> Args {
+        let mut args = Args::new();
+        args.insert("path".to_string(), Value::String(path.display().to_string()));
+        args
+    }
+
+    #[tokio::test]
+    async fn append() {
+        init_test_logger();
+        let dir = tempfile::TempDir::new().unwrap();
+        let file_path = dir.path().join("out.log");
+
+        fs::write(&file_path, "existing\n").unwrap();
+
+        write_events(path_args(&file_path), vec![Event::from("new")]).await;
+        assert_eq!("existing\nnew\n", fs::read_to_string(&file_path).unwrap());
+
+        let mut args = path_args(&file_path);
+        args.insert("append".to_string(), Value::Boolean(false));
+
+        write_events(args, vec![Event::from("a"), Event::from("b")]).await;
+        assert_eq!("a\nb\n", fs::read_to_string(&file_path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn rotate_and_compress() {
+        init_test_logger();
+        let dir = tempfile::TempDir::new().unwrap();
+        let file_path = dir.path().join("out.log");
+        let line = "x".repeat(300 * 1024);
+
+        let mut args = path_args(&file_path);
+        args.insert("rotate_size_mb".to_string(), Value::Integer(1));
+        args.insert("retain".to_string(), Value::Integer(2));
+        args.insert("compress".to_string(), Value::Boolean(true));
+
+        // 3 lines fit in a file, so 10 lines means 3 rotations, with the oldest discarded
+        write_events(args, (0..10).map(|i| Event::from(format!("{}{}", i, line).as_str())).collect()).await;
+
+        let read_gz = |n: usize| {
+            let mut contents = String::new();
+            GzDecoder::new(fs::File::open(format!("{}.{}.gz", file_path.display(), n)).unwrap()).read_to_string(&mut contents).unwrap();
+            contents
+        };
+
+        assert_eq!(format!("9{}\n", line), fs::read_to_string(&file_path).unwrap());
+        assert!(read_gz(1).starts_with('6'));
+        assert_eq!(3, read_gz(1).lines().count());
+        assert!(read_gz(2).starts_with('3'));
+        assert!(!Path::new(format!("{}.3.gz", file_path.display()).as_str()).exists());
+    }
+
+    #[tokio::test]
+    async fn path_template() {
+        init_test_logger();
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let mut args = path_args(&dir.path().join("{host}").join("%Y-%m-%d.log"));
+        args.insert("max_open_files".to_string(), Value::Integer(1));
+
+        // alternate hosts so the files are closed and re-opened
+        write_events(args, vec![
+            Event::Json(json!({"host": "web1", "t": "2023-07-07T14:02:12Z", "n": 1})),
+            Event::Json(json!({"host": "web2", "t": "2023-07-07T14:02:12Z", "n": 2})),
+            Event::Json(json!({"host": "web1", "t": "2023-07-08T00:00:01Z", "n": 3})),
+            Event::Json(json!({"host": "web1", "t": "2023-07-07T23:59:59Z", "n": 4})),
+        ]).await;
+
+        let read = |p: &str| fs::read_to_string(dir.path().join(p)).unwrap();
+
+        assert_eq!(4, read("web1/2023-07-07.log").lines().count() + read("web2/2023-07-07.log").lines().count() + read("web1/2023-07-08.log").lines().count());
+        assert!(read("web1/2023-07-07.log").contains("\"n\":4"));
+        assert!(read("web2/2023-07-07.log").contains("\"n\":2"));
+        assert!(read("web1/2023-07-08.log").contains("\"n\":3"));
+    }
+
+    #[tokio::test]
+    async fn path_outside_dir() {
+        init_test_logger();
+        let dir = tempfile::TempDir::new().unwrap();
+        let archive = dir.path().join("archive");
+
+        write_events(path_args(&archive.join("{host}").join("{app}.log")), vec![
+            Event::Json(json!({"host": "../../etc/cron.d/x", "app": "a"})),
+            Event::Json(json!({"host": "..", "app": "b"})),
+        ]).await;
+
+        assert!(archive.join(".._.._etc_cron.d_x").join("a.log").exists());
+        assert!(archive.join("__").join("b.log").exists());
+        assert!(!dir.path().join("b.log").exists());
+    }
+
+    #[tokio::test]
+    async fn rotate_after_close() {
+        init_test_logger();
+        let dir = tempfile::TempDir::new().unwrap();
+
+        let mut args = path_args(&dir.path().join("{host}.log"));
+        args.insert("max_open_files".to_string(), Value::Integer(1));
+        args.insert("rotate_secs".to_string(), Value::Integer(1));
+
+        let (trigger, tripwire) = Tripwire::new();
+        let mut output = FileOutput::new(args, tripwire).await.expect("Error creating FileOutput");
+        let (sender, receiver) = broadcast::channel(4);
+        let semaphore = Arc::new(Semaphore::new(4));
+
+        output.connect_receiver(receiver);
+
+        let jh = tokio::spawn(async move { output.run().await });
+
+        // web1 is closed to make room for web2, but should still be rotated once it's a second old
+        for (n, host) in ["web1", "web2", "web1"].iter().enumerate() {
+            if n == 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+            }
+
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            sender.send((Event::Json(json!({"host": host, "n": n})), Arc::new(permit), Arc::new(Callback::empty()))).expect("Error sending");
+        }
+
+        drop(sender);
+        jh.await.unwrap();
+        drop(trigger);
+
+        let read = |p: &str| fs::read_to_string(dir.path().join(p)).unwrap();
+
+        assert!(read("web1.log.1").contains("\"n\":0"));
+        assert!(read("web1.log").contains("\"n\":2"));
+        assert!(!read("web1.log").contains("\"n\":0"));
+    }
+}
@@ -62,10 +62,12 @@ impl Plugin for FortinetParser {
 
             let event = match event {
                 Event::None => {
+                    callback.call();
                     continue // nothing to do here
                 }
                 Event::Json(_) => {
                     warn!("Found JSON log for Syslog");
+                    callback.call();
                     continue;
                 }
                 Event::String(msg) => {
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::Map;
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::common::logging::{debug, error, warn};
+use crate::event::{Event, JsonValue};
+use crate::field_path::FieldPath;
+use crate::{Args, Plugin, send_event, connect_receiver, create_event_stream, get_receiver, recv_event, create_sender_semaphore};
+use crate::plugin::{PluginType, ChannelType};
+
+/// Max depth of patterns referencing patterns, to catch cycles
+const MAX_PATTERN_DEPTH: usize = 32;
+
+/// Prefix of the regex group names generated for `%{NAME:field}` references, as fields can contain characters groups can't
+const GROUP_PREFIX: &str = "__grok";
+
+/// The built-in library of patterns, referenced as `%{NAME}` or `%{NAME:field}`
+const PATTERNS: &[(&str, &str)] = &[
+    ("INT", r"[+-]?\d+"),
+    ("NUMBER", r"[+-]?(?:\d+(?:\.\d*)?|\.\d+)"),
+    ("WORD", r"\b\w+\b"),
+    ("NOTSPACE", r"\S+"),
+    ("SPACE", r"\s*"),
+    ("DATA", r".*?"),
+    ("GREEDYDATA", r".*"),
+    ("QUOTEDSTRING", r#""(?:[^"\\]|\\.)*""#),
+    ("USER", r"[a-zA-Z0-9._@-]+"),
+    ("IPV4", r"(?:(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)\.){3}(?:25[0-5]|2[0-4]\d|1\d\d|[1-9]?\d)"),
+    // the IPv6 pattern from Logstash, with word boundaries so times and "a:b:" aren't matched,
+    // and the embedded IPv4 forms first so they aren't cut short at the first '.'
+    ("IPV6", r"(?:\b(?:(?:[0-9A-Fa-f]{1,4}:){7}(?:[0-9A-Fa-f]{1,4}\b|:)|(?:[0-9A-Fa-f]{1,4}:){6}(?:%{IPV4}\b|:[0-9A-Fa-f]{1,4}\b|:)|(?:[0-9A-Fa-f]{1,4}:){5}(?::%{IPV4}\b|(?::[0-9A-Fa-f]{1,4}){1,2}\b|:)|(?:[0-9A-Fa-f]{1,4}:){4}(?:(?::[0-9A-Fa-f]{1,4})?:%{IPV4}\b|(?::[0-9A-Fa-f]{1,4}){1,3}\b|:)|(?:[0-9A-Fa-f]{1,4}:){3}(?:(?::[0-9A-Fa-f]{1,4}){0,2}:%{IPV4}\b|(?::[0-9A-Fa-f]{1,4}){1,4}\b|:)|(?:[0-9A-Fa-f]{1,4}:){2}(?:(?::[0-9A-Fa-f]{1,4}){0,3}:%{IPV4}\b|(?::[0-9A-Fa-f]{1,4}){1,5}\b|:)|(?:[0-9A-Fa-f]{1,4}:){1}(?:(?::[0-9A-Fa-f]{1,4}){0,4}:%{IPV4}\b|(?::[0-9A-Fa-f]{1,4}){1,6}\b|:))|:(?:(?::[0-9A-Fa-f]{1,4}){0,5}:%{IPV4}\b|(?::[0-9A-Fa-f]{1,4}){1,7}\b|:))(?:%\w+)?"),
+    ("IP", r"(?:%{IPV6}|%{IPV4})"),
+    ("HOSTNAME", r"\b[0-9A-Za-z][0-9A-Za-z-]{0,62}(?:\.[0-9A-Za-z][0-9A-Za-z-]{0,62})*\.?\b"),
+    ("IPORHOST", r"(?:%{IP}|%{HOSTNAME})"),
+    ("PORT", r"\d{1,5}"),
+    ("PATH", r"/[^\s?#]*"),
+    ("URIPATHPARAM", r"\S+"),
+    ("MONTH", r"\b(?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)[a-z]*\b"),
+    ("MONTHNUM", r"(?:0?[1-9]|1[0-2])"),
+    ("MONTHDAY", r"(?:0[1-9]|[12]\d|3[01]|[1-9])"),
+    ("DAY", r"\b(?:Mon|Tue|Wed|Thu|Fri|Sat|Sun)[a-z]*\b"),
+    ("YEAR", r"\d{4}"),
+    ("HOUR", r"(?:[01]?\d|2[0-3])"),
+    ("MINUTE", r"[0-5]\d"),
+    ("SECOND", r"(?:[0-5]\d|60)(?:[.,]\d+)?"),
+    ("TIME", r"%{HOUR}:%{MINUTE}:%{SECOND}"),
+    ("ISO8601_TIMEZONE", r"(?:Z|[+-]%{HOUR}(?::?%{MINUTE}))"),
+    ("TIMESTAMP_ISO8601", r"%{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{TIME}%{ISO8601_TIMEZONE}?"),
+    ("HTTPDATE", r"%{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} [+-]\d{4}"),
+    ("SYSLOGTIMESTAMP", r"%{MONTH} +%{MONTHDAY} %{TIME}"),
+    ("LOGLEVEL", r"(?i:trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|alert|emerg(?:ency)?|fatal|severe)"),
+    ("COMMONAPACHELOG", r#"%{IPORHOST:host} %{USER:ident} %{USER:user} \[%{HTTPDATE:timestamp}\] "%{WORD:method} %{NOTSPACE:path} HTTP/%{NUMBER:proto}" %{INT:status:int} (?:%{INT:size:int}|-)"#),
+    ("COMBINEDAPACHELOG", r#"%{COMMONAPACHELOG} "%{DATA:ref}" "%{DATA:user_agent}""#),
+];
+
+/// The type a capture is converted to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl CaptureType {
+    fn parse(type_str: &str) -> Result<Self> {
+        match type_str {
+            "string" => Ok(CaptureType::String),
+            "int" => Ok(CaptureType::Int),
+            "float" => Ok(CaptureType::Float),
+            "bool" => Ok(CaptureType::Bool),
+            _ => bail!("Unknown type '{}'; use one of: string, int, float, bool", type_str)
+        }
+    }
+
+    /// Converts the capture, leaving it as a string if it cannot be converted
+    fn convert(&self, capture: &str) -> JsonValue {
+        let converted = match self {
+            CaptureType::String => None,
+            CaptureType::Int => capture.parse::<i64>().ok().map(JsonValue::from),
+            CaptureType::Float => capture.parse::<f64>().ok().map(JsonValue::from),
+            CaptureType::Bool => capture.parse::<bool>().ok().map(JsonValue::from),
+        };
+
+        converted.unwrap_or_else(|| JsonValue::from(capture))
+    }
+}
+
+/// What to do with an event that does not match any of the patterns
+#[derive(Debug, Clone, PartialEq)]
+pub enum NoMatch {
+    Drop,
+    Tag(String),
+    Pass,
+}
+
+impl NoMatch {
+    /// Parses the 'on_no_match' and 'tag' args; the tag defaults to `_<plugin name>_failure`
+    pub fn from_args(args: &Args, plugin_name: &str, default: &str) -> Result<Self> {
+        let on_no_match = args.get("on_no_match").unwrap_or(&Value::String(default.to_string())).to_owned();
+        let on_no_match = on_no_match.as_str().ok_or_else(|| anyhow!("The 'on_no_match' arg for {} does not appear to be a string", plugin_name))?;
+
+        match on_no_match {
+            "drop" => Ok(NoMatch::Drop),
+            "pass" => Ok(NoMatch::Pass),
+            "tag" => {
+                let tag = args.get("tag").unwrap_or(&Value::String(format!("_{}_failure", plugin_name))).to_owned();
+                let tag = tag.as_str().ok_or_else(|| anyhow!("The 'tag' arg for {} does not appear to be a string", plugin_name))?;
+
+                Ok(NoMatch::Tag(tag.to_string()))
+            }
+            _ => bail!("Unknown value '{}' for 'on_no_match' for {}; use one of: drop, tag, pass", on_no_match, plugin_name)
+        }
+    }
+
+    /// Handles an event that did not match, returning None if it should be dropped
+    pub fn apply(&self, mut obj: Map<String, JsonValue>) -> Option<Map<String, JsonValue>> {
+        match self {
+            NoMatch::Drop => return None,
+            NoMatch::Pass => (),
+            NoMatch::Tag(tag) => {
+                match obj.entry("tags").or_insert_with(|| JsonValue::Array(Vec::new())) {
+                    JsonValue::Array(tags) => tags.push(JsonValue::from(tag.as_str())),
+                    _ => warn!("The 'tags' field is not an array; cannot tag event")
+                }
+            }
+        }
+
+        Some(obj)
+    }
+}
+
+/// A field a regex group is inserted into
+#[derive(Debug, Clone)]
+struct Capture {
+    field: String,
+    path: FieldPath,
+}
+
+/// Expands the `%{NAME}`, `%{NAME:field}`, and `%{NAME:field:type}` references in a pattern to a regex,
+/// recording the field of each generated group, and the type of any typed captures
+fn expand(pattern: &str, library: &HashMap<String, String>, groups: &mut HashMap<String, Capture>, types: &mut HashMap<String, CaptureType>, depth: usize) -> Result<String> {
+    if depth > MAX_PATTERN_DEPTH {
+        bail!("Patterns nested too deeply; is there a cycle?");
+    }
+
+    let mut ret = String::new();
+    let mut rest = pattern;
+
+    while let Some(start) = rest.find("%{") {
+        let end = rest[start..].find('}').ok_or_else(|| anyhow!("Unclosed pattern reference in: {}", pattern))? + start;
+        let mut parts = rest[start + 2..end].splitn(3, ':');
+        let name = parts.next().unwrap_or_default();
+        let sub_pattern = library.get(name).ok_or_else(|| anyhow!("Unknown pattern: {}", name))?;
+        let sub_regex = expand(sub_pattern, library, groups, types, depth + 1)?;
+
+        ret.push_str(&rest[..start]);
+
+        match parts.next() {
+            Some(field) => {
+                if let Some(type_str) = parts.next() {
+                    types.insert(field.to_string(), CaptureType::parse(type_str)?);
+                }
+
+                let group = format!("{}{}", GROUP_PREFIX, groups.len());
+                let path = FieldPath::parse(field).with_context(|| format!("Parsing the field of %{{{}:{}}}", name, field))?;
+
+                groups.insert(group.clone(), Capture { field: field.to_string(), path });
+                ret.push_str(format!("(?P<{}>{})", group, sub_regex).as_str());
+            }
+            None => ret.push_str(format!("(?:{})", sub_regex).as_str())
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    ret.push_str(rest);
+
+    Ok(ret)
+}
+
+/// The compiled patterns, and how to handle the captures
+#[derive(Debug)]
+struct Grok {
+    regexes: Vec<Regex>,
+    groups: HashMap<String, Capture>,
+    types: HashMap<String, CaptureType>,
+    field: Option<String>,
+    no_match: NoMatch,
+}
+
+impl Grok {
+    /// Applies the patterns to the event, returning None if the event should be dropped
+    fn process(&self, event: Event) -> Option<Event> {
+        let obj = match event {
+            Event::Json(JsonValue::Object(obj)) => obj,
+            Event::String(s) => {
+                let mut obj = Map::new();
+                obj.insert("message".to_string(), JsonValue::from(s));
+                obj
+            }
+            Event::Json(_) | Event::None => return Some(event)
+        };
+
+        let field = self.field.as_deref().unwrap_or("message");
+        let captures = obj.get(field).and_then(|v| v.as_str()).and_then(|text| {
+            self.regexes.iter().find_map(|re| {
+                re.captures(text).map(|caps| {
+                    re.capture_names()
+                      .flatten()
+                      .filter_map(|name| caps.name(name).map(|m| (self.capture(name), m.as_str().to_string())))
+                      .collect::<Vec<_>>()
+                })
+            })
+        });
+
+        let captures = match captures {
+            Some(captures) => captures,
+            None => return Some(Event::Json(JsonValue::Object(self.no_match.apply(obj)?)))
+        };
+
+        let mut event = JsonValue::Object(obj);
+
+        for (capture, value) in captures {
+            let value = match self.types.get(&capture.field) {
+                Some(t) => t.convert(value.as_str()),
+                None => JsonValue::from(value)
+            };
+
+            if let Err(e) = capture.path.insert(&mut event, value) {
+                warn!("Error setting '{}': {}", capture.field, e);
+            }
+        }
+
+        Some(Event::Json(event))
+    }
+
+    /// The field for a regex group; groups named in the pattern with `(?P<field>...)` are used as-is
+    fn capture(&self, group: &str) -> Capture {
+        match self.groups.get(group) {
+            Some(capture) => capture.clone(),
+            None => Capture { field: group.to_string(), path: FieldPath::from_keys([group]) }
+        }
+    }
+}
+
+pub struct GrokTransform {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    grok: Grok,
+}
+
+impl GrokTransform {
+    fn parse_args(args: &Args) -> Result<Grok> {
+        let mut library = PATTERNS.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+
+        if let Some(custom) = args.get("custom_patterns") {
+            let custom = custom.as_table().ok_or_else(|| anyhow!("The 'custom_patterns' arg for {} does not appear to be a table", Self::name()))?;
+
+            for (name, pattern) in custom {
+                let pattern = pattern.as_str().ok_or_else(|| anyhow!("The custom pattern '{}' for {} does not appear to be a string", name, Self::name()))?;
+                library.insert(name.clone(), pattern.to_string());
+            }
+        }
+
+        let patterns = match args.get("pattern").or_else(|| args.get("patterns")) {
+            Some(Value::String(s)) => vec![s.clone()],
+            Some(Value::Array(a)) => {
+                a.iter()
+                 .map(|p| p.as_str().map(|s| s.to_string()))
+                 .collect::<Option<Vec<_>>>()
+                 .ok_or_else(|| anyhow!("Found non-string pattern in 'patterns' arg for {}", Self::name()))?
+            }
+            Some(_) => bail!("The 'patterns' arg for {} must be a string or an array of strings", Self::name()),
+            None => bail!("Could not find 'pattern' or 'patterns' arg for {}", Self::name())
+        };
+
+        let mut groups = HashMap::new();
+        let mut types = HashMap::new();
+        let mut regexes = Vec::with_capacity(patterns.len());
+
+        for pattern in patterns {
+            let expanded = expand(pattern.as_str(), &library, &mut groups, &mut types, 0).with_context(|| format!("Expanding pattern for {}: {}", Self::name(), pattern))?;
+            let regex = Regex::new(expanded.as_str()).with_context(|| format!("Compiling pattern for {}: {}", Self::name(), pattern))?;
+
+            regexes.push(regex);
+        }
+
+        if let Some(arg_types) = args.get("types") {
+            let arg_types = arg_types.as_table().ok_or_else(|| anyhow!("The 'types' arg for {} does not appear to be a table", Self::name()))?;
+
+            for (field, type_str) in arg_types {
+                let type_str = type_str.as_str().ok_or_else(|| anyhow!("The type of '{}' for {} does not appear to be a string", field, Self::name()))?;
+                types.insert(field.clone(), CaptureType::parse(type_str)?);
+            }
+        }
+
+        let field = match args.get("field") {
+            None => None,
+            Some(f) => Some(f.as_str().ok_or_else(|| anyhow!("The 'field' arg for {} does not appear to be a string", Self::name()))?.to_string())
+        };
+
+        let no_match = NoMatch::from_args(args, Self::name(), "pass")?;
+
+        Ok(Grok { regexes, groups, types, field, no_match })
+    }
+}
+
+#[async_trait]
+impl Plugin for GrokTransform {
+    fn name() -> &'static str {
+        "grok"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+        debug!("GrokTransform args: {:#?}", args);
+
+        let grok = GrokTransform::parse_args(&args)?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(GrokTransform {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            sender,
+            semaphore,
+            grok,
+        }))
+    }
+
+    async fn run(&mut self) {
+        let mut event_stream = create_event_stream!(self);
+
+        while let Some(event) = event_stream.next().await {
+            let (event, callback) = recv_event!(event);
+
+            if Event::None == event {
+                callback.call();
+                continue // nothing to do here
+            }
+
+            let event = match self.grok.process(event) {
+                Some(event) => event,
+                None => {
+                    // dropped, so it's done being processed
+                    callback.call();
+                    continue
+                }
+            };
+
+            send_event!(self, event, callback);
+        }
+    }
+
+    // boilerplate methods
+    get_receiver!{}
+    connect_receiver!{}
+}
+
+
+#[cfg(test)]
+mod grok_tests {
+    use serde_json::json;
+    use toml::Value;
+
+    use crate::Args;
+    use crate::event::Event;
+    use crate::plugins::grok::GrokTransform;
+
+    fn args(pattern: &str, extra: &[(&str, Value)]) -> Args {
+        let mut args = Args::new();
+
+        args.insert("pattern".to_string(), Value::String(pattern.to_string()));
+
+        for (k, v) in extra {
+            args.insert(k.to_string(), v.clone());
+        }
+
+        args
+    }
+
+    #[test]
+    fn combined_log() {
+        let grok = GrokTransform::parse_args(&args("%{COMBINEDAPACHELOG}", &[])).expect("Error parsing args");
+        let line = r#"192.168.1.7 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08""#;
+
+        let event = grok.process(Event::from(line)).expect("Event dropped");
+
+        assert_eq!(Event::Json(json!({
+            "message": line,
+            "host": "192.168.1.7",
+            "ident": "-",
+            "user": "frank",
+            "timestamp": "10/Oct/2000:13:55:36 -0700",
+            "method": "GET",
+            "path": "/apache_pb.gif",
+            "proto": "1.0",
+            "status": 200,
+            "size": 2326,
+            "ref": "http://www.example.com/start.html",
+            "user_agent": "Mozilla/4.08"
+        })), event);
+    }
+
+    #[test]
+    fn field_and_types() {
+        let mut types = toml::value::Table::new();
+        types.insert("port".to_string(), Value::String("int".to_string()));
+
+        let grok = GrokTransform::parse_args(&args(r"(?P<ip>%{IP}):(?P<port>\d+) took %{NUMBER:secs:float}s",
+                                                   &[("field", Value::String("msg".to_string())), ("types", Value::Table(types))])).expect("Error parsing args");
+
+        let event = grok.process(Event::Json(json!({"msg": "10.0.0.1:8080 took 0.25s"}))).expect("Event dropped");
+
+        assert_eq!(Event::Json(json!({"msg": "10.0.0.1:8080 took 0.25s", "ip": "10.0.0.1", "port": 8080, "secs": 0.25})), event);
+    }
+
+    #[test]
+    fn nested_fields() {
+        let grok = GrokTransform::parse_args(&args(r"%{IP:client.ip}:%{PORT:client.port:int} %{WORD:method}", &[])).expect("Error parsing args");
+
+        let event = grok.process(Event::from("10.0.0.1:8080 GET")).expect("Event dropped");
+
+        assert_eq!(Event::Json(json!({
+            "message": "10.0.0.1:8080 GET",
+            "client": {"ip": "10.0.0.1", "port": 8080},
+            "method": "GET"
+        })), event);
+
+        assert!(GrokTransform::parse_args(&args("%{IP:client..ip}", &[])).is_err());
+    }
+
+    #[test]
+    fn ip_addresses() {
+        let grok = GrokTransform::parse_args(&args("%{IP:ip}", &[("on_no_match", Value::String("drop".to_string()))])).expect("Error parsing args");
+
+        for ip in ["10.0.0.1", "2001:db8::1", "::1", "::", "fe80::1%eth0", "::ffff:192.168.1.1", "1:2:3:4:5:6:7:8", "1:2:3:4:5:6:7::"] {
+            assert_eq!(Some(Event::Json(json!({"message": format!("from {} at", ip), "ip": ip}))),
+                       grok.process(Event::from(format!("from {} at", ip).as_str())), "{}", ip);
+        }
+
+        // times and words with colons aren't addresses
+        for line in ["14:02:12", "a:b:", "abc:def:", "at 14:02:12 a:b: c"] {
+            assert_eq!(None, grok.process(Event::from(line)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn no_match() {
+        let pass = GrokTransform::parse_args(&args("^%{INT:n}$", &[])).expect("Error parsing args");
+        assert_eq!(Some(Event::Json(json!({"message": "abc"}))), pass.process(Event::from("abc")));
+
+        let drop = GrokTransform::parse_args(&args("^%{INT:n}$", &[("on_no_match", Value::String("drop".to_string()))])).expect("Error parsing args");
+        assert_eq!(None, drop.process(Event::from("abc")));
+
+        let tag = GrokTransform::parse_args(&args("^%{INT:n}$", &[("on_no_match", Value::String("tag".to_string()))])).expect("Error parsing args");
+        assert_eq!(Some(Event::Json(json!({"message": "abc", "tags": ["_grok_failure"]}))), tag.process(Event::from("abc")));
+    }
+
+    #[test]
+    fn bad_patterns() {
+        assert!(GrokTransform::parse_args(&args("%{NOPE:x}", &[])).is_err());
+        assert!(GrokTransform::parse_args(&args("%{INT:x", &[])).is_err());
+
+        let mut custom = toml::value::Table::new();
+        custom.insert("LOOP".to_string(), Value::String("%{LOOP}".to_string()));
+        assert!(GrokTransform::parse_args(&args("%{LOOP}", &[("custom_patterns", Value::Table(custom))])).is_err());
+    }
+}
@@ -1,7 +1,8 @@
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
+use serde_json::Map;
 use stream_cancel::{StreamExt, Tripwire};
 use tokio::sync::{broadcast, Semaphore};
 use tokio::sync::broadcast::{Receiver, Sender};
@@ -9,34 +10,197 @@ use tokio_stream::{StreamExt as TokioStreamExt};
 use tokio_stream::wrappers::BroadcastStream;
 use toml::{Value as TomlValue, Value};
 
+use crate::common::hostname;
 use crate::common::logging::{error, warn};
 use crate::event::{Event, JsonValue};
+use crate::field_path::FieldPath;
+use crate::template::{event_time, Template};
 use crate::{Args, Plugin, send_event, connect_receiver, create_event_stream, get_receiver, recv_event, create_sender_semaphore};
 use crate::plugin::{PluginType, ChannelType};
 
 
-pub struct InsertFieldTransform {
-    tripwire: Tripwire,
-    receiver: Option<Receiver<ChannelType>>,
-    sender: Sender<ChannelType>,
-    semaphore: Arc<Semaphore>,
-    field: String,
-    value: JsonValue,
-    overwrite: bool,
+/// Looks up an environment variable by name
+type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// Looks up a variable in the environment of the process
+fn process_env(name: &str) -> Option<String> {
+    std::env::var(name).ok()
 }
 
-fn toml2jsonvalue(value: &TomlValue) -> Result<JsonValue> {
+/// Converts a TOML value to JSON, expanding `${ENV_VAR}` references in all strings
+pub fn toml2jsonvalue(value: &TomlValue) -> Result<JsonValue> {
+    toml2jsonvalue_with(value, &process_env)
+}
+
+/// Converts a TOML value to JSON, expanding `${ENV_VAR}` references from the given environment
+fn toml2jsonvalue_with(value: &TomlValue, env: Env) -> Result<JsonValue> {
     match value {
-        Value::String(s) => { Ok(JsonValue::from(s.clone())) }
+        Value::String(s) => { Ok(JsonValue::from(expand_env(s, env)?)) }
         Value::Integer(i) => { Ok(JsonValue::from(*i)) }
         Value::Float(f) => { Ok(JsonValue::from(*f)) }
         Value::Boolean(b) => { Ok(JsonValue::from(*b)) }
-        Value::Array(_) => { Err(anyhow!("Array not a valid JSON value")) }
-        Value::Datetime(_) => { Err(anyhow!("DateTime not a valid JSON value")) }
-        Value::Table(_) => { Err(anyhow!("Table not a valid JSON value")) }
+        Value::Array(a) => { Ok(JsonValue::Array(a.iter().map(|v| toml2jsonvalue_with(v, env)).collect::<Result<Vec<_>>>()?)) }
+        Value::Datetime(dt) => { Ok(JsonValue::from(dt.to_string())) }
+        Value::Table(t) => {
+            Ok(JsonValue::Object(t.iter().map(|(k, v)| Ok((k.clone(), toml2jsonvalue_with(v, env)?))).collect::<Result<Map<_, _>>>()?))
+        }
+    }
+}
+
+/// Replaces `${NAME}` with the value of the environment variable, and `$${` with a literal `${`
+///
+/// `${HOSTNAME}` falls back to the local hostname when the variable isn't set, as it usually isn't exported.
+fn expand_env(s: &str, env: Env) -> Result<String> {
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            ret.push_str(&rest[..start]);
+            ret.push('{');
+            rest = &rest[start + 2..];
+            continue
+        }
+
+        ret.push_str(&rest[..start]);
+
+        let end = rest[start..].find('}').ok_or_else(|| anyhow!("Unclosed '${{' in value: {}", s))? + start;
+        let name = &rest[start + 2..end];
+
+        let value = match env(name) {
+            Some(value) => value,
+            None if name == "HOSTNAME" => hostname()?,
+            None => bail!("The environment variable '{}' is not set", name)
+        };
+
+        ret.push_str(value.as_str());
+        rest = &rest[end + 1..];
+    }
+
+    ret.push_str(rest);
+
+    Ok(ret)
+}
+
+/// A value to insert, either fixed at startup, or rendered from the event
+#[derive(Debug, Clone, PartialEq)]
+enum FieldValue {
+    Static(JsonValue),
+    Template(Template),
+}
+
+impl FieldValue {
+    fn from_toml(value: &TomlValue, env: Env) -> Result<Self> {
+        Ok(match toml2jsonvalue_with(value, env)? {
+            JsonValue::String(s) if is_template(s.as_str()) => FieldValue::Template(Template::parse(s.as_str())?),
+            value => FieldValue::Static(value)
+        })
+    }
+}
+
+/// Returns true if the string has a `{field}` reference or a `{{` escape, so it's rendered for each event;
+/// other strings containing braces, like `{"a": 1}` or `a{b`, are inserted as-is
+fn is_template(s: &str) -> bool {
+    let mut rest = s;
+
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+
+        if rest.starts_with('{') {
+            return true
+        }
+
+        let name = rest.strip_prefix('+').unwrap_or(rest);
+
+        if let Some(end) = name.find('}') {
+            if end > 0 && name[..end].chars().all(|c| c.is_alphanumeric() || "_-.@".contains(c)) {
+                return true
+            }
+        }
+    }
+
+    false
+}
+
+/// Inserts fields into events
+#[derive(Debug)]
+struct FieldInserter {
+    fields: Vec<(FieldPath, FieldValue)>,
+    overwrite: bool,
+    ts_field: String,
+}
+
+impl FieldInserter {
+    fn from_args(args: &Args, plugin_name: &str, env: Env) -> Result<Self> {
+        let mut fields = Vec::new();
+
+        // a single field is a top-level key, even if it contains a `.`
+        if let Some(field) = args.get("field") {
+            let field = field.as_str().ok_or_else(|| anyhow!("The 'field' arg for {} does not appear to be a string", plugin_name))?;
+            let value = args.get("value").ok_or_else(|| anyhow!("Could not find 'value' arg for {}", plugin_name))?;
+
+            fields.push((FieldPath::from_keys([field]), FieldValue::from_toml(value, env)?));
+        }
+
+        if let Some(table) = args.get("fields") {
+            let table = table.as_table().ok_or_else(|| anyhow!("The 'fields' arg for {} does not appear to be a table", plugin_name))?;
+
+            for (field, value) in table {
+                fields.push((FieldPath::parse(field)?, FieldValue::from_toml(value, env)?));
+            }
+        }
+
+        if fields.is_empty() {
+            bail!("Could not find 'field' or 'fields' arg for {}", plugin_name);
+        }
+
+        let overwrite = args.get("overwrite").unwrap_or(&Value::Boolean(false));
+        let overwrite = overwrite.as_bool().ok_or_else(|| anyhow!("The 'overwrite' are for {} does not appear to be a bool", plugin_name))?;
+
+        let ts_field = args.get("ts_field").unwrap_or(&Value::String("t".to_string())).to_owned();
+        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for {} does not appear to be a string", plugin_name))?.to_string();
+
+        Ok(FieldInserter { fields, overwrite, ts_field })
+    }
+
+    /// Inserts each field in order, so templates can reference fields inserted before them
+    fn process(&self, mut event: Event) -> Event {
+        for (path, value) in self.fields.iter() {
+            let json = match event {
+                Event::Json(ref mut json) => json,
+                _ => return event
+            };
+
+            if !self.overwrite && path.get(json).is_some() {
+                continue
+            }
+
+            let value = match value {
+                FieldValue::Static(value) => value.clone(),
+                FieldValue::Template(template) => {
+                    JsonValue::from(template.render(&event, &event_time(&event, self.ts_field.as_str())))
+                }
+            };
+
+            if let Event::Json(ref mut json) = event {
+                if let Err(e) = path.insert(json, value) {
+                    warn!("Error inserting field: {}", e);
+                }
+            }
+        }
+
+        event
     }
 }
 
+pub struct InsertFieldTransform {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    inserter: FieldInserter,
+}
+
 #[async_trait]
 impl Plugin for InsertFieldTransform {
     fn name() -> &'static str {
@@ -44,14 +208,7 @@ impl Plugin for InsertFieldTransform {
     }
 
     async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
-        let field = args.get("field").ok_or_else(|| anyhow!("Could not find 'field' arg for {}", Self::name()))?;
-        let field = field.as_str().ok_or_else(|| anyhow!("The 'field' arg for {} does not appear to be a string", Self::name()))?;
-
-        let value = args.get("value").ok_or_else(|| anyhow!("Could not find 'value' arg for {}", Self::name()))?;
-        let value = toml2jsonvalue(value)?;
-
-        let overwrite = args.get("overwrite").unwrap_or(&Value::Boolean(false));
-        let overwrite = overwrite.as_bool().ok_or_else(|| anyhow!("The 'overwrite' are for {} does not appear to be a bool", Self::name()))?;
+        let inserter = FieldInserter::from_args(&args, Self::name(), &process_env)?;
 
         // setup the channel
         let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
@@ -61,9 +218,7 @@ impl Plugin for InsertFieldTransform {
             receiver: None, // set in connect_receiver
             sender,
             semaphore,
-            field: field.to_string(),
-            value,
-            overwrite
+            inserter
         }))
     }
 
@@ -76,17 +231,12 @@ impl Plugin for InsertFieldTransform {
 
             let event = match event {
                 Event::None => {
+                    callback.call();
                     continue // nothing to do here
                 }
                 Event::Json(json) => {
                     match json {
-                        JsonValue::Object(mut obj) => {
-                            if self.overwrite || !obj.contains_key(&self.field) {
-                                obj.insert(self.field.clone(), self.value.clone());
-                            }
-
-                            Event::Json(JsonValue::from(obj))
-                        }
+                        JsonValue::Object(_) => self.inserter.process(Event::Json(json)),
                         _ => {
                             error!("Invalid JSON value received");
                             return
@@ -95,6 +245,7 @@ impl Plugin for InsertFieldTransform {
                 }
                 Event::String(_) => {
                     warn!("Found non-JSON log, skipping {}", Self::name());
+                    callback.call();
                     continue
                 }
             };
@@ -108,3 +259,104 @@ impl Plugin for InsertFieldTransform {
     get_receiver!{}
     connect_receiver!{}
 }
+
+
+#[cfg(test)]
+mod insert_field_tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::Args;
+    use crate::common::hostname;
+    use crate::event::Event;
+    use crate::plugins::insert_field::{expand_env, FieldInserter};
+
+    /// Looks up variables from a fixed test environment, rather than the process's
+    fn test_env(name: &str) -> Option<String> {
+        let env = HashMap::from([("LOG_SHIP_TEST_ENV", "prod"), ("LOG_SHIP_TEST_DC", "dc1")]);
+
+        env.get(name).map(|v| v.to_string())
+    }
+
+    fn inserter(args: &str) -> FieldInserter {
+        let args: Args = toml::from_str(args).expect("Error parsing TOML");
+
+        FieldInserter::from_args(&args, "insert_field", &test_env).expect("Error parsing args")
+    }
+
+    #[test]
+    fn single_field() {
+        let inserter = inserter(r#"field = "source"
+                                   value = "my_app""#);
+
+        assert_eq!(Event::Json(json!({"source": "my_app"})), inserter.process(Event::Json(json!({}))));
+        assert_eq!(Event::Json(json!({"source": "other"})), inserter.process(Event::Json(json!({"source": "other"}))));
+    }
+
+    #[test]
+    fn single_field_is_a_key() {
+        let inserter = inserter(r#"field = "dc.name"
+                                   value = "us-east""#);
+
+        assert_eq!(Event::Json(json!({"dc.name": "us-east"})), inserter.process(Event::Json(json!({}))));
+    }
+
+    #[test]
+    fn many_fields() {
+        let inserter = inserter(r#"overwrite = true
+                                   [fields]
+                                   env = "${LOG_SHIP_TEST_ENV}"
+                                   "dc.name" = "us-east"
+                                   "dc.racks" = [1, 2]
+                                   tags = ["a", { b = true }]
+                                   when = 1979-05-27T07:32:00Z
+                                   url = "https://{host}/{path}"
+                                   "#);
+        let event = inserter.process(Event::Json(json!({"host": "web1", "path": "index.html", "env": "dev"})));
+
+        assert_eq!(Event::Json(json!({
+            "host": "web1",
+            "path": "index.html",
+            "env": "prod",
+            "dc": {"name": "us-east", "racks": [1, 2]},
+            "tags": ["a", {"b": true}],
+            "when": "1979-05-27T07:32:00Z",
+            "url": "https://web1/index.html"
+        })), event);
+    }
+
+    #[test]
+    fn literals_and_templates() {
+        let inserter = inserter(r#"[fields]
+                                   json = '{"a":1}'
+                                   brace = "a{b"
+                                   spaced = "{not a field}"
+                                   percent = "100%"
+                                   escaped = "{{host}} is {host}"
+                                   time = "{host}-%Y"
+                                   "#);
+        let event = inserter.process(Event::Json(json!({"host": "web1", "t": "2023-07-07T14:02:12Z"})));
+
+        assert_eq!(Event::Json(json!({
+            "host": "web1",
+            "t": "2023-07-07T14:02:12Z",
+            "json": "{\"a\":1}",
+            "brace": "a{b",
+            "spaced": "{not a field}",
+            "percent": "100%",
+            "escaped": "{host} is web1",
+            "time": "web1-2023"
+        })), event);
+    }
+
+    #[test]
+    fn env() {
+        // HOSTNAME isn't in the test environment, so it falls back to the local hostname
+        assert_eq!("dc1-x", expand_env("${LOG_SHIP_TEST_DC}-x", &test_env).unwrap());
+        assert_eq!("${LOG_SHIP_TEST_DC} $5", expand_env("$${LOG_SHIP_TEST_DC} $5", &test_env).unwrap());
+        assert_eq!(hostname().unwrap(), expand_env("${HOSTNAME}", &test_env).unwrap());
+        assert!(expand_env("${LOG_SHIP_TEST_MISSING}", &test_env).is_err());
+        assert!(expand_env("${LOG_SHIP_TEST_DC", &test_env).is_err());
+    }
+}
@@ -72,6 +72,7 @@ impl Plugin for InsertTimestampTransform {
 
             let event = match event {
                 Event::None => {
+                    callback.call();
                     continue // nothing to do here
                 }
                 Event::Json(json) => {
@@ -100,6 +101,7 @@ impl Plugin for InsertTimestampTransform {
                 }
                 Event::String(_) => {
                     warn!("Found non-JSON log, skipping {}", Self::name());
+                    callback.call();
                     continue
                 }
             };
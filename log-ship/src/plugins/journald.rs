@@ -1,28 +1,489 @@
-use std::{fs, mem};
+use std::fs;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::sync::{Arc};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::Map;
 use systemd::{journal, JournalSeek};
 use stream_cancel::{StreamExt, Tripwire};
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio_stream::{StreamExt as TokioStreamExt};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use toml::Value;
 
-use crate::common::logging::{debug, error};
+use crate::common::logging::{debug, error, warn};
 use crate::{create_sender_semaphore, get_receiver, send_event};
-use crate::event::Event;
+use crate::event::{Event, JsonValue};
+use crate::field_path::glob_match;
 use crate::plugin::{Args, Callback, ChannelType, Plugin, PluginType};
+use crate::plugins::parse_ts::TsOutput;
+
+/// The syslog names of the `PRIORITY` values
+const SEVERITIES: &[&str] = &["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+/// Well-known journal fields that always hold an integer
+const NUMERIC_FIELDS: &[&str] = &[
+    "PRIORITY", "SYSLOG_FACILITY", "SYSLOG_PID", "ERRNO", "CODE_LINE",
+    "_PID", "_UID", "_GID", "_AUDIT_SESSION", "_AUDIT_LOGINUID", "_SYSTEMD_OWNER_UID",
+    "_SOURCE_REALTIME_TIMESTAMP", "_SOURCE_MONOTONIC_TIMESTAMP", "__REALTIME_TIMESTAMP", "__MONOTONIC_TIMESTAMP",
+];
+
+/// A term of the journal's match filter
+#[derive(Debug, Clone, PartialEq)]
+enum JournalMatch {
+    /// entries where the field has the value; matches on the same field are OR'd, different fields are AND'd
+    Field { field: String, value: String },
+    /// OR's the matches before it with the ones after it, like `+` for `journalctl`
+    Or,
+}
 
-pub struct JournaldInput {
-    journal_type: String,
+impl JournalMatch {
+    /// Parses `FIELD=value`, `PRIORITY<=level`, or `+` into the matches to add
+    fn parse(s: &str) -> anyhow::Result<Vec<JournalMatch>> {
+        if s == "+" {
+            return Ok(vec![JournalMatch::Or]);
+        }
+
+        let (field, values) = if let Some((field, level)) = s.split_once("<=") {
+            if field != "PRIORITY" {
+                bail!("Only PRIORITY can be matched with <=, found '{}'", s);
+            }
+
+            let level = severity_level(level).ok_or_else(|| anyhow!("Unknown priority '{}'; use 0-7 or a name like 'warning'", level))?;
+
+            (field, (0..=level).map(|l| l.to_string()).collect::<Vec<_>>())
+        } else if let Some((field, value)) = s.split_once('=') {
+            (field, vec![value.to_string()])
+        } else {
+            bail!("Matches must look like FIELD=value or PRIORITY<=level, found '{}'", s);
+        };
+
+        if field.is_empty() || !field.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') {
+            bail!("Invalid journal field '{}'; fields are upper-case letters, digits, and underscores", field);
+        }
+
+        Ok(values.into_iter().map(|value| JournalMatch::Field { field: field.to_string(), value }).collect())
+    }
+
+    fn apply(&self, journal: &mut journal::Journal) -> anyhow::Result<()> {
+        match self {
+            JournalMatch::Field { field, value } => journal.match_add(field, value.as_str())?,
+            JournalMatch::Or => journal.match_or()?
+        };
+
+        Ok( () )
+    }
+}
+
+/// Parses a priority as either a number or a syslog name
+fn severity_level(level: &str) -> Option<usize> {
+    match level.parse::<usize>() {
+        Ok(l) if l < SEVERITIES.len() => Some(l),
+        Ok(_) => None,
+        Err(_) => SEVERITIES.iter().position(|s| *s == level)
+    }
+}
+
+/// Converts the fields of journal entries into events
+#[derive(Debug)]
+struct EntryMapper {
+    include_fields: Vec<String>,
+    exclude_fields: Vec<String>,
+    rename_fields: HashMap<String, String>,
+    ts: Option<(String, TsOutput)>,
+    severity_field: Option<String>,
+    native_types: bool,
+}
+
+impl EntryMapper {
+    fn from_args(args: &Args, plugin_name: &str) -> anyhow::Result<Self> {
+        let globs = |name: &str| -> anyhow::Result<Vec<String>> {
+            match args.get(name) {
+                None => Ok(vec![]),
+                Some(Value::Array(a)) => {
+                    a.iter()
+                     .map(|f| f.as_str().map(|f| f.to_string()))
+                     .collect::<Option<Vec<_>>>()
+                     .ok_or_else(|| anyhow!("Found non-string field in '{}' arg for {}", name, plugin_name))
+                }
+                Some(_) => bail!("The '{}' arg for {} does not appear to be an array of strings", name, plugin_name)
+            }
+        };
+
+        let include_fields = globs("include_fields")?;
+        let exclude_fields = globs("exclude_fields")?;
+
+        let rename_fields = match args.get("rename_fields") {
+            None => HashMap::new(),
+            Some(Value::Table(t)) => {
+                t.iter()
+                 .map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                 .collect::<Option<HashMap<_, _>>>()
+                 .ok_or_else(|| anyhow!("Found non-string value in 'rename_fields' arg for {}", plugin_name))?
+            }
+            Some(_) => bail!("The 'rename_fields' arg for {} does not appear to be a table", plugin_name)
+        };
+
+        let ts = match args.get("ts_field") {
+            None => None,
+            Some(f) => {
+                let f = f.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for {} does not appear to be a string", plugin_name))?;
+
+                Some((f.to_string(), TsOutput::from_args(args, plugin_name)?))
+            }
+        };
+
+        let severity_field = match args.get("severity_field") {
+            None => None,
+            Some(f) => Some(f.as_str().ok_or_else(|| anyhow!("The 'severity_field' arg for {} does not appear to be a string", plugin_name))?.to_string())
+        };
+
+        let native_types = args.get("native_types").unwrap_or(&Value::Boolean(false));
+        let native_types = native_types.as_bool().ok_or_else(|| anyhow!("The 'native_types' arg for {} does not appear to be a boolean", plugin_name))?;
+
+        Ok(EntryMapper { include_fields, exclude_fields, rename_fields, ts, severity_field, native_types })
+    }
+
+    /// Converts an entry into an event; `realtime` is used when the entry has no `__REALTIME_TIMESTAMP`
+    fn map(&self, entry: BTreeMap<String, String>, realtime: Option<SystemTime>) -> JsonValue {
+        let mut map = Map::new();
+
+        if let Some((ts_field, ts_type)) = &self.ts {
+            let timestamp = entry.get("__REALTIME_TIMESTAMP")
+                                 .and_then(|usec| usec.parse::<i64>().ok())
+                                 .and_then(|usec| Utc.timestamp_opt(usec.div_euclid(1_000_000), (usec.rem_euclid(1_000_000) * 1_000) as u32).single())
+                                 .or_else(|| realtime.map(DateTime::<Utc>::from));
+
+            if let Some(timestamp) = timestamp.and_then(|t| ts_type.format(&t)) {
+                map.insert(ts_field.clone(), timestamp);
+            }
+        }
+
+        if let Some(severity_field) = &self.severity_field {
+            if let Some(severity) = entry.get("PRIORITY").and_then(|p| p.parse::<usize>().ok()).and_then(|p| SEVERITIES.get(p)) {
+                map.insert(severity_field.clone(), JsonValue::from(*severity));
+            }
+        }
+
+        for (field, value) in entry {
+            // the timestamp has already been converted
+            if self.ts.is_some() && field == "__REALTIME_TIMESTAMP" {
+                continue
+            }
+
+            if !self.include_fields.is_empty() && !self.include_fields.iter().any(|g| glob_match(g, &field)) {
+                continue
+            }
+
+            if self.exclude_fields.iter().any(|g| glob_match(g, &field)) {
+                continue
+            }
+
+            let value = match value.parse::<i64>() {
+                Ok(n) if self.native_types && NUMERIC_FIELDS.contains(&field.as_str()) => JsonValue::from(n),
+                _ => JsonValue::String(value)
+            };
+
+            let field = self.rename_fields.get(&field).cloned().unwrap_or(field);
+
+            // don't clobber the timestamp or severity
+            map.entry(field).or_insert(value);
+        }
+
+        JsonValue::Object(map)
+    }
+}
+
+/// Where the journal entries are read from
+#[derive(Debug, Clone, PartialEq)]
+enum JournalSource {
+    /// the local journals: all, system, or user
+    Local(String),
+    Namespace(String),
+    Directory(String),
+    Files(Vec<String>),
+}
+
+impl JournalSource {
+    fn from_args(args: &Args, plugin_name: &str) -> anyhow::Result<Self> {
+        let mut sources = vec![];
+
+        if let Some(journal_type) = args.get("journal") {
+            let journal_type = journal_type.as_str().ok_or_else(|| anyhow!("The 'journal' arg for {} does not appear to be a string", plugin_name))?;
+
+            match journal_type {
+                "all"|"system"|"user" => (),
+                _ => bail!("Unknown journal '{}', please leave blank or using one of 'system' or 'user'", journal_type)
+            }
+
+            sources.push(JournalSource::Local(journal_type.to_string()));
+        }
+
+        if let Some(namespace) = args.get("namespace") {
+            let namespace = namespace.as_str().ok_or_else(|| anyhow!("The 'namespace' arg for {} does not appear to be a string", plugin_name))?;
+
+            sources.push(JournalSource::Namespace(namespace.to_string()));
+        }
+
+        if let Some(directory) = args.get("directory") {
+            let directory = directory.as_str().ok_or_else(|| anyhow!("The 'directory' arg for {} does not appear to be a string", plugin_name))?;
+
+            if !PathBuf::from(directory).is_dir() {
+                bail!("The 'directory' arg for {} is not a directory: {}", plugin_name, directory);
+            }
+
+            sources.push(JournalSource::Directory(directory.to_string()));
+        }
+
+        if let Some(files) = args.get("files") {
+            let files = match files {
+                Value::String(f) => vec![f.clone()],
+                Value::Array(a) => {
+                    a.iter()
+                     .map(|f| f.as_str().map(|f| f.to_string()))
+                     .collect::<Option<Vec<_>>>()
+                     .ok_or_else(|| anyhow!("Found non-string file in 'files' arg for {}", plugin_name))?
+                }
+                _ => bail!("The 'files' arg for {} must be a string or an array of strings", plugin_name)
+            };
+
+            if files.is_empty() {
+                bail!("The 'files' arg for {} cannot be empty", plugin_name);
+            }
+
+            if let Some(missing) = files.iter().find(|f| !PathBuf::from(f).is_file()) {
+                bail!("The journal file {} for {} does not exist", missing, plugin_name);
+            }
+
+            sources.push(JournalSource::Files(files));
+        }
+
+        match sources.len() {
+            0 => Ok(JournalSource::Local("all".to_string())),
+            1 => Ok(sources.remove(0)),
+            _ => bail!("Only one of 'journal', 'namespace', 'directory', or 'files' can be specified for {}", plugin_name)
+        }
+    }
+
+    fn open(&self) -> anyhow::Result<journal::Journal> {
+        Ok(match self {
+            JournalSource::Local(journal_type) => {
+                journal::OpenOptions::default()
+                    .all_namespaces(journal_type == "all")
+                    .system(journal_type == "system")
+                    .current_user(journal_type == "user")
+                    .open()?
+            }
+            JournalSource::Namespace(namespace) => journal::OpenOptions::default().open_namespace(namespace.as_str())?,
+            JournalSource::Directory(directory) => journal::OpenDirectoryOptions::default().open_directory(directory.as_str())?,
+            JournalSource::Files(files) => journal::OpenFilesOptions::default().open_files(files.iter().map(|f| f.as_str()))?,
+        })
+    }
+}
+
+/// Parses the `since` arg into microseconds since the epoch
+fn parse_since(since: &Value, plugin_name: &str) -> anyhow::Result<u64> {
+    let since = match since {
+        Value::Integer(secs) => Utc.timestamp_opt(*secs, 0).single(),
+        Value::Datetime(dt) => DateTime::parse_from_rfc3339(dt.to_string().as_str()).ok().map(|dt| dt.with_timezone(&Utc)),
+        Value::String(s) => DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)),
+        _ => None
+    }.ok_or_else(|| anyhow!("The 'since' arg for {} must be an RFC 3339 timestamp, or seconds since the epoch", plugin_name))?;
+
+    u64::try_from(since.timestamp_micros()).map_err(|_| anyhow!("The 'since' arg for {} cannot be before 1970", plugin_name))
+}
+
+/// Where to start reading when the cursor can't be used
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InvalidCursor {
+    Head,
+    Tail,
+}
+
+/// Reads entries from the journal, on a blocking thread
+#[derive(Debug)]
+struct JournalReader {
+    source: JournalSource,
+    matches: Vec<JournalMatch>,
+    mapper: EntryMapper,
     from_beginning: bool,
+    since: Option<u64>,
+    on_invalid_cursor: InvalidCursor,
     cursor_file_path: PathBuf,
+}
+
+impl JournalReader {
+    /// Positions the journal: the head, the saved cursor, `since`, or the start of the journal
+    fn seek(&self, journal: &mut journal::Journal) -> anyhow::Result<()> {
+        if self.from_beginning {
+            journal.seek(JournalSeek::Head)?;
+            return Ok( () );
+        }
+
+        if !self.cursor_file_path.exists() {
+            if let Some(usec) = self.since {
+                journal.seek(JournalSeek::ClockRealtime { usec })?;
+            }
+
+            return Ok( () );
+        }
+
+        let res = fs::read_to_string(&self.cursor_file_path)
+            .with_context(|| format!("Reading cursor file {}", self.cursor_file_path.display()))
+            .and_then(|cursor| {
+                if cursor.trim().is_empty() {
+                    bail!("Cursor file {} is empty", self.cursor_file_path.display());
+                }
+
+                journal.seek_cursor(cursor.trim()).context("Seeking to cursor")
+            });
+
+        // the cursor can be invalid after the journal is vacuumed, so fall back instead of failing
+        if let Err(e) = res {
+            warn!("Invalid journald cursor, reading from the {:?} instead: {:#}", self.on_invalid_cursor, e);
+
+            match self.on_invalid_cursor {
+                InvalidCursor::Head => journal.seek(JournalSeek::Head)?,
+                InvalidCursor::Tail => journal.seek(JournalSeek::Tail)?,
+            };
+        }
+
+        Ok( () )
+    }
+
+    /// Reads entries, sending them with their cursor, until `running` is false or the channel closes
+    fn read(&self, tx: &UnboundedSender<anyhow::Result<(Event, String)>>, running: &AtomicBool) -> anyhow::Result<()> {
+        let mut journal = self.source.open().context("Opening journal")?;
+
+        for m in self.matches.iter() {
+            m.apply(&mut journal).context("Adding journal match")?;
+        }
+
+        self.seek(&mut journal)?;
+
+        // first get any entries that already exist
+        // see: https://www.freedesktop.org/software/systemd/man/sd_journal_wait.html#Examples
+        let mut await_entry = false;
+
+        while running.load(Ordering::Relaxed) {
+            let op_entry = if await_entry {
+                journal.await_next_entry(Some(Duration::from_micros(100)))
+            } else {
+                journal.next_entry()
+            }.context("Getting next journald entry")?;
+
+            if op_entry.is_none() && !await_entry {
+                debug!("Awaiting new entries");
+                await_entry = true;
+            }
+
+            if let Some(entry) = op_entry {
+                let event = Event::Json(self.mapper.map(entry, journal.timestamp().ok()));
+
+                // grab the cursor
+                let cursor = journal.cursor().context("Getting journald cursor")?;
+
+                if tx.send(Ok((event, cursor))).is_err() {
+                    break
+                }
+            }
+        }
+
+        Ok( () )
+    }
+}
+
+/// Writes cursors to the cursor file in the order the entries were read, once all the entries before them are processed.
+/// An entry that is never called back holds the cursor at the entry before it, so every path that drops an event must call its callback.
+#[derive(Debug)]
+struct CursorTracker {
+    path: PathBuf,
+    interval: Duration,
+    state: Mutex<CursorState>,
+}
+
+#[derive(Debug, Default)]
+struct CursorState {
+    /// the sequence number of the next entry in the order they were read
+    next_seq: u64,
+    /// processed entries that are waiting on an earlier one
+    processed: BTreeMap<u64, String>,
+    /// the latest cursor that can be written
+    cursor: Option<String>,
+    dirty: bool,
+    last_write: Option<Instant>,
+}
+
+impl CursorTracker {
+    fn new(path: PathBuf, interval: Duration) -> Self {
+        CursorTracker { path, interval, state: Mutex::new(CursorState::default()) }
+    }
+
+    /// Marks the entry with the sequence number as processed, writing the cursor if it's been long enough
+    fn processed(&self, seq: u64, cursor: String) {
+        let mut guard = self.state.lock().expect("Cursor lock poisoned");
+        let state = &mut *guard;
+
+        // ignore entries that were already written, as a callback can be called more than once
+        if seq < state.next_seq {
+            return;
+        }
+
+        state.processed.insert(seq, cursor);
+
+        while let Some(cursor) = state.processed.remove(&state.next_seq) {
+            state.cursor = Some(cursor);
+            state.next_seq += 1;
+            state.dirty = true;
+        }
+
+        let throttled = matches!(state.last_write, Some(t) if t.elapsed() < self.interval);
+
+        if !throttled {
+            self.write(state);
+        }
+    }
+
+    /// Writes any cursor that hasn't been written yet
+    fn flush(&self) {
+        let mut state = self.state.lock().expect("Cursor lock poisoned");
+
+        self.write(&mut state);
+    }
+
+    fn write(&self, state: &mut CursorState) {
+        if !state.dirty {
+            return;
+        }
+
+        if let Some(cursor) = &state.cursor {
+            match fs::write(&self.path, cursor) {
+                Ok(_) => {
+                    state.dirty = false;
+                    state.last_write = Some(Instant::now());
+                }
+                Err(e) => error!("Error writing to cursor file {}: {:?}", self.path.display(), e)
+            }
+        }
+    }
+}
+
+impl Drop for CursorTracker {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+pub struct JournaldInput {
+    reader: Arc<JournalReader>,
+    cursor_interval: Duration,
     sender: Sender<ChannelType>,
     semaphore: Arc<Semaphore>,
     tripwire: Tripwire,
@@ -35,32 +496,69 @@ impl Plugin for JournaldInput {
     }
 
     async fn new(args: Args, tripwire: Tripwire) -> anyhow::Result<Box<PluginType>> where Self: Sized {
-        // which journal to open: system, user, all (default)
-        let journal_type = args.get("journal").map(|v| v.to_owned()).unwrap_or_else(|| Value::String("all".to_string()));
-        let journal_type = journal_type.as_str().ok_or_else(|| anyhow!("The 'journal' arg for {} does not appear to be a string", Self::name()))?;
-        debug!("Journal Type: {}", journal_type);
+        // which journal to open: system, user, all (default), a namespace, or a directory or files of journals
+        let source = JournalSource::from_args(&args, Self::name())?;
+        debug!("Journal source: {:?}", source);
+
+        let matches = match args.get("matches") {
+            None => vec![],
+            Some(Value::Array(a)) => {
+                let mut matches = vec![];
+
+                for m in a {
+                    let m = m.as_str().ok_or_else(|| anyhow!("Found non-string match in 'matches' arg for {}", Self::name()))?;
+                    matches.extend(JournalMatch::parse(m)?);
+                }
 
-        match journal_type {
-            "all"|"system"|"user" => (),
-            _ => bail!("Unknown journal '{}', please leave blank or using one of 'system' or 'user'", journal_type)
-        }
+                matches
+            }
+            Some(_) => bail!("The 'matches' arg for {} does not appear to be an array of strings", Self::name())
+        };
+        debug!("Matches: {:?}", matches);
+
+        let mapper = EntryMapper::from_args(&args, Self::name())?;
 
         // read from the beginning?
         let from_beginning = args.get("from_beginning").unwrap_or(&Value::Boolean(false));
         let from_beginning = from_beginning.as_bool().ok_or_else(|| anyhow!("The 'from_beginning' arg for {} does not appear to be a boolean", Self::name()))?;
         debug!("From beginning: {}", from_beginning);
 
+        // where to start when there is no cursor
+        let since = args.get("since").map(|s| parse_since(s, Self::name())).transpose()?;
+
+        // where to start when the cursor is invalid
+        let on_invalid_cursor = args.get("on_invalid_cursor").unwrap_or(&Value::String("head".to_string())).to_owned();
+        let on_invalid_cursor = match on_invalid_cursor.as_str() {
+            Some("head") => InvalidCursor::Head,
+            Some("tail") => InvalidCursor::Tail,
+            _ => bail!("The 'on_invalid_cursor' arg for {} must be one of: head, tail", Self::name())
+        };
+
         // grab an cursor file
         let cursor_file_path = args.get("cursor_file").ok_or_else(|| anyhow!("Could not find 'cursor_file' arg for {}", Self::name()))?;
         let cursor_file_path = cursor_file_path.as_str().ok_or_else(|| anyhow!("The 'cursor_file' arg for {} does not appear to be a string", Self::name()))?;
         let cursor_file_path = PathBuf::from(cursor_file_path.to_string());
 
+        // how often the cursor file is written
+        let cursor_interval = args.get("cursor_interval_ms").unwrap_or(&Value::Integer(1000));
+        let cursor_interval = cursor_interval.as_integer().ok_or_else(|| anyhow!("The 'cursor_interval_ms' arg for {} does not appear to be an integer", Self::name()))?;
+        let cursor_interval = Duration::from_millis(u64::try_from(cursor_interval).map_err(|_| anyhow!("The 'cursor_interval_ms' arg for {} cannot be negative", Self::name()))?);
+
         let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
 
-        Ok(Box::new(JournaldInput {
-            journal_type: journal_type.to_string(),
+        let reader = Arc::new(JournalReader {
+            source,
+            matches,
+            mapper,
             from_beginning,
+            since,
+            on_invalid_cursor,
             cursor_file_path,
+        });
+
+        Ok(Box::new(JournaldInput {
+            reader,
+            cursor_interval,
             sender,
             semaphore,
             tripwire
@@ -71,73 +569,50 @@ impl Plugin for JournaldInput {
         let running = Arc::new(AtomicBool::new(true));
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
 
-        let journal_type = mem::take(&mut self.journal_type);
-        let from_beginning = self.from_beginning;
-
-        let cursor_file_path_clone = self.cursor_file_path.clone();
+        let reader = self.reader.clone();
         let running_clone = running.clone();
 
         tokio::task::spawn_blocking(move || {
             // have to make the Journal in this thread
-            let mut journal = journal::OpenOptions::default()
-                .all_namespaces(journal_type == "all")
-                .system(journal_type == "system")
-                .current_user(journal_type == "user")
-                .open().expect("Error opening journal");
-
-            if from_beginning {
-                journal.seek(JournalSeek::Head).expect("Error seeking");
-            } else if cursor_file_path_clone.exists() {
-                // try to open the cursor file, and read in that value
-                let cursor = fs::read_to_string(cursor_file_path_clone).expect("Error reading from cursor file");
-
-                journal.seek_cursor(cursor).expect("Error seeking");
-            }
-
-            // first get any entries that already exist
-            // see: https://www.freedesktop.org/software/systemd/man/sd_journal_wait.html#Examples
-            let mut await_entry = false;
-
-            while running_clone.load(Ordering::Relaxed) {
-                let op_entry = if await_entry {
-                    journal.await_next_entry(Some(Duration::from_micros(100)))
-                } else {
-                    journal.next_entry()
-                }.expect("Error getting next journald entry");
-
-                if op_entry.is_none() && !await_entry {
-                    debug!("Awaiting new entries");
-                    await_entry = true;
-                }
-
-                if let Some(entry) = op_entry {
-                    let map = entry.into_iter()
-                                   .map(|(k, v)| (k, serde_json::Value::String(v)))
-                                   .collect::<serde_json::Map<String, serde_json::Value>>();
-
-                    let event = Event::Json(serde_json::Value::Object(map));
-
-                    // grab the cursor
-                    let cursor = journal.cursor().unwrap();
-
-                    tx.send((event, cursor)).expect("Error sending on channel");
-                }
+            if let Err(e) = reader.read(&tx, running_clone.as_ref()) {
+                let _ = tx.send(Err(e));
             }
         });
 
         let mut recv_stream = UnboundedReceiverStream::new(rx).take_until_if(self.tripwire.clone());
-        let cursor_file_path = Arc::new(mem::take(&mut self.cursor_file_path));
 
-        while let Some((event, cursor)) = recv_stream.next().await {
-            let cursor_file_path_clone = cursor_file_path.clone();
-
-            let callback = Arc::new(Callback::new(move || {
-                fs::write(cursor_file_path_clone.as_ref(), &cursor)
-                    .expect("Error writing to cursor file");
-            }));
+        // the tracker writes any remaining cursor when the last callback is dropped
+        let tracker = Arc::new(CursorTracker::new(self.reader.cursor_file_path.clone(), self.cursor_interval));
+        let mut flush_interval = tokio::time::interval(self.cursor_interval.max(Duration::from_millis(1)));
+        let mut seq = 0;
+
+        loop {
+            tokio::select! {
+                res = recv_stream.next() => {
+                    let (event, cursor) = match res {
+                        Some(Ok(res)) => res,
+                        // the reader has stopped, so stop the input; the rest of the route shuts down once it's drained
+                        Some(Err(e)) => {
+                            error!("Error reading journal; stopping the journald input: {:#}", e);
+                            break
+                        }
+                        None => break
+                    };
+
+                    let tracker_clone = tracker.clone();
+                    let entry_seq = seq;
+                    seq += 1;
+
+                    let callback = Arc::new(Callback::new(move || {
+                        tracker_clone.processed(entry_seq, cursor.clone());
+                    }));
+
+                    // send the event along
+                    send_event!(self, event, callback);
+                }
 
-            // send the event along
-            send_event!(self, event, callback);
+                _ = flush_interval.tick() => tracker.flush()
+            }
         }
 
         // stop our loop above
@@ -150,12 +625,29 @@ impl Plugin for JournaldInput {
 
 #[cfg(test)]
 mod journald_input_tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::time::Duration;
+
+    use serde_json::json;
     use stream_cancel::Tripwire;
     use toml::Value;
 
     use crate::common::init_test_logger;
     use crate::plugin::{Args, Plugin};
-    use crate::plugins::journald::JournaldInput;
+    use crate::plugins::journald::{CursorTracker, EntryMapper, JournaldInput, JournalMatch, JournalSource, parse_since};
+
+    fn entry() -> BTreeMap<String, String> {
+        [
+            ("__CURSOR", "s=739ad463348b4ceca5a9e69c95a3c93f;i=4ece7;b=6c7c6013a8674a48a2d2ac1a4c1d6a42;m=1d0b6c4a;t=5ffa5a5a8e89d;x=9c4d8ce0b6a0d7a3"),
+            ("__REALTIME_TIMESTAMP", "1688738532123456"),
+            ("_SOURCE_REALTIME_TIMESTAMP", "1688738532120000"),
+            ("_SYSTEMD_UNIT", "sshd.service"),
+            ("_PID", "1234"),
+            ("PRIORITY", "4"),
+            ("MESSAGE", "Failed password for root from 10.0.0.5"),
+        ].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
 
     #[tokio::test]
     async fn new() {
@@ -168,4 +660,170 @@ mod journald_input_tests {
 
         JournaldInput::new(args, tripwire).await.expect("Error creating JournaldInput");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn matches() {
+        assert_eq!(vec![JournalMatch::Field { field: "_SYSTEMD_UNIT".to_string(), value: "sshd.service".to_string() }],
+                   JournalMatch::parse("_SYSTEMD_UNIT=sshd.service").unwrap());
+        assert_eq!(vec![JournalMatch::Or], JournalMatch::parse("+").unwrap());
+
+        let priorities = JournalMatch::parse("PRIORITY<=err").unwrap();
+
+        assert_eq!(priorities, JournalMatch::parse("PRIORITY<=3").unwrap());
+        assert_eq!(4, priorities.len());
+        assert_eq!(JournalMatch::Field { field: "PRIORITY".to_string(), value: "3".to_string() }, priorities[3]);
+
+        assert!(JournalMatch::parse("_PID<=3").is_err());
+        assert!(JournalMatch::parse("PRIORITY<=8").is_err());
+        assert!(JournalMatch::parse("message=hi").is_err());
+        assert!(JournalMatch::parse("MESSAGE").is_err());
+    }
+
+    #[test]
+    fn map_entry() {
+        // the default is every field as a string
+        let mapper = EntryMapper::from_args(&Args::new(), "journald").unwrap();
+        let event = mapper.map(entry(), None);
+
+        assert_eq!(7, event.as_object().unwrap().len());
+        assert_eq!(json!("1234"), event["_PID"]);
+
+        let args: Args = toml::from_str(r#"
+            exclude_fields = ["__*", "_SOURCE_*"]
+            rename_fields = { MESSAGE = "message", _SYSTEMD_UNIT = "unit" }
+            ts_field = "t"
+            ts_type = "rfc3339"
+            severity_field = "severity"
+            native_types = true
+        "#).unwrap();
+        let mapper = EntryMapper::from_args(&args, "journald").unwrap();
+
+        assert_eq!(json!({
+            "t": "2023-07-07T14:02:12.123456+00:00",
+            "severity": "warning",
+            "unit": "sshd.service",
+            "_PID": 1234,
+            "PRIORITY": 4,
+            "message": "Failed password for root from 10.0.0.5"
+        }), mapper.map(entry(), None));
+
+        let args: Args = toml::from_str(r#"include_fields = ["MESSAGE", "_SYSTEMD_*"]"#).unwrap();
+        let mapper = EntryMapper::from_args(&args, "journald").unwrap();
+
+        assert_eq!(json!({
+            "_SYSTEMD_UNIT": "sshd.service",
+            "MESSAGE": "Failed password for root from 10.0.0.5"
+        }), mapper.map(entry(), None));
+    }
+
+    #[test]
+    fn sources() {
+        let source = |args: &str| {
+            let args: Args = toml::from_str(args).unwrap();
+
+            JournalSource::from_args(&args, "journald")
+        };
+
+        assert_eq!(JournalSource::Local("all".to_string()), source("").unwrap());
+        assert_eq!(JournalSource::Local("system".to_string()), source(r#"journal = "system""#).unwrap());
+        assert_eq!(JournalSource::Namespace("audit".to_string()), source(r#"namespace = "audit""#).unwrap());
+
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+
+        assert_eq!(JournalSource::Directory(dir.to_string()), source(format!("directory = {:?}", dir).as_str()).unwrap());
+        assert!(source(r#"directory = "/does/not/exist""#).is_err());
+        assert!(source(r#"files = ["/does/not/exist.journal"]"#).is_err());
+        assert!(source(r#"files = []"#).is_err());
+        assert!(source(format!("journal = \"system\"\ndirectory = {:?}", dir).as_str()).is_err());
+        assert!(source(r#"journal = "other""#).is_err());
+    }
+
+    #[test]
+    fn since() {
+        assert_eq!(1688738532000000, parse_since(&Value::Integer(1688738532), "journald").unwrap());
+        assert_eq!(1688738532500000, parse_since(&Value::String("2023-07-07T16:02:12.5+02:00".to_string()), "journald").unwrap());
+
+        let args: Args = toml::from_str("since = 2023-07-07T14:02:12Z").unwrap();
+        assert_eq!(1688738532000000, parse_since(args.get("since").unwrap(), "journald").unwrap());
+
+        assert!(parse_since(&Value::String("yesterday".to_string()), "journald").is_err());
+        assert!(parse_since(&Value::Integer(-1), "journald").is_err());
+    }
+
+    #[test]
+    fn cursor_order() {
+        let path = std::env::temp_dir().join(format!("journald_cursor_order_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let tracker = CursorTracker::new(path.clone(), Duration::ZERO);
+
+        // nothing is written until the first entry has been processed
+        tracker.processed(1, "c1".to_string());
+        tracker.processed(2, "c2".to_string());
+        assert!(!path.exists());
+
+        tracker.processed(0, "c0".to_string());
+        assert_eq!("c2", fs::read_to_string(&path).unwrap());
+
+        tracker.processed(4, "c4".to_string());
+        assert_eq!("c2", fs::read_to_string(&path).unwrap());
+
+        // throttled writes are flushed when the tracker is dropped
+        let tracker = CursorTracker::new(path.clone(), Duration::from_secs(3600));
+
+        tracker.processed(0, "d0".to_string());
+        tracker.processed(1, "d1".to_string());
+        assert_eq!("d0", fs::read_to_string(&path).unwrap());
+
+        drop(tracker);
+        assert_eq!("d1", fs::read_to_string(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cursor_gaps() {
+        let path = std::env::temp_dir().join(format!("journald_cursor_gaps_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let tracker = CursorTracker::new(path.clone(), Duration::ZERO);
+
+        tracker.processed(0, "c0".to_string());
+        assert_eq!("c0", fs::read_to_string(&path).unwrap());
+
+        // repeated callbacks aren't kept around
+        tracker.processed(0, "c0".to_string());
+        assert!(tracker.state.lock().unwrap().processed.is_empty());
+
+        // entry 1 hasn't been called back, so the cursor waits on it no matter how many entries are behind it
+        for seq in 2..20_002 {
+            tracker.processed(seq, format!("c{}", seq));
+        }
+
+        assert_eq!("c0", fs::read_to_string(&path).unwrap());
+
+        tracker.processed(1, "c1".to_string());
+        assert_eq!("c20001", fs::read_to_string(&path).unwrap());
+        assert!(tracker.state.lock().unwrap().processed.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bad_cursor_args() {
+        let (_trigger, tripwire) = Tripwire::new();
+        let mut args = Args::new();
+
+        args.insert("channel_size".to_string(), Value::Integer(1));
+        args.insert("cursor_file".to_string(), Value::String("/tmp/cursor".to_string()));
+        args.insert("on_invalid_cursor".to_string(), Value::String("middle".to_string()));
+
+        assert!(JournaldInput::new(args.clone(), tripwire.clone()).await.is_err());
+
+        args.insert("on_invalid_cursor".to_string(), Value::String("tail".to_string()));
+        args.insert("cursor_interval_ms".to_string(), Value::Integer(-1));
+
+        assert!(JournaldInput::new(args, tripwire).await.is_err());
+    }
+}
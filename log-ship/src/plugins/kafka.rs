@@ -0,0 +1,626 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use futures::stream::FuturesOrdered;
+use rdkafka::{ClientConfig, ClientContext};
+use rdkafka::consumer::{Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::Message;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::producer::future_producer::OwnedDeliveryResult;
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::codec::OutputCodec;
+use crate::common::logging::{debug, error, warn};
+use crate::{Args, connect_receiver, create_event_stream, create_sender_semaphore, get_receiver, recv_event, send_event};
+use crate::event::{Event, JsonValue};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback, DeadLetter};
+use crate::template::{event_time, Template};
+
+
+/// Gets the 'brokers' arg as either a comma separated string, or an array of strings
+fn get_brokers(args: &Args, plugin_name: &str) -> Result<String> {
+    let brokers = args.get("brokers").ok_or_else(|| anyhow!("Could not find 'brokers' arg for {}", plugin_name))?;
+
+    match brokers {
+        Value::String(s) => Ok(s.clone()),
+        Value::Array(a) => {
+            let brokers = a.iter()
+                .map(|b| b.as_str().map(|s| s.to_string()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| anyhow!("Found non-string broker in 'brokers' arg for {}", plugin_name))?;
+
+            Ok(brokers.join(","))
+        }
+        _ => bail!("The 'brokers' arg for {} must be a string or an array of strings", plugin_name)
+    }
+}
+
+/// Sets any additional librdkafka configuration found in the 'options' table arg
+fn set_options(config: &mut ClientConfig, args: &Args, plugin_name: &str) -> Result<()> {
+    if let Some(options) = args.get("options") {
+        let options = options.as_table().ok_or_else(|| anyhow!("The 'options' arg for {} does not appear to be a table", plugin_name))?;
+
+        for (k, v) in options.iter() {
+            let v = match v {
+                Value::String(s) => s.clone(),
+                Value::Integer(i) => i.to_string(),
+                Value::Float(f) => f.to_string(),
+                Value::Boolean(b) => b.to_string(),
+                _ => bail!("The option '{}' for {} must be a string, number, or boolean", k, plugin_name)
+            };
+
+            config.set(k, v);
+        }
+    }
+
+    Ok( () )
+}
+
+
+/// Tracks the messages in flight for each partition, so an offset is only stored once
+/// it, and every message before it in the partition, has been processed.
+/// A message that is never called back holds its partition at the message before it.
+#[derive(Debug, Default)]
+struct OffsetTracker {
+    /// the offsets of each partition, and if they've been processed
+    partitions: HashMap<(String, i32), BTreeMap<i64, bool>>,
+}
+
+impl OffsetTracker {
+    /// Records that the message was received, and is in flight
+    fn received(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.partitions.entry((topic.to_string(), partition)).or_default().insert(offset, false);
+    }
+
+    /// Marks the message as processed, returning the offset to store if the partition can advance
+    fn processed(&mut self, topic: &str, partition: i32, offset: i64) -> Option<i64> {
+        let pending = self.partitions.get_mut(&(topic.to_string(), partition))?;
+
+        // ignore offsets that were already stored, as a callback can be called more than once
+        *pending.get_mut(&offset)? = true;
+
+        let mut store = None;
+
+        while let Some(first) = pending.first_entry() {
+            if !*first.get() {
+                break;
+            }
+
+            store = Some(first.remove_entry().0);
+        }
+
+        store
+    }
+
+    /// Forgets the messages of a partition that is no longer assigned to this consumer
+    fn revoked(&mut self, topic: &str, partition: i32) {
+        self.partitions.remove(&(topic.to_string(), partition));
+    }
+}
+
+/// Forgets the in flight messages of partitions revoked in a rebalance, as their offsets can no longer be stored
+struct KafkaInputContext {
+    offsets: Arc<Mutex<OffsetTracker>>,
+}
+
+impl ClientContext for KafkaInputContext {}
+
+impl ConsumerContext for KafkaInputContext {
+    fn pre_rebalance(&self, rebalance: &Rebalance) {
+        if let Rebalance::Revoke(partitions) = rebalance {
+            let mut offsets = self.offsets.lock().expect("Offsets lock poisoned");
+
+            for p in partitions.elements() {
+                debug!("Partition {}:{} revoked", p.topic(), p.partition());
+                offsets.revoked(p.topic(), p.partition());
+            }
+        }
+    }
+}
+
+/// Input plugin that consumes from Kafka topics as part of a consumer group
+pub struct KafkaInput {
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    tripwire: Tripwire,
+    consumer: Arc<StreamConsumer<KafkaInputContext>>,
+    offsets: Arc<Mutex<OffsetTracker>>,
+    try_parse: bool, // should we try and parse as JSON
+    dead_letter: Option<DeadLetter>,
+}
+
+#[async_trait]
+impl Plugin for KafkaInput {
+    fn name() -> &'static str where Self: Sized {
+        "kafka"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
+        debug!("KafkaInput args: {:#?}", args);
+
+        let brokers = get_brokers(&args, Self::name())?;
+
+        let group_id = args.get("group_id").ok_or_else(|| anyhow!("Could not find 'group_id' arg for {}", Self::name()))?;
+        let group_id = group_id.as_str().ok_or_else(|| anyhow!("The 'group_id' arg for {} does not appear to be a string", Self::name()))?;
+
+        let topics = args.get("topics").ok_or_else(|| anyhow!("Could not find 'topics' arg for {}", Self::name()))?;
+        let topics = match topics {
+            Value::String(s) => vec![s.as_str()],
+            Value::Array(a) => {
+                a.iter()
+                 .map(|t| t.as_str())
+                 .collect::<Option<Vec<_>>>()
+                 .ok_or_else(|| anyhow!("Found non-string topic in 'topics' arg for {}", Self::name()))?
+            }
+            _ => bail!("The 'topics' arg for {} must be a string or an array of strings", Self::name())
+        };
+
+        let try_parse = args.get("parse_json").unwrap_or(&Value::Boolean(false));
+        let try_parse = try_parse.as_bool().ok_or_else(|| anyhow!("The 'parse_json' arg for {} does not appear to be a boolean", Self::name()))?;
+        let from_beginning = args.get("from_beginning").unwrap_or(&Value::Boolean(false));
+        let from_beginning = from_beginning.as_bool().ok_or_else(|| anyhow!("The 'from_beginning' arg for {} does not appear to be a boolean", Self::name()))?;
+
+        let mut config = ClientConfig::new();
+
+        // offsets are stored when the callback is called, and committed periodically by librdkafka
+        config.set("bootstrap.servers", brokers.as_str())
+              .set("group.id", group_id)
+              .set("enable.auto.commit", "true")
+              .set("enable.auto.offset.store", "false")
+              .set("auto.offset.reset", if from_beginning { "earliest" } else { "latest" });
+
+        set_options(&mut config, &args, Self::name())?;
+
+        let offsets = Arc::new(Mutex::new(OffsetTracker::default()));
+        let context = KafkaInputContext { offsets: offsets.clone() };
+        let consumer: StreamConsumer<KafkaInputContext> = config.create_with_context(context).with_context(|| format!("Creating Kafka consumer for {}", brokers))?;
+
+        consumer.subscribe(topics.as_slice()).with_context(|| format!("Subscribing to topics: {}", topics.join(", ")))?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(KafkaInput {
+            sender,
+            semaphore,
+            tripwire,
+            consumer: Arc::new(consumer),
+            offsets,
+            try_parse,
+            dead_letter: None, // set in connect_dead_letter
+        }))
+    }
+
+    async fn run(&mut self) {
+        debug!("KafkaInput running...");
+
+        let consumer = self.consumer.clone();
+        let mut message_stream = consumer.stream().take_until_if(self.tripwire.clone());
+
+        while let Some(message_res) = message_stream.next().await {
+            let message = match message_res {
+                Ok(m) => m,
+                Err(e) => {
+                    // be robust here, just log and keep going
+                    error!("Error receiving from Kafka: {}", e);
+                    continue
+                }
+            };
+
+            let topic = message.topic().to_string();
+            let partition = message.partition();
+            let offset = message.offset();
+            let consumer_clone = self.consumer.clone();
+            let offsets_clone = self.offsets.clone();
+
+            self.offsets.lock().expect("Offsets lock poisoned").received(topic.as_str(), partition, offset);
+
+            // create a callback that stores the offset once every earlier message has been processed, so it's committed
+            let cb = Arc::new(Callback::new(move || {
+                let store = offsets_clone.lock().expect("Offsets lock poisoned").processed(topic.as_str(), partition, offset);
+
+                if let Some(store) = store {
+                    if let Err(e) = consumer_clone.store_offset(topic.as_str(), partition, store) {
+                        error!("Error storing offset {} for {}:{}: {}", store, topic, partition, e);
+                    }
+                }
+            }));
+
+            let payload = match message.payload_view::<str>() {
+                None => {
+                    cb.call();
+                    continue
+                }
+                Some(Err(e)) => {
+                    warn!("Error decoding message as UTF-8: {:?}", e);
+                    cb.call();
+                    continue
+                }
+                Some(Ok(payload)) => payload.to_string()
+            };
+
+            let event = if self.try_parse {
+                match serde_json::from_str(payload.as_str()) {
+                    Ok(json) => Event::Json(json),
+                    Err(e) => {
+                        match &self.dead_letter {
+                            // the offset is stored once the dead-letter output has written it
+                            Some(dead_letter) => dead_letter.send(Self::name(), e, Event::String(payload), cb).await,
+                            None => {
+                                warn!("Error parsing JSON: {:?}", e);
+
+                                // count it as processed
+                                cb.call();
+                            }
+                        }
+
+                        continue
+                    }
+                }
+            } else {
+                Event::String(payload)
+            };
+
+            // send the event along
+            send_event!(self, event, cb);
+        }
+
+        debug!("KafkaInput closing");
+    }
+
+    // boilerplate method
+    get_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+
+/// Output plugin that produces to a Kafka topic
+pub struct KafkaOutput {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    producer: FutureProducer,
+    topic: Template,
+    key_field: Option<String>,
+    ts_field: String,
+    codec: OutputCodec,
+    dead_letter: Option<DeadLetter>,
+}
+
+impl KafkaOutput {
+    /// Calls the callback if the message was delivered, otherwise handles the failure
+    async fn handle_delivery(&self, res: Result<OwnedDeliveryResult, futures::channel::oneshot::Canceled>, callback: Arc<Callback>, original: Option<Event>) {
+        match res {
+            Ok(Ok(_)) => callback.call(),
+            Ok(Err((e, message))) => {
+                self.failed(format!("Error delivering message to {}: {}", message.topic(), e), callback, original).await
+            }
+            Err(_) => self.failed("Delivery of message to Kafka was cancelled".to_string(), callback, original).await
+        }
+    }
+
+    /// Sends a message that could not be delivered, after librdkafka's retries, to the dead-letter output, or logs and drops it
+    async fn failed(&self, error: String, callback: Arc<Callback>, original: Option<Event>) {
+        match (&self.dead_letter, original) {
+            (Some(dead_letter), Some(original)) => dead_letter.send(Self::name(), error, original, callback).await,
+            _ => {
+                error!("{}; dropping the log", error);
+                callback.call();
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for KafkaOutput {
+    fn name() -> &'static str where Self: Sized {
+        "kafka"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
+        debug!("KafkaOutput args: {:#?}", args);
+
+        let brokers = get_brokers(&args, Self::name())?;
+
+        let topic = args.get("topic").ok_or_else(|| anyhow!("Could not find 'topic' arg for {}", Self::name()))?;
+        let topic = topic.as_str().ok_or_else(|| anyhow!("The 'topic' arg for {} does not appear to be a string", Self::name()))?;
+        let topic = Template::parse(topic).with_context(|| format!("Parsing the 'topic' arg for {}", Self::name()))?;
+
+        let key_field = match args.get("key_field") {
+            Some(k) => Some(k.as_str().ok_or_else(|| anyhow!("The 'key_field' arg for {} does not appear to be a string", Self::name()))?.to_string()),
+            None => None
+        };
+
+        let ts_field = args.get("ts_field").unwrap_or(&Value::String("t".to_string())).to_owned();
+        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for {} does not appear to be a string", Self::name()))?.to_string();
+
+        let compression = args.get("compression").unwrap_or(&Value::String("none".to_string())).to_owned();
+        let compression = compression.as_str().ok_or_else(|| anyhow!("The 'compression' arg for {} does not appear to be a string", Self::name()))?;
+
+        match compression {
+            "none" | "gzip" | "snappy" | "lz4" | "zstd" => (),
+            _ => bail!("Unknown compression '{}' for {}; use one of: none, gzip, snappy, lz4, zstd", compression, Self::name())
+        }
+
+        let mut config = ClientConfig::new();
+
+        config.set("bootstrap.servers", brokers.as_str())
+              .set("compression.type", compression)
+              .set("acks", "all");
+
+        set_options(&mut config, &args, Self::name())?;
+
+        let producer: FutureProducer = config.create().with_context(|| format!("Creating Kafka producer for {}", brokers))?;
+        let codec = OutputCodec::from_args(&args, Self::name())?;
+
+        Ok(Box::new(KafkaOutput {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            producer,
+            topic,
+            key_field,
+            ts_field,
+            codec,
+            dead_letter: None, // set in connect_dead_letter
+        }))
+    }
+
+    async fn run(&mut self) {
+        debug!("KafkaOutput running...");
+
+        let mut event_stream = create_event_stream!(self);
+
+        // messages waiting to be delivered, in the order they were sent
+        let mut pending = FuturesOrdered::new();
+
+        loop {
+            let op_event = tokio::select! {
+                Some((res, callback, original)) = TokioStreamExt::next(&mut pending), if !pending.is_empty() => {
+                    self.handle_delivery(res, callback, original).await;
+
+                    continue
+                }
+                op_event = event_stream.next() => op_event
+            };
+
+            let event = match op_event {
+                Some(event) => event,
+                None => break
+            };
+
+            let (event, callback) = recv_event!(event);
+
+            // skip the None events
+            if Event::None == event {
+                callback.call();
+                continue;
+            }
+
+            let topic = self.topic.render(&event, &event_time(&event, self.ts_field.as_str()));
+            let key = match (&event, self.key_field.as_ref()) {
+                (Event::Json(json), Some(key_field)) => match json.get(key_field) {
+                    None | Some(JsonValue::Null) => None,
+                    Some(JsonValue::String(s)) => Some(s.clone()),
+                    Some(v) => Some(v.to_string())
+                },
+                _ => None
+            };
+            let payload = match self.codec.serialize(&event) {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("Error serializing event: {}", e);
+                    callback.call();
+                    continue;
+                }
+            };
+
+            // only keep a copy of the log if it could be sent to the dead-letter output
+            let original = self.dead_letter.as_ref().map(|_| event.clone());
+            let mut record = FutureRecord::to(topic.as_str()).payload(payload.as_slice());
+
+            if let Some(key) = key.as_ref() {
+                record = record.key(key.as_str());
+            }
+
+            loop {
+                match self.producer.send_result(record) {
+                    Ok(delivery) => {
+                        pending.push_back(async move { (delivery.await, callback, original) });
+                        break;
+                    }
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                        record = r;
+
+                        // wait for something to be delivered, then try again
+                        match TokioStreamExt::next(&mut pending).await {
+                            Some((res, callback, original)) => self.handle_delivery(res, callback, original).await,
+                            None => tokio::time::sleep(Duration::from_millis(10)).await
+                        }
+                    }
+                    Err((e, _)) => {
+                        self.failed(format!("Error sending message to {}: {}", topic, e), callback, original).await;
+                        break;
+                    }
+                }
+            }
+        }
+
+        // wait for everything to be delivered
+        while let Some((res, callback, original)) = TokioStreamExt::next(&mut pending).await {
+            self.handle_delivery(res, callback, original).await;
+        }
+
+        debug!("KafkaOutput closing");
+    }
+
+    // boilerplate method
+    connect_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+
+/// These tests require a broker; see int_tests/kafka.sh
+#[cfg(test)]
+mod kafka_tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use rdkafka::TopicPartitionList;
+    use rdkafka::consumer::{ConsumerContext, Rebalance};
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::sync::{broadcast, Semaphore};
+    use toml::Value;
+
+    use crate::common::init_test_logger;
+    use crate::event::Event;
+    use crate::plugin::{Args, Callback, Plugin};
+    use crate::plugins::kafka::{KafkaInput, KafkaInputContext, KafkaOutput, OffsetTracker};
+
+    #[test]
+    fn offsets_in_order() {
+        let mut tracker = OffsetTracker::default();
+
+        for offset in 10..15 {
+            tracker.received("logs", 0, offset);
+        }
+
+        tracker.received("logs", 1, 3);
+
+        // later messages can't be stored before earlier ones
+        assert_eq!(None, tracker.processed("logs", 0, 12));
+        assert_eq!(None, tracker.processed("logs", 0, 11));
+        assert_eq!(Some(3), tracker.processed("logs", 1, 3));
+        assert_eq!(Some(12), tracker.processed("logs", 0, 10));
+
+        // repeated, or unknown, callbacks are ignored
+        assert_eq!(None, tracker.processed("logs", 0, 11));
+        assert_eq!(None, tracker.processed("logs", 2, 1));
+
+        assert_eq!(Some(13), tracker.processed("logs", 0, 13));
+        assert_eq!(Some(14), tracker.processed("logs", 0, 14));
+
+        // a message that is never processed holds the partition, no matter how many are behind it
+        for offset in 100..20_100 {
+            tracker.received("logs", 0, offset);
+        }
+
+        for offset in 101..20_100 {
+            assert_eq!(None, tracker.processed("logs", 0, offset));
+        }
+
+        assert_eq!(Some(20_099), tracker.processed("logs", 0, 100));
+    }
+
+    #[test]
+    fn revoked_partitions() {
+        let offsets = Arc::new(Mutex::new(OffsetTracker::default()));
+        let context = KafkaInputContext { offsets: offsets.clone() };
+        let mut revoked = TopicPartitionList::new();
+
+        revoked.add_partition("logs", 0);
+
+        {
+            let mut tracker = offsets.lock().unwrap();
+
+            tracker.received("logs", 0, 10);
+            tracker.received("logs", 0, 11);
+            tracker.received("logs", 1, 5);
+        }
+
+        context.pre_rebalance(&Rebalance::Revoke(&revoked));
+
+        let mut tracker = offsets.lock().unwrap();
+
+        // callbacks for the revoked partition are ignored, the others are stored
+        assert_eq!(None, tracker.processed("logs", 0, 10));
+        assert_eq!(Some(5), tracker.processed("logs", 1, 5));
+        assert!(!tracker.partitions.contains_key(&("logs".to_string(), 0)));
+
+        // once the partition is assigned again, its messages are tracked from scratch
+        tracker.received("logs", 0, 11);
+        assert_eq!(Some(11), tracker.processed("logs", 0, 11));
+    }
+
+    fn brokers() -> String {
+        std::env::var("KAFKA_BROKERS").unwrap_or_else(|_| "localhost:9092".to_string())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn output_then_input() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let topic = format!("log-ship-test-{}", std::process::id());
+
+        // produce a few events
+        let mut args = Args::new();
+        args.insert("brokers".to_string(), Value::String(brokers()));
+        args.insert("topic".to_string(), Value::String(format!("{}-{{app}}", topic)));
+        args.insert("key_field".to_string(), Value::String("host".to_string()));
+        args.insert("compression".to_string(), Value::String("gzip".to_string()));
+
+        let mut output = KafkaOutput::new(args, tripwire.clone()).await.expect("Error creating KafkaOutput");
+        let (sender, receiver) = broadcast::channel(10);
+        let semaphore = Arc::new(Semaphore::new(10));
+        let delivered = Arc::new(AtomicUsize::new(0));
+
+        output.connect_receiver(receiver);
+
+        let output_jh = tokio::spawn(async move { output.run().await });
+
+        for i in 0..5 {
+            let delivered_clone = delivered.clone();
+            let callback = Arc::new(Callback::new(move || { delivered_clone.fetch_add(1, Ordering::SeqCst); }));
+            let event = Event::Json(json!({"app": "web", "host": "web1", "i": i}));
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+            sender.send((event, Arc::new(permit), callback)).expect("Error sending");
+        }
+
+        while delivered.load(Ordering::SeqCst) < 5 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // consume them back
+        let mut args = Args::new();
+        args.insert("channel_size".to_string(), Value::Integer(10));
+        args.insert("brokers".to_string(), Value::String(brokers()));
+        args.insert("topics".to_string(), Value::String(format!("{}-web", topic)));
+        args.insert("group_id".to_string(), Value::String(topic.clone()));
+        args.insert("from_beginning".to_string(), Value::Boolean(true));
+        args.insert("parse_json".to_string(), Value::Boolean(true));
+
+        let mut input = KafkaInput::new(args, tripwire.clone()).await.expect("Error creating KafkaInput");
+        let mut recv = input.get_receiver();
+
+        let input_jh = tokio::spawn(async move { input.run().await });
+
+        for i in 0..5 {
+            let (event, _permit, callback) = recv.recv().await.expect("Error receiving");
+
+            assert_eq!(Event::Json(json!({"app": "web", "host": "web1", "i": i})), event);
+            callback.call();
+        }
+
+        trigger.cancel();
+
+        output_jh.await.expect("Error waiting on output");
+        input_jh.await.expect("Error waiting on input");
+    }
+}
@@ -0,0 +1,417 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::Map;
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::common::logging::{debug, error, warn};
+use crate::event::{Event, JsonValue};
+use crate::field_path::{FieldPath, glob_match};
+use crate::{Args, Plugin, send_event, connect_receiver, create_event_stream, get_receiver, recv_event, create_sender_semaphore};
+use crate::plugin::{PluginType, ChannelType};
+
+/// Parses key/value pairs like `a=1 b="two words"`
+#[derive(Debug)]
+struct KvParser {
+    field: String,
+    remove_field: bool,
+    strip_prefix: Option<Regex>,
+    /// a single space means any run of whitespace
+    pair_separator: String,
+    kv_separator: String,
+    quotes: Vec<char>,
+    infer_types: bool,
+    include_keys: Vec<String>,
+    exclude_keys: Vec<String>,
+    target: Option<FieldPath>,
+    prefix: String,
+    overwrite: bool,
+}
+
+/// Grabs an optional string arg, with a default
+fn string_arg(args: &Args, name: &str, default: &str, plugin_name: &str) -> Result<String> {
+    let value = args.get(name).unwrap_or(&Value::String(default.to_string())).to_owned();
+
+    value.as_str().map(|s| s.to_string()).ok_or_else(|| anyhow!("The '{}' arg for {} does not appear to be a string", name, plugin_name))
+}
+
+/// Grabs an optional bool arg, with a default
+fn bool_arg(args: &Args, name: &str, default: bool, plugin_name: &str) -> Result<bool> {
+    let value = args.get(name).unwrap_or(&Value::Boolean(default)).to_owned();
+
+    value.as_bool().ok_or_else(|| anyhow!("The '{}' arg for {} does not appear to be a bool", name, plugin_name))
+}
+
+/// Grabs an optional list of strings
+fn keys_arg(args: &Args, name: &str, plugin_name: &str) -> Result<Vec<String>> {
+    match args.get(name) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(a)) => {
+            a.iter()
+             .map(|k| k.as_str().map(|k| k.to_string()))
+             .collect::<Option<Vec<_>>>()
+             .ok_or_else(|| anyhow!("Found non-string key in '{}' arg for {}", name, plugin_name))
+        }
+        Some(_) => bail!("The '{}' arg for {} does not appear to be an array of strings", name, plugin_name)
+    }
+}
+
+impl KvParser {
+    fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        let field = string_arg(args, "field", "message", plugin_name)?;
+        let remove_field = bool_arg(args, "remove_field", false, plugin_name)?;
+
+        let strip_prefix = match args.get("strip_prefix") {
+            None => None,
+            Some(prefix) => {
+                let prefix = prefix.as_str().ok_or_else(|| anyhow!("The 'strip_prefix' arg for {} does not appear to be a string", plugin_name))?;
+
+                // anchor the regex, so it only matches the start
+                Some(Regex::new(format!("^(?:{})", prefix).as_str()).map_err(|e| anyhow!("Error parsing 'strip_prefix' for {}: {}", plugin_name, e))?)
+            }
+        };
+
+        let pair_separator = string_arg(args, "pair_separator", " ", plugin_name)?;
+        let kv_separator = string_arg(args, "kv_separator", "=", plugin_name)?;
+
+        if pair_separator.is_empty() || kv_separator.is_empty() {
+            bail!("The 'pair_separator' and 'kv_separator' args for {} cannot be empty", plugin_name);
+        }
+
+        if pair_separator == kv_separator {
+            bail!("The 'pair_separator' and 'kv_separator' args for {} cannot be the same", plugin_name);
+        }
+
+        let quotes = string_arg(args, "quotes", "\"'", plugin_name)?.chars().collect();
+        let infer_types = bool_arg(args, "infer_types", true, plugin_name)?;
+        let include_keys = keys_arg(args, "include_keys", plugin_name)?;
+        let exclude_keys = keys_arg(args, "exclude_keys", plugin_name)?;
+
+        let target = match args.get("target") {
+            None => None,
+            Some(t) => Some(FieldPath::parse(t.as_str().ok_or_else(|| anyhow!("The 'target' arg for {} does not appear to be a string", plugin_name))?)?)
+        };
+
+        let prefix = string_arg(args, "prefix", "", plugin_name)?;
+        let overwrite = bool_arg(args, "overwrite", false, plugin_name)?;
+
+        Ok(KvParser {
+            field,
+            remove_field,
+            strip_prefix,
+            pair_separator,
+            kv_separator,
+            quotes,
+            infer_types,
+            include_keys,
+            exclude_keys,
+            target,
+            prefix,
+            overwrite,
+        })
+    }
+
+    /// The length of the pair separator at the start of the string, if there is one
+    fn pair_separator_len(&self, s: &str) -> Option<usize> {
+        if self.pair_separator == " " {
+            let len = s.len() - s.trim_start().len();
+            (len > 0).then_some(len)
+        } else {
+            s.starts_with(self.pair_separator.as_str()).then_some(self.pair_separator.len())
+        }
+    }
+
+    /// Reads a key or value, returning it, whether it was quoted, and the rest of the string
+    fn read_token<'a>(&self, s: &'a str, is_key: bool) -> (String, bool, &'a str) {
+        let s = if self.pair_separator == " " { s } else { s.trim_start() };
+
+        // quoted, with backslash escapes
+        if let Some(quote) = s.chars().next().filter(|c| self.quotes.contains(c)) {
+            let mut token = String::new();
+            let mut chars = s.char_indices().skip(1);
+
+            while let Some((i, c)) = chars.next() {
+                if c == quote {
+                    return (token, true, &s[i + c.len_utf8()..]);
+                } else if c == '\\' {
+                    if let Some((_, c)) = chars.next() {
+                        token.push(c);
+                    }
+                } else {
+                    token.push(c);
+                }
+            }
+
+            // unclosed, so take the rest
+            return (token, true, "");
+        }
+
+        let end = s.char_indices()
+                   .map(|(i, _)| i)
+                   .find(|i| {
+                       let rest = &s[*i..];
+                       self.pair_separator_len(rest).is_some() || (is_key && rest.starts_with(self.kv_separator.as_str()))
+                   })
+                   .unwrap_or(s.len());
+
+        (s[..end].trim().to_string(), false, &s[end..])
+    }
+
+    fn infer_type(&self, value: String, quoted: bool) -> JsonValue {
+        if !self.infer_types || quoted || value.is_empty() {
+            return JsonValue::from(value);
+        }
+
+        match value.as_str() {
+            "true" => return JsonValue::from(true),
+            "false" => return JsonValue::from(false),
+            _ => ()
+        }
+
+        // numbers with leading zeros are usually IDs, so leave them alone
+        let digits = value.trim_start_matches('-');
+        let leading_zero = digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.");
+
+        if leading_zero || !value.bytes().all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b)) {
+            return JsonValue::from(value);
+        }
+
+        if let Ok(i) = value.parse::<i64>() {
+            JsonValue::from(i)
+        } else if let Some(f) = value.parse::<f64>().ok().filter(|f| f.is_finite()) {
+            JsonValue::from(f)
+        } else {
+            JsonValue::from(value)
+        }
+    }
+
+    fn keep_key(&self, key: &str) -> bool {
+        (self.include_keys.is_empty() || self.include_keys.iter().any(|k| glob_match(k, key))) &&
+            !self.exclude_keys.iter().any(|k| glob_match(k, key))
+    }
+
+    /// Parses all the pairs in the string; words without a key/value separator are skipped
+    fn parse(&self, s: &str) -> Vec<(String, JsonValue)> {
+        let mut rest = match &self.strip_prefix {
+            Some(re) => re.find(s).map(|m| &s[m.end()..]).unwrap_or(s),
+            None => s
+        };
+
+        let mut pairs = Vec::new();
+
+        loop {
+            while let Some(len) = self.pair_separator_len(rest) {
+                rest = &rest[len..];
+            }
+
+            if rest.is_empty() {
+                break
+            }
+
+            let (key, _, r) = self.read_token(rest, true);
+
+            rest = match r.strip_prefix(self.kv_separator.as_str()) {
+                Some(r) => {
+                    let (value, quoted, r) = self.read_token(r, false);
+
+                    if !key.is_empty() && self.keep_key(key.as_str()) {
+                        pairs.push((format!("{}{}", self.prefix, key), self.infer_type(value, quoted)));
+                    }
+
+                    r
+                }
+                None if r.len() == rest.len() => &r[r.chars().next().map(|c| c.len_utf8()).unwrap_or(0)..], // nothing consumed, so skip a char
+                None => r
+            };
+        }
+
+        pairs
+    }
+
+    fn process(&self, event: Event) -> Event {
+        let obj = match event {
+            Event::Json(JsonValue::Object(obj)) => obj,
+            Event::String(s) => {
+                let mut obj = Map::new();
+                obj.insert("message".to_string(), JsonValue::from(s));
+                obj
+            }
+            Event::Json(_) | Event::None => return event
+        };
+
+        let mut json = JsonValue::Object(obj);
+
+        let pairs = match json.get(self.field.as_str()).and_then(|v| v.as_str()) {
+            Some(s) => self.parse(s),
+            None => return Event::Json(json)
+        };
+
+        if self.remove_field {
+            json.as_object_mut().unwrap().remove(self.field.as_str());
+        }
+
+        for (key, value) in pairs {
+            let path = match &self.target {
+                Some(target) => target.join(key.as_str()),
+                None => FieldPath::from_keys([key])
+            };
+
+            if !self.overwrite && path.get(&json).is_some() {
+                continue
+            }
+
+            if let Err(e) = path.insert(&mut json, value) {
+                warn!("Error inserting key/value pair: {}", e);
+            }
+        }
+
+        Event::Json(json)
+    }
+}
+
+pub struct KvTransform {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    parser: KvParser,
+}
+
+#[async_trait]
+impl Plugin for KvTransform {
+    fn name() -> &'static str {
+        "kv"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+        debug!("KvTransform args: {:#?}", args);
+
+        let parser = KvParser::from_args(&args, Self::name())?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(KvTransform {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            sender,
+            semaphore,
+            parser,
+        }))
+    }
+
+    async fn run(&mut self) {
+        let mut event_stream = create_event_stream!(self);
+
+        // grab an event and pass it along
+        while let Some(event) = event_stream.next().await {
+            let (event, callback) = recv_event!(event);
+
+            if let Event::None = event {
+                callback.call();
+                continue // nothing to do here
+            }
+
+            let event = self.parser.process(event);
+
+            send_event!(self, event, callback);
+        }
+    }
+
+    // boilerplate methods
+    get_receiver!{}
+    connect_receiver!{}
+}
+
+
+#[cfg(test)]
+mod kv_tests {
+    use serde_json::json;
+
+    use crate::Args;
+    use crate::event::Event;
+    use crate::plugins::kv::KvParser;
+
+    fn parser(args: &str) -> KvParser {
+        let args: Args = toml::from_str(args).expect("Error parsing TOML");
+
+        KvParser::from_args(&args, "kv").expect("Error parsing args")
+    }
+
+    #[test]
+    fn fortinet() {
+        let parser = parser(r#"strip_prefix = '<\d+>'
+                              remove_field = true
+                              exclude_keys = ["dev*"]"#);
+        let line = r#"<190>date=2023-07-07 time=14:02:12 devname=FGT60D devid=FGT60D logid=1059028704 type=utm vd="root" appid=15895 user="" srcip=192.168.1.110 srcport=38348 policyid=1 sessionid=0962 msg="Network.Service: SSL," apprisk=elevated"#;
+
+        assert_eq!(Event::Json(json!({
+            "date": "2023-07-07",
+            "time": "14:02:12",
+            "logid": 1059028704,
+            "type": "utm",
+            "vd": "root",
+            "appid": 15895,
+            "user": "",
+            "srcip": "192.168.1.110",
+            "srcport": 38348,
+            "policyid": 1,
+            "sessionid": "0962",
+            "msg": "Network.Service: SSL,",
+            "apprisk": "elevated"
+        })), parser.process(Event::from(line)));
+    }
+
+    #[test]
+    fn sonicwall() {
+        let parser = parser(r#"include_keys = ["id", "time", "pri", "msg", "src", "dst", "proto"]
+                              target = "fw"
+                              "#);
+        let line = r#"id=firewall sn=0017C5 time="2023-07-07 14:02:12 UTC" fw=10.0.0.1 pri=6 c=1024 m=537 msg="Connection Closed" src=10.0.0.5:51234:X0 dst=8.8.8.8:53:X1 proto=udp/dns"#;
+
+        assert_eq!(Event::Json(json!({
+            "message": line,
+            "fw": {
+                "id": "firewall",
+                "time": "2023-07-07 14:02:12 UTC",
+                "pri": 6,
+                "msg": "Connection Closed",
+                "src": "10.0.0.5:51234:X0",
+                "dst": "8.8.8.8:53:X1",
+                "proto": "udp/dns"
+            }
+        })), parser.process(Event::from(line)));
+    }
+
+    #[test]
+    fn separators_and_types() {
+        let parser = parser(r#"field = "data"
+                              pair_separator = ","
+                              kv_separator = ":"
+                              prefix = "kv_"
+                              "#);
+        let event = Event::Json(json!({"data": r#"a: 1, b : -2.5,c:true, d:'it\'s, quoted', e:, bare, f:1e3, g:inf, a:dup"#, "kv_a": "keep"}));
+
+        assert_eq!(Event::Json(json!({
+            "data": r#"a: 1, b : -2.5,c:true, d:'it\'s, quoted', e:, bare, f:1e3, g:inf, a:dup"#,
+            "kv_a": "keep",
+            "kv_b": -2.5,
+            "kv_c": true,
+            "kv_d": "it's, quoted",
+            "kv_e": "",
+            "kv_f": 1000.0,
+            "kv_g": "inf"
+        })), parser.process(event));
+
+        let parser = self::parser(r#"infer_types = false
+                                    overwrite = true"#);
+        assert_eq!(Event::Json(json!({"message": "a=1  b=true", "a": "1", "b": "true"})), parser.process(Event::Json(json!({"message": "a=1  b=true"}))));
+    }
+}
@@ -0,0 +1,687 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use futures::{SinkExt, StreamExt as FuturesStreamExt};
+use stream_cancel::{StreamExt, TakeUntilIf, Tripwire};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::{Instant, timeout};
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_util::codec::Framed;
+use toml::Value;
+
+use crate::common::logging::{debug, error, info, warn};
+use crate::{Args, connect_receiver, create_event_stream, create_sender_semaphore, get_receiver, recv_event};
+use crate::event::Event;
+use crate::log_ship_protocol::{Compression, Frame, LogShipCodec, is_frame_too_large};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback, DeadLetter};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Connection = Framed<TcpStream, LogShipCodec>;
+
+
+/// Input plugin that receives events from other log-ship instances
+pub struct LogShipInput {
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    tripwire: Tripwire,
+    token: Arc<String>,
+    socket: TakeUntilIf<TcpListenerStream, Tripwire>,
+}
+
+impl LogShipInput {
+    /// Performs the handshake, then sends along the events of each batch; acking the batch once all have been processed
+    async fn handle_connection(stream: TcpStream, token: Arc<String>, sender: Sender<ChannelType>, semaphore: Arc<Semaphore>, tripwire: Tripwire) -> Result<()> {
+        let peer = stream.peer_addr()?;
+
+        // the compression is only used for batches, which we never send
+        let (mut sink, frames) = Framed::new(stream, LogShipCodec::new(Compression::None)).split();
+        let mut frames = frames.take_until_if(tripwire);
+
+        let hello = match timeout(HANDSHAKE_TIMEOUT, frames.next()).await {
+            Err(_) => bail!("Timed out waiting for hello from {}", peer),
+            Ok(None) => return Ok( () ),
+            Ok(Some(frame)) => frame?
+        };
+
+        if let Err(reason) = hello.check_hello(token.as_str()) {
+            sink.send(Frame::Reject(reason.clone())).await?;
+            bail!("Rejected connection from {}: {}", peer, reason);
+        }
+
+        sink.send(Frame::Ok).await?;
+
+        info!("Accepted connection from {}", peer);
+
+        // acks are written from their own task, as they're sent from the callbacks
+        let (ack_tx, mut ack_rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(seq) = ack_rx.recv().await {
+                if let Err(e) = sink.send(Frame::Ack(seq)).await {
+                    debug!("Error sending ack to {}: {}", peer, e);
+                    break;
+                }
+            }
+        });
+
+        while let Some(frame) = frames.next().await {
+            let (seq, events) = match frame? {
+                Frame::Batch { seq, events } => (seq, events),
+                frame => {
+                    warn!("Unexpected frame from {}: {:?}", peer, frame);
+                    continue
+                }
+            };
+
+            if events.is_empty() {
+                let _ = ack_tx.send(seq);
+                continue
+            }
+
+            // count down the events in the batch, sending the ack when the last one is processed
+            let remaining = Arc::new(AtomicUsize::new(events.len()));
+
+            for event in events {
+                let remaining = remaining.clone();
+                let ack_tx = ack_tx.clone();
+                let cb = Arc::new(Callback::new(move || {
+                    if remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        let _ = ack_tx.send(seq);
+                    }
+                }));
+
+                let permit = match semaphore.clone().acquire_owned().await {
+                    Ok(p) => p,
+                    Err(_) => return Ok( () )
+                };
+
+                if let Err(e) = sender.send((event, Arc::new(permit), cb)) {
+                    bail!("Error sending event: {:?}", e);
+                }
+            }
+        }
+
+        debug!("Connection from {} closed", peer);
+
+        Ok( () )
+    }
+}
+
+#[async_trait]
+impl Plugin for LogShipInput {
+    fn name() -> &'static str where Self: Sized {
+        "log_ship"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
+        debug!("LogShipInput args: {:#?}", args);
+
+        let token = args.get("token").ok_or_else(|| anyhow!("Could not find 'token' arg for {}", Self::name()))?;
+        let token = token.as_str().ok_or_else(|| anyhow!("The 'token' arg for {} does not appear to be a string", Self::name()))?.to_string();
+
+        // grab the host and port
+        let host = args.get("host").ok_or_else(|| anyhow!("Could not find 'host' arg for {}", Self::name()))?;
+        let host = host.as_str().ok_or_else(|| anyhow!("The 'host' arg for {} does not appear to be a string", Self::name()))?;
+        let port = args.get("port").ok_or_else(|| anyhow!("Could not find 'port' arg for {}", Self::name()))?;
+        let port = port.as_integer().ok_or_else(|| anyhow!("The 'port' arg for {} does not appear to be an integer", Self::name()))?;
+
+        let listener = TcpListener::bind((host, port as u16)).await.with_context(|| format!("Binding to {}:{}", host, port))?;
+        let socket = TcpListenerStream::new(listener).take_until_if(tripwire.clone());
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(LogShipInput {
+            sender,
+            semaphore,
+            tripwire,
+            token: Arc::new(token),
+            socket,
+        }))
+    }
+
+    async fn run(&mut self) {
+        debug!("LogShipInput running...");
+
+        while let Some(stream_res) = self.socket.next().await {
+            let stream = match stream_res {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Error accepting connection: {:?}", e);
+                    continue
+                }
+            };
+
+            let token = self.token.clone();
+            let sender = self.sender.clone();
+            let semaphore = self.semaphore.clone();
+            let tripwire = self.tripwire.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = LogShipInput::handle_connection(stream, token, sender, semaphore, tripwire).await {
+                    warn!("{}", e);
+                }
+            });
+        }
+
+        debug!("LogShipInput closing");
+    }
+
+    // boilerplate method
+    get_receiver!{}
+}
+
+
+/// A batch that has been sent, but not yet acknowledged
+struct PendingBatch {
+    seq: u64,
+    events: Vec<Event>,
+    callbacks: Vec<Arc<Callback>>,
+}
+
+/// Output plugin that sends events to another log-ship instance, failing over between targets
+pub struct LogShipOutput {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    targets: Vec<String>,
+    token: String,
+    compression: Compression,
+    batch_size: usize,
+    batch_timeout: Duration,
+    max_in_flight: usize,
+    dead_letter: Option<DeadLetter>,
+}
+
+impl LogShipOutput {
+    /// Connects to a single target, and performs the handshake
+    async fn connect_to(&self, target: &str) -> Result<Connection> {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(target)).await
+            .map_err(|_| anyhow!("Timed out connecting"))??;
+        let mut conn = Framed::new(stream, LogShipCodec::new(self.compression));
+
+        conn.send(Frame::hello(self.token.as_str())).await?;
+
+        match timeout(HANDSHAKE_TIMEOUT, conn.next()).await {
+            Err(_) => bail!("Timed out waiting for handshake"),
+            Ok(None) => bail!("Connection closed during handshake"),
+            Ok(Some(frame)) => match frame? {
+                Frame::Ok => Ok(conn),
+                Frame::Reject(reason) => bail!("Connection rejected: {}", reason),
+                frame => bail!("Unexpected frame during handshake: {:?}", frame)
+            }
+        }
+    }
+
+    /// Tries each target in order until one connects, then resends any unacknowledged batches.
+    /// Returns None if we're shutdown before connecting.
+    async fn connect(&self, pending: &VecDeque<PendingBatch>) -> Option<Connection> {
+        let mut wait = Duration::from_millis(500);
+
+        loop {
+            for target in self.targets.iter() {
+                let mut conn = match self.connect_to(target).await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("Unable to connect to {}: {}", target, e);
+                        continue
+                    }
+                };
+
+                info!("Connected to {}", target);
+
+                let mut resend_res = Ok( () );
+
+                for batch in pending.iter() {
+                    resend_res = conn.feed(Frame::Batch { seq: batch.seq, events: batch.events.clone() }).await;
+
+                    if resend_res.is_err() {
+                        break;
+                    }
+                }
+
+                match resend_res.and(conn.flush().await) {
+                    Ok(_) => return Some(conn),
+                    Err(e) => warn!("Error resending batches to {}: {}", target, e)
+                }
+            }
+
+            // none of the targets are available, so back-off
+            if timeout(wait, self.tripwire.clone()).await.is_ok() {
+                return None
+            }
+
+            wait = (wait * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Sends a batch, splitting it if it's too large for a single frame; returns None if we're shutdown before it's sent
+    async fn send_batch(&self, mut conn: Connection, pending: &mut VecDeque<PendingBatch>, next_seq: &mut u64, events: Vec<Event>, callbacks: Vec<Arc<Callback>>) -> Option<Connection> {
+        let mut to_send = vec![(events, callbacks)];
+
+        while let Some((events, callbacks)) = to_send.pop() {
+            let seq = *next_seq;
+            *next_seq += 1;
+
+            match conn.send(Frame::Batch { seq, events: events.clone() }).await {
+                Ok(_) => pending.push_back(PendingBatch { seq, events, callbacks }),
+                Err(e) if is_frame_too_large(&e) => {
+                    if events.len() == 1 {
+                        self.reject(events, callbacks, e).await;
+                        continue;
+                    }
+
+                    // send each half on its own, the first half first
+                    let mut events = events;
+                    let mut callbacks = callbacks;
+                    let rest = (events.split_off(events.len() / 2), callbacks.split_off(callbacks.len() / 2));
+
+                    debug!("Splitting a batch of {} events that is too large to send", events.len() + rest.0.len());
+
+                    to_send.push(rest);
+                    to_send.push((events, callbacks));
+                }
+                Err(e) => {
+                    // reconnecting resends everything pending, including this batch
+                    pending.push_back(PendingBatch { seq, events, callbacks });
+
+                    warn!("Error sending batch: {}", e);
+                    conn = self.connect(pending).await?;
+                }
+            }
+        }
+
+        Some(conn)
+    }
+
+    /// Sends an event that is too large to ever be sent to the dead-letter output, or logs and drops it
+    async fn reject(&self, events: Vec<Event>, callbacks: Vec<Arc<Callback>>, error: std::io::Error) {
+        for (event, callback) in events.into_iter().zip(callbacks) {
+            match &self.dead_letter {
+                Some(dead_letter) => dead_letter.send(Self::name(), &error, event, callback).await,
+                None => {
+                    error!("Unable to send log: {}; dropping it", error);
+                    callback.call();
+                }
+            }
+        }
+    }
+
+    /// Calls the callbacks for the batch that was acknowledged
+    fn ack(pending: &mut VecDeque<PendingBatch>, seq: u64) {
+        match pending.iter().position(|b| b.seq == seq) {
+            Some(i) => {
+                let batch = pending.remove(i).unwrap();
+
+                for cb in batch.callbacks {
+                    cb.call();
+                }
+            }
+            None => warn!("Ack for unknown batch: {}", seq)
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for LogShipOutput {
+    fn name() -> &'static str where Self: Sized {
+        "log_ship"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
+        debug!("LogShipOutput args: {:#?}", args);
+
+        let targets = args.get("targets").ok_or_else(|| anyhow!("Could not find 'targets' arg for {}", Self::name()))?;
+        let targets = match targets {
+            Value::String(s) => vec![s.clone()],
+            Value::Array(a) => {
+                a.iter()
+                 .map(|t| t.as_str().map(|s| s.to_string()))
+                 .collect::<Option<Vec<_>>>()
+                 .ok_or_else(|| anyhow!("Found non-string target in 'targets' arg for {}", Self::name()))?
+            }
+            _ => bail!("The 'targets' arg for {} must be a string or an array of strings", Self::name())
+        };
+
+        if targets.is_empty() {
+            bail!("The 'targets' arg for {} must contain at least one host:port", Self::name());
+        }
+
+        let token = args.get("token").ok_or_else(|| anyhow!("Could not find 'token' arg for {}", Self::name()))?;
+        let token = token.as_str().ok_or_else(|| anyhow!("The 'token' arg for {} does not appear to be a string", Self::name()))?.to_string();
+
+        let compression = args.get("compression").unwrap_or(&Value::String("none".to_string())).to_owned();
+        let compression = compression.as_str().ok_or_else(|| anyhow!("The 'compression' arg for {} does not appear to be a string", Self::name()))?;
+        let compression = Compression::parse(compression).with_context(|| format!("Parsing the 'compression' arg for {}", Self::name()))?;
+
+        let batch_size = args.get("batch_size").unwrap_or(&Value::Integer(500));
+        let batch_size = batch_size.as_integer().ok_or_else(|| anyhow!("The 'batch_size' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if !(1..=10_000).contains(&batch_size) {
+            bail!("Nonsensical value {} for 'batch_size' for {}; should be between 1 and 10000", batch_size, Self::name());
+        }
+
+        let batch_timeout = args.get("batch_timeout_ms").unwrap_or(&Value::Integer(1000));
+        let batch_timeout = batch_timeout.as_integer().ok_or_else(|| anyhow!("The 'batch_timeout_ms' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if batch_timeout < 1 {
+            bail!("The 'batch_timeout_ms' arg for {} must be positive", Self::name());
+        }
+
+        let max_in_flight = args.get("max_in_flight").unwrap_or(&Value::Integer(8));
+        let max_in_flight = max_in_flight.as_integer().ok_or_else(|| anyhow!("The 'max_in_flight' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if !(1..=1024).contains(&max_in_flight) {
+            bail!("Nonsensical value {} for 'max_in_flight' for {}; should be between 1 and 1024", max_in_flight, Self::name());
+        }
+
+        Ok(Box::new(LogShipOutput {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            targets,
+            token,
+            compression,
+            batch_size: batch_size as usize,
+            batch_timeout: Duration::from_millis(batch_timeout as u64),
+            max_in_flight: max_in_flight as usize,
+            dead_letter: None, // set in connect_dead_letter
+        }))
+    }
+
+    async fn run(&mut self) {
+        debug!("LogShipOutput running...");
+
+        let mut event_stream = create_event_stream!(self);
+        let mut pending = VecDeque::new();
+        let mut events = Vec::with_capacity(self.batch_size);
+        let mut callbacks = Vec::with_capacity(self.batch_size);
+        let mut next_seq = 0_u64;
+        let mut deadline = Instant::now() + self.batch_timeout;
+        let mut done = false;
+
+        let mut conn = match self.connect(&pending).await {
+            Some(conn) => conn,
+            None => return
+        };
+
+        while !done || !events.is_empty() {
+            let send_batch = tokio::select! {
+                frame = conn.next() => {
+                    match frame {
+                        Some(Ok(Frame::Ack(seq))) => LogShipOutput::ack(&mut pending, seq),
+                        Some(Ok(frame)) => warn!("Unexpected frame: {:?}", frame),
+                        Some(Err(e)) => {
+                            warn!("Error reading from connection: {}", e);
+                            conn = match self.connect(&pending).await { Some(c) => c, None => return };
+                        }
+                        None => {
+                            warn!("Connection closed");
+                            conn = match self.connect(&pending).await { Some(c) => c, None => return };
+                        }
+                    }
+
+                    false
+                }
+                _ = tokio::time::sleep_until(deadline), if !events.is_empty() && pending.len() < self.max_in_flight => true,
+                op_event = event_stream.next(), if !done && pending.len() < self.max_in_flight => {
+                    match op_event {
+                        None => {
+                            // send whatever we have
+                            done = true;
+                            !events.is_empty()
+                        }
+                        Some(event) => {
+                            let (event, callback) = recv_event!(event);
+
+                            // skip the None events
+                            if Event::None == event {
+                                callback.call();
+                                continue
+                            }
+
+                            if events.is_empty() {
+                                deadline = Instant::now() + self.batch_timeout;
+                            }
+
+                            events.push(event);
+                            callbacks.push(callback);
+
+                            events.len() >= self.batch_size
+                        }
+                    }
+                }
+            };
+
+            if send_batch {
+                let batch = (std::mem::take(&mut events), std::mem::take(&mut callbacks));
+
+                conn = match self.send_batch(conn, &mut pending, &mut next_seq, batch.0, batch.1).await {
+                    Some(c) => c,
+                    None => return
+                };
+            }
+        }
+
+        // give the remote a chance to ack what's been sent
+        let drain_deadline = Instant::now() + DRAIN_TIMEOUT;
+
+        while !pending.is_empty() {
+            match tokio::time::timeout_at(drain_deadline, conn.next()).await {
+                Ok(Some(Ok(Frame::Ack(seq)))) => LogShipOutput::ack(&mut pending, seq),
+                Ok(Some(Ok(frame))) => warn!("Unexpected frame: {:?}", frame),
+                _ => break
+            }
+        }
+
+        if !pending.is_empty() {
+            warn!("{} batches were not acknowledged before shutting down", pending.len());
+        }
+
+        debug!("LogShipOutput closing");
+    }
+
+    // boilerplate method
+    connect_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+
+#[cfg(test)]
+mod log_ship_tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{broadcast, Semaphore};
+    use tokio_util::codec::Framed;
+    use toml::Value;
+
+    use crate::common::init_test_logger;
+    use crate::event::Event;
+    use crate::log_ship_protocol::{Compression, Frame, LogShipCodec};
+    use crate::plugin::{Args, Callback, Plugin};
+    use crate::plugins::log_ship::{LogShipInput, LogShipOutput};
+
+    async fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Error binding");
+        listener.local_addr().unwrap().port()
+    }
+
+    async fn create_input(port: u16, tripwire: Tripwire) -> Box<crate::plugin::PluginType> {
+        let mut args = Args::new();
+        args.insert("channel_size".to_string(), Value::Integer(10));
+        args.insert("host".to_string(), Value::String("127.0.0.1".to_string()));
+        args.insert("port".to_string(), Value::Integer(port as i64));
+        args.insert("token".to_string(), Value::String("secret".to_string()));
+
+        LogShipInput::new(args, tripwire).await.expect("Error creating LogShipInput")
+    }
+
+    #[tokio::test]
+    async fn failover_and_ack() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let (port, dead_port) = (free_port().await, free_port().await);
+
+        let mut input = create_input(port, tripwire.clone()).await;
+        let mut recv = input.get_receiver();
+        let input_jh = tokio::spawn(async move { input.run().await });
+
+        // the first target isn't listening, so we should failover to the second
+        let mut args = Args::new();
+        args.insert("targets".to_string(), Value::Array(vec![
+            Value::String(format!("127.0.0.1:{}", dead_port)),
+            Value::String(format!("127.0.0.1:{}", port)),
+        ]));
+        args.insert("token".to_string(), Value::String("secret".to_string()));
+        args.insert("compression".to_string(), Value::String("zstd".to_string()));
+        args.insert("batch_size".to_string(), Value::Integer(3));
+
+        let mut output = LogShipOutput::new(args, tripwire.clone()).await.expect("Error creating LogShipOutput");
+        let (sender, receiver) = broadcast::channel(10);
+        let semaphore = Arc::new(Semaphore::new(10));
+        let acked = Arc::new(AtomicUsize::new(0));
+
+        output.connect_receiver(receiver);
+
+        let output_jh = tokio::spawn(async move { output.run().await });
+
+        for i in 0..3 {
+            let acked_clone = acked.clone();
+            let callback = Arc::new(Callback::new(move || { acked_clone.fetch_add(1, Ordering::SeqCst); }));
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+            sender.send((Event::Json(json!({"i": i})), Arc::new(permit), callback)).expect("Error sending");
+        }
+
+        for i in 0..3 {
+            let (event, _permit, callback) = recv.recv().await.expect("Error receiving");
+
+            assert_eq!(Event::Json(json!({"i": i})), event);
+
+            // nothing is acked until the whole batch is processed
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(0, acked.load(Ordering::SeqCst));
+
+            callback.call();
+        }
+
+        // wait for the ack
+        for _ in 0..50 {
+            if acked.load(Ordering::SeqCst) == 3 {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(3, acked.load(Ordering::SeqCst));
+
+        trigger.cancel();
+
+        output_jh.await.expect("Error waiting on output");
+        input_jh.await.expect("Error waiting on input");
+    }
+
+    #[tokio::test]
+    async fn split_large_batches() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Error binding");
+        let port = listener.local_addr().unwrap().port();
+
+        // a server that acks every batch, and sends back the number of events in each
+        let (batch_tx, mut batch_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("Error accepting");
+            let mut conn = Framed::new(stream, LogShipCodec::new(Compression::None));
+
+            assert!(matches!(conn.next().await, Some(Ok(Frame::Hello { .. }))));
+            conn.send(Frame::Ok).await.expect("Error sending ok");
+
+            while let Some(Ok(Frame::Batch { seq, events })) = conn.next().await {
+                batch_tx.send(events.len()).unwrap();
+                conn.send(Frame::Ack(seq)).await.expect("Error sending ack");
+            }
+        });
+
+        let mut args = Args::new();
+        args.insert("targets".to_string(), Value::String(format!("127.0.0.1:{}", port)));
+        args.insert("token".to_string(), Value::String("secret".to_string()));
+        args.insert("batch_size".to_string(), Value::Integer(3));
+
+        let mut output = LogShipOutput::new(args, tripwire.clone()).await.expect("Error creating LogShipOutput");
+        let (sender, receiver) = broadcast::channel(10);
+        let semaphore = Arc::new(Semaphore::new(10));
+        let acked = Arc::new(AtomicUsize::new(0));
+
+        output.connect_receiver(receiver);
+
+        let output_jh = tokio::spawn(async move { output.run().await });
+
+        // together these are larger than a frame, and the last is too large on its own
+        for len in [33, 33, 65] {
+            let acked_clone = acked.clone();
+            let callback = Arc::new(Callback::new(move || { acked_clone.fetch_add(1, Ordering::SeqCst); }));
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+
+            sender.send((Event::String("x".repeat(len * 1024 * 1024)), Arc::new(permit), callback)).expect("Error sending");
+        }
+
+        assert_eq!(Some(1), batch_rx.recv().await);
+        assert_eq!(Some(1), batch_rx.recv().await);
+
+        // the log that's too large is dropped, so it doesn't hold up the source
+        for _ in 0..50 {
+            if acked.load(Ordering::SeqCst) == 3 {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(3, acked.load(Ordering::SeqCst));
+
+        trigger.cancel();
+        output_jh.await.expect("Error waiting on output");
+    }
+
+    #[tokio::test]
+    async fn reject_bad_token() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let port = free_port().await;
+
+        let mut input = create_input(port, tripwire.clone()).await;
+        let input_jh = tokio::spawn(async move { input.run().await });
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.expect("Error connecting");
+        let mut conn = Framed::new(stream, LogShipCodec::new(Compression::None));
+
+        conn.send(Frame::hello("wrong")).await.expect("Error sending hello");
+
+        let frame = conn.next().await.expect("Connection closed").expect("Error reading frame");
+
+        assert_eq!(Frame::Reject("Invalid token".to_string()), frame);
+
+        trigger.cancel();
+        input_jh.await.expect("Error waiting on input");
+    }
+}
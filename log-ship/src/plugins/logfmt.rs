@@ -62,11 +62,15 @@ impl Plugin for LogFmtParser {
             debug!("GOT EVENT: {:?}", event);
 
             match event {
-                Event::None => continue,
+                Event::None => {
+                    callback.call();
+                    continue
+                }
                 Event::Json(mut json) => {
                     let json = match json.as_object_mut() {
                         None => {
                             warn!("JSON not an object!");
+                            callback.call();
                             continue;
                         }
                         Some(j) => j
@@ -100,6 +104,7 @@ impl Plugin for LogFmtParser {
                 }
                 Event::String(_) => {
                     warn!("Received text; expecting JSON");
+                    callback.call();
                     continue
                 }
             }
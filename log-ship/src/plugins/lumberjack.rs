@@ -1,32 +1,128 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
-use serde_json::Value;
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
 use stream_cancel::{StreamExt, TakeUntilIf, Tripwire};
 use tokio::net::TcpListener;
 use tokio::sync::{broadcast, Semaphore};
 use tokio::sync::broadcast::{Receiver, Sender};
-use tokio::io::{AsyncReadExt, BufReader};
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_stream::{StreamExt as TokioStreamExt};
 use tokio_util::codec::FramedRead;
-use tower::Service;
 
-use crate::common::logging::{debug, error, warn};
+use crate::common::logging::{debug, error};
 use crate::{Args, create_sender_semaphore, get_receiver};
 use crate::event::Event;
 use crate::lumberjack_decoder::LumberjackCodec;
-use crate::plugin::{Plugin, PluginType, ChannelType, Callback};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback, DeadLetter};
+use crate::plugins::fields::flatten_into;
+use crate::plugins::parse_ts::TsOutput;
 
+const DEFAULT_BEATS_FIELDS: &[&str] = &["@metadata", "agent", "ecs"];
+
+/// What to do with the metadata subtrees Beats adds to each event
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BeatsMetadata {
+    Keep,
+    Flatten,
+    Strip,
+}
+
+/// Converts the JSON sent by Beats into a log
+#[derive(Debug)]
+struct BeatsDecoder {
+    ts_field: String,
+    ts_type: TsOutput,
+    metadata: BeatsMetadata,
+    metadata_fields: Vec<String>,
+    peer_field: Option<String>,
+}
+
+impl BeatsDecoder {
+    fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        // grab an optional timestamp field
+        let ts_field = args.get("ts_field").unwrap_or(&toml::Value::String("t".to_string())).to_owned();
+        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for {} does not appear to be a string", plugin_name))?.to_string();
+        let ts_type = TsOutput::from_args(args, plugin_name)?;
+
+        let metadata = args.get("metadata").unwrap_or(&toml::Value::String("keep".to_string())).to_owned();
+        let metadata = match metadata.as_str() {
+            Some("keep") => BeatsMetadata::Keep,
+            Some("flatten") => BeatsMetadata::Flatten,
+            Some("strip") => BeatsMetadata::Strip,
+            _ => bail!("The 'metadata' arg for {} must be one of: keep, flatten, strip", plugin_name)
+        };
+
+        let metadata_fields = match args.get("metadata_fields") {
+            None => DEFAULT_BEATS_FIELDS.iter().map(|f| f.to_string()).collect(),
+            Some(toml::Value::Array(a)) => {
+                a.iter()
+                 .map(|f| f.as_str().map(|f| f.to_string()))
+                 .collect::<Option<Vec<_>>>()
+                 .ok_or_else(|| anyhow!("Found non-string field in 'metadata_fields' arg for {}", plugin_name))?
+            }
+            Some(_) => bail!("The 'metadata_fields' arg for {} does not appear to be an array of strings", plugin_name)
+        };
+
+        let peer_field = match args.get("peer_field") {
+            None => None,
+            Some(f) => Some(f.as_str().ok_or_else(|| anyhow!("The 'peer_field' arg for {} does not appear to be a string", plugin_name))?.to_string())
+        };
+
+        Ok(BeatsDecoder { ts_field, ts_type, metadata, metadata_fields, peer_field })
+    }
+
+    /// Decodes the raw JSON of an event; values that aren't objects are put in the `message` field
+    fn decode(&self, raw: &str, peer: Option<&SocketAddr>) -> Result<Value> {
+        let mut obj = match serde_json::from_str(raw)? {
+            Value::Object(obj) => obj,
+            value => {
+                let mut obj = Map::new();
+                obj.insert("message".to_string(), value);
+                obj
+            }
+        };
+
+        // move the Beats timestamp into ts_field
+        let timestamp = obj.get("@timestamp")
+                           .and_then(|t| t.as_str())
+                           .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                           .and_then(|t| self.ts_type.format(&t.with_timezone(&Utc)));
+
+        if let Some(timestamp) = timestamp {
+            obj.remove("@timestamp");
+            obj.insert(self.ts_field.clone(), timestamp);
+        }
+
+        if self.metadata != BeatsMetadata::Keep {
+            for field in self.metadata_fields.iter() {
+                if let (BeatsMetadata::Flatten, Some(value)) = (self.metadata, obj.remove(field.as_str())) {
+                    let mut flat = Map::new();
+                    flatten_into(field.clone(), value, ".", &mut flat);
+                    obj.extend(flat);
+                }
+            }
+        }
+
+        if let (Some(peer_field), Some(peer)) = (&self.peer_field, peer) {
+            obj.insert(peer_field.clone(), Value::from(peer.to_string()));
+        }
+
+        Ok(Value::Object(obj))
+    }
+}
 
 /// This is _basically_ Logstash
 pub struct LumberjackInput {
     sender: Sender<ChannelType>,
     semaphore: Arc<Semaphore>,
     tripwire: Tripwire,
-    ts_field: String,
-    socket: TakeUntilIf<TcpListenerStream, Tripwire>
+    decoder: Arc<BeatsDecoder>,
+    socket: TakeUntilIf<TcpListenerStream, Tripwire>,
+    dead_letter: Option<DeadLetter>,
 }
 
 #[async_trait]
@@ -38,9 +134,7 @@ impl Plugin for LumberjackInput {
     async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
         debug!("LumberjackInput args: {:#?}", args);
 
-        // grab an optional timestamp field
-        let ts_field = args.get("ts_field").unwrap_or(&toml::Value::String("t".to_string())).to_owned();
-        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("The 'ts_field' arg for {} does not appear to be a string", Self::name()))?.to_string();
+        let decoder = Arc::new(BeatsDecoder::from_args(&args, Self::name())?);
 
         // grab the host and port
         let host = args.get("host").ok_or_else(|| anyhow!("Could not find 'host' arg for {}", Self::name()))?;
@@ -58,8 +152,9 @@ impl Plugin for LumberjackInput {
             sender,
             semaphore,
             tripwire,
-            ts_field,
-            socket
+            decoder,
+            socket,
+            dead_letter: None, // set in connect_dead_letter
         }))
     }
 
@@ -77,6 +172,8 @@ impl Plugin for LumberjackInput {
                 }
             };
 
+            let peer = stream.peer_addr().ok();
+
             // let (stream, _addr) = self.socket.accept().await.expect("Getting connection");
             let mut reader = FramedRead::new(stream, LumberjackCodec {});
 
@@ -86,30 +183,34 @@ impl Plugin for LumberjackInput {
                 let channel_clone = self.sender.clone();
                 let semaphore_clone = self.semaphore.clone();
                 let cb = no_op_callback.clone();
+                let dead_letter = self.dead_letter.clone();
+                let decoder = self.decoder.clone();
 
                 tokio::spawn( async move {
                     for event in res.events {
-                        let json: Value = match serde_json::from_str(event.raw.as_str()) {
+                        let json = match decoder.decode(event.raw.as_str(), peer.as_ref()) {
                             Ok(v) => v,
                             Err(e) => {
-                                error!("Error parsing JSON: {:?}", e);
+                                match &dead_letter {
+                                    Some(dead_letter) => dead_letter.send(LumberjackInput::name(), e, Event::String(event.raw), cb.clone()).await,
+                                    None => error!("Error parsing JSON: {:?}", e)
+                                }
+
                                 continue
                             }
                         };
 
-                        if json.is_object() {
-                            let event = Event::Json(json);
+                        let event = Event::Json(json);
 
-                            let permit = match semaphore_clone.clone().acquire_owned().await {
-                                Ok(p) => p,
-                                Err(_) => return
-                            };
+                        let permit = match semaphore_clone.clone().acquire_owned().await {
+                            Ok(p) => p,
+                            Err(_) => return
+                        };
 
-                            // send down the channel
-                            if let Err(e) = channel_clone.send((event, Arc::new(permit), cb.clone())) {
-                                error!("Error sending event: {:?}", e);
-                                return;
-                            }
+                        // send down the channel
+                        if let Err(e) = channel_clone.send((event, Arc::new(permit), cb.clone())) {
+                            error!("Error sending event: {:?}", e);
+                            return;
                         }
                     }
                 });
@@ -123,4 +224,78 @@ impl Plugin for LumberjackInput {
     // boilerplate method
     get_receiver!{}
 
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+
+#[cfg(test)]
+mod lumberjack_tests {
+    use serde_json::json;
+
+    use crate::Args;
+    use crate::plugins::lumberjack::BeatsDecoder;
+
+    const BEATS_EVENT: &str = r#"{"@timestamp":"2023-07-07T14:02:12.000Z","@metadata":{"beat":"filebeat","type":"_doc"},"agent":{"name":"web1","version":"8.8.2"},"ecs":{"version":"8.0.0"},"message":"hello","log":{"offset":42}}"#;
+
+    fn decoder(args: &str) -> BeatsDecoder {
+        let args: Args = toml::from_str(args).expect("Error parsing TOML");
+
+        BeatsDecoder::from_args(&args, "lumberjack").expect("Error parsing args")
+    }
+
+    #[test]
+    fn keep_metadata() {
+        let decoder = decoder("");
+
+        assert_eq!(json!({
+            "t": 1688738532000_i64,
+            "@metadata": {"beat": "filebeat", "type": "_doc"},
+            "agent": {"name": "web1", "version": "8.8.2"},
+            "ecs": {"version": "8.0.0"},
+            "message": "hello",
+            "log": {"offset": 42}
+        }), decoder.decode(BEATS_EVENT, None).unwrap());
+    }
+
+    #[test]
+    fn flatten_and_strip_metadata() {
+        let decoder = self::decoder(r#"metadata = "flatten"
+                                      ts_field = "time"
+                                      ts_type = "rfc3339"
+                                      peer_field = "peer""#);
+        let peer = "10.0.0.5:51234".parse().unwrap();
+
+        assert_eq!(json!({
+            "time": "2023-07-07T14:02:12+00:00",
+            "@metadata.beat": "filebeat",
+            "@metadata.type": "_doc",
+            "agent.name": "web1",
+            "agent.version": "8.8.2",
+            "ecs.version": "8.0.0",
+            "message": "hello",
+            "log": {"offset": 42},
+            "peer": "10.0.0.5:51234"
+        }), decoder.decode(BEATS_EVENT, Some(&peer)).unwrap());
+
+        let decoder = self::decoder(r#"metadata = "strip"
+                                      metadata_fields = ["@metadata", "ecs"]"#);
+
+        assert_eq!(json!({
+            "t": 1688738532000_i64,
+            "agent": {"name": "web1", "version": "8.8.2"},
+            "message": "hello",
+            "log": {"offset": 42}
+        }), decoder.decode(BEATS_EVENT, None).unwrap());
+    }
+
+    #[test]
+    fn non_objects() {
+        let decoder = decoder("");
+
+        assert_eq!(json!({"message": "just a string"}), decoder.decode(r#""just a string""#, None).unwrap());
+        assert_eq!(json!({"message": [1, 2]}), decoder.decode("[1, 2]", None).unwrap());
+        assert!(decoder.decode("not json", None).is_err());
+    }
 }
@@ -1,11 +1,18 @@
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, bail};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 
-use heim::cpu::{stats, times, usage};
+use heim::cpu::{stats, times};
+use heim::cpu::os::unix::loadavg;
 use heim::memory::{memory, swap};
 use heim::units::ratio;
 
@@ -14,8 +21,11 @@ use heim::disk::{io_counters, partitions};
 
 use heim::memory::os::linux::MemoryExt;
 use heim::net::os::linux::IoCountersExt;
+use heim::process::Pid;
+use heim::process::os::linux::ProcessExt;
 use heim::units::time::second;
 use heim::units::information::byte;
+use regex::Regex;
 use serde_json::{Map, json};
 use stream_cancel::{StreamExt, Tripwire};
 
@@ -28,41 +38,421 @@ use tokio::time::Instant;
 
 use toml::Value;
 
+use crate::common::hostname;
 use crate::common::logging::{debug, error, warn};
 use crate::{Args, create_sender_semaphore, get_receiver};
 use crate::event::{Event, JsonValue};
 use crate::plugin::{Plugin, PluginType, ChannelType, Callback};
+use crate::plugins::parse_ts::TsOutput;
+
+/// The host used when the 'host' arg isn't set, and the hostname can't be found
+const DEFAULT_HOST: &str = "localhost";
+
+const AVAILABLE_METRICS: &[&str] = &["cpu", "memory", "disk", "net", "process", "load", "filesystem", "tcp"];
+
+/// The names of the states in `/proc/net/tcp`, indexed by their value
+const TCP_STATES: &[&str] = &[
+    "unknown", "established", "syn_sent", "syn_recv", "fin_wait1", "fin_wait2", "time_wait",
+    "close", "close_wait", "last_ack", "listen", "closing", "new_syn_recv",
+];
+
+/// The usage of a single process, or all the processes with the same name
+#[derive(Debug, Clone, PartialEq)]
+struct ProcessSample {
+    pid: Option<Pid>,
+    name: String,
+    count: u64,
+    /// `None` until the process has been seen twice
+    cpu_percent: Option<f64>,
+    rss_bytes: u64,
+    open_fds: Option<u64>,
+    threads: Option<u64>,
+    /// per-second rates, or totals, depending on the counter mode
+    bytes_read: Option<f64>,
+    bytes_written: Option<f64>,
+}
+
+impl ProcessSample {
+    fn dimensions(&self) -> Map<String, JsonValue> {
+        let mut dimensions = Map::new();
+
+        match self.pid {
+            Some(pid) => dimensions.insert("pid".to_string(), json!(pid)),
+            None => dimensions.insert("count".to_string(), json!(self.count)),
+        };
+
+        dimensions.insert("name".to_string(), json!(self.name));
+
+        dimensions
+    }
+
+    fn values(&self, counters: CounterMode) -> Map<String, JsonValue> {
+        let mut values = Map::new();
+
+        if let Some(cpu_percent) = self.cpu_percent {
+            values.insert("cpu_percent".to_string(), json!(cpu_percent));
+        }
+
+        values.insert("rss_bytes".to_string(), json!(self.rss_bytes));
+        values.insert("open_fds".to_string(), json!(self.open_fds));
+        values.insert("threads".to_string(), json!(self.threads));
+
+        for (name, value) in [("bytes_read", self.bytes_read), ("bytes_written", self.bytes_written)] {
+            match (counters, value) {
+                (CounterMode::Rate, Some(v)) => values.insert(format!("{}_sec", name), json!(v)),
+                (CounterMode::Cumulative, Some(v)) => values.insert(name.to_string(), json!(v as u64)),
+                (_, None) => None
+            };
+        }
+
+        values
+    }
+}
+
+/// Adds optional values, ignoring any that are missing
+fn add_optional<T: std::ops::Add<Output=T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ProcessSort {
+    Cpu,
+    Memory,
+}
+
+/// Picks which processes are sent as events
+#[derive(Debug, Clone)]
+struct ProcessSelector {
+    include: Option<Regex>,
+    group_by_name: bool,
+    sort: ProcessSort,
+    /// 0 sends every process
+    top: usize,
+}
+
+impl ProcessSelector {
+    fn from_args(args: &Args, plugin_name: &str) -> anyhow::Result<Self> {
+        let include = match args.get("process_include") {
+            None => None,
+            Some(r) => {
+                let r = r.as_str().ok_or_else(|| anyhow!("Parameter 'process_include' must be a string for plugin '{}'", plugin_name))?;
+
+                Some(Regex::new(r).map_err(|e| anyhow!("Invalid regex for 'process_include' for plugin '{}': {}", plugin_name, e))?)
+            }
+        };
+
+        let group_by = args.get("process_group_by").unwrap_or(&Value::String("pid".to_string())).to_owned();
+        let group_by_name = match group_by.as_str() {
+            Some("pid") => false,
+            Some("name") => true,
+            _ => bail!("Parameter 'process_group_by' must be one of pid or name for plugin '{}'", plugin_name)
+        };
+
+        let sort = args.get("process_sort").unwrap_or(&Value::String("cpu".to_string())).to_owned();
+        let sort = match sort.as_str() {
+            Some("cpu") => ProcessSort::Cpu,
+            Some("memory") => ProcessSort::Memory,
+            _ => bail!("Parameter 'process_sort' must be one of cpu or memory for plugin '{}'", plugin_name)
+        };
+
+        let top = args.get("process_top").unwrap_or(&Value::Integer(10));
+        let top = top.as_integer().ok_or_else(|| anyhow!("Parameter 'process_top' must be an integer for plugin '{}'", plugin_name))?;
+        let top = usize::try_from(top).map_err(|_| anyhow!("Parameter 'process_top' cannot be negative for plugin '{}'", plugin_name))?;
+
+        Ok(ProcessSelector { include, group_by_name, sort, top })
+    }
+
+    fn includes(&self, name: &str) -> bool {
+        self.include.as_ref().map(|r| r.is_match(name)).unwrap_or(true)
+    }
+
+    /// Groups, sorts, and truncates the samples
+    fn select(&self, samples: Vec<ProcessSample>) -> Vec<ProcessSample> {
+        let mut samples = if self.group_by_name {
+            let mut groups = BTreeMap::<String, ProcessSample>::new();
+
+            for sample in samples {
+                match groups.get_mut(&sample.name) {
+                    None => {
+                        groups.insert(sample.name.clone(), ProcessSample { pid: None, ..sample });
+                    }
+                    Some(group) => {
+                        group.count += sample.count;
+                        group.cpu_percent = add_optional(group.cpu_percent, sample.cpu_percent);
+                        group.rss_bytes += sample.rss_bytes;
+                        group.open_fds = group.open_fds.zip(sample.open_fds).map(|(a, b)| a + b);
+                        group.threads = group.threads.zip(sample.threads).map(|(a, b)| a + b);
+                        group.bytes_read = add_optional(group.bytes_read, sample.bytes_read);
+                        group.bytes_written = add_optional(group.bytes_written, sample.bytes_written);
+                    }
+                }
+            }
+
+            groups.into_values().collect()
+        } else {
+            samples
+        };
+
+        match self.sort {
+            ProcessSort::Cpu => samples.sort_by(|a, b| b.cpu_percent.unwrap_or(0.0).total_cmp(&a.cpu_percent.unwrap_or(0.0))),
+            ProcessSort::Memory => samples.sort_by_key(|s| std::cmp::Reverse(s.rss_bytes)),
+        }
+
+        if self.top > 0 {
+            samples.truncate(self.top);
+        }
+
+        samples
+    }
+}
+
+/// Counts the connections in each state, from the contents of `/proc/net/tcp` or `/proc/net/tcp6`
+fn count_tcp_states(contents: &str, counts: &mut BTreeMap<&'static str, u64>) {
+    // skip the header; the state is the 4th column, in hex
+    for line in contents.lines().skip(1) {
+        let state = match line.split_whitespace().nth(3).and_then(|s| usize::from_str_radix(s, 16).ok()) {
+            Some(s) => s,
+            None => continue
+        };
+
+        *counts.entry(TCP_STATES.get(state).copied().unwrap_or("unknown")).or_default() += 1;
+    }
+}
+
+/// Returns the total and free inodes of the filesystem at the mount point
+fn inodes(mount_point: &Path) -> Option<(u64, u64)> {
+    let path = CString::new(mount_point.as_os_str().as_bytes()).ok()?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: path is NUL terminated, and stat is only read when statvfs succeeds
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+
+    let stat = unsafe { stat.assume_init() };
+
+    #[allow(clippy::unnecessary_cast)] // the field types differ between platforms
+    Some((stat.f_files as u64, stat.f_ffree as u64))
+}
+
+/// Counts the open file descriptors of a process; requires permission to read its `/proc` entry
+fn process_fds(pid: Pid) -> Option<u64> {
+    fs::read_dir(format!("/proc/{}/fd", pid)).ok().map(|d| d.count() as u64)
+}
+
+fn process_threads(pid: Pid) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+
+    status.lines()
+          .find_map(|l| l.strip_prefix("Threads:"))
+          .and_then(|t| t.trim().parse().ok())
+}
+
+/// Whether counters are sent as per-second rates, or as their running totals
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CounterMode {
+    Rate,
+    Cumulative,
+}
+
+/// Remembers the last value of each counter, so rates are computed from the real time between samples
+#[derive(Debug)]
+struct Counters {
+    mode: CounterMode,
+    previous: HashMap<String, (Instant, u64)>,
+}
+
+impl Counters {
+    fn new(mode: CounterMode) -> Self {
+        Counters { mode, previous: HashMap::new() }
+    }
+
+    /// Returns the per-second rate since the counter was last sampled, or its total;
+    /// there is no rate the first time a counter is sampled
+    fn value(&mut self, key: &str, value: u64, now: Instant) -> Option<f64> {
+        if self.mode == CounterMode::Cumulative {
+            return Some(value as f64);
+        }
+
+        let (then, previous) = self.previous.insert(key.to_string(), (now, value))?;
+        let elapsed = now.duration_since(then).as_secs_f64();
+
+        // a counter that goes backwards has been reset
+        if elapsed <= 0.0 || value < previous {
+            return None;
+        }
+
+        Some((value - previous) as f64 / elapsed)
+    }
+
+    /// Inserts the counter into the values, as `rate_name` or `total_name` depending on the mode
+    fn insert(&mut self, values: &mut Map<String, JsonValue>, key: &str, rate_name: &str, total_name: &str, value: u64, now: Instant) {
+        match (self.mode, self.value(format!("{}/{}", key, total_name).as_str(), value, now)) {
+            (CounterMode::Rate, Some(rate)) => values.insert(rate_name.to_string(), json!(rate)),
+            (CounterMode::Cumulative, _) => values.insert(total_name.to_string(), json!(value)),
+            (CounterMode::Rate, None) => None
+        };
+    }
+
+    /// Forgets the counters that weren't sampled at `now`, like those of processes that have exited
+    fn retain_sampled(&mut self, now: Instant) {
+        self.previous.retain(|_, (then, _)| *then >= now);
+    }
+}
+
+/// The shape of the events, which is the same for every metric set
+#[derive(Debug, Clone)]
+struct Envelope {
+    host: String,
+    ts_field: String,
+    ts_type: TsOutput,
+    /// one event per value, instead of one per sample
+    long: bool,
+    counters: CounterMode,
+}
+
+impl Envelope {
+    fn from_args(args: &Args, plugin_name: &str) -> anyhow::Result<Self> {
+        let host = match args.get("host") {
+            Some(h) => h.as_str().ok_or_else(|| anyhow!("Parameter 'host' must be a string for plugin '{}'", plugin_name))?.to_string(),
+            None => hostname().unwrap_or_else(|e| {
+                warn!("{}; using '{}' as the host for plugin '{}'", e, DEFAULT_HOST, plugin_name);
+                DEFAULT_HOST.to_string()
+            })
+        };
+
+        let ts_field = args.get("ts_field").unwrap_or(&Value::String("t".to_string())).to_owned();
+        let ts_field = ts_field.as_str().ok_or_else(|| anyhow!("Parameter 'ts_field' must be a string for plugin '{}'", plugin_name))?.to_string();
+        let ts_type = TsOutput::from_args(args, plugin_name)?;
+
+        let format = args.get("format").unwrap_or(&Value::String("wide".to_string())).to_owned();
+        let long = match format.as_str() {
+            Some("wide") => false,
+            Some("long") => true,
+            _ => bail!("Parameter 'format' must be one of wide or long for plugin '{}'", plugin_name)
+        };
+
+        let counters = args.get("counters").unwrap_or(&Value::String("rate".to_string())).to_owned();
+        let counters = match counters.as_str() {
+            Some("rate") => CounterMode::Rate,
+            Some("cumulative") => CounterMode::Cumulative,
+            _ => bail!("Parameter 'counters' must be one of rate or cumulative for plugin '{}'", plugin_name)
+        };
+
+        Ok(Envelope { host, ts_field, ts_type, long, counters })
+    }
+
+    /// Builds the events for a sample; `dimensions` identify what was sampled, like the device, and are in every event
+    fn events(&self, metric_set: &str, time: &DateTime<Utc>, dimensions: Map<String, JsonValue>, values: Map<String, JsonValue>) -> Vec<JsonValue> {
+        if values.is_empty() {
+            return vec![];
+        }
+
+        let mut envelope = Map::new();
+
+        envelope.insert("metric_set".to_string(), json!(metric_set));
+        envelope.insert("host".to_string(), json!(self.host));
+
+        if let Some(ts) = self.ts_type.format(time) {
+            envelope.insert(self.ts_field.clone(), ts);
+        }
+
+        envelope.extend(dimensions);
+
+        if self.long {
+            values.into_iter().map(|(metric, value)| {
+                let mut event = envelope.clone();
+
+                event.insert("metric".to_string(), json!(metric));
+                event.insert("value".to_string(), value);
+
+                JsonValue::Object(event)
+            }).collect()
+        } else {
+            envelope.extend(values);
+
+            vec![JsonValue::Object(envelope)]
+        }
+    }
+}
+
+/// Sends the events of the metric sets
+#[derive(Clone)]
+struct Emitter {
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    envelope: Arc<Envelope>,
+    callback: Arc<Callback>,
+}
+
+impl Emitter {
+    async fn send(&self, metric_set: &str, time: &DateTime<Utc>, dimensions: Map<String, JsonValue>, values: Map<String, JsonValue>) -> anyhow::Result<()> {
+        for event in self.envelope.events(metric_set, time, dimensions, values) {
+            let permit = match self.semaphore.clone().acquire_owned().await {
+                Ok(p) => p,
+                Err(_) => return Ok( () )
+            };
+
+            if let Err(e) = self.sender.send((Event::Json(event), Arc::new(permit), self.callback.clone())) {
+                bail!("Error sending event: {:?}", e);
+            }
+        }
+
+        Ok( () )
+    }
+
+    fn counters(&self) -> Counters {
+        Counters::new(self.envelope.counters)
+    }
+}
 
 pub struct Metrics {
     sender: Sender<ChannelType>,
     semaphore: Arc<Semaphore>,
     tripwire: Tripwire,
+    envelope: Arc<Envelope>,
     metrics: HashSet<String>,
     cpu_interval: u64,
     mem_interval: u64,
     disk_interval: u64,
-    net_interval: u64
+    net_interval: u64,
+    process_interval: u64,
+    process_selector: ProcessSelector,
+    load_interval: u64,
+    fs_interval: u64,
+    tcp_interval: u64,
 }
 
 impl Metrics {
-    async fn send_event(event: Event, semaphore: Arc<Semaphore>, sender: Sender<ChannelType>, cb: Arc<Callback>) -> anyhow::Result<()> {
-        let permit = match semaphore.clone().acquire_owned().await {
-            Ok(p) => p,
-            Err(_) => return Ok( () )
+    /// Waits for the rest of the poll interval, returning true if the tripwire has tripped
+    async fn wait(tripwire: &Tripwire, start: Instant, interval_duration: Duration, kind: &str) -> bool {
+        let event_duration = Instant::now().duration_since(start);
+
+        let wait_duration = if event_duration > interval_duration {
+            warn!("Took longer to collect {} metrics than the poll interval: {}s > {}s", kind, event_duration.as_secs(), interval_duration.as_secs());
+
+            // we took longer to record the metrics than we are supposed to poll!!!
+            // so just wait a *very* short time to see if the tripwire has tripped
+            Duration::from_micros(1)
+        } else {
+            // otherwise, compute how long we should wait, given how long it too to gather the info
+            interval_duration - event_duration
         };
-        if let Err(e) = sender.send((event, Arc::new(permit), cb)) {
-            bail!("Error sending event: {:?}", e);
-        }
 
-        Ok( () )
+        tokio::time::timeout(wait_duration, tripwire.clone()).await.is_ok()
     }
 
-    async fn cpu(tripwire: Tripwire, sender: Sender<ChannelType>, semaphore: Arc<Semaphore>, interval: u64) -> anyhow::Result<()> {
-        let no_op_callback = Arc::new(Callback::empty());
+    async fn cpu(tripwire: Tripwire, emitter: Emitter, interval: u64) -> anyhow::Result<()> {
         let interval_duration = Duration::from_secs(interval);
+        let mut counters = emitter.counters();
+        let mut previous_times = None;
 
         loop {
             let start = Instant::now();
+            let time = Utc::now();
 
             let cpu_time_stream = times().await?;
             pin_mut!(cpu_time_stream);
@@ -71,46 +461,39 @@ impl Metrics {
                 .enumerate()
                 .take_until_if(tripwire.clone()); // to short-circuit if we've been cancelled
 
-            let mut event_map = Map::<String, JsonValue>::new();
+            let mut values = Map::<String, JsonValue>::new();
+            let (mut busy, mut idle) = (0.0, 0.0);
 
             while let Some((i, cpu_time)) = cpu_time_stream.next().await {
                 let cpu_time = cpu_time?;
 
-                event_map.insert(format!("cpu{}.system", i), json!(cpu_time.system().get::<second>()));
-                event_map.insert(format!("cpu{}.user", i), json!(cpu_time.user().get::<second>()));
-                event_map.insert(format!("cpu{}.idle", i), json!(cpu_time.idle().get::<second>()));
-            }
-
-            let stats1 = stats().await?;
-            let usage1 = usage().await?;
-            tokio::time::sleep(Duration::from_secs(1)).await;
-            let stats2 = stats().await?;
-            let usage2 = usage().await?;
+                values.insert(format!("cpu{}.system", i), json!(cpu_time.system().get::<second>()));
+                values.insert(format!("cpu{}.user", i), json!(cpu_time.user().get::<second>()));
+                values.insert(format!("cpu{}.idle", i), json!(cpu_time.idle().get::<second>()));
 
-            event_map.insert("ctx_switch_per_sec".to_string(), json!(stats2.ctx_switches() - stats1.ctx_switches()));
-            event_map.insert("int_per_sec".to_string(), json!(stats2.interrupts() - stats1.interrupts()));
+                busy += cpu_time.system().get::<second>() + cpu_time.user().get::<second>();
+                idle += cpu_time.idle().get::<second>();
+            }
 
-            event_map.insert("load_percent".to_string(), json!((usage2 - usage1).get::<ratio::percent>()));
+            // the share of the time since the last poll spent in user or system
+            if let Some((previous_busy, previous_idle)) = previous_times.replace((busy, idle)) {
+                let total = (busy - previous_busy) + (idle - previous_idle);
 
-            // send the event
-            Metrics::send_event(Event::Json(json!(event_map)), semaphore.clone(), sender.clone(), no_op_callback.clone()).await?;
-
-            let event_duration = Instant::now().duration_since(start);
+                if total > 0.0 {
+                    values.insert("load_percent".to_string(), json!(100.0 * (busy - previous_busy) / total));
+                }
+            }
 
-            let wait_duration = if event_duration > interval_duration {
-                warn!("Took longer to collect CPU metrics than the poll interval: {}s > {}s", event_duration.as_secs(), interval_duration.as_secs());
+            let stats = stats().await?;
+            let now = Instant::now();
 
-                // we took longer to record the metrics than we are supposed to poll!!!
-                // so just wait a *very* short time to see if the tripwire has tripped
-                Duration::from_micros(1)
-            } else {
-                // otherwise, compute how long we should wait, given how long it too to gather the info
-                interval_duration - event_duration
-            };
+            counters.insert(&mut values, "cpu", "ctx_switch_per_sec", "ctx_switches", stats.ctx_switches(), now);
+            counters.insert(&mut values, "cpu", "int_per_sec", "interrupts", stats.interrupts(), now);
 
-            let ret = tokio::time::timeout(wait_duration, tripwire.clone()).await;
+            // send the event
+            emitter.send("cpu", &time, Map::new(), values).await?;
 
-            if ret.is_ok() {
+            if Metrics::wait(&tripwire, start, interval_duration, "CPU").await {
                 // tripwire has tripped, so just return
                 debug!("CPU metrics finished");
                 return Ok( () )
@@ -118,43 +501,32 @@ impl Metrics {
         }
     }
 
-    async fn mem(tripwire: Tripwire, sender: Sender<ChannelType>, semaphore: Arc<Semaphore>, interval: u64) -> anyhow::Result<()> {
-        let no_op_callback = Arc::new(Callback::empty());
+    async fn mem(tripwire: Tripwire, emitter: Emitter, interval: u64) -> anyhow::Result<()> {
         let interval_duration = Duration::from_secs(interval);
 
         loop {
             let start = Instant::now();
-            let mut event_map = Map::<String, JsonValue>::new();
+            let time = Utc::now();
+            let mut values = Map::<String, JsonValue>::new();
 
             let memory = memory().await?;
             let swap = swap().await?;
 
-            //TODO: consider adding more
-            event_map.insert("memory.free_bytes".to_string(), json!(memory.free().get::<byte>()));
-            event_map.insert("memory.used_bytes".to_string(), json!(memory.used().get::<byte>()));
+            values.insert("memory.total_bytes".to_string(), json!(memory.total().get::<byte>()));
+            values.insert("memory.free_bytes".to_string(), json!(memory.free().get::<byte>()));
+            values.insert("memory.used_bytes".to_string(), json!(memory.used().get::<byte>()));
+            values.insert("memory.available_bytes".to_string(), json!(memory.available().get::<byte>()));
+            values.insert("memory.cached_bytes".to_string(), json!(memory.cached().get::<byte>()));
+            values.insert("memory.buffers_bytes".to_string(), json!(memory.buffers().get::<byte>()));
+            values.insert("memory.shared_bytes".to_string(), json!(memory.shared().get::<byte>()));
 
-            event_map.insert("swap.free_bytes".to_string(), json!(swap.free().get::<byte>()));
-            event_map.insert("swap.used_bytes".to_string(), json!(swap.used().get::<byte>()));
+            values.insert("swap.free_bytes".to_string(), json!(swap.free().get::<byte>()));
+            values.insert("swap.used_bytes".to_string(), json!(swap.used().get::<byte>()));
 
             // send the event
-            Metrics::send_event(Event::Json(json!(event_map)), semaphore.clone(), sender.clone(), no_op_callback.clone()).await?;
-
-            let event_duration = Instant::now().duration_since(start);
-
-            let wait_duration = if event_duration > interval_duration {
-                warn!("Took longer to collect CPU metrics than the poll interval: {}s > {}s", event_duration.as_secs(), interval_duration.as_secs());
+            emitter.send("memory", &time, Map::new(), values).await?;
 
-                // we took longer to record the metrics than we are supposed to poll!!!
-                // so just wait a *very* short time to see if the tripwire has tripped
-                Duration::from_micros(1)
-            } else {
-                // otherwise, compute how long we should wait, given how long it too to gather the info
-                interval_duration - event_duration
-            };
-
-            let ret = tokio::time::timeout(wait_duration, tripwire.clone()).await;
-
-            if ret.is_ok() {
+            if Metrics::wait(&tripwire, start, interval_duration, "Memory").await {
                 // tripwire has tripped, so just return
                 debug!("Memory metrics finished");
                 return Ok( () )
@@ -162,15 +534,13 @@ impl Metrics {
         }
     }
 
-    async fn disk(tripwire: Tripwire, sender: Sender<ChannelType>, semaphore: Arc<Semaphore>, interval: u64) -> anyhow::Result<()> {
-        let no_op_callback = Arc::new(Callback::empty());
+    async fn disk(tripwire: Tripwire, emitter: Emitter, interval: u64) -> anyhow::Result<()> {
         let interval_duration = Duration::from_secs(interval);
+        let mut counters = emitter.counters();
 
         loop {
             let start = Instant::now();
-            let mut event_map = Map::<String, JsonValue>::new();
-
-            let mut disk_counter_values = BTreeMap::new();
+            let time = Utc::now();
 
             let disk_counters = io_counters().await?.take_until_if(tripwire.clone());
 
@@ -183,44 +553,19 @@ impl Metrics {
                     None => continue
                 }.to_string();
 
-                disk_counter_values.insert(device_name, vec![
-                    counter.read_count(),
-                    counter.write_count(),
-                    counter.read_bytes().get::<byte>(),
-                    counter.write_bytes().get::<byte>(),
-                ]
-                );
-            }
-
-            // now sleep for a second
-            tokio::time::sleep(Duration::from_secs(1)).await;
-
-            let disk_counters = io_counters().await?.take_until_if(tripwire.clone());
-
-            futures::pin_mut!(disk_counters);
-
-            while let Some(counter) = disk_counters.next().await {
-                let counter = counter?;
-                let device_name = match counter.device_name().to_str() {
-                    Some(s) => s,
-                    None => continue
-                }.to_string();
-
-                let values = match disk_counter_values.get(&device_name) {
-                    Some(v) => v,
-                    None => continue
-                };
+                let now = Instant::now();
+                let mut values = Map::<String, JsonValue>::new();
 
-                event_map.clear();
-                event_map.insert("device".to_string(), json!(device_name));
+                counters.insert(&mut values, &device_name, "reads_sec", "reads", counter.read_count(), now);
+                counters.insert(&mut values, &device_name, "writes_sec", "writes", counter.write_count(), now);
+                counters.insert(&mut values, &device_name, "bytes_read_sec", "bytes_read", counter.read_bytes().get::<byte>(), now);
+                counters.insert(&mut values, &device_name, "bytes_written_sec", "bytes_written", counter.write_bytes().get::<byte>(), now);
 
-                event_map.insert("reads_sec".to_string(), json!(counter.read_count() - values[0]));
-                event_map.insert("writes_sec".to_string(), json!(counter.write_count() - values[1]));
-                event_map.insert("bytes_read_sec".to_string(), json!(counter.read_bytes().get::<byte>() - values[2]));
-                event_map.insert("bytes_written_sec".to_string(), json!(counter.write_bytes().get::<byte>() - values[3]));
+                let mut dimensions = Map::new();
+                dimensions.insert("device".to_string(), json!(device_name));
 
                 // send the event
-                Metrics::send_event(Event::Json(json!(event_map)), semaphore.clone(), sender.clone(), no_op_callback.clone()).await?;
+                emitter.send("disk", &time, dimensions, values).await?;
             }
 
             let partition_stream = partitions().await?.take_until_if(tripwire.clone());
@@ -235,32 +580,18 @@ impl Metrics {
                     Err(_) => continue
                 };
 
-                event_map.clear();
-                event_map.insert("mount_point".to_string(), json!(mount_point));
+                let mut dimensions = Map::new();
+                dimensions.insert("mount_point".to_string(), json!(mount_point));
 
-                event_map.insert("free_bytes".to_string(), json!(usage.free().get::<byte>()));
-                event_map.insert("used_bytes".to_string(), json!(usage.used().get::<byte>()));
+                let mut values = Map::new();
+                values.insert("free_bytes".to_string(), json!(usage.free().get::<byte>()));
+                values.insert("used_bytes".to_string(), json!(usage.used().get::<byte>()));
 
                 // send the event
-                Metrics::send_event(Event::Json(json!(event_map)), semaphore.clone(), sender.clone(), no_op_callback.clone()).await?;
+                emitter.send("disk", &time, dimensions, values).await?;
             }
 
-            let event_duration = Instant::now().duration_since(start);
-
-            let wait_duration = if event_duration > interval_duration {
-                warn!("Took longer to collect CPU metrics than the poll interval: {}s > {}s", event_duration.as_secs(), interval_duration.as_secs());
-
-                // we took longer to record the metrics than we are supposed to poll!!!
-                // so just wait a *very* short time to see if the tripwire has tripped
-                Duration::from_micros(1)
-            } else {
-                // otherwise, compute how long we should wait, given how long it too to gather the info
-                interval_duration - event_duration
-            };
-
-            let ret = tokio::time::timeout(wait_duration, tripwire.clone()).await;
-
-            if ret.is_ok() {
+            if Metrics::wait(&tripwire, start, interval_duration, "Disk").await {
                 // tripwire has tripped, so just return
                 debug!("Disk metrics finished");
                 return Ok( () )
@@ -268,15 +599,14 @@ impl Metrics {
         }
     }
 
-    async fn net(tripwire: Tripwire, sender: Sender<ChannelType>, semaphore: Arc<Semaphore>, interval: u64) -> anyhow::Result<()> {
-        let no_op_callback = Arc::new(Callback::empty());
+    async fn net(tripwire: Tripwire, emitter: Emitter, interval: u64) -> anyhow::Result<()> {
         let interval_duration = Duration::from_secs(interval);
+        let mut counters = emitter.counters();
 
         loop {
             let start = Instant::now();
-            let mut event_map = Map::<String, JsonValue>::new();
+            let time = Utc::now();
 
-            let mut net_counter_values = BTreeMap::new();
             let net_counters = heim::net::io_counters().await?.take_until_if(tripwire.clone());
 
             futures::pin_mut!(net_counters);
@@ -285,67 +615,220 @@ impl Metrics {
                 let counter = counter?;
                 let interface = counter.interface().to_string();
 
-                net_counter_values.insert(interface, vec![
-                    counter.bytes_sent().get::<byte>(),
-                    counter.bytes_recv().get::<byte>(),
-                    counter.packets_sent(),
-                    counter.packets_recv(),
-                    counter.errors_sent(),
-                    counter.errors_recv(),
-                    counter.drop_sent(),
-                    counter.drop_recv(),
-                ]);
+                let now = Instant::now();
+                let mut values = Map::<String, JsonValue>::new();
+
+                counters.insert(&mut values, &interface, "bytes_sent_sec", "bytes_sent", counter.bytes_sent().get::<byte>(), now);
+                counters.insert(&mut values, &interface, "bytes_recv_sec", "bytes_recv", counter.bytes_recv().get::<byte>(), now);
+                counters.insert(&mut values, &interface, "packets_sent_sec", "packets_sent", counter.packets_sent(), now);
+                counters.insert(&mut values, &interface, "packets_recv_sec", "packets_recv", counter.packets_recv(), now);
+                counters.insert(&mut values, &interface, "errors_sent_sec", "errors_sent", counter.errors_sent(), now);
+                counters.insert(&mut values, &interface, "errors_recv_sec", "errors_recv", counter.errors_recv(), now);
+                counters.insert(&mut values, &interface, "drop_sent_sec", "drop_sent", counter.drop_sent(), now);
+                counters.insert(&mut values, &interface, "drop_recv_sec", "drop_recv", counter.drop_recv(), now);
+
+                let mut dimensions = Map::new();
+                dimensions.insert("interface".to_string(), json!(interface));
+
+                // send the event
+                emitter.send("net", &time, dimensions, values).await?;
             }
 
-            // now sleep for a second
-            tokio::time::sleep(Duration::from_secs(1)).await;
+            if Metrics::wait(&tripwire, start, interval_duration, "Net").await {
+                // tripwire has tripped, so just return
+                debug!("Net metrics finished");
+                return Ok( () )
+            } // otherwise, we just loop again
+        }
+    }
 
-            let net_counters = heim::net::io_counters().await?.take_until_if(tripwire.clone());
+    async fn process(tripwire: Tripwire, emitter: Emitter, interval: u64, selector: ProcessSelector) -> anyhow::Result<()> {
+        let interval_duration = Duration::from_secs(interval);
+        let mut counters = emitter.counters();
 
-            futures::pin_mut!(net_counters);
+        // the CPU time of each process at the last poll; always a rate, regardless of the counter mode
+        let mut cpu_times = Counters::new(CounterMode::Rate);
 
-            while let Some(counter) = net_counters.next().await {
-                let counter = counter?;
-                let interface = counter.interface().to_string();
-                let values = match net_counter_values.get(&interface) {
-                    Some(v) => v,
-                    None => continue
+        loop {
+            let start = Instant::now();
+            let time = Utc::now();
+
+            // processes come and go, so any that error are skipped
+            let mut samples = vec![];
+            let processes = heim::process::processes().await?.take_until_if(tripwire.clone());
+
+            futures::pin_mut!(processes);
+
+            while let Some(process) = processes.next().await {
+                let process = match process {
+                    Ok(p) => p,
+                    Err(_) => continue
+                };
+
+                let name = match process.name().await {
+                    Ok(n) if selector.includes(&n) => n,
+                    _ => continue
+                };
+
+                let (cpu_time, memory) = match (process.cpu_time().await, process.memory().await) {
+                    (Ok(c), Ok(m)) => (c, m),
+                    _ => continue
+                };
+
+                let pid = process.pid();
+                let now = Instant::now();
+
+                // CPU time is tracked in microseconds, so the rate is the share of a single CPU
+                let cpu_micros = ((cpu_time.user() + cpu_time.system()).get::<second>() * 1_000_000.0) as u64;
+                let cpu_percent = cpu_times.value(pid.to_string().as_str(), cpu_micros, now).map(|r| r / 10_000.0);
+
+                let (bytes_read, bytes_written) = match process.io_counters().await {
+                    Ok(io) => (
+                        counters.value(format!("{}/bytes_read", pid).as_str(), io.bytes_read().get::<byte>(), now),
+                        counters.value(format!("{}/bytes_written", pid).as_str(), io.bytes_written().get::<byte>(), now),
+                    ),
+                    Err(_) => (None, None)
                 };
 
-                event_map.clear();
-                event_map.insert("interface".to_string(), json!(interface));
+                samples.push(ProcessSample {
+                    pid: Some(pid),
+                    name,
+                    count: 1,
+                    cpu_percent,
+                    rss_bytes: memory.rss().get::<byte>(),
+                    open_fds: process_fds(pid),
+                    threads: process_threads(pid),
+                    bytes_read,
+                    bytes_written,
+                });
+            }
 
-                event_map.insert("bytes_sent_sec".to_string(), json!(counter.bytes_sent().get::<byte>() - values[0]));
-                event_map.insert("bytes_recv_sec".to_string(), json!(counter.bytes_recv().get::<byte>() - values[1]));
-                event_map.insert("packets_sent_sec".to_string(), json!(counter.packets_sent() - values[2]));
-                event_map.insert("packets_recv_sec".to_string(), json!(counter.packets_recv() - values[3]));
-                event_map.insert("errors_sent_sec".to_string(), json!(counter.errors_sent() - values[4]));
-                event_map.insert("errors_recv_sec".to_string(), json!(counter.errors_recv() - values[5]));
-                event_map.insert("drop_sent_sec".to_string(), json!(counter.drop_sent() - values[6]));
-                event_map.insert("drop_recv_sec".to_string(), json!(counter.drop_recv() - values[7]));
+            // forget the processes that have exited
+            cpu_times.retain_sampled(start);
+            counters.retain_sampled(start);
 
+            for sample in selector.select(samples) {
                 // send the event
-                Metrics::send_event(Event::Json(json!(event_map)), semaphore.clone(), sender.clone(), no_op_callback.clone()).await?;
+                emitter.send("process", &time, sample.dimensions(), sample.values(emitter.envelope.counters)).await?;
             }
 
-            let event_duration = Instant::now().duration_since(start);
+            if Metrics::wait(&tripwire, start, interval_duration, "Process").await {
+                // tripwire has tripped, so just return
+                debug!("Process metrics finished");
+                return Ok( () )
+            } // otherwise, we just loop again
+        }
+    }
+
+    async fn load(tripwire: Tripwire, emitter: Emitter, interval: u64) -> anyhow::Result<()> {
+        let interval_duration = Duration::from_secs(interval);
+
+        loop {
+            let start = Instant::now();
+            let time = Utc::now();
+            let mut values = Map::<String, JsonValue>::new();
 
-            let wait_duration = if event_duration > interval_duration {
-                warn!("Took longer to collect CPU metrics than the poll interval: {}s > {}s", event_duration.as_secs(), interval_duration.as_secs());
+            let (load1, load5, load15) = loadavg().await?;
 
-                // we took longer to record the metrics than we are supposed to poll!!!
-                // so just wait a *very* short time to see if the tripwire has tripped
-                Duration::from_micros(1)
-            } else {
-                // otherwise, compute how long we should wait, given how long it too to gather the info
-                interval_duration - event_duration
-            };
+            values.insert("load.1m".to_string(), json!(load1.get::<ratio::ratio>()));
+            values.insert("load.5m".to_string(), json!(load5.get::<ratio::ratio>()));
+            values.insert("load.15m".to_string(), json!(load15.get::<ratio::ratio>()));
 
-            let ret = tokio::time::timeout(wait_duration, tripwire.clone()).await;
+            // send the event
+            emitter.send("load", &time, Map::new(), values).await?;
 
-            if ret.is_ok() {
+            if Metrics::wait(&tripwire, start, interval_duration, "Load").await {
                 // tripwire has tripped, so just return
-                debug!("Net metrics finished");
+                debug!("Load metrics finished");
+                return Ok( () )
+            } // otherwise, we just loop again
+        }
+    }
+
+    async fn filesystem(tripwire: Tripwire, emitter: Emitter, interval: u64) -> anyhow::Result<()> {
+        let interval_duration = Duration::from_secs(interval);
+
+        loop {
+            let start = Instant::now();
+            let time = Utc::now();
+
+            let partition_stream = partitions().await?.take_until_if(tripwire.clone());
+
+            futures::pin_mut!(partition_stream);
+
+            while let Some(partition) = partition_stream.next().await {
+                let partition = partition?;
+                let usage = match partition.usage().await {
+                    Ok(u) => u,
+                    Err(_) => continue
+                };
+
+                // skip pseudo filesystems like proc and sysfs
+                if usage.total().get::<byte>() == 0 {
+                    continue
+                }
+
+                let mut dimensions = Map::new();
+                dimensions.insert("mount_point".to_string(), json!(partition.mount_point().display().to_string()));
+                dimensions.insert("file_system".to_string(), json!(partition.file_system().as_str()));
+
+                let mut values = Map::new();
+                values.insert("total_bytes".to_string(), json!(usage.total().get::<byte>()));
+                values.insert("free_bytes".to_string(), json!(usage.free().get::<byte>()));
+                values.insert("used_bytes".to_string(), json!(usage.used().get::<byte>()));
+
+                if let Some((total, free)) = inodes(partition.mount_point()) {
+                    values.insert("inodes_total".to_string(), json!(total));
+                    values.insert("inodes_free".to_string(), json!(free));
+                    values.insert("inodes_used".to_string(), json!(total.saturating_sub(free)));
+                }
+
+                // send the event
+                emitter.send("filesystem", &time, dimensions, values).await?;
+            }
+
+            if Metrics::wait(&tripwire, start, interval_duration, "Filesystem").await {
+                // tripwire has tripped, so just return
+                debug!("Filesystem metrics finished");
+                return Ok( () )
+            } // otherwise, we just loop again
+        }
+    }
+
+    async fn tcp(tripwire: Tripwire, emitter: Emitter, interval: u64) -> anyhow::Result<()> {
+        let interval_duration = Duration::from_secs(interval);
+
+        loop {
+            let start = Instant::now();
+            let time = Utc::now();
+            let mut values = Map::<String, JsonValue>::new();
+            let mut counts = BTreeMap::new();
+
+            // tcp6 is missing when IPv6 is disabled
+            for file in ["/proc/net/tcp", "/proc/net/tcp6"] {
+                if let Ok(contents) = tokio::fs::read_to_string(file).await {
+                    count_tcp_states(&contents, &mut counts);
+                }
+            }
+
+            for state in TCP_STATES.iter().skip(1) {
+                values.insert(format!("tcp.{}", state), json!(counts.get(state).copied().unwrap_or(0)));
+            }
+
+            values.insert("tcp.total".to_string(), json!(counts.values().sum::<u64>()));
+
+            for (name, file) in [("udp", "/proc/net/udp"), ("udp6", "/proc/net/udp6")] {
+                if let Ok(contents) = tokio::fs::read_to_string(file).await {
+                    values.insert(format!("{}.sockets", name), json!(contents.lines().skip(1).count()));
+                }
+            }
+
+            // send the event
+            emitter.send("tcp", &time, Map::new(), values).await?;
+
+            if Metrics::wait(&tripwire, start, interval_duration, "TCP").await {
+                // tripwire has tripped, so just return
+                debug!("TCP metrics finished");
                 return Ok( () )
             } // otherwise, we just loop again
         }
@@ -362,21 +845,21 @@ impl Plugin for Metrics {
         debug!("Metrics args: {:#?}", args);
 
         let default_metrics = vec!["cpu", "memory", "disk", "net"].into_iter().map(|s| Value::String(s.to_string())).collect::<Vec<_>>();
-        let metrics = args.get("metrics").cloned().unwrap_or_else(|| Value::Array(default_metrics.clone()));
+        let metrics = args.get("metrics").cloned().unwrap_or_else(|| Value::Array(default_metrics));
         let mut metric_set = HashSet::new();
 
         match metrics {
             Value::String(ref s) => {
-                if !default_metrics.contains(&metrics) {
-                    bail!("Unknown metric {} for plugin '{}'; available metrics: cpu, memory, disk, net", s, Self::name());
+                if !AVAILABLE_METRICS.contains(&s.as_str()) {
+                    bail!("Unknown metric {} for plugin '{}'; available metrics: {}", s, Self::name(), AVAILABLE_METRICS.join(", "));
                 }
                 metric_set.insert(s.to_owned());
             },
             Value::Array(a) => {
                 for m in a.into_iter() {
                     if let Some(s) = m.as_str() {
-                        if !default_metrics.contains(&m) {
-                            bail!("Unknown metric {} for plugin '{}'; available metrics: cpu, memory, disk, net", s, Self::name());
+                        if !AVAILABLE_METRICS.contains(&s) {
+                            bail!("Unknown metric {} for plugin '{}'; available metrics: {}", s, Self::name(), AVAILABLE_METRICS.join(", "));
                         }
                         metric_set.insert(s.to_string());
                     } else {
@@ -415,6 +898,38 @@ impl Plugin for Metrics {
             bail!("Nonsensical value {} for net_poll_secs for plugin '{}'; should be between 5 and 3600 seconds", net_interval, Self::name());
         }
 
+        let process_interval = args.get("process_poll_secs").unwrap_or(&Value::Integer(30));
+        let process_interval = process_interval.as_integer().ok_or_else(|| anyhow!("Parameter 'process_poll_secs' must be an integer for plugin '{}'", Self::name()))?;
+
+        if !(5..=3600).contains(&process_interval) {
+            bail!("Nonsensical value {} for process_poll_secs for plugin '{}'; should be between 5 and 3600 seconds", process_interval, Self::name());
+        }
+
+        let process_selector = ProcessSelector::from_args(&args, Self::name())?;
+
+        let load_interval = args.get("load_poll_secs").unwrap_or(&Value::Integer(5));
+        let load_interval = load_interval.as_integer().ok_or_else(|| anyhow!("Parameter 'load_poll_secs' must be an integer for plugin '{}'", Self::name()))?;
+
+        if !(5..=3600).contains(&load_interval) {
+            bail!("Nonsensical value {} for load_poll_secs for plugin '{}'; should be between 5 and 3600 seconds", load_interval, Self::name());
+        }
+
+        let fs_interval = args.get("fs_poll_secs").unwrap_or(&Value::Integer(60));
+        let fs_interval = fs_interval.as_integer().ok_or_else(|| anyhow!("Parameter 'fs_poll_secs' must be an integer for plugin '{}'", Self::name()))?;
+
+        if !(5..=3600).contains(&fs_interval) {
+            bail!("Nonsensical value {} for fs_poll_secs for plugin '{}'; should be between 5 and 3600 seconds", fs_interval, Self::name());
+        }
+
+        let tcp_interval = args.get("tcp_poll_secs").unwrap_or(&Value::Integer(5));
+        let tcp_interval = tcp_interval.as_integer().ok_or_else(|| anyhow!("Parameter 'tcp_poll_secs' must be an integer for plugin '{}'", Self::name()))?;
+
+        if !(5..=3600).contains(&tcp_interval) {
+            bail!("Nonsensical value {} for tcp_poll_secs for plugin '{}'; should be between 5 and 3600 seconds", tcp_interval, Self::name());
+        }
+
+        let envelope = Arc::new(Envelope::from_args(&args, Self::name())?);
+
         // setup the channel
         let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
 
@@ -422,47 +937,76 @@ impl Plugin for Metrics {
             sender,
             semaphore,
             tripwire,
+            envelope,
             metrics: metric_set,
             cpu_interval: cpu_interval as u64,
             mem_interval: mem_interval as u64,
             disk_interval: disk_interval as u64,
-            net_interval: net_interval as u64
+            net_interval: net_interval as u64,
+            process_interval: process_interval as u64,
+            process_selector,
+            load_interval: load_interval as u64,
+            fs_interval: fs_interval as u64,
+            tcp_interval: tcp_interval as u64,
         }))
     }
 
     async fn run(&mut self) {
         let mut tasks = JoinSet::new();
 
+        let emitter = Emitter {
+            sender: self.sender.clone(),
+            semaphore: self.semaphore.clone(),
+            envelope: self.envelope.clone(),
+            callback: Arc::new(Callback::empty()),
+        };
+
         if self.metrics.contains("cpu") {
             let tripwire = self.tripwire.clone();
-            let sender = self.sender.clone();
-            let semaphore = self.semaphore.clone();
 
-            tasks.spawn(Metrics::cpu(tripwire, sender, semaphore, self.cpu_interval));
+            tasks.spawn(Metrics::cpu(tripwire, emitter.clone(), self.cpu_interval));
         }
 
         if self.metrics.contains("memory") {
             let tripwire = self.tripwire.clone();
-            let sender = self.sender.clone();
-            let semaphore = self.semaphore.clone();
 
-            tasks.spawn(Metrics::mem(tripwire, sender, semaphore, self.mem_interval));
+            tasks.spawn(Metrics::mem(tripwire, emitter.clone(), self.mem_interval));
         }
 
         if self.metrics.contains("disk") {
             let tripwire = self.tripwire.clone();
-            let sender = self.sender.clone();
-            let semaphore = self.semaphore.clone();
 
-            tasks.spawn(Metrics::disk(tripwire, sender, semaphore, self.disk_interval));
+            tasks.spawn(Metrics::disk(tripwire, emitter.clone(), self.disk_interval));
         }
 
         if self.metrics.contains("net") {
             let tripwire = self.tripwire.clone();
-            let sender = self.sender.clone();
-            let semaphore = self.semaphore.clone();
 
-            tasks.spawn(Metrics::net(tripwire, sender, semaphore, self.net_interval));
+            tasks.spawn(Metrics::net(tripwire, emitter.clone(), self.net_interval));
+        }
+
+        if self.metrics.contains("process") {
+            let tripwire = self.tripwire.clone();
+
+            tasks.spawn(Metrics::process(tripwire, emitter.clone(), self.process_interval, self.process_selector.clone()));
+        }
+
+        if self.metrics.contains("load") {
+            let tripwire = self.tripwire.clone();
+
+            tasks.spawn(Metrics::load(tripwire, emitter.clone(), self.load_interval));
+        }
+
+        if self.metrics.contains("filesystem") {
+            let tripwire = self.tripwire.clone();
+
+            tasks.spawn(Metrics::filesystem(tripwire, emitter.clone(), self.fs_interval));
+        }
+
+        if self.metrics.contains("tcp") {
+            let tripwire = self.tripwire.clone();
+
+            tasks.spawn(Metrics::tcp(tripwire, emitter.clone(), self.tcp_interval));
         }
 
         // loop through all the tasks waiting for them to finish
@@ -491,7 +1035,13 @@ mod metrics_tests {
     use toml::Value;
     use crate::common::init_test_logger;
     use crate::plugin::{Args, Plugin};
-    use crate::plugins::metrics::Metrics;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use serde_json::{Map, json};
+    use tokio::time::{Duration, Instant};
+
+    use crate::plugins::metrics::{count_tcp_states, inodes, CounterMode, Counters, Envelope, Metrics, ProcessSample, ProcessSelector};
 
     #[tokio::test]
     async fn cpu() {
@@ -522,4 +1072,175 @@ mod metrics_tests {
         //
         // println!("EVENT: {:?}", event);
     }
-}
\ No newline at end of file
+
+    fn sample(pid: i32, name: &str, cpu_percent: f64, rss_bytes: u64) -> ProcessSample {
+        ProcessSample {
+            pid: Some(pid),
+            name: name.to_string(),
+            count: 1,
+            cpu_percent: Some(cpu_percent),
+            rss_bytes,
+            open_fds: Some(10),
+            threads: Some(2),
+            bytes_read: Some(100.0),
+            bytes_written: Some(50.0),
+        }
+    }
+
+    #[test]
+    fn process_selection() {
+        let samples = vec![
+            sample(1, "systemd", 0.1, 12_000),
+            sample(200, "postgres", 30.0, 500_000),
+            sample(201, "postgres", 25.0, 400_000),
+            sample(300, "nginx", 40.0, 20_000),
+        ];
+
+        // top 2 by CPU, per PID
+        let args: Args = toml::from_str("process_top = 2").unwrap();
+        let selector = ProcessSelector::from_args(&args, "metrics").unwrap();
+        let selected = selector.select(samples.clone());
+
+        assert_eq!(vec![Some(300), Some(200)], selected.iter().map(|s| s.pid).collect::<Vec<_>>());
+
+        // grouped by name, and sorted by memory
+        let args: Args = toml::from_str(r#"
+            process_group_by = "name"
+            process_sort = "memory"
+            process_top = 0
+        "#).unwrap();
+        let selector = ProcessSelector::from_args(&args, "metrics").unwrap();
+        let selected = selector.select(samples);
+
+        assert_eq!(3, selected.len());
+        assert_eq!(ProcessSample {
+            pid: None,
+            name: "postgres".to_string(),
+            count: 2,
+            cpu_percent: Some(55.0),
+            rss_bytes: 900_000,
+            open_fds: Some(20),
+            threads: Some(4),
+            bytes_read: Some(200.0),
+            bytes_written: Some(100.0),
+        }, selected[0]);
+        assert_eq!(json!(2), selected[0].dimensions()["count"]);
+        assert_eq!(json!(200.0), selected[0].values(CounterMode::Rate)["bytes_read_sec"]);
+        assert_eq!(json!(200), selected[0].values(CounterMode::Cumulative)["bytes_read"]);
+
+        let args: Args = toml::from_str(r#"process_include = "^(nginx|postgres)$""#).unwrap();
+        let selector = ProcessSelector::from_args(&args, "metrics").unwrap();
+
+        assert!(selector.includes("nginx"));
+        assert!(!selector.includes("systemd"));
+
+        for bad in [r#"process_include = "(""#, r#"process_sort = "io""#, r#"process_group_by = "user""#, "process_top = -1"] {
+            let args: Args = toml::from_str(bad).unwrap();
+            assert!(ProcessSelector::from_args(&args, "metrics").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn envelope() {
+        let args: Args = toml::from_str(r#"
+            host = "web-1"
+            ts_type = "epoch_s"
+        "#).unwrap();
+        let envelope = Envelope::from_args(&args, "metrics").unwrap();
+        let time = chrono::DateTime::parse_from_rfc3339("2023-01-02T03:04:05Z").unwrap().into();
+
+        let mut dimensions = Map::new();
+        dimensions.insert("device".to_string(), json!("sda"));
+
+        let mut values = Map::new();
+        values.insert("reads_sec".to_string(), json!(1.5));
+        values.insert("writes_sec".to_string(), json!(2.0));
+
+        assert_eq!(vec![json!({
+            "metric_set": "disk",
+            "host": "web-1",
+            "t": 1672628645,
+            "device": "sda",
+            "reads_sec": 1.5,
+            "writes_sec": 2.0,
+        })], envelope.events("disk", &time, dimensions.clone(), values.clone()));
+
+        // no values, no events
+        assert!(envelope.events("disk", &time, dimensions.clone(), Map::new()).is_empty());
+
+        let args: Args = toml::from_str(r#"
+            host = "web-1"
+            ts_field = "time"
+            ts_type = "epoch_s"
+            format = "long"
+        "#).unwrap();
+        let envelope = Envelope::from_args(&args, "metrics").unwrap();
+
+        assert_eq!(vec![
+            json!({ "metric_set": "disk", "host": "web-1", "time": 1672628645, "device": "sda", "metric": "reads_sec", "value": 1.5 }),
+            json!({ "metric_set": "disk", "host": "web-1", "time": 1672628645, "device": "sda", "metric": "writes_sec", "value": 2.0 }),
+        ], envelope.events("disk", &time, dimensions, values));
+
+        // set the host, so only the arg being tested can be the problem
+        for bad in ["host = \"web-1\"\nformat = \"tall\"", "host = \"web-1\"\ncounters = \"delta\"", "host = 7"] {
+            let args: Args = toml::from_str(bad).unwrap();
+            assert!(Envelope::from_args(&args, "metrics").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn counters() {
+        let start = Instant::now();
+        let mut counters = Counters::new(CounterMode::Rate);
+        let mut values = Map::new();
+
+        // no rate on the first sample
+        counters.insert(&mut values, "sda", "reads_sec", "reads", 100, start);
+        assert!(values.is_empty());
+
+        // the rate uses the real time between the samples
+        counters.insert(&mut values, "sda", "reads_sec", "reads", 400, start + Duration::from_secs(2));
+        assert_eq!(json!(150.0), values["reads_sec"]);
+
+        // a reset counter has no rate
+        assert_eq!(None, counters.value("sda/reads", 10, start + Duration::from_secs(3)));
+
+        // counters that aren't sampled are forgotten
+        counters.value("sdb/reads", 10, start + Duration::from_secs(3));
+        counters.retain_sampled(start + Duration::from_secs(3));
+        counters.value("sdb/reads", 20, start + Duration::from_secs(4));
+        counters.retain_sampled(start + Duration::from_secs(4));
+        assert_eq!(None, counters.value("sda/reads", 20, start + Duration::from_secs(5)));
+
+        let mut counters = Counters::new(CounterMode::Cumulative);
+        let mut values = Map::new();
+
+        counters.insert(&mut values, "sda", "reads_sec", "reads", 100, start);
+        assert_eq!(json!(100), values["reads"]);
+        assert!(!values.contains_key("reads_sec"));
+    }
+
+    #[test]
+    fn tcp_states() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 20422 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1538 00000000:0000 0A 00000000:00000000 00:00000000 00000000   114        0 23117 1 0000000000000000 100 0 0 10 0
+   2: 0A00000F:0016 0A000005:C5E2 01 00000000:00000000 02:000A7B2C 00000000     0        0 58219 2 0000000000000000 20 4 31 10 -1
+   3: 0A00000F:D3A8 5DB8D822:01BB 06 00000000:00000000 03:00000F3B 00000000     0        0 0 3 0000000000000000
+";
+        let mut counts = BTreeMap::new();
+
+        count_tcp_states(tcp, &mut counts);
+        count_tcp_states(tcp, &mut counts);
+
+        assert_eq!(BTreeMap::from([("established", 2), ("listen", 4), ("time_wait", 2)]), counts);
+    }
+
+    #[test]
+    fn root_inodes() {
+        let (total, free) = inodes(Path::new("/")).expect("Error getting inodes for /");
+
+        assert!(free <= total);
+        assert!(inodes(Path::new("/does/not/exist")).is_none());
+    }
+}
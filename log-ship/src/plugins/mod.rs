@@ -13,6 +13,16 @@ mod lumberjack;
 mod logfmt;
 mod udp_socket;
 mod fortinet;
+mod elasticsearch;
+mod kafka;
+mod log_ship;
+mod grok;
+mod web_server;
+mod parse_ts;
+mod fields;
+mod kv;
+mod coerce;
+mod prometheus_scrape;
 
 pub use file::{FileInput, FileOutput};
 pub use journald::JournaldInput;
@@ -29,6 +39,16 @@ pub use lumberjack::LumberjackInput;
 pub use crate::plugins::logfmt::LogFmtParser;
 pub use udp_socket::UdpSocketInput;
 pub use fortinet::FortinetParser;
+pub use elasticsearch::ElasticsearchOutput;
+pub use kafka::{KafkaInput, KafkaOutput};
+pub use log_ship::{LogShipInput, LogShipOutput};
+pub use grok::GrokTransform;
+pub use web_server::WebServerTransform;
+pub use parse_ts::ParseTimestampTransform;
+pub use fields::FieldsTransform;
+pub use kv::KvTransform;
+pub use coerce::CoerceTransform;
+pub use prometheus_scrape::PrometheusScrapeInput;
 
 
 // #[cfg(test)]
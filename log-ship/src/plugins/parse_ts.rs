@@ -0,0 +1,426 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Local, NaiveDateTime, TimeZone, Utc};
+use serde_json::Map;
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::common::logging::{debug, error, warn};
+use crate::event::{Event, JsonValue};
+use crate::{Args, Plugin, send_event, connect_receiver, create_event_stream, get_receiver, recv_event, create_sender_semaphore};
+use crate::plugin::{PluginType, ChannelType};
+use crate::plugins::grok::NoMatch;
+
+const DEFAULT_FORMATS: &[&str] = &["epoch", "rfc3339", "rfc2822", "syslog"];
+
+/// A format tried when parsing a timestamp
+#[derive(Debug, Clone, PartialEq)]
+enum TsFormat {
+    /// seconds, milliseconds, or nanoseconds; guessed from the size of the number
+    Epoch,
+    EpochSecs,
+    EpochMillis,
+    EpochNanos,
+    Rfc3339,
+    Rfc2822,
+    /// `Oct  3 08:28:06`, which has no year
+    Syslog,
+    /// a chrono format that includes the timezone
+    WithZone(String),
+    /// a chrono format without a timezone, so the configured one is used
+    Naive(String),
+}
+
+impl TsFormat {
+    fn parse(format: &str) -> Self {
+        match format {
+            "epoch" => TsFormat::Epoch,
+            "epoch_s" => TsFormat::EpochSecs,
+            "epoch_ms" => TsFormat::EpochMillis,
+            "epoch_ns" => TsFormat::EpochNanos,
+            "rfc3339" => TsFormat::Rfc3339,
+            "rfc2822" => TsFormat::Rfc2822,
+            "syslog" => TsFormat::Syslog,
+            f if f.contains("%z") || f.contains("%:z") || f.contains("%#z") || f.contains("%s") => TsFormat::WithZone(f.to_string()),
+            f => TsFormat::Naive(f.to_string())
+        }
+    }
+}
+
+/// The representation of the parsed timestamp
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TsOutput {
+    EpochSecs,
+    EpochMillis,
+    EpochNanos,
+    Rfc3339,
+    Rfc2822,
+}
+
+/// The timezone applied to timestamps that don't have one
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TsZone {
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl TsZone {
+    fn parse(timezone: &str) -> Result<Self> {
+        match timezone {
+            "local" => Ok(TsZone::Local),
+            "utc" | "UTC" => Ok(TsZone::Fixed(FixedOffset::east_opt(0).unwrap())),
+            offset => {
+                let dt = DateTime::parse_from_str(format!("2000-01-01 00:00:00 {}", offset).as_str(), "%Y-%m-%d %H:%M:%S %z")
+                    .map_err(|_| anyhow!("Unknown timezone '{}'; use local, utc, or an offset like +05:30", offset))?;
+
+                Ok(TsZone::Fixed(*dt.offset()))
+            }
+        }
+    }
+
+    fn to_utc(self, naive: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            TsZone::Local => Local.from_local_datetime(naive).earliest().map(|dt| dt.with_timezone(&Utc)),
+            TsZone::Fixed(offset) => offset.from_local_datetime(naive).single().map(|dt| dt.with_timezone(&Utc)),
+        }
+    }
+
+    fn current_year(self) -> i32 {
+        match self {
+            TsZone::Local => Local::now().year(),
+            TsZone::Fixed(offset) => Utc::now().with_timezone(&offset).year(),
+        }
+    }
+}
+
+/// Converts epoch seconds with a fractional part to a time
+fn from_epoch_secs(secs: f64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(secs.floor() as i64, ((secs - secs.floor()) * 1e9) as u32).single()
+}
+
+/// Converts epoch nanoseconds to a time, reading integers directly, as an f64 doesn't have enough precision
+fn from_epoch_nanos(value: &JsonValue, nanos: f64) -> Option<DateTime<Utc>> {
+    match value.as_i64().or_else(|| value.as_str().and_then(|s| s.trim().parse::<i64>().ok())) {
+        Some(nanos) => Some(Utc.timestamp_nanos(nanos)),
+        None if nanos.abs() < i64::MAX as f64 => Some(Utc.timestamp_nanos(nanos as i64)),
+        None => None
+    }
+}
+
+/// A list of formats, and the timezone for formats without one, used to parse timestamps
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimestampFormats {
+    formats: Vec<TsFormat>,
+    timezone: TsZone,
+}
+
+impl TimestampFormats {
+    /// Parses the `formats` and `timezone` args
+    pub fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        let formats = match args.get("formats") {
+            None => DEFAULT_FORMATS.iter().map(|f| TsFormat::parse(f)).collect(),
+            Some(Value::String(f)) => vec![TsFormat::parse(f)],
+            Some(Value::Array(a)) => {
+                a.iter()
+                 .map(|f| f.as_str().map(TsFormat::parse))
+                 .collect::<Option<Vec<_>>>()
+                 .ok_or_else(|| anyhow!("Found non-string format in 'formats' arg for {}", plugin_name))?
+            }
+            Some(_) => bail!("The 'formats' arg for {} must be a string or an array of strings", plugin_name)
+        };
+
+        if formats.is_empty() {
+            bail!("The 'formats' arg for {} must contain at least one format", plugin_name);
+        }
+
+        let timezone = args.get("timezone").unwrap_or(&Value::String("utc".to_string())).to_owned();
+        let timezone = timezone.as_str().ok_or_else(|| anyhow!("The 'timezone' arg for {} does not appear to be a string", plugin_name))?;
+        let timezone = TsZone::parse(timezone)?;
+
+        Ok(TimestampFormats { formats, timezone })
+    }
+
+    /// Parses the value with the first matching format
+    pub fn parse(&self, value: &JsonValue) -> Option<DateTime<Utc>> {
+        self.formats.iter().find_map(|format| self.parse_with(value, format))
+    }
+
+    /// Tries to parse the value with the format
+    fn parse_with(&self, value: &JsonValue, format: &TsFormat) -> Option<DateTime<Utc>> {
+        // numbers can be given as strings
+        let number = match value {
+            JsonValue::Number(n) => n.as_f64(),
+            JsonValue::String(s) => s.trim().parse::<f64>().ok().filter(|n| n.is_finite()),
+            _ => None
+        };
+
+        match (format, number) {
+            (TsFormat::Epoch, Some(n)) => {
+                if n.abs() < 1e11 {
+                    from_epoch_secs(n)
+                } else if n.abs() < 1e14 {
+                    Utc.timestamp_millis_opt(n as i64).single()
+                } else if n.abs() < 1e17 {
+                    from_epoch_secs(n / 1e6)
+                } else {
+                    from_epoch_nanos(value, n)
+                }
+            }
+            (TsFormat::EpochSecs, Some(n)) => from_epoch_secs(n),
+            (TsFormat::EpochMillis, Some(n)) => Utc.timestamp_millis_opt(n as i64).single(),
+            (TsFormat::EpochNanos, Some(n)) => from_epoch_nanos(value, n),
+            (_, _) => {
+                let s = value.as_str()?.trim();
+
+                match format {
+                    TsFormat::Rfc3339 => DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)),
+                    TsFormat::Rfc2822 => DateTime::parse_from_rfc2822(s).ok().map(|dt| dt.with_timezone(&Utc)),
+                    TsFormat::Syslog => {
+                        // guess the year; if the time would be in the future, it's from last year
+                        let year = self.timezone.current_year();
+                        let naive = NaiveDateTime::parse_from_str(format!("{} {}", year, s).as_str(), "%Y %b %e %H:%M:%S").ok()?;
+                        let dt = self.timezone.to_utc(&naive)?;
+
+                        if dt > Utc::now() + Duration::days(1) {
+                            self.timezone.to_utc(&naive.with_year(year - 1)?)
+                        } else {
+                            Some(dt)
+                        }
+                    }
+                    TsFormat::WithZone(f) => DateTime::parse_from_str(s, f).ok().map(|dt| dt.with_timezone(&Utc)),
+                    TsFormat::Naive(f) => self.timezone.to_utc(&NaiveDateTime::parse_from_str(s, f).ok()?),
+                    _ => None
+                }
+            }
+        }
+    }
+
+}
+
+impl TsOutput {
+    /// Parses the `ts_type` arg
+    pub fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        let output = args.get("ts_type").unwrap_or(&Value::String("epoch".to_string())).to_owned();
+
+        Ok(match output.as_str() {
+            Some("epoch") | Some("epoch_ms") => TsOutput::EpochMillis,
+            Some("epoch_s") => TsOutput::EpochSecs,
+            Some("epoch_ns") => TsOutput::EpochNanos,
+            Some("rfc3339") => TsOutput::Rfc3339,
+            Some("rfc2822") => TsOutput::Rfc2822,
+            _ => bail!("The 'ts_type' arg for {} must be one of: epoch, epoch_s, epoch_ns, rfc3339, rfc2822", plugin_name)
+        })
+    }
+
+    /// Formats the time; returns None for epoch nanoseconds outside of the years 1677 to 2262, which don't fit in an i64
+    pub fn format(&self, dt: &DateTime<Utc>) -> Option<JsonValue> {
+        Some(match self {
+            TsOutput::EpochSecs => JsonValue::from(dt.timestamp()),
+            TsOutput::EpochMillis => JsonValue::from(dt.timestamp_millis()),
+            TsOutput::EpochNanos => JsonValue::from(dt.timestamp().checked_mul(1_000_000_000)?.checked_add(dt.timestamp_subsec_nanos() as i64)?),
+            TsOutput::Rfc3339 => JsonValue::from(dt.to_rfc3339()),
+            TsOutput::Rfc2822 => JsonValue::from(dt.to_rfc2822()),
+        })
+    }
+}
+
+/// Parses the timestamp in a field
+#[derive(Debug)]
+struct TimestampParser {
+    field: String,
+    target_field: String,
+    formats: TimestampFormats,
+    output: TsOutput,
+    failure: NoMatch,
+}
+
+impl TimestampParser {
+    fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        let field = args.get("field").unwrap_or(&Value::String("t".to_string())).to_owned();
+        let field = field.as_str().ok_or_else(|| anyhow!("The 'field' arg for {} does not appear to be a string", plugin_name))?.to_string();
+
+        let target_field = match args.get("target_field") {
+            None => field.clone(),
+            Some(t) => t.as_str().ok_or_else(|| anyhow!("The 'target_field' arg for {} does not appear to be a string", plugin_name))?.to_string()
+        };
+
+        let formats = TimestampFormats::from_args(args, plugin_name)?;
+        let output = TsOutput::from_args(args, plugin_name)?;
+
+        let tag = args.get("tag").unwrap_or(&Value::String(format!("_{}_failure", plugin_name))).to_owned();
+        let tag = tag.as_str().ok_or_else(|| anyhow!("The 'tag' arg for {} does not appear to be a string", plugin_name))?;
+
+        Ok(TimestampParser { field, target_field, formats, output, failure: NoMatch::Tag(tag.to_string()) })
+    }
+
+    /// Parses the timestamp in the field, falling back to the current time and tagging the log if it cannot be parsed
+    fn process(&self, mut obj: Map<String, JsonValue>) -> Map<String, JsonValue> {
+        let parsed = obj.get(self.field.as_str()).and_then(|value| self.formats.parse(value)).and_then(|dt| self.output.format(&dt));
+
+        match parsed {
+            Some(ts) => {
+                obj.insert(self.target_field.clone(), ts);
+                obj
+            }
+            None => {
+                if let Some(now) = self.output.format(&Utc::now()) {
+                    obj.insert(self.target_field.clone(), now);
+                }
+
+                self.failure.apply(obj).unwrap_or_default()
+            }
+        }
+    }
+}
+
+pub struct ParseTimestampTransform {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    parser: TimestampParser,
+}
+
+#[async_trait]
+impl Plugin for ParseTimestampTransform {
+    fn name() -> &'static str {
+        "parse_ts"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+        debug!("ParseTimestampTransform args: {:#?}", args);
+
+        let parser = TimestampParser::from_args(&args, Self::name())?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(ParseTimestampTransform {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            sender,
+            semaphore,
+            parser,
+        }))
+    }
+
+    async fn run(&mut self) {
+        let mut event_stream = create_event_stream!(self);
+
+        // grab an event and pass it along
+        while let Some(event) = event_stream.next().await {
+            let (event, callback) = recv_event!(event);
+
+            let event = match event {
+                Event::None => {
+                    callback.call();
+                    continue // nothing to do here
+                }
+                Event::Json(JsonValue::Object(obj)) => Event::Json(JsonValue::Object(self.parser.process(obj))),
+                Event::Json(_) => {
+                    error!("Invalid JSON value received");
+                    return
+                }
+                Event::String(_) => {
+                    warn!("Found non-JSON log, skipping {}", Self::name());
+                    callback.call();
+                    continue
+                }
+            };
+
+            send_event!(self, event, callback);
+        }
+    }
+
+    // boilerplate methods
+    get_receiver!{}
+    connect_receiver!{}
+}
+
+
+#[cfg(test)]
+mod parse_ts_tests {
+    use chrono::{Datelike, Utc};
+    use serde_json::json;
+    use toml::Value;
+
+    use crate::Args;
+    use crate::event::JsonValue;
+    use crate::plugins::parse_ts::TimestampParser;
+
+    const EXPECTED_MS: i64 = 1688738532000; // 2023-07-07T14:02:12Z
+
+    fn parser(extra: &[(&str, Value)]) -> TimestampParser {
+        let mut args = Args::new();
+
+        for (k, v) in extra {
+            args.insert(k.to_string(), v.clone());
+        }
+
+        TimestampParser::from_args(&args, "parse_ts").expect("Error parsing args")
+    }
+
+    fn parse(parser: &TimestampParser, value: JsonValue) -> JsonValue {
+        let obj = json!({"t": value}).as_object().unwrap().clone();
+        JsonValue::Object(parser.process(obj))
+    }
+
+    #[test]
+    fn default_formats() {
+        let parser = parser(&[]);
+
+        for value in [json!(1688738532), json!(1688738532000_i64), json!(1688738532000000000_i64), json!("1688738532"), json!(1688738532.0),
+                      json!("2023-07-07T14:02:12Z"), json!("2023-07-07T16:02:12+02:00"), json!("Fri, 07 Jul 2023 14:02:12 +0000")] {
+            assert_eq!(json!({"t": EXPECTED_MS}), parse(&parser, value.clone()), "{}", value);
+        }
+    }
+
+    #[test]
+    fn syslog_and_timezones() {
+        let parser = parser(&[("formats", Value::String("syslog".to_string())), ("timezone", Value::String("+02:00".to_string())), ("ts_type", Value::String("rfc3339".to_string()))]);
+        let year = Utc::now().year();
+        let parsed = parse(&parser, json!("Jan  1 02:00:00"));
+
+        assert_eq!(json!({"t": format!("{}-01-01T00:00:00+00:00", year)}), parsed);
+
+        let parser = self::parser(&[("formats", Value::Array(vec![Value::String("%Y-%m-%d %H:%M:%S".to_string())])), ("timezone", Value::String("-0500".to_string()))]);
+        assert_eq!(json!({"t": EXPECTED_MS}), parse(&parser, json!("2023-07-07 09:02:12")));
+    }
+
+    #[test]
+    fn failure_falls_back_to_now() {
+        let parser = parser(&[("target_field", Value::String("ts".to_string())), ("ts_type", Value::String("epoch_s".to_string()))]);
+        let parsed = parse(&parser, json!("not a time"));
+        let now = Utc::now().timestamp();
+
+        assert_eq!(json!("not a time"), parsed["t"]);
+        assert!((parsed["ts"].as_i64().unwrap() - now).abs() <= 1);
+        assert_eq!(json!(["_parse_ts_failure"]), parsed["tags"]);
+    }
+
+    #[test]
+    fn nanos() {
+        let parser = parser(&[("ts_type", Value::String("epoch_ns".to_string()))]);
+
+        // integers are read exactly, not through an f64
+        assert_eq!(json!({"t": 1688738532123456789_i64}), parse(&parser, json!(1688738532123456789_i64)));
+        assert_eq!(json!({"t": 1688738532123456789_i64}), parse(&parser, json!("1688738532123456789")));
+
+        // the year 5138 doesn't fit in an i64 of nanoseconds, and NaN or infinity don't parse, so the current time is used
+        let parser = self::parser(&[("ts_type", Value::String("epoch_ns".to_string())), ("target_field", Value::String("ts".to_string()))]);
+
+        for value in [json!(99999999999_i64), json!("NaN"), json!("inf"), json!("-infinity")] {
+            let parsed = parse(&parser, value.clone());
+
+            assert_eq!(value, parsed["t"]);
+            assert!(parsed["ts"].is_i64());
+            assert_eq!(json!(["_parse_ts_failure"]), parsed["tags"], "{}", value);
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::future::join_all;
+use reqwest::Client;
+use serde_json::{json, Map};
+use stream_cancel::Tripwire;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use toml::Value;
+
+use crate::common::logging::{debug, error, warn};
+use crate::{Args, create_sender_semaphore, get_receiver};
+use crate::event::{Event, JsonValue};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback, DeadLetter};
+
+/// A single sample from the Prometheus text exposition format
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    name: String,
+    labels: Map<String, JsonValue>,
+    value: f64,
+    metric_type: String,
+    /// milliseconds since the epoch, if the sample has one
+    timestamp: Option<i64>,
+}
+
+impl Sample {
+    fn to_json(&self, target: &str, scrape_time: i64) -> JsonValue {
+        // JSON doesn't have NaN or infinities
+        let value = if self.value.is_finite() {
+            json!(self.value)
+        } else if self.value.is_nan() {
+            json!("NaN")
+        } else if self.value > 0.0 {
+            json!("+Inf")
+        } else {
+            json!("-Inf")
+        };
+
+        json!({
+            "name": self.name,
+            "labels": self.labels,
+            "value": value,
+            "type": self.metric_type,
+            "timestamp": self.timestamp.unwrap_or(scrape_time),
+            "target": target,
+        })
+    }
+}
+
+/// Parses a sample value, including the special values
+fn parse_value(value: &str) -> Result<f64> {
+    match value {
+        "NaN" => Ok(f64::NAN),
+        "+Inf" | "Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        v => v.parse::<f64>().map_err(|_| anyhow!("Invalid value '{}'", v))
+    }
+}
+
+/// Parses the `{name="value",...}` labels, returning them and the rest of the line
+fn parse_labels(s: &str) -> Result<(Map<String, JsonValue>, &str)> {
+    let mut labels = Map::new();
+    let mut rest = s.trim_start();
+
+    loop {
+        rest = rest.trim_start_matches([' ', ',']);
+
+        if let Some(r) = rest.strip_prefix('}') {
+            return Ok((labels, r));
+        }
+
+        let (name, r) = rest.split_once('=').ok_or_else(|| anyhow!("Expected '=' after label name"))?;
+        let name = name.trim();
+
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid label name '{}'", name);
+        }
+
+        let mut chars = r.trim_start().strip_prefix('"').ok_or_else(|| anyhow!("Expected '\"' to start the value of label '{}'", name))?.char_indices();
+        let start = r.len() - r.trim_start().len() + 1;
+        let mut value = String::new();
+
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => bail!("Unterminated value for label '{}'", name)
+                },
+                Some((_, c)) => value.push(c),
+                None => bail!("Unterminated value for label '{}'", name)
+            }
+        };
+
+        labels.insert(name.to_string(), JsonValue::String(value));
+        rest = &r[start + end + 1..];
+    }
+}
+
+/// Finds the type of a sample from the `# TYPE` of its metric family
+fn family_type(name: &str, types: &HashMap<String, String>) -> String {
+    if let Some(t) = types.get(name) {
+        return t.clone();
+    }
+
+    ["_bucket", "_sum", "_count", "_total", "_created"].iter()
+        .filter_map(|suffix| name.strip_suffix(suffix))
+        .find_map(|family| types.get(family))
+        .cloned()
+        .unwrap_or_else(|| "untyped".to_string())
+}
+
+/// Parses the text exposition format, returning the samples and any lines that could not be parsed
+fn parse_exposition(body: &str) -> (Vec<Sample>, Vec<(usize, String, String)>) {
+    let mut types = HashMap::new();
+    let mut samples = vec![];
+    let mut errors = vec![];
+
+    for (i, line) in body.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue
+        }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.split_whitespace();
+
+            if let (Some("TYPE"), Some(name), Some(metric_type)) = (parts.next(), parts.next(), parts.next()) {
+                types.insert(name.to_string(), metric_type.to_string());
+            }
+
+            continue
+        }
+
+        match parse_sample(line, &types) {
+            Ok(sample) => samples.push(sample),
+            Err(e) => errors.push((i + 1, line.to_string(), e.to_string()))
+        }
+    }
+
+    (samples, errors)
+}
+
+fn parse_sample(line: &str, types: &HashMap<String, String>) -> Result<Sample> {
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace()).ok_or_else(|| anyhow!("Missing value"))?;
+    let name = &line[..name_end];
+
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+        bail!("Invalid metric name '{}'", name);
+    }
+
+    let (labels, rest) = match line[name_end..].strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (Map::new(), &line[name_end..])
+    };
+
+    let mut parts = rest.split_whitespace();
+    let value = parse_value(parts.next().ok_or_else(|| anyhow!("Missing value"))?)?;
+    let timestamp = parts.next().map(|t| t.parse::<i64>().map_err(|_| anyhow!("Invalid timestamp '{}'", t))).transpose()?;
+
+    if parts.next().is_some() {
+        bail!("Unexpected text after the timestamp");
+    }
+
+    Ok(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+        metric_type: family_type(name, types),
+        timestamp,
+    })
+}
+
+/// Scrapes Prometheus `/metrics` endpoints
+pub struct PrometheusScrapeInput {
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    tripwire: Tripwire,
+    client: Client,
+    urls: Vec<String>,
+    interval: Duration,
+    dead_letter: Option<DeadLetter>,
+}
+
+impl PrometheusScrapeInput {
+    /// Scrapes a single target, sending its samples and any lines that could not be parsed
+    async fn scrape(&self, url: &str) -> Result<()> {
+        let scrape_time = Utc::now().timestamp_millis();
+
+        let response = self.client.get(url)
+            .header("Accept", "text/plain;version=0.0.4")
+            .send().await
+            .and_then(|r| r.error_for_status())
+            .context("Requesting metrics")?;
+
+        let body = response.text().await.context("Reading metrics")?;
+        let (samples, errors) = parse_exposition(body.as_str());
+
+        debug!("Scraped {} samples from {}", samples.len(), url);
+
+        let no_op_callback = Arc::new(Callback::empty());
+
+        for sample in samples {
+            let permit = match self.semaphore.clone().acquire_owned().await {
+                Ok(p) => p,
+                Err(_) => return Ok( () )
+            };
+
+            if let Err(e) = self.sender.send((Event::Json(sample.to_json(url, scrape_time)), Arc::new(permit), no_op_callback.clone())) {
+                bail!("Error sending event: {:?}", e);
+            }
+        }
+
+        if !errors.is_empty() {
+            warn!("{} lines from {} could not be parsed; first error on line {}: {}", errors.len(), url, errors[0].0, errors[0].2);
+        }
+
+        if let Some(dead_letter) = &self.dead_letter {
+            for (line_num, line, e) in errors {
+                dead_letter.send(Self::name(), format!("{} line {}: {}", url, line_num, e), Event::String(line), no_op_callback.clone()).await;
+            }
+        }
+
+        Ok( () )
+    }
+}
+
+#[async_trait]
+impl Plugin for PrometheusScrapeInput {
+    fn name() -> &'static str where Self: Sized {
+        "prometheus_scrape"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
+        debug!("PrometheusScrapeInput args: {:#?}", args);
+
+        let urls = match args.get("urls") {
+            Some(Value::String(u)) => vec![u.clone()],
+            Some(Value::Array(a)) => {
+                a.iter()
+                 .map(|u| u.as_str().map(|u| u.to_string()))
+                 .collect::<Option<Vec<_>>>()
+                 .ok_or_else(|| anyhow!("Found non-string URL in 'urls' arg for {}", Self::name()))?
+            }
+            Some(_) => bail!("The 'urls' arg for {} must be a string or an array of strings", Self::name()),
+            None => bail!("Could not find 'urls' arg for {}", Self::name())
+        };
+
+        if urls.is_empty() {
+            bail!("The 'urls' arg for {} cannot be empty", Self::name());
+        }
+
+        let interval = args.get("scrape_secs").unwrap_or(&Value::Integer(15));
+        let interval = interval.as_integer().ok_or_else(|| anyhow!("The 'scrape_secs' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if !(1..=3600).contains(&interval) {
+            bail!("Nonsensical value {} for 'scrape_secs' for {}; should be between 1 and 3600 seconds", interval, Self::name());
+        }
+
+        let timeout = args.get("timeout_secs").unwrap_or(&Value::Integer(10));
+        let timeout = timeout.as_integer().ok_or_else(|| anyhow!("The 'timeout_secs' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if !(1..=interval).contains(&timeout) {
+            bail!("Nonsensical value {} for 'timeout_secs' for {}; should be between 1 and 'scrape_secs'", timeout, Self::name());
+        }
+
+        let client = Client::builder()
+            .timeout(Duration::from_secs(timeout as u64))
+            .build()
+            .context("Creating HTTP client")?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(PrometheusScrapeInput {
+            sender,
+            semaphore,
+            tripwire,
+            client,
+            urls,
+            interval: Duration::from_secs(interval as u64),
+            dead_letter: None, // set in connect_dead_letter
+        }))
+    }
+
+    async fn run(&mut self) {
+        debug!("PrometheusScrapeInput running...");
+
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = self.tripwire.clone() => break
+            }
+
+            // scrape all the targets at the same time, so a slow one doesn't delay the others
+            let this = &*self;
+            let results = join_all(this.urls.iter().map(|url| this.scrape(url))).await;
+
+            for (url, res) in self.urls.iter().zip(results) {
+                if let Err(e) = res {
+                    error!("Error scraping {}: {:#}", url, e);
+                }
+            }
+        }
+
+        debug!("PrometheusScrapeInput closing");
+    }
+
+    // boilerplate method
+    get_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+
+#[cfg(test)]
+mod prometheus_scrape_tests {
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use toml::Value;
+
+    use crate::common::init_test_logger;
+    use crate::plugin::{Args, Plugin};
+    use crate::event::Event;
+    use crate::plugins::prometheus_scrape::{parse_exposition, PrometheusScrapeInput};
+
+    const EXPOSITION: &str = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"}    3 1395066363000
+
+# Escaping in label values:
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9
+
+# A histogram
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.05"} 24054
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+http_request_duration_seconds_sum 53423
+http_request_duration_seconds_count 144320
+go_gc_duration_seconds{quantile="0"} NaN
+this is not a metric
+bad_value 12abc
+"#;
+
+    #[test]
+    fn parse() {
+        let (samples, errors) = parse_exposition(EXPOSITION);
+
+        assert_eq!(8, samples.len());
+
+        let first = samples[0].to_json("http://localhost/metrics", 0);
+        assert_eq!(json!({
+            "name": "http_requests_total",
+            "labels": {"method": "post", "code": "200"},
+            "value": 1027.0,
+            "type": "counter",
+            "timestamp": 1395066363000_i64,
+            "target": "http://localhost/metrics"
+        }), first);
+
+        assert_eq!(json!({"path": "C:\\DIR\\FILE.TXT", "error": "Cannot find file:\n\"FILE.TXT\""}), json!(samples[2].labels));
+        assert_eq!("untyped", samples[2].metric_type);
+        assert_eq!(42, samples[2].to_json("t", 42)["timestamp"]);
+
+        assert_eq!("histogram", samples[4].metric_type);
+        assert_eq!(json!({"le": "+Inf"}), json!(samples[4].labels));
+        assert_eq!("histogram", samples[6].metric_type);
+        assert_eq!(json!("NaN"), samples[7].to_json("t", 0)["value"]);
+
+        assert_eq!(vec![16, 17], errors.iter().map(|e| e.0).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+
+        // a stand-in for an application's /metrics endpoint
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("Error binding");
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.expect("Error accepting");
+                let mut stream = BufReader::new(stream);
+
+                // read the request headers
+                loop {
+                    let mut line = String::new();
+
+                    if stream.read_line(&mut line).await.expect("Error reading") == 0 || line == "\r\n" {
+                        break;
+                    }
+                }
+
+                let body = "# TYPE up gauge\nup 1\nqueue_depth{queue=\"jobs\"} 7\n";
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+
+                stream.get_mut().write_all(response.as_bytes()).await.expect("Error writing");
+            }
+        });
+
+        let mut args = Args::new();
+        args.insert("channel_size".to_string(), Value::Integer(10));
+        args.insert("urls".to_string(), Value::String(format!("http://127.0.0.1:{}/metrics", port)));
+
+        let mut input = PrometheusScrapeInput::new(args, tripwire).await.expect("Error creating PrometheusScrapeInput");
+        let mut recv = input.get_receiver();
+
+        let jh = tokio::spawn(async move { input.run().await });
+
+        let (event, _permit, _callback) = recv.recv().await.expect("Error receiving");
+        let event = match event { Event::Json(json) => json, e => panic!("Unexpected event: {:?}", e) };
+
+        assert_eq!(json!("up"), event["name"]);
+        assert_eq!(json!("gauge"), event["type"]);
+        assert_eq!(json!(1.0), event["value"]);
+
+        let (event, _permit, _callback) = recv.recv().await.expect("Error receiving");
+        let event = match event { Event::Json(json) => json, e => panic!("Unexpected event: {:?}", e) };
+
+        assert_eq!(json!({"queue": "jobs"}), event["labels"]);
+
+        trigger.cancel();
+        jh.await.expect("Error waiting");
+    }
+
+    #[tokio::test]
+    async fn bad_args() {
+        let (_trigger, tripwire) = Tripwire::new();
+
+        for bad in ["", "urls = []", "urls = 7", r#"urls = "http://localhost"
+                                                    scrape_secs = 0"#, r#"urls = "http://localhost"
+                                                    timeout_secs = 60"#] {
+            let mut args: Args = toml::from_str(bad).unwrap();
+            args.insert("channel_size".to_string(), Value::Integer(1));
+
+            assert!(PrometheusScrapeInput::new(args, tripwire.clone()).await.is_err(), "{}", bad);
+        }
+    }
+}
@@ -1,14 +1,14 @@
-use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::sync::{Arc, Once};
+use std::time::Duration;
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 use async_trait::async_trait;
 use pyembed::MainPythonInterpreter;
 
 use pyo3::prelude::*;
-use pyo3::types::{PyBool, PyDict, PyFloat, PyInt, PyString, PyTuple};
+use pyo3::types::{IntoPyDict, PyBool, PyDateTime, PyDict, PyFloat, PyInt, PyList, PyString, PyTuple};
 use stream_cancel::{StreamExt, Tripwire};
 use serde_json::{Value as JsonValue};
 
@@ -19,11 +19,15 @@ use tokio_stream::wrappers::BroadcastStream;
 use toml::Value;
 
 use crate::common::logging::{debug, error};
-use crate::{Args, connect_receiver, create_event_stream, create_sender_semaphore, get_receiver, Plugin, recv_event, send_event};
+use crate::{Args, connect_receiver, create_event_stream, create_sender_semaphore, get_receiver, Plugin, recv_event};
 use crate::event::{Event};
-use crate::plugin::{PluginType, ChannelType};
+use crate::plugin::{PluginType, ChannelType, DeadLetter, Callback};
+use crate::plugins::insert_field::toml2jsonvalue;
 
 const DEFAULT_FUNCTION_NAME: &str = "process";
+const INIT_FUNCTION_NAME: &str = "init";
+const FLUSH_FUNCTION_NAME: &str = "flush";
+const SHUTDOWN_FUNCTION_NAME: &str = "shutdown";
 
 include!("../../pyembedded/default_python_config.rs");
 
@@ -32,15 +36,299 @@ static mut INTERPRETER: Option<MainPythonInterpreter<'static, 'static>> = None;
 static INIT_INTERPRETER: Once = Once::new();
 
 
+/// Initializes the embedded interpreter, if it hasn't been already
+fn init_interpreter() {
+    INIT_INTERPRETER.call_once(|| {
+        // comes from the default_python_config.rs file above
+        let mut python_config = default_python_config();
+
+        python_config.multiprocessing_auto_dispatch = false;
+        python_config.multiprocessing_start_method = pyembed::MultiprocessingStartMethod::None;
+        python_config.terminfo_resolution = pyembed::TerminfoResolution::None;
+
+        python_config.interpreter_config.profile = pyembed::PythonInterpreterProfile::Isolated;
+        python_config.interpreter_config.isolated = Some(true);
+        python_config.interpreter_config.user_site_directory = Some(false);
+        python_config.interpreter_config.interactive = Some(false);
+
+        match MainPythonInterpreter::new(python_config) {
+            Ok(interpreter) => {
+                unsafe { INTERPRETER.insert(interpreter) };
+            }
+            Err(err) => {
+                error!("Error initializing Python interpreter: {err}");
+                panic!("Error initializing Python interpreter: {err}");
+            }
+        }
+    });
+}
+
+/// Recursively converts JSON into the equivalent Python objects
+fn json_to_py(py: Python, value: &JsonValue) -> PyResult<PyObject> {
+    Ok(match value {
+        JsonValue::Null => py.None(),
+        JsonValue::Bool(b) => (*b).into_py(py),
+        JsonValue::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                u.into_py(py)
+            } else if let Some(i) = n.as_i64() {
+                i.into_py(py)
+            } else {
+                n.as_f64().unwrap_or(f64::NAN).into_py(py)
+            }
+        }
+        JsonValue::String(s) => s.as_str().into_py(py),
+        JsonValue::Array(array) => {
+            let items = array.iter().map(|v| json_to_py(py, v)).collect::<PyResult<Vec<_>>>()?;
+
+            PyList::new(py, items).into_py(py)
+        }
+        JsonValue::Object(object) => {
+            let dict = PyDict::new(py);
+
+            for (k, v) in object.iter() {
+                dict.set_item(k, json_to_py(py, v)?)?;
+            }
+
+            dict.into_py(py)
+        }
+    })
+}
+
+/// Recursively converts a Python object returned from the function into JSON;
+/// a `datetime` is converted into milliseconds since the epoch, with naive ones treated as UTC
+fn py_to_json(obj: &PyAny) -> anyhow::Result<JsonValue> {
+    // bool is a subclass of int, so it must be checked first
+    Ok(if obj.is_none() {
+        JsonValue::Null
+    } else if obj.is_instance_of::<PyBool>()? {
+        JsonValue::from(obj.extract::<bool>()?)
+    } else if obj.is_instance_of::<PyInt>()? {
+        if let Ok(i) = obj.extract::<i64>() {
+            JsonValue::from(i)
+        } else {
+            JsonValue::from(obj.extract::<u64>().map_err(|_| anyhow!("Integer {} is too large to convert to JSON", obj))?)
+        }
+    } else if obj.is_instance_of::<PyFloat>()? {
+        let f = obj.extract::<f64>()?;
+
+        // JSON has no NaN or infinity
+        if !f.is_finite() {
+            bail!("Float {} cannot be converted to JSON", f);
+        }
+
+        JsonValue::from(f)
+    } else if obj.is_instance_of::<PyString>()? {
+        JsonValue::from(obj.extract::<String>()?)
+    } else if obj.is_instance_of::<PyDateTime>()? {
+        // timestamp() treats naive datetimes as local time, so make them UTC first
+        let dt = if obj.call_method0("utcoffset")?.is_none() {
+            let utc = obj.py().import("datetime")?.getattr("timezone")?.getattr("utc")?;
+            obj.call_method("replace", (), Some([("tzinfo", utc)].into_py_dict(obj.py())))?
+        } else {
+            obj
+        };
+
+        let secs: f64 = dt.call_method0("timestamp")?.extract()?;
+
+        JsonValue::from((secs * 1000.0).round() as i64)
+    } else if let Ok(dict) = obj.downcast::<PyDict>() {
+        let mut map = serde_json::Map::new();
+
+        for (k, v) in dict.iter() {
+            let k: String = k.extract().map_err(|_| anyhow!("Dict keys must be strings, not: {}", k.get_type()))?;
+
+            map.insert(k, py_to_json(v)?);
+        }
+
+        JsonValue::Object(map)
+    } else if let Ok(list) = obj.downcast::<PyList>() {
+        JsonValue::Array(list.iter().map(py_to_json).collect::<anyhow::Result<_>>()?)
+    } else if let Ok(tuple) = obj.downcast::<PyTuple>() {
+        JsonValue::Array(tuple.iter().map(py_to_json).collect::<anyhow::Result<_>>()?)
+    } else {
+        return Err(anyhow!("Error getting type for PyObject: {:?}", obj));
+    })
+}
+
+/// Converts a dict returned from the Python function into an event
+fn dict_to_event(obj: &PyAny) -> anyhow::Result<Event> {
+    if !obj.is_instance_of::<PyDict>()? {
+        return Err(anyhow!("Expected a dict to be returned, not: {}", obj.get_type()));
+    }
+
+    Ok(Event::Json(py_to_json(obj)?))
+}
+
+
+/// Converts what the Python function returned into events: a dict is a single event,
+/// a list, tuple, or generator of dicts are many events, and None is no events
+fn returned_events(ret_obj: &PyAny) -> anyhow::Result<Vec<Event>> {
+    // simply skip if it's none
+    if ret_obj.is_none() {
+        return Ok(vec![])
+    }
+
+    if ret_obj.is_instance_of::<PyDict>()? {
+        return Ok(vec![dict_to_event(ret_obj)?])
+    }
+
+    // strings are iterable, but almost certainly a mistake
+    if ret_obj.is_instance_of::<PyString>()? {
+        return Err(anyhow!("Expected a dict, list of dicts, or None to be returned, not a str"));
+    }
+
+    // otherwise, it's a list, tuple, or generator of dicts; each is its own event
+    let iter = ret_obj.iter().map_err(|_| anyhow!("Expected a dict, list of dicts, or None to be returned, not: {}", ret_obj.get_type()))?;
+
+    iter.map(|item| dict_to_event(item.context("Error iterating over the returned value")?)).collect()
+}
+
+
 /// Transformer plugin that processes through Python
 pub struct PythonScript {
     // interpreter: Mutex<MainPythonInterpreter<'a, 'a>>,
     python_function: PyObject,
+    flush_function: Option<PyObject>,
+    shutdown_function: Option<PyObject>,
+    flush_interval: Duration,
     arg_type: String, //TODO: Change to an enum
     tripwire: Tripwire,
     receiver: Option<Receiver<ChannelType>>,
     sender: Sender<ChannelType>,
     semaphore: Arc<Semaphore>,
+    dead_letter: Option<DeadLetter>,
+}
+
+impl PythonScript {
+    /// Calls the Python function on the event, and sends along what it returns
+    async fn process(&self, event: Event, callback: Arc<Callback>) -> anyhow::Result<()> {
+        // convert the event to the correct type based upon what's being asked for
+        let event = match (event, self.arg_type.as_str()) {
+            (Event::None, _) => {
+                callback.call();
+                return Ok( () )
+            }
+            (Event::Json(json), "str") => { Event::String(json.to_string()) },
+            (Event::String(string), "dict") => {
+                match serde_json::from_str(string.as_str()) {
+                    Err(e) => {
+                        if let Some(dead_letter) = &self.dead_letter {
+                            dead_letter.send(Self::name(), e, Event::String(string), callback).await;
+                            return Ok( () );
+                        }
+
+                        bail!("While trying to run a Python script which requested the log as a dict, non-JSON log provided");
+                    }
+                    Ok(json) => Event::Json(json)
+                }
+            },
+            (event, _) => event
+        };
+
+        // only keep a copy of the log if it could be sent to the dead-letter output
+        let original = self.dead_letter.as_ref().map(|_| event.clone());
+
+        let call_res = {
+            // call the Python function
+            let interpreter = unsafe { INTERPRETER.as_ref().expect("Python interpreter NOT initialized!!!") };
+            interpreter.with_gil(|py| -> anyhow::Result<Vec<Event>> {
+                let args = match event {
+                    Event::None => {
+                        error!("Event::None; should never happen");
+                        return Ok(vec![])
+                    }
+                    Event::Json(json) => {
+                        if !json.is_object() {
+                            return Err(anyhow!("Error converting log to JSON"));
+                        }
+
+                        PyTuple::new(py, [json_to_py(py, &json)?])
+                    }
+                    Event::String(string) => {
+                        let s = PyString::new(py, string.as_str());
+                        PyTuple::new(py, [s])
+                    }
+                };
+
+                // call the function, and convert what it returns into events
+                let ret_obj = self.python_function.call1(py, args).context("Error calling Python function".to_string())?;
+
+                returned_events(ret_obj.as_ref(py))
+            })
+        };
+
+        // debug!("Python sending: {:?}", event);
+
+        // send the event
+        match call_res {
+            Err(e) => {
+                match (&self.dead_letter, original) {
+                    (Some(dead_letter), Some(original)) => dead_letter.send(Self::name(), format!("{:#}", e), original, callback).await,
+                    _ => {
+                        error!("Error running Python script: {:?}", e);
+                        callback.call();
+                    }
+                }
+
+                Ok( () )
+            }
+            Ok(events) => self.send_events(events, callback).await
+        }
+    }
+
+    /// Calls an optional function in the script that takes no arguments, like `flush`, and sends along what it returns
+    async fn call_hook(&self, function: Option<&PyObject>, name: &str) -> anyhow::Result<()> {
+        let function = match function {
+            Some(f) => f,
+            None => return Ok( () )
+        };
+
+        let call_res = {
+            let interpreter = unsafe { INTERPRETER.as_ref().expect("Python interpreter NOT initialized!!!") };
+            interpreter.with_gil(|py| -> anyhow::Result<Vec<Event>> {
+                let ret_obj = function.call0(py).context(format!("Error calling Python function '{}'", name))?;
+
+                returned_events(ret_obj.as_ref(py))
+            })
+        };
+
+        match call_res {
+            // the script is still usable, so keep going
+            Err(e) => {
+                error!("Error running Python script: {:?}", e);
+                Ok( () )
+            }
+            Ok(events) => self.send_events(events, Arc::new(Callback::empty())).await
+        }
+    }
+
+    /// Sends the events returned from the script; the callback is called once they've all been called back
+    async fn send_events(&self, events: Vec<Event>, callback: Arc<Callback>) -> anyhow::Result<()> {
+        let callback = match events.len() {
+            // filtered, so call the callback
+            0 => {
+                callback.call();
+                return Ok( () )
+            }
+            1 => callback,
+            // split into many events, so only call back once they've all been called back
+            count => Callback::shared(count, callback)
+        };
+
+        for event in events {
+            let permit = match self.semaphore.clone().acquire_owned().await {
+                Ok(p) => p,
+                Err(_) => return Ok( () )
+            };
+
+            if let Err(e) = self.sender.send((event, Arc::new(permit), callback.clone())) {
+                bail!("Error sending event: {:?}", e);
+            }
+        }
+
+        Ok( () )
+    }
 }
 
 #[async_trait]
@@ -53,29 +341,7 @@ impl Plugin for PythonScript {
         debug!("PythonScript args: {:#?}", args);
 
         // initialize the interpreter if we haven't already
-        INIT_INTERPRETER.call_once(|| {
-            // comes from the default_python_config.rs file above
-            let mut python_config = default_python_config();
-
-            python_config.multiprocessing_auto_dispatch = false;
-            python_config.multiprocessing_start_method = pyembed::MultiprocessingStartMethod::None;
-            python_config.terminfo_resolution = pyembed::TerminfoResolution::None;
-
-            python_config.interpreter_config.profile = pyembed::PythonInterpreterProfile::Isolated;
-            python_config.interpreter_config.isolated = Some(true);
-            python_config.interpreter_config.user_site_directory = Some(false);
-            python_config.interpreter_config.interactive = Some(false);
-
-            match MainPythonInterpreter::new(python_config) {
-                Ok(interpreter) => {
-                    unsafe { INTERPRETER.insert(interpreter) };
-                }
-                Err(err) => {
-                    error!("Error initializing Python interpreter: {err}");
-                    panic!("Error initializing Python interpreter: {err}");
-                }
-            }
-        });
+        init_interpreter();
 
         let file_path = args.get("path").ok_or_else(|| anyhow!("Could not find 'path' arg for python transformer"))?;
         let file_path = file_path.as_str().ok_or_else(|| anyhow!("The 'path' arg for python transformer does not appear to be a string"))?;
@@ -92,15 +358,38 @@ impl Plugin for PythonScript {
             .as_str()
             .ok_or_else(|| anyhow!("The 'function' arg for python transformer does not appear to be a string"))?;
 
-        // parse out the code, and find the appropriate function
+        // the optional params passed to the script's init function
+        let params = match args.get("params") {
+            None => JsonValue::Object(serde_json::Map::new()),
+            Some(params @ Value::Table(_)) => toml2jsonvalue(params)?,
+            Some(_) => bail!("The 'params' arg for python transformer must be a table")
+        };
+
+        let flush_secs = args.get("flush_secs").unwrap_or(&Value::Integer(60));
+        let flush_secs = flush_secs.as_integer().ok_or_else(|| anyhow!("The 'flush_secs' arg for python transformer does not appear to be an integer"))?;
+
+        if !(1..=3600).contains(&flush_secs) {
+            bail!("The 'flush_secs' arg for python transformer must be between 1 and 3600 seconds");
+        }
+
+        // parse out the code, and find the appropriate functions
         let interpreter = unsafe { INTERPRETER.as_ref().expect("Python interpreter NOT initialized!!!") };
-        let python_function = interpreter.with_gil(|py| -> anyhow::Result<PyObject> {
+        let (python_function, flush_function, shutdown_function) = interpreter.with_gil(|py| -> anyhow::Result<(PyObject, Option<PyObject>, Option<PyObject>)> {
             let module = PyModule::from_code(py, code.as_str(), file_path, "log-ship")
                 .context(format!("Unable to parse code find in script {}", file_path))?;
             let func = module.getattr(function_name)
                 .context(format!("Unable to find a function named '{}' in script {}", function_name, file_path))?;
 
-            Ok(func.into_py(py))
+            // the other functions are optional
+            let optional_function = |name: &str| module.getattr(name).ok().filter(|f| f.is_callable()).map(|f| f.into_py(py));
+
+            // setup the script before any logs are processed
+            if let Some(init) = optional_function(INIT_FUNCTION_NAME) {
+                init.call1(py, (json_to_py(py, &params)?,))
+                    .context(format!("Error calling Python function '{}' in script {}", INIT_FUNCTION_NAME, file_path))?;
+            }
+
+            Ok((func.into_py(py), optional_function(FLUSH_FUNCTION_NAME), optional_function(SHUTDOWN_FUNCTION_NAME)))
         })?;
 
         // get the type to pass to the function
@@ -119,11 +408,15 @@ impl Plugin for PythonScript {
         Ok(Box::new(PythonScript {
             // interpreter: Mutex::new(interpreter),
             python_function,
+            flush_function,
+            shutdown_function,
+            flush_interval: Duration::from_secs(flush_secs as u64),
             arg_type: arg_type.to_string(),
             tripwire,
             receiver: None,
             sender,
             semaphore,
+            dead_letter: None, // set in connect_dead_letter
         }))
     }
 
@@ -131,120 +424,300 @@ impl Plugin for PythonScript {
         debug!("Python running...");
 
         let mut event_stream = create_event_stream!(self);
+        let mut flush_interval = tokio::time::interval(self.flush_interval);
 
-        while let Some(event) = event_stream.next().await {
-            let (event, callback) = recv_event!(event);
-
-            // convert the event to the correct type based upon what's being asked for
-            let event = match (event, self.arg_type.as_str()) {
-                (Event::None, _) => continue,
-                (Event::Json(json), "str") => { Event::String(json.to_string()) },
-                (Event::String(string), "dict") => {
-                    match serde_json::from_str(string.as_str()) {
-                        Err(_e) => {
-                            error!("While trying to run a Python script which requested the log as a dict, non-JSON log provided");
-                            return;
-                        }
-                        Ok(json) => Event::Json(json)
-                    }
-                },
-                (event, _) => event
-            };
+        // the first tick completes immediately
+        flush_interval.tick().await;
 
-            let call_res = {
-                // call the Python function
-                let interpreter = unsafe { INTERPRETER.as_ref().expect("Python interpreter NOT initialized!!!") };
-                interpreter.with_gil(|py| -> anyhow::Result<Option<Event>> {
-                    let args = match event {
-                        Event::None => {
-                            error!("Event::None; should never happen");
-                            return Ok(None)
-                        }
-                        Event::Json(json) => {
-                            let dict = PyDict::new(py);
-                            let json = json.as_object().ok_or_else(|| anyhow!("Error converting log to JSON"))?;
-
-                            // go through the JSON adding everything to the dict
-                            for (k, v) in json.into_iter() {
-                                if v.is_u64() {
-                                    dict.set_item(k, v.as_u64().unwrap())?;
-                                } else if v.is_i64() {
-                                    dict.set_item(k, v.as_i64().unwrap())?;
-                                } else if v.is_f64() {
-                                    dict.set_item(k, v.as_f64().unwrap())?;
-                                } else if v.is_boolean() {
-                                    dict.set_item(k, v.as_bool().unwrap())?;
-                                } else if v.is_null() {
-                                    dict.set_item(k, py.None())?;
-                                } else if v.is_string() {
-                                    dict.set_item(k, v.as_str().unwrap())?;
-                                } else {
-                                    // everything else we just treat as a string
-                                    dict.set_item(k, v.to_string())?;
-                                }
-                            }
-
-                            PyTuple::new(py, [dict])
-                        }
-                        Event::String(string) => {
-                            let s = PyString::new(py, string.as_str());
-                            PyTuple::new(py, [s])
-                        }
+        loop {
+            tokio::select! {
+                event = event_stream.next() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break
                     };
 
-                    // call the function, and extract the result as a string
-                    let ret_obj = self.python_function.call1(py, args).context("Error calling Python function".to_string())?;
+                    let (event, callback) = recv_event!(event);
 
-                    // simply skip if it's none
-                    if ret_obj.is_none(py) {
-                        return Ok(None)
+                    // stop processing, but still give the script a chance to shutdown
+                    if let Err(e) = self.process(event, callback).await {
+                        error!("{:#}", e);
+                        break;
                     }
+                }
 
-                    let dict: HashMap<String, PyObject> = ret_obj.extract(py)?;
-                    let mut json_map = serde_json::Map::new();
-
-                    for (key, value) in dict.into_iter() {
-                        if value.as_ref(py).is_instance_of::<PyString>()? {
-                            let s: String = value.extract(py)?;
-                            json_map.insert(key, JsonValue::from(s));
-                        } else if value.as_ref(py).is_instance_of::<PyInt>()? {
-                            let i: i64 = value.extract(py)?;
-                            json_map.insert(key, JsonValue::from(i));
-                        } else if value.as_ref(py).is_instance_of::<PyFloat>()? {
-                            let f: f64 = value.extract(py)?;
-                            json_map.insert(key, JsonValue::from(f));
-                        } else if value.as_ref(py).is_instance_of::<PyBool>()? {
-                            let b: bool = value.extract(py)?;
-                            json_map.insert(key, JsonValue::from(b));
-                        } else if value.is_none(py) {
-                            json_map.insert(key, JsonValue::Null);
-                        } else {
-                            return Err(anyhow!("Error getting type for PyObject: {:?}", value));
-                        }
-                    }
-
-                    Ok(Some(Event::Json(JsonValue::from(json_map))))
-                })
-            };
-
-            // debug!("Python sending: {:?}", event);
-
-            // send the event
-            match call_res {
-                Err(e) => { error!("Error running Python script: {:?}", e); }
-                Ok(op_event) => {
-                    if let Some(event) = op_event {
-                        send_event!(self, event, callback);
-                    } else {
-                        // filtered, so call the callback
-                        callback.call();
+                _ = flush_interval.tick(), if self.flush_function.is_some() => {
+                    if let Err(e) = self.call_hook(self.flush_function.as_ref(), FLUSH_FUNCTION_NAME).await {
+                        error!("{:#}", e);
+                        break;
                     }
                 }
             }
         }
+
+        // the tripwire has tripped, there are no more events, or the script failed
+        if let Err(e) = self.call_hook(self.shutdown_function.as_ref(), SHUTDOWN_FUNCTION_NAME).await {
+            error!("{:#}", e);
+        }
     }
 
     // boilerplate methods
     get_receiver!{}
     connect_receiver!{}
-}
\ No newline at end of file
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
+}
+
+#[cfg(test)]
+mod python_tests {
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use pyo3::prelude::*;
+    use pyo3::types::PyBool;
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::sync::{broadcast, Semaphore};
+    use tokio::sync::broadcast::Sender;
+    use tokio::time::timeout;
+    use toml::Value;
+
+    use crate::common::init_test_logger;
+    use crate::event::Event;
+    use crate::plugin::{Args, Callback, ChannelType, Plugin, PluginType};
+    use crate::plugins::python::{init_interpreter, INTERPRETER, json_to_py, py_to_json, PythonScript, returned_events};
+
+    /// Runs the closure with the GIL of the embedded interpreter
+    fn with_gil<R>(f: impl FnOnce(Python) -> R) -> R {
+        init_interpreter();
+
+        let interpreter = unsafe { INTERPRETER.as_ref().expect("Python interpreter NOT initialized!!!") };
+        interpreter.with_gil(f)
+    }
+
+    /// Evaluates the Python expression, and converts the result to JSON
+    fn eval_json(expr: &str) -> anyhow::Result<serde_json::Value> {
+        with_gil(|py| py_to_json(py.eval(expr, None, None).expect("Error evaluating expression")))
+    }
+
+    /// Writes the code to a script, and creates a PythonScript for it with the extra args
+    async fn script(code: &str, extra: &str, tripwire: Tripwire) -> anyhow::Result<Box<PluginType>> {
+        let path = tempfile::TempDir::new().unwrap().into_path().join("script.py");
+        fs::write(&path, code).expect("Error writing script");
+
+        let mut args: Args = toml::from_str(extra).expect("Error parsing args");
+        args.insert("path".to_string(), Value::String(path.display().to_string()));
+        args.insert("channel_size".to_string(), Value::Integer(10));
+        args.entry("arg_type".to_string()).or_insert(Value::String("dict".to_string()));
+
+        PythonScript::new(args, tripwire).await
+    }
+
+    /// Sends the event to the plugin, returning the number of times its callback has been called
+    fn send(sender: &Sender<ChannelType>, event: Event) -> Arc<AtomicUsize> {
+        let called = Arc::new(AtomicUsize::new(0));
+        let called_clone = called.clone();
+        let callback = Arc::new(Callback::new(move || { called_clone.fetch_add(1, Ordering::SeqCst); }));
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+
+        sender.send((event, Arc::new(permit), callback)).expect("Error sending");
+
+        called
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let value = json!({
+            "big": u64::MAX,
+            "negative": i64::MIN,
+            "float": 2.5,
+            "flag": true,
+            "nothing": null,
+            "nested": {"list": [1, "a", {"b": [false, []]}], "empty": {}}
+        });
+
+        with_gil(|py| {
+            let obj = json_to_py(py, &value).expect("Error converting to Python");
+            let obj = obj.as_ref(py);
+
+            assert_eq!(u64::MAX, obj.get_item("big").unwrap().extract::<u64>().unwrap());
+            assert!(obj.get_item("flag").unwrap().is_instance_of::<PyBool>().unwrap());
+            assert_eq!(value, py_to_json(obj).expect("Error converting to JSON"));
+        });
+    }
+
+    #[test]
+    fn python_types() {
+        // bool is a subclass of int, but stays a bool
+        assert_eq!(json!([true, 1, 0, false]), eval_json("[True, 1, 0, False]").unwrap());
+        assert_eq!(json!(u64::MAX), eval_json("2**64 - 1").unwrap());
+        assert_eq!(json!({"t": [1, "a", [2.5, null]]}), eval_json("{'t': (1, 'a', (2.5, None))}").unwrap());
+
+        assert!(eval_json("2**64").is_err());
+        assert!(eval_json("{1: 'a'}").is_err());
+        assert!(eval_json("{'a': object()}").is_err());
+    }
+
+    #[test]
+    fn non_finite_floats() {
+        for expr in ["float('nan')", "float('inf')", "[float('-inf')]"] {
+            assert!(eval_json(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn datetimes() {
+        let expected = json!(1688738532123_i64); // 2023-07-07T14:02:12.123Z
+
+        // naive datetimes are UTC, regardless of the local timezone
+        assert_eq!(expected, eval_json("__import__('datetime').datetime(2023, 7, 7, 14, 2, 12, 123000)").unwrap());
+        assert_eq!(expected, eval_json("__import__('datetime').datetime(2023, 7, 7, 14, 2, 12, 123000, tzinfo=__import__('datetime').timezone.utc)").unwrap());
+        assert_eq!(expected, eval_json("__import__('datetime').datetime(2023, 7, 7, 16, 2, 12, 123000, tzinfo=__import__('datetime').timezone(__import__('datetime').timedelta(hours=2)))").unwrap());
+    }
+
+    #[test]
+    fn returned_values() {
+        let returned = |expr: &str| with_gil(|py| returned_events(py.eval(expr, None, None).expect("Error evaluating expression")));
+        let events = vec![Event::Json(json!({"i": 0})), Event::Json(json!({"i": 1}))];
+
+        assert_eq!(events, returned("[{'i': 0}, {'i': 1}]").unwrap());
+        assert_eq!(events, returned("({'i': 0}, {'i': 1})").unwrap());
+        assert_eq!(events, returned("({'i': i} for i in range(2))").unwrap());
+        assert_eq!(vec![Event::Json(json!({"i": 0}))], returned("{'i': 0}").unwrap());
+        assert!(returned("None").unwrap().is_empty());
+        assert!(returned("[]").unwrap().is_empty());
+
+        // strings are iterable, but aren't logs
+        for expr in ["'abc'", "['abc']", "[{'i': 0}, 1]", "5"] {
+            assert!(returned(expr).is_err(), "{}", expr);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn split_and_filtered() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let code = "def process(log):\n    return [dict(log, n=n) for n in range(log['count'])]\n";
+
+        let mut transform = script(code, "", tripwire).await.expect("Error creating PythonScript");
+        let (sender, receiver) = broadcast::channel(10);
+
+        transform.connect_receiver(receiver);
+        let mut output = transform.get_receiver();
+
+        let jh = tokio::spawn(async move { transform.run().await });
+
+        let filtered = send(&sender, Event::Json(json!({"count": 0})));
+        let split = send(&sender, Event::Json(json!({"count": 3})));
+        let mut callbacks = Vec::new();
+
+        for n in 0..3 {
+            let (event, _, callback) = timeout(Duration::from_secs(5), output.recv()).await.expect("Timed out").unwrap();
+
+            assert_eq!(Event::Json(json!({"count": 3, "n": n})), event);
+            callbacks.push(callback);
+        }
+
+        // a log the script returned nothing for is called back right away
+        assert_eq!(1, filtered.load(Ordering::SeqCst));
+
+        // the original log is only called back once every log derived from it has been
+        for callback in callbacks {
+            assert_eq!(0, split.load(Ordering::SeqCst));
+            callback.call();
+        }
+
+        assert_eq!(1, split.load(Ordering::SeqCst));
+
+        trigger.cancel();
+        jh.await.unwrap();
+    }
+
+    /// A script using every hook; `shutdown` writes to the file in its params
+    const HOOKS: &str = r#"
+params = None
+
+def init(p):
+    global params
+    params = p
+
+def process(log):
+    log['env'] = params['env']
+    return log
+
+def flush():
+    return {'flushed': True}
+
+def shutdown():
+    with open(params['shutdown_path'], 'w') as f:
+        f.write('done')
+"#;
+
+    /// The args for `HOOKS`, flushing every second
+    fn hooks_args(shutdown_path: &std::path::Path) -> String {
+        format!("flush_secs = 1\n[params]\nenv = \"prod\"\nshutdown_path = '{}'\n", shutdown_path.display())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn hooks() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let shutdown_path = tempfile::TempDir::new().unwrap().into_path().join("shutdown");
+
+        let mut transform = script(HOOKS, hooks_args(&shutdown_path).as_str(), tripwire).await.expect("Error creating PythonScript");
+        let (sender, receiver) = broadcast::channel(10);
+
+        transform.connect_receiver(receiver);
+        let mut output = transform.get_receiver();
+
+        let jh = tokio::spawn(async move { transform.run().await });
+
+        // init is called with the params
+        send(&sender, Event::Json(json!({"a": 1})));
+        let (event, _, _) = timeout(Duration::from_secs(5), output.recv()).await.expect("Timed out").unwrap();
+        assert_eq!(Event::Json(json!({"a": 1, "env": "prod"})), event);
+
+        // what flush returns is sent along
+        let (event, _, _) = timeout(Duration::from_secs(5), output.recv()).await.expect("Timed out").unwrap();
+        assert_eq!(Event::Json(json!({"flushed": true})), event);
+        assert!(!shutdown_path.exists());
+
+        // shutdown is called once the tripwire trips
+        trigger.cancel();
+        jh.await.unwrap();
+        assert_eq!("done", fs::read_to_string(&shutdown_path).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_after_error() {
+        init_test_logger();
+        let (_trigger, tripwire) = Tripwire::new();
+        let shutdown_path = tempfile::TempDir::new().unwrap().into_path().join("shutdown");
+
+        let mut transform = script(HOOKS, hooks_args(&shutdown_path).as_str(), tripwire).await.expect("Error creating PythonScript");
+        let (sender, receiver) = broadcast::channel(10);
+
+        transform.connect_receiver(receiver);
+
+        let jh = tokio::spawn(async move { transform.run().await });
+
+        // without a dead-letter output, a log that isn't JSON stops the script, but it's still shutdown
+        send(&sender, Event::String("not json".to_string()));
+
+        timeout(Duration::from_secs(5), jh).await.expect("Timed out").unwrap();
+        assert_eq!("done", fs::read_to_string(&shutdown_path).unwrap());
+    }
+
+    #[tokio::test]
+    async fn bad_args() {
+        let (_trigger, tripwire) = Tripwire::new();
+
+        for extra in ["flush_secs = 0", "flush_secs = 3601", "flush_secs = \"60\"", "params = 5", "params = [\"a\"]", "arg_type = \"int\""] {
+            assert!(script(HOOKS, extra, tripwire.clone()).await.is_err(), "{}", extra);
+        }
+    }
+}
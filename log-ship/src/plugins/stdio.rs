@@ -12,6 +12,7 @@ use tokio_stream::{StreamExt as TokioStreamExt};
 use tokio_stream::wrappers::{BroadcastStream, LinesStream};
 use toml::Value;
 
+use crate::codec::OutputCodec;
 use crate::common::logging::{debug, error, warn};
 use crate::{Args, connect_receiver, create_event_stream, create_sender_semaphore, get_receiver, recv_event, send_event};
 use crate::event::Event;
@@ -89,6 +90,7 @@ impl Plugin for StdInput {
 pub struct StdOutput {
     tripwire: Tripwire,
     receiver: Option<Receiver<ChannelType>>,
+    codec: OutputCodec,
 }
 
 #[async_trait]
@@ -97,10 +99,13 @@ impl Plugin for StdOutput {
         "stdout"
     }
 
-    async fn new(_args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+        let codec = OutputCodec::from_args(&args, Self::name())?;
+
         Ok(Box::new(StdOutput {
             tripwire,
             receiver: None, // set in connect_receiver
+            codec,
         }))
     }
 
@@ -117,10 +122,17 @@ impl Plugin for StdOutput {
                 continue
             }
 
-            let line = event.to_string() + "\n";
+            let line = match self.codec.encode(&event) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Error serializing event: {}", e);
+                    callback.call();
+                    continue
+                }
+            };
 
             // write the line, and call the callback
-            stdout().write_all(line.as_bytes()).await.expect("Error writing to STDOUT");
+            stdout().write_all(line.as_slice()).await.expect("Error writing to STDOUT");
             callback.call();
         }
 
@@ -1,23 +1,206 @@
-use anyhow::{anyhow, Result, Context};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result, Context};
 use async_trait::async_trait;
+use futures::future::join_all;
 use stream_cancel::{StreamExt, Tripwire};
-use tokio::io::{BufWriter, AsyncWriteExt};
+use tokio::io::{BufWriter, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpStream};
 use tokio::sync::broadcast::Receiver;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, timeout};
+use toml::Value;
 
 use tokio_stream::{StreamExt as TokioStreamExt};
 use tokio_stream::wrappers::{BroadcastStream};
 
-use crate::common::logging::{debug, error};
+use crate::codec::OutputCodec;
+use crate::common::logging::{debug, error, info, warn};
 use crate::{Args, connect_receiver, create_event_stream, recv_event};
 use crate::event::Event;
-use crate::plugin::{Plugin, PluginType, ChannelType};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback};
+
+/// Max number of events written to an endpoint before flushing
+const FLUSH_EVERY: usize = 1000;
+/// Number of events queued for an endpoint before the route waits
+const ENDPOINT_QUEUE_SIZE: usize = 1024;
 
+/// A serialized event, and its callback
+type Item = (Vec<u8>, Arc<Callback>);
+
+/// How events are spread across the endpoints
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Strategy {
+    /// each event goes to the next healthy endpoint
+    RoundRobin,
+    /// each event goes to the healthy endpoint with the fewest events waiting to be written
+    LeastPending,
+    /// every event goes to the first healthy endpoint
+    Failover,
+}
+
+impl Strategy {
+    fn parse(strategy: &str) -> Result<Self> {
+        match strategy {
+            "round_robin" => Ok(Strategy::RoundRobin),
+            "least_pending" => Ok(Strategy::LeastPending),
+            "failover" => Ok(Strategy::Failover),
+            _ => bail!("Unknown strategy '{}'; use one of: round_robin, least_pending, failover", strategy)
+        }
+    }
+}
+
+/// State of an endpoint, shared between the output and the endpoint's task
+struct EndpointState {
+    addr: String,
+    healthy: AtomicBool,
+    /// events sent to the endpoint that haven't been written & flushed
+    pending: AtomicUsize,
+}
 
 pub struct TcpSocketOutput {
     tripwire: Tripwire,
-    tcp_stream: BufWriter<TcpStream>,
     receiver: Option<Receiver<ChannelType>>,
+    endpoints: Vec<Arc<EndpointState>>,
+    // the connections made in new, taken in run
+    connections: Vec<Option<TcpStream>>,
+    strategy: Strategy,
+    health_check: Duration,
+    next: usize,
+    codec: OutputCodec,
+}
+
+impl TcpSocketOutput {
+    /// Picks a healthy endpoint according to the strategy
+    fn choose(&mut self) -> Option<usize> {
+        let healthy = |i: &usize| self.endpoints[*i].healthy.load(Ordering::SeqCst);
+        let len = self.endpoints.len();
+
+        match self.strategy {
+            Strategy::RoundRobin => {
+                let i = (0..len).map(|i| (self.next + i) % len).find(healthy)?;
+                self.next = (i + 1) % len;
+                Some(i)
+            }
+            Strategy::LeastPending => {
+                (0..len).filter(healthy).min_by_key(|i| self.endpoints[*i].pending.load(Ordering::SeqCst))
+            }
+            Strategy::Failover => (0..len).find(healthy)
+        }
+    }
+
+    /// Writes items from the queue to the endpoint until the queue is closed, returning None.
+    /// If the connection fails, the items that were not flushed are returned.
+    async fn write_items(state: &EndpointState, stream: TcpStream, items: &mut mpsc::Receiver<Item>) -> Option<Vec<Item>> {
+        let (mut reader, writer) = stream.into_split();
+        let mut writer = BufWriter::new(writer);
+        let mut unflushed: Vec<Item> = Vec::new();
+        let mut buf = [0_u8; 1024];
+
+        loop {
+            let res = tokio::select! {
+                item = items.recv() => {
+                    let (line, callback) = match item {
+                        Some(item) => item,
+                        None => {
+                            if let Err(e) = writer.flush().await {
+                                error!("Error flushing to {}: {:?}", state.addr, e);
+                            } else {
+                                unflushed.into_iter().for_each(|(_, cb)| cb.call());
+                            }
+
+                            return None
+                        }
+                    };
+
+                    let mut res = writer.write_all(line.as_slice()).await;
+                    unflushed.push((line, callback));
+
+                    // flush once there is nothing else waiting to be written
+                    if res.is_ok() && (state.pending.load(Ordering::SeqCst) <= unflushed.len() || unflushed.len() >= FLUSH_EVERY) {
+                        res = writer.flush().await;
+
+                        if res.is_ok() {
+                            state.pending.fetch_sub(unflushed.len(), Ordering::SeqCst);
+                            unflushed.drain(..).for_each(|(_, cb)| cb.call());
+                        }
+                    }
+
+                    res
+                }
+                // nothing should be sent to us, but a read tells us when the remote closes the connection
+                read = reader.read(&mut buf) => {
+                    match read {
+                        Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by remote")),
+                        Ok(_) => Ok( () ),
+                        Err(e) => Err(e)
+                    }
+                }
+            };
+
+            if let Err(e) = res {
+                warn!("Error writing to {}: {}", state.addr, e);
+                return Some(unflushed)
+            }
+        }
+    }
+
+    /// Writes the items sent to an endpoint, reconnecting when the connection fails.
+    /// While the endpoint is down, any items sent to it are handed back to be sent elsewhere.
+    async fn run_endpoint(state: Arc<EndpointState>, mut stream: Option<TcpStream>, mut items: mpsc::Receiver<Item>, retry: mpsc::UnboundedSender<Item>, health_check: Duration, tripwire: Tripwire) {
+        let mut next_check = Instant::now() + health_check;
+
+        loop {
+            let connection = match stream.take() {
+                Some(s) => s,
+                None => {
+                    tokio::select! {
+                        item = items.recv() => {
+                            match item {
+                                Some(item) => {
+                                    state.pending.fetch_sub(1, Ordering::SeqCst);
+                                    let _ = retry.send(item);
+                                }
+                                None => return
+                            }
+                        }
+                        _ = tokio::time::sleep_until(next_check) => {
+                            next_check = Instant::now() + health_check;
+
+                            match timeout(health_check, TcpStream::connect(state.addr.as_str())).await {
+                                Ok(Ok(s)) => stream = Some(s),
+                                Ok(Err(e)) => debug!("Health check for {} failed: {}", state.addr, e),
+                                Err(_) => debug!("Health check for {} timed out", state.addr)
+                            }
+                        }
+                        _ = tripwire.clone() => return
+                    }
+
+                    continue
+                }
+            };
+
+            info!("Connected to {}", state.addr);
+            state.healthy.store(true, Ordering::SeqCst);
+
+            let unflushed = match TcpSocketOutput::write_items(&state, connection, &mut items).await {
+                Some(unflushed) => unflushed,
+                None => return
+            };
+
+            // take the endpoint out of rotation, and send what wasn't written elsewhere
+            state.healthy.store(false, Ordering::SeqCst);
+            state.pending.fetch_sub(unflushed.len(), Ordering::SeqCst);
+            next_check = Instant::now() + health_check;
+
+            for item in unflushed {
+                let _ = retry.send(item);
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -29,55 +212,267 @@ impl Plugin for TcpSocketOutput {
     async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> where Self: Sized {
         debug!("TcpSocket args: {:#?}", args);
 
-        // grab the host and port
-        let host = args.get("host").ok_or_else(|| anyhow!("Could not find 'host' arg for {}", Self::name()))?;
-        let host = host.as_str().ok_or_else(|| anyhow!("The 'host' arg for {} does not appear to be a string", Self::name()))?;
-        let port = args.get("port").ok_or_else(|| anyhow!("Could not find 'port' arg for {}", Self::name()))?;
-        let port = port.as_integer().ok_or_else(|| anyhow!("The 'port' arg for {} does not appear to be an integer", Self::name()))?;
+        // grab the endpoints, or the host and port
+        let addrs = match args.get("endpoints") {
+            Some(Value::String(s)) => vec![s.clone()],
+            Some(Value::Array(a)) => {
+                a.iter()
+                 .map(|e| e.as_str().map(|s| s.to_string()))
+                 .collect::<Option<Vec<_>>>()
+                 .ok_or_else(|| anyhow!("Found non-string endpoint in 'endpoints' arg for {}", Self::name()))?
+            }
+            Some(_) => bail!("The 'endpoints' arg for {} must be a string or an array of strings", Self::name()),
+            None => {
+                let host = args.get("host").ok_or_else(|| anyhow!("Could not find 'host' or 'endpoints' arg for {}", Self::name()))?;
+                let host = host.as_str().ok_or_else(|| anyhow!("The 'host' arg for {} does not appear to be a string", Self::name()))?;
+                let port = args.get("port").ok_or_else(|| anyhow!("Could not find 'port' arg for {}", Self::name()))?;
+                let port = port.as_integer().ok_or_else(|| anyhow!("The 'port' arg for {} does not appear to be an integer", Self::name()))?;
+
+                vec![format!("{}:{}", host, port)]
+            }
+        };
+
+        if addrs.is_empty() {
+            bail!("The 'endpoints' arg for {} must contain at least one host:port", Self::name());
+        }
+
+        let strategy = args.get("strategy").unwrap_or(&Value::String("round_robin".to_string())).to_owned();
+        let strategy = strategy.as_str().ok_or_else(|| anyhow!("The 'strategy' arg for {} does not appear to be a string", Self::name()))?;
+        let strategy = Strategy::parse(strategy).with_context(|| format!("Parsing the 'strategy' arg for {}", Self::name()))?;
+
+        let health_check = args.get("health_check_secs").unwrap_or(&Value::Integer(5));
+        let health_check = health_check.as_integer().ok_or_else(|| anyhow!("The 'health_check_secs' arg for {} does not appear to be an integer", Self::name()))?;
+
+        if !(1..=3600).contains(&health_check) {
+            bail!("Nonsensical value {} for 'health_check_secs' for {}; should be between 1 and 3600", health_check, Self::name());
+        }
+
+        let codec = OutputCodec::from_args(&args, Self::name())?;
 
-        let tcp_stream = TcpStream::connect((host, port as u16)).await.with_context(|| format!("Connecting to remote host: {}:{}", host, port))?;
-        let tcp_stream = BufWriter::new(tcp_stream);
+        // connect to all the endpoints; ones that are down are retried by the health check
+        let mut endpoints = Vec::with_capacity(addrs.len());
+        let mut connections = Vec::with_capacity(addrs.len());
+        let mut last_err = None;
+
+        for addr in addrs {
+            let connection = match TcpStream::connect(addr.as_str()).await.with_context(|| format!("Connecting to remote host: {}", addr)) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    warn!("{:?}", e);
+                    last_err = Some(e);
+                    None
+                }
+            };
+
+            endpoints.push(Arc::new(EndpointState {
+                addr,
+                healthy: AtomicBool::new(connection.is_some()),
+                pending: AtomicUsize::new(0),
+            }));
+            connections.push(connection);
+        }
+
+        if let (true, Some(e)) = (connections.iter().all(|c| c.is_none()), last_err) {
+            return Err(e);
+        }
 
         Ok(Box::new(TcpSocketOutput {
             tripwire,
-            tcp_stream,
             receiver: None, // set in connect_receiver
+            endpoints,
+            connections,
+            strategy,
+            health_check: Duration::from_secs(health_check as u64),
+            next: 0,
+            codec,
         }))
     }
 
     async fn run(&mut self) {
         let mut event_stream = create_event_stream!(self);
+        let (retry_tx, mut retry_rx) = mpsc::unbounded_channel();
+        let mut senders = Vec::with_capacity(self.endpoints.len());
+        let mut join_handles = Vec::with_capacity(self.endpoints.len());
 
-        while let Some(event) = event_stream.next().await {
-            let (event, callback) = recv_event!(event);
+        for (state, connection) in self.endpoints.iter().zip(self.connections.drain(..)) {
+            let (sender, items) = mpsc::channel(ENDPOINT_QUEUE_SIZE);
 
-            // skip the None events
-            if Event::None == event {
-                callback.call();
-                continue;
-            }
+            senders.push(sender);
+            join_handles.push(tokio::spawn(TcpSocketOutput::run_endpoint(
+                state.clone(),
+                connection,
+                items,
+                retry_tx.clone(),
+                self.health_check,
+                self.tripwire.clone()
+            )));
+        }
 
-            let event_str = event.to_string();
+        // only the endpoints hand back items
+        drop(retry_tx);
 
-            if let Err(e) = self.tcp_stream.write_all(event_str.as_bytes()).await {
-                error!("Error writing to tcp_stream: {:?}", e);
-                return;
-            }
+        'events: loop {
+            let item = tokio::select! {
+                // send items from failed endpoints first
+                biased;
+                Some(item) = retry_rx.recv() => item,
+                event = event_stream.next() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break
+                    };
+                    let (event, callback) = recv_event!(event);
+
+                    // skip the None events
+                    if Event::None == event {
+                        callback.call();
+                        continue;
+                    }
+
+                    match self.codec.encode(&event) {
+                        Ok(b) => (b, callback),
+                        Err(e) => {
+                            warn!("Error serializing event: {}", e);
+                            callback.call();
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            // wait for an endpoint to become healthy
+            let i = loop {
+                if let Some(i) = self.choose() {
+                    break i
+                }
+
+                warn!("No healthy endpoints, waiting...");
 
-            if let Err(e) = self.tcp_stream.write_all("\n".as_bytes()).await {
-                error!("Error writing to tcp_stream: {:?}", e);
+                if timeout(self.health_check, self.tripwire.clone()).await.is_ok() {
+                    break 'events
+                }
+            };
+
+            self.endpoints[i].pending.fetch_add(1, Ordering::SeqCst);
+
+            if senders[i].send(item).await.is_err() {
+                error!("Endpoint {} is no longer running", self.endpoints[i].addr);
                 return;
             }
-
-            // call the callback
-            callback.call();
         }
 
-        if let Err(e) = self.tcp_stream.flush().await {
-            error!("Error flushing tcp_stream: {:?}", e);
-        }
+        // closing the queues causes the endpoints to flush and exit
+        drop(senders);
+        join_all(join_handles).await;
     }
 
     // boilerplate method
     connect_receiver!{}
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tcp_socket_tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::json;
+    use stream_cancel::Tripwire;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{broadcast, Semaphore};
+    use tokio::sync::broadcast::Sender;
+    use tokio::time::timeout;
+    use toml::Value;
+
+    use crate::common::init_test_logger;
+    use crate::event::Event;
+    use crate::plugin::{Args, Callback, ChannelType, Plugin};
+    use crate::plugins::TcpSocketOutput;
+
+    async fn create_output(endpoints: &[&TcpListener], strategy: &str, tripwire: Tripwire) -> (tokio::task::JoinHandle<()>, Sender<ChannelType>) {
+        let mut args = Args::new();
+        let endpoints = endpoints.iter().map(|l| Value::String(l.local_addr().unwrap().to_string())).collect();
+
+        args.insert("endpoints".to_string(), Value::Array(endpoints));
+        args.insert("strategy".to_string(), Value::String(strategy.to_string()));
+        args.insert("health_check_secs".to_string(), Value::Integer(1));
+
+        let mut output = TcpSocketOutput::new(args, tripwire).await.expect("Error creating TcpSocketOutput");
+        let (sender, receiver) = broadcast::channel(10);
+
+        output.connect_receiver(receiver);
+
+        (tokio::spawn(async move { output.run().await }), sender)
+    }
+
+    async fn send(sender: &Sender<ChannelType>, i: i64) {
+        let permit = Arc::new(Semaphore::new(1)).acquire_owned().await.unwrap();
+        sender.send((Event::Json(json!({"i": i})), Arc::new(permit), Arc::new(Callback::empty()))).expect("Error sending");
+    }
+
+    async fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        timeout(Duration::from_secs(5), reader.read_line(&mut line)).await.expect("Timed out reading").expect("Error reading");
+        line
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let listener1 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener2 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (jh, sender) = create_output(&[&listener1, &listener2], "round_robin", tripwire).await;
+        let mut reader1 = BufReader::new(listener1.accept().await.unwrap().0);
+        let mut reader2 = BufReader::new(listener2.accept().await.unwrap().0);
+
+        for i in 0..4 {
+            send(&sender, i).await;
+        }
+
+        assert_eq!("{\"i\":0}\n", read_line(&mut reader1).await);
+        assert_eq!("{\"i\":1}\n", read_line(&mut reader2).await);
+        assert_eq!("{\"i\":2}\n", read_line(&mut reader1).await);
+        assert_eq!("{\"i\":3}\n", read_line(&mut reader2).await);
+
+        trigger.cancel();
+        jh.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        init_test_logger();
+        let (trigger, tripwire) = Tripwire::new();
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary_addr = primary.local_addr().unwrap();
+        let secondary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (jh, sender) = create_output(&[&primary, &secondary], "failover", tripwire).await;
+        let mut primary_reader = BufReader::new(primary.accept().await.unwrap().0);
+        let mut secondary_reader = BufReader::new(secondary.accept().await.unwrap().0);
+
+        send(&sender, 0).await;
+        assert_eq!("{\"i\":0}\n", read_line(&mut primary_reader).await);
+
+        // take down the primary
+        drop(primary_reader);
+        drop(primary);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        send(&sender, 1).await;
+        assert_eq!("{\"i\":1}\n", read_line(&mut secondary_reader).await);
+
+        // bring the primary back, and wait for the health check to put it back into rotation
+        let primary = TcpListener::bind(primary_addr).await.unwrap();
+        let mut primary_reader = BufReader::new(timeout(Duration::from_secs(5), primary.accept()).await.unwrap().unwrap().0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        send(&sender, 2).await;
+        assert_eq!("{\"i\":2}\n", read_line(&mut primary_reader).await);
+
+        trigger.cancel();
+        jh.await.unwrap();
+    }
+}
@@ -12,7 +12,7 @@ use tokio_util::udp::UdpFramed;
 use crate::common::logging::{debug, error};
 use crate::{Args, connect_receiver, create_event_stream, create_sender_semaphore, get_receiver, recv_event};
 use crate::event::Event;
-use crate::plugin::{Plugin, PluginType, ChannelType, Callback};
+use crate::plugin::{Plugin, PluginType, ChannelType, Callback, DeadLetter};
 
 
 pub struct UdpSocketInput {
@@ -20,7 +20,8 @@ pub struct UdpSocketInput {
     semaphore: Arc<Semaphore>,
     udp_stream: TakeUntilIf<UdpFramed<LinesCodec>, Tripwire>,
     tripwire: Tripwire,
-    try_parse: bool
+    try_parse: bool,
+    dead_letter: Option<DeadLetter>,
 }
 
 #[async_trait]
@@ -55,6 +56,7 @@ impl Plugin for UdpSocketInput {
             udp_stream,
             tripwire,
             try_parse,
+            dead_letter: None, // set in connect_dead_letter
         }))
     }
 
@@ -70,6 +72,7 @@ impl Plugin for UdpSocketInput {
                     let channel_clone = self.sender.clone();
                     let semaphore_clone = self.semaphore.clone();
                     let cb = no_op_callback.clone();
+                    let dead_letter = self.dead_letter.clone();
                     let permit = match semaphore_clone.acquire_owned().await {
                         Ok(p) => p,
                         Err(_) => return
@@ -81,7 +84,15 @@ impl Plugin for UdpSocketInput {
                         let json = match serde_json::from_str(line.as_str()) {
                             Ok(j) => j,
                             Err(e) => {
-                                error!("Error parsing JSON: {:?}", e);
+                                match dead_letter {
+                                    Some(dead_letter) => {
+                                        // hold the permit until the dead letter is sent, so the input still applies back pressure
+                                        dead_letter.send(UdpSocketInput::name(), e, Event::String(line), cb).await;
+                                        drop(permit);
+                                    }
+                                    None => error!("Error parsing JSON: {:?}", e)
+                                }
+
                                 return;
                             }
                         };
@@ -107,4 +118,8 @@ impl Plugin for UdpSocketInput {
 
     // boilerplate method
     get_receiver!{}
+
+    fn connect_dead_letter(&mut self, dead_letter: DeadLetter) {
+        self.dead_letter.replace(dead_letter);
+    }
 }
\ No newline at end of file
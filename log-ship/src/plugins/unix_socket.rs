@@ -11,7 +11,8 @@ use tokio::sync::broadcast::Receiver;
 use tokio_stream::{StreamExt as TokioStreamExt};
 use tokio_stream::wrappers::{BroadcastStream};
 
-use crate::common::logging::{debug, error, info};
+use crate::codec::OutputCodec;
+use crate::common::logging::{debug, error, info, warn};
 use crate::duration_ms;
 use crate::{Args, connect_receiver, create_event_stream, recv_event};
 use crate::event::Event;
@@ -22,6 +23,7 @@ pub struct UnixSocketOutput {
     tripwire: Tripwire,
     socket: BufWriter<UnixStream>,
     receiver: Option<Receiver<ChannelType>>,
+    codec: OutputCodec,
 }
 
 #[async_trait]
@@ -38,11 +40,13 @@ impl Plugin for UnixSocketOutput {
         let file_path = file_path.as_str().ok_or_else(|| anyhow!("The 'path' arg for UnixSocketOutput does not appear to be a string"))?;
         let socket = BufWriter::new(UnixStream::connect(file_path).await
             .with_context(|| format!("opening Unix socket {}", file_path))?);
+        let codec = OutputCodec::from_args(&args, Self::name())?;
 
         Ok(Box::new(UnixSocketOutput {
             tripwire,
             socket,
             receiver: None, // set in connect_receiver
+            codec,
         }))
     }
 
@@ -62,14 +66,16 @@ impl Plugin for UnixSocketOutput {
                 continue;
             }
 
-            let event_str = event.to_string();
+            let event_bytes = match self.codec.encode(&event) {
+                Ok(b) => b,
+                Err(e) => {
+                    warn!("Error serializing event: {}", e);
+                    callback.call();
+                    continue;
+                }
+            };
 
-            if let Err(e) = self.socket.write_all(event_str.as_bytes()).await {
-                error!("Error writing to socket: {:?}", e);
-                return;
-            }
-
-            if let Err(e) = self.socket.write_all("\n".as_bytes()).await {
+            if let Err(e) = self.socket.write_all(event_bytes.as_slice()).await {
                 error!("Error writing to socket: {:?}", e);
                 return;
             }
@@ -0,0 +1,359 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone, Utc};
+use regex::Regex;
+use serde_json::Map;
+use stream_cancel::{StreamExt, Tripwire};
+use tokio::sync::{broadcast, Semaphore};
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_stream::{StreamExt as TokioStreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use toml::Value;
+
+use crate::common::logging::{debug, error};
+use crate::event::{Event, JsonValue};
+use crate::{Args, Plugin, send_event, connect_receiver, create_event_stream, get_receiver, recv_event, create_sender_semaphore};
+use crate::plugin::{PluginType, ChannelType};
+use crate::plugins::grok::NoMatch;
+
+// pattern for: %t \"%r\" %>s %O \"%{Referer}i\" \"%{User-Agent}i\"
+const COMBINED_PATTERN: &str = r#"^\[(.+)] "([A-Z]+) (.+) (.+)" (\d+) (\d+) "(.+)" "(.+)""#;
+// pattern for: %t \"%r\" %>s %O
+const COMMON_PATTERN: &str = r#"^\[(.+)] "([A-Z]+) (.+) (.+)" (\d+) (\d+)"#;
+// pattern for: 2023/07/07 14:02:12 [error] 1234#5678: *1 message
+const NGINX_ERROR_PATTERN: &str = r"^(\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2}) \[(\w+)\] (\d+)#(\d+): (?:\*(\d+) )?(.*)$";
+const NGINX_CLIENT_PATTERN: &str = r", client: ([^,]+)";
+
+#[derive(Debug, Clone)]
+enum Format {
+    Combined(Regex),
+    Common(Regex),
+    NginxError(Regex, Regex),
+    ApacheError,
+}
+
+/// Parses the access & error logs of Apache httpd and Nginx
+#[derive(Debug)]
+struct WebServerParser {
+    format: Format,
+    local_time: bool, // error logs don't include a timezone
+    field: String,
+    no_match: NoMatch,
+}
+
+impl WebServerParser {
+    fn from_args(args: &Args, plugin_name: &str) -> Result<Self> {
+        let format = args.get("format").ok_or_else(|| anyhow!("Could not find 'format' arg for {}", plugin_name))?;
+        let format = format.as_str().ok_or_else(|| anyhow!("The 'format' arg for {} does not appear to be a string", plugin_name))?;
+
+        let format = match format {
+            "combined" => Format::Combined(Regex::new(COMBINED_PATTERN)?),
+            "common" => Format::Common(Regex::new(COMMON_PATTERN)?),
+            "nginx_error" => Format::NginxError(Regex::new(NGINX_ERROR_PATTERN)?, Regex::new(NGINX_CLIENT_PATTERN)?),
+            "apache_error" => Format::ApacheError,
+            _ => bail!("Unknown format '{}' for {}; use one of: combined, common, nginx_error, apache_error", format, plugin_name)
+        };
+
+        let timezone = args.get("timezone").unwrap_or(&Value::String("local".to_string())).to_owned();
+        let local_time = match timezone.as_str() {
+            Some("local") => true,
+            Some("utc") => false,
+            _ => bail!("The 'timezone' arg for {} must be either 'local' or 'utc'", plugin_name)
+        };
+
+        let field = args.get("field").unwrap_or(&Value::String("message".to_string())).to_owned();
+        let field = field.as_str().ok_or_else(|| anyhow!("The 'field' arg for {} does not appear to be a string", plugin_name))?.to_string();
+
+        let no_match = NoMatch::from_args(args, plugin_name, "drop")?;
+
+        Ok(WebServerParser { format, local_time, field, no_match })
+    }
+
+    /// Parses the log, returning None if the event should be dropped
+    fn process(&self, event: Event) -> Option<Event> {
+        let obj = match event {
+            // unparsed logs are replaced with the parsed fields
+            Event::String(s) => {
+                if let Some(parsed) = self.parse(s.as_str()) {
+                    return Some(Event::Json(JsonValue::Object(parsed)))
+                }
+
+                let mut obj = Map::new();
+                obj.insert(self.field.clone(), JsonValue::from(s));
+                obj
+            }
+            Event::Json(JsonValue::Object(mut obj)) => {
+                if let Some(parsed) = obj.get(self.field.as_str()).and_then(|v| v.as_str()).and_then(|s| self.parse(s)) {
+                    obj.extend(parsed);
+                    return Some(Event::Json(JsonValue::Object(obj)))
+                }
+
+                obj
+            }
+            event => return Some(event)
+        };
+
+        self.no_match.apply(obj).map(|obj| Event::Json(JsonValue::Object(obj)))
+    }
+
+    /// Converts a time without a timezone to epoch milliseconds
+    fn naive_to_millis(&self, naive: &NaiveDateTime) -> Option<i64> {
+        if self.local_time {
+            Local.from_local_datetime(naive).earliest().map(|dt| dt.timestamp_millis())
+        } else {
+            Some(Utc.from_utc_datetime(naive).timestamp_millis())
+        }
+    }
+
+    fn parse(&self, log: &str) -> Option<Map<String, JsonValue>> {
+        match &self.format {
+            Format::Combined(re) => Self::parse_access(log, re, true),
+            Format::Common(re) => Self::parse_access(log, re, false),
+            Format::NginxError(re, client_re) => self.parse_nginx_error(log, re, client_re),
+            Format::ApacheError => self.parse_apache_error(log),
+        }
+    }
+
+    /// Parses "combined" or "common" logs from Apache httpd 2.4 and Nginx
+    /// See: https://httpd.apache.org/docs/2.4/logs.html#accesslog
+    /// and: https://docs.nginx.com/nginx/admin-guide/monitoring/logging/
+    fn parse_access(log: &str, re: &Regex, combined: bool) -> Option<Map<String, JsonValue>> {
+        let mut ret = Map::new();
+
+        // first split on space to get %h %l %u and rest
+        let parts = log.splitn(4, ' ').collect::<Vec<_>>();
+
+        if parts.len() != 4 {
+            return None
+        }
+
+        ret.insert("host".to_string(), JsonValue::from(parts[0]));
+
+        if parts[2] != "-" {
+            ret.insert("user".to_string(), JsonValue::from(parts[2]));
+        }
+
+        let caps = re.captures(parts[3])?;
+        let t = DateTime::parse_from_str(&caps[1], "%d/%b/%Y:%H:%M:%S %z").ok()?;
+
+        ret.insert("t".to_string(), JsonValue::from(t.timestamp_millis()));
+        ret.insert("method".to_string(), JsonValue::from(&caps[2]));
+        ret.insert("path".to_string(), JsonValue::from(&caps[3]));
+        // remove the redundant HTTP/ prefix
+        ret.insert("proto".to_string(), JsonValue::from(caps[4].replace("HTTP/", "")));
+        ret.insert("status".to_string(), JsonValue::from(caps[5].parse::<i64>().ok()?));
+        ret.insert("size".to_string(), JsonValue::from(caps[6].parse::<i64>().ok()?));
+
+        if combined {
+            if &caps[7] != "-" {
+                ret.insert("ref".to_string(), JsonValue::from(&caps[7]));
+            }
+
+            ret.insert("user_agent".to_string(), JsonValue::from(&caps[8]));
+        }
+
+        Some(ret)
+    }
+
+    /// Parses the error logs from Apache httpd 2.4
+    /// See: https://httpd.apache.org/docs/2.4/logs.html#errorlog
+    /// and: https://httpd.apache.org/docs/2.4/mod/core.html#errorlogformat
+    fn parse_apache_error(&self, log: &str) -> Option<Map<String, JsonValue>> {
+        let mut ret = Map::new();
+        let parts = log.splitn(4, "] ").collect::<Vec<_>>();
+
+        if parts.len() != 4 {
+            return None
+        }
+
+        let (date, level, pid, rest) = (parts[0].replace('[', ""), parts[1].replace('[', ""), parts[2], parts[3]);
+
+        // Wed Oct 11 14:32:52.123456 2000
+        let t = NaiveDateTime::parse_from_str(date.as_str(), "%a %b %d %H:%M:%S%.f %Y").ok()?;
+
+        ret.insert("t".to_string(), JsonValue::from(self.naive_to_millis(&t)?));
+        ret.insert("level".to_string(), JsonValue::from(level));
+
+        // some formats have a tid as well
+        match pid.split_once(':') {
+            Some((pid, tid)) => {
+                ret.insert("pid".to_string(), JsonValue::from(pid.replace("[pid ", "").parse::<i64>().ok()?));
+                ret.insert("tid".to_string(), JsonValue::from(tid.replace("tid ", "").parse::<i64>().ok()?));
+            }
+            None => {
+                ret.insert("pid".to_string(), JsonValue::from(pid.replace("[pid ", "").parse::<i64>().ok()?));
+            }
+        }
+
+        if rest.starts_with("[client") {
+            let (client, message) = rest.split_once("] ")?;
+
+            ret.insert("client".to_string(), JsonValue::from(client.replace("[client ", "")));
+            ret.insert("message".to_string(), JsonValue::from(message));
+        } else {
+            ret.insert("message".to_string(), JsonValue::from(rest));
+        }
+
+        Some(ret)
+    }
+
+    /// Parses the error logs from Nginx
+    /// See: https://nginx.org/en/docs/ngx_core_module.html#error_log
+    fn parse_nginx_error(&self, log: &str, re: &Regex, client_re: &Regex) -> Option<Map<String, JsonValue>> {
+        let mut ret = Map::new();
+        let caps = re.captures(log)?;
+        let t = NaiveDateTime::parse_from_str(&caps[1], "%Y/%m/%d %H:%M:%S").ok()?;
+
+        ret.insert("t".to_string(), JsonValue::from(self.naive_to_millis(&t)?));
+        ret.insert("level".to_string(), JsonValue::from(&caps[2]));
+        ret.insert("pid".to_string(), JsonValue::from(caps[3].parse::<i64>().ok()?));
+        ret.insert("tid".to_string(), JsonValue::from(caps[4].parse::<i64>().ok()?));
+
+        if let Some(cid) = caps.get(5) {
+            ret.insert("cid".to_string(), JsonValue::from(cid.as_str().parse::<i64>().ok()?));
+        }
+
+        if let Some(client) = client_re.captures(&caps[6]) {
+            ret.insert("client".to_string(), JsonValue::from(&client[1]));
+        }
+
+        ret.insert("message".to_string(), JsonValue::from(&caps[6]));
+
+        Some(ret)
+    }
+}
+
+pub struct WebServerTransform {
+    tripwire: Tripwire,
+    receiver: Option<Receiver<ChannelType>>,
+    sender: Sender<ChannelType>,
+    semaphore: Arc<Semaphore>,
+    parser: WebServerParser,
+}
+
+#[async_trait]
+impl Plugin for WebServerTransform {
+    fn name() -> &'static str {
+        "web_server"
+    }
+
+    async fn new(args: Args, tripwire: Tripwire) -> Result<Box<PluginType>> {
+        debug!("WebServerTransform args: {:#?}", args);
+
+        let parser = WebServerParser::from_args(&args, Self::name())?;
+
+        // setup the channel
+        let (sender, semaphore) = create_sender_semaphore!(args, tripwire);
+
+        Ok(Box::new(WebServerTransform {
+            tripwire,
+            receiver: None, // set in connect_receiver
+            sender,
+            semaphore,
+            parser,
+        }))
+    }
+
+    async fn run(&mut self) {
+        let mut event_stream = create_event_stream!(self);
+
+        while let Some(event) = event_stream.next().await {
+            let (event, callback) = recv_event!(event);
+
+            if Event::None == event {
+                callback.call();
+                continue // nothing to do here
+            }
+
+            let event = match self.parser.process(event) {
+                Some(event) => event,
+                None => {
+                    // filtered, so call the callback
+                    callback.call();
+                    continue
+                }
+            };
+
+            send_event!(self, event, callback);
+        }
+    }
+
+    // boilerplate methods
+    get_receiver!{}
+    connect_receiver!{}
+}
+
+
+#[cfg(test)]
+mod web_server_tests {
+    use serde_json::json;
+    use toml::Value;
+
+    use crate::Args;
+    use crate::event::{Event, JsonValue};
+    use crate::plugins::web_server::WebServerParser;
+
+    /// Runs each line through the transform, and compares it to the output of web_servers.py (run with TZ=UTC)
+    fn check_golden(format: &str, golden: &[(&str, JsonValue)]) {
+        let mut args = Args::new();
+
+        args.insert("format".to_string(), Value::String(format.to_string()));
+        args.insert("timezone".to_string(), Value::String("utc".to_string()));
+
+        let parser = WebServerParser::from_args(&args, "web_server").expect("Error parsing args");
+
+        for (line, expected) in golden {
+            let expected = match expected {
+                JsonValue::Null => None,
+                json => Some(Event::Json(json.clone()))
+            };
+
+            assert_eq!(expected, parser.process(Event::from(*line)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn combined_golden() {
+        check_golden("combined", &[
+            (r#"192.168.1.7 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)""#,
+             json!({"host": "192.168.1.7", "user": "frank", "t": 971211336000_i64, "method": "GET", "path": "/apache_pb.gif", "proto": "1.0", "status": 200, "size": 2326, "ref": "http://www.example.com/start.html", "user_agent": "Mozilla/4.08 [en] (Win98; I ;Nav)"})),
+            (r#"10.0.0.1 - - [07/Jul/2023:14:02:12 +0000] "POST /api/v1/items?id=3 HTTP/1.1" 201 17 "-" "curl/7.88.1""#,
+             json!({"host": "10.0.0.1", "t": 1688738532000_i64, "method": "POST", "path": "/api/v1/items?id=3", "proto": "1.1", "status": 201, "size": 17, "user_agent": "curl/7.88.1"})),
+            (r#"2001:db8::1 - - [31/Dec/2022:23:59:59 +0100] "GET /a path/with spaces HTTP/2.0" 404 0 "https://example.com/" "Mozilla/5.0 (X11; Linux x86_64)""#,
+             json!({"host": "2001:db8::1", "t": 1672527599000_i64, "method": "GET", "path": "/a path/with spaces", "proto": "2.0", "status": 404, "size": 0, "ref": "https://example.com/", "user_agent": "Mozilla/5.0 (X11; Linux x86_64)"})),
+            (r#"10.0.0.2 - - [07/Jul/2023:14:02:12 +0000] "GET / HTTP/1.1" 304 - "-" "curl/7.88.1""#, JsonValue::Null),
+            ("garbage", JsonValue::Null),
+        ]);
+    }
+
+    #[test]
+    fn apache_error_golden() {
+        check_golden("apache_error", &[
+            ("[Wed Oct 11 14:32:52.123456 2000] [core:error] [pid 35708:tid 4328636416] [client 72.15.99.187:53012] AH00128: File does not exist: /usr/local/apache2/htdocs/favicon.ico",
+             json!({"t": 971274772123_i64, "level": "core:error", "pid": 35708, "tid": 4328636416_i64, "client": "72.15.99.187:53012", "message": "AH00128: File does not exist: /usr/local/apache2/htdocs/favicon.ico"})),
+            ("[Fri Jul 07 14:02:12.000001 2023] [mpm_event:notice] [pid 1:tid 140] AH00489: Apache/2.4.57 (Unix) configured -- resuming normal operations",
+             json!({"t": 1688738532000_i64, "level": "mpm_event:notice", "pid": 1, "tid": 140, "message": "AH00489: Apache/2.4.57 (Unix) configured -- resuming normal operations"})),
+            ("[Sun Dec 31 23:59:59 2023] [ssl:warn] [pid 42] AH01909: server certificate does NOT include an ID which matches the server name",
+             json!({"t": 1704067199000_i64, "level": "ssl:warn", "pid": 42, "message": "AH01909: server certificate does NOT include an ID which matches the server name"})),
+            ("not an error log", JsonValue::Null),
+        ]);
+    }
+
+    #[test]
+    fn nginx_error_and_common() {
+        check_golden("nginx_error", &[
+            (r#"2023/07/07 14:02:12 [error] 1234#5678: *9 open() "/srv/x" failed (2: No such file or directory), client: 10.0.0.1, server: example.com, request: "GET /x HTTP/1.1""#,
+             json!({"t": 1688738532000_i64, "level": "error", "pid": 1234, "tid": 5678, "cid": 9, "client": "10.0.0.1",
+                    "message": r#"open() "/srv/x" failed (2: No such file or directory), client: 10.0.0.1, server: example.com, request: "GET /x HTTP/1.1""#})),
+            ("2023/07/07 14:02:12 [notice] 1#1: start worker processes",
+             json!({"t": 1688738532000_i64, "level": "notice", "pid": 1, "tid": 1, "message": "start worker processes"})),
+        ]);
+
+        check_golden("common", &[
+            (r#"10.0.0.1 - bob [07/Jul/2023:14:02:12 +0000] "GET /index.html HTTP/1.1" 200 512"#,
+             json!({"host": "10.0.0.1", "user": "bob", "t": 1688738532000_i64, "method": "GET", "path": "/index.html", "proto": "1.1", "status": 200, "size": 512})),
+        ]);
+    }
+}
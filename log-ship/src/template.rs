@@ -0,0 +1,264 @@
+//! Templates used to build strings (index names, paths, etc) from an event and its timestamp
+//!
+//! A template is a string with `{field}` references, which are replaced with the value of the field
+//! in the event, and [strftime](https://docs.rs/chrono/latest/chrono/format/strftime/index.html)
+//! specifiers like `%Y-%m-%d`, which are replaced using the event's timestamp.
+//! Use `{{` and `}}` for literal braces.
+//!
+//! When rendering a line of output, values of `{field}` references containing whitespace or quotes are quoted;
+//! `{+field}` references are always written as-is.
+//!
+//! When rendering a path, `/`, `\\` and NUL characters in `{field}` values are replaced with `_`, as are values made
+//! up only of dots, so a field can't point the path at another directory.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use chrono::format::{Item, StrftimeItems};
+
+use crate::event::{Event, JsonValue};
+
+/// Value used when a field referenced in a template is not found in the event
+const MISSING_FIELD: &str = "-";
+
+/// How field values are written when rendering a template
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Render {
+    Plain,
+    Line,
+    Path,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Time(String),
+    Field { name: String, raw: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses a template string, ensuring all field references are closed, and all time specifiers are valid
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut field = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => field.push(c),
+                            None => bail!("Unclosed field reference '{{{}' in template: {}", field, template)
+                        }
+                    }
+
+                    let (name, raw) = match field.strip_prefix('+') {
+                        Some(name) => (name.to_string(), true),
+                        None => (field, false)
+                    };
+
+                    if name.is_empty() {
+                        bail!("Empty field reference in template: {}", template);
+                    }
+
+                    Self::push_literal(&mut parts, &mut literal, template)?;
+                    parts.push(Part::Field { name, raw });
+                }
+                '}' => bail!("Unmatched '}}' in template: {}", template),
+                c => literal.push(c)
+            }
+        }
+
+        Self::push_literal(&mut parts, &mut literal, template)?;
+
+        Ok(Template { parts })
+    }
+
+    /// Adds the current literal to the parts, checking any time specifiers
+    fn push_literal(parts: &mut Vec<Part>, literal: &mut String, template: &str) -> Result<()> {
+        if literal.is_empty() {
+            return Ok( () )
+        }
+
+        let literal = std::mem::take(literal);
+
+        if literal.contains('%') {
+            if StrftimeItems::new(literal.as_str()).any(|i| i == Item::Error) {
+                bail!("Invalid time specifier in template: {}", template);
+            }
+
+            parts.push(Part::Time(literal));
+        } else {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok( () )
+    }
+
+    /// Renders the template given the event and time
+    pub fn render<Tz: TimeZone>(&self, event: &Event, time: &DateTime<Tz>) -> String where Tz::Offset: std::fmt::Display {
+        self.render_impl(event, time, Render::Plain)
+    }
+
+    /// Renders the template as a line of output, quoting `{field}` values that contain whitespace or quotes
+    pub fn render_line<Tz: TimeZone>(&self, event: &Event, time: &DateTime<Tz>) -> String where Tz::Offset: std::fmt::Display {
+        self.render_impl(event, time, Render::Line)
+    }
+
+    /// Renders the template as a path, cleaning `{field}` values so they can't add or climb directories
+    pub fn render_path<Tz: TimeZone>(&self, event: &Event, time: &DateTime<Tz>) -> String where Tz::Offset: std::fmt::Display {
+        self.render_impl(event, time, Render::Path)
+    }
+
+    fn render_impl<Tz: TimeZone>(&self, event: &Event, time: &DateTime<Tz>, mode: Render) -> String where Tz::Offset: std::fmt::Display {
+        let mut ret = String::new();
+
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(s) => ret.push_str(s),
+                Part::Time(fmt) => ret.push_str(time.format(fmt).to_string().as_str()),
+                Part::Field { name, raw } => {
+                    let value = match event {
+                        Event::Json(json) => json.get(name),
+                        _ => None
+                    };
+
+                    match value {
+                        None | Some(JsonValue::Null) => ret.push_str(MISSING_FIELD),
+                        Some(JsonValue::String(s)) if mode == Render::Path => ret.push_str(path_segment(s).as_str()),
+                        Some(JsonValue::String(s)) if mode == Render::Line && !raw && needs_quotes(s) => ret.push_str(JsonValue::from(s.as_str()).to_string().as_str()),
+                        Some(JsonValue::String(s)) => ret.push_str(s),
+                        Some(v) if mode == Render::Path => ret.push_str(path_segment(v.to_string().as_str()).as_str()),
+                        Some(v) => ret.push_str(v.to_string().as_str())
+                    }
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+/// Returns true if the string is empty, or contains whitespace, quotes, or control characters
+pub fn needs_quotes(s: &str) -> bool {
+    s.is_empty() || s.chars().any(|c| c.is_whitespace() || c == '"' || c.is_control())
+}
+
+/// Replaces path separators and NUL characters with `_`, and empty, `.` or `..` values with underscores
+fn path_segment(s: &str) -> String {
+    if s.chars().all(|c| c == '.') {
+        return "_".repeat(s.len().max(1))
+    }
+
+    s.replace(['/', '\\', '\0'], "_")
+}
+
+/// Gets the timestamp of an event from the given field, falling back to the current time.
+/// Integers are treated as epoch milliseconds (or seconds if too small to be milliseconds), and strings as RFC3339.
+pub fn event_time(event: &Event, ts_field: &str) -> DateTime<Utc> {
+    let value = match event {
+        Event::Json(json) => json.get(ts_field),
+        _ => None
+    };
+
+    let time = match value {
+        Some(JsonValue::Number(n)) => {
+            n.as_i64().and_then(|i| {
+                // anything before 1973 in ms is assumed to be seconds
+                if i.abs() < 100_000_000_000 {
+                    Utc.timestamp_opt(i, 0).single()
+                } else {
+                    Utc.timestamp_millis_opt(i).single()
+                }
+            })
+        }
+        Some(JsonValue::String(s)) => DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&Utc)),
+        _ => None
+    };
+
+    time.unwrap_or_else(Utc::now)
+}
+
+
+#[cfg(test)]
+mod template_tests {
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    use crate::event::Event;
+    use crate::template::{event_time, Template};
+
+    #[test]
+    fn render() {
+        let time = Utc.with_ymd_and_hms(2023, 7, 7, 14, 2, 12).unwrap();
+        let event = Event::Json(json!({"host": "web1", "status": 200}));
+
+        let template = Template::parse("logs-%Y.%m.%d").expect("Error parsing template");
+        assert_eq!("logs-2023.07.07", template.render(&event, &time));
+
+        let template = Template::parse("/archive/{host}/%Y-%m-%d.log").expect("Error parsing template");
+        assert_eq!("/archive/web1/2023-07-07.log", template.render(&event, &time));
+
+        let template = Template::parse("{host}:{status} {missing} {{literal}}").expect("Error parsing template");
+        assert_eq!("web1:200 - {literal}", template.render(&event, &time));
+    }
+
+    #[test]
+    fn render_line() {
+        let time = Utc.with_ymd_and_hms(2023, 7, 7, 14, 2, 12).unwrap();
+        let event = Event::Json(json!({"host": "web1", "severity": "the worst", "message": "disk \"sda\" is full"}));
+        let template = Template::parse("{host} {severity}: {+message}").expect("Error parsing template");
+
+        assert_eq!("web1 \"the worst\": disk \"sda\" is full", template.render_line(&event, &time));
+        assert_eq!("web1 the worst: disk \"sda\" is full", template.render(&event, &time));
+    }
+
+    #[test]
+    fn render_path() {
+        let time = Utc.with_ymd_and_hms(2023, 7, 7, 14, 2, 12).unwrap();
+        let template = Template::parse("/archive/{host}/{app}.log").expect("Error parsing template");
+
+        let event = Event::Json(json!({"host": "../../etc/cron.d/x", "app": ".."}));
+        assert_eq!("/archive/.._.._etc_cron.d_x/__.log", template.render_path(&event, &time));
+
+        let event = Event::Json(json!({"host": "web1\0", "app": "..\\app"}));
+        assert_eq!("/archive/web1_/.._app.log", template.render_path(&event, &time));
+
+        let event = Event::Json(json!({"host": "", "app": ["a/b"]}));
+        assert_eq!("/archive/_/[\"a_b\"].log", template.render_path(&event, &time));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Template::parse("logs-{host").is_err());
+        assert!(Template::parse("logs-{}").is_err());
+        assert!(Template::parse("logs-{+}").is_err());
+        assert!(Template::parse("logs-}").is_err());
+        assert!(Template::parse("logs-%Q").is_err());
+    }
+
+    #[test]
+    fn time_from_event() {
+        let expected = Utc.with_ymd_and_hms(2023, 7, 7, 14, 2, 12).unwrap();
+
+        assert_eq!(expected, event_time(&Event::Json(json!({"t": 1688738532000_i64})), "t"));
+        assert_eq!(expected, event_time(&Event::Json(json!({"t": 1688738532})), "t"));
+        assert_eq!(expected, event_time(&Event::Json(json!({"t": "2023-07-07T14:02:12Z"})), "t"));
+    }
+}